@@ -0,0 +1,14 @@
+-- Add down migration script here
+
+DELETE FROM queue;
+ALTER TABLE queue DROP CONSTRAINT queue_pkey;
+ALTER TABLE queue DROP COLUMN test_definition_id;
+ALTER TABLE queue ADD COLUMN test_definition_index INTEGER NOT NULL;
+ALTER TABLE queue ADD PRIMARY KEY (testee_id, test_definition_index);
+
+ALTER TABLE test_metadata
+    DROP COLUMN IF EXISTS test_definition_version,
+    DROP COLUMN IF EXISTS test_definition_id;
+
+DROP TABLE IF EXISTS test_definition_versions;
+DROP TABLE IF EXISTS test_definitions;
@@ -0,0 +1,30 @@
+-- Add up migration script here
+
+-- Test definitions are synced from test_definitions.yaml on startup. Each definition keeps a stable id (matched by test name)
+-- and a new version row is written whenever the yaml content for that test changes.
+CREATE TABLE test_definitions (
+    id UUID PRIMARY KEY DEFAULT (uuid_generate_v4()),
+    test_name VARCHAR NOT NULL UNIQUE,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE test_definition_versions (
+    test_definition_id UUID NOT NULL REFERENCES test_definitions(id),
+    version INTEGER NOT NULL,
+    definition JSONB NOT NULL,                  -- The serialized (ungraded) Test
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (test_definition_id, version)
+);
+
+-- Tests graded before this migration have no recorded definition, so these stay nullable.
+ALTER TABLE test_metadata
+    ADD COLUMN test_definition_id UUID REFERENCES test_definitions(id),
+    ADD COLUMN test_definition_version INTEGER;
+
+-- Queue entries pointed at a position in the yaml file, which cannot be mapped to a definition id. The queue only holds
+-- day-of entries, so it is cleared rather than migrated.
+DELETE FROM queue;
+ALTER TABLE queue DROP CONSTRAINT queue_pkey;
+ALTER TABLE queue DROP COLUMN test_definition_index;
+ALTER TABLE queue ADD COLUMN test_definition_id UUID NOT NULL REFERENCES test_definitions(id);
+ALTER TABLE queue ADD PRIMARY KEY (testee_id, test_definition_id);
@@ -0,0 +1,9 @@
+-- Add up migration script here
+
+-- Admins manage users and test definitions, proctors administer tests, and front desk staff only manage the queue and
+-- view pass/fail lists. Existing accounts keep the powers they had as proctors.
+ALTER TABLE users
+    ADD COLUMN role VARCHAR(20) NOT NULL DEFAULT 'proctor' CHECK (role IN ('admin', 'proctor', 'front_desk'));
+
+-- Someone has to be able to manage the other accounts, so the oldest account becomes the first admin.
+UPDATE users SET role = 'admin' WHERE id = (SELECT id FROM users ORDER BY created_at LIMIT 1);
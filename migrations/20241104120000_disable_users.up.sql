@@ -0,0 +1,4 @@
+-- Add up migration script here
+
+-- Disabled accounts are kept so that the tests they proctored still reference them, but they can no longer log in.
+ALTER TABLE users ADD COLUMN disabled_at TIMESTAMP WITH TIME ZONE;
@@ -0,0 +1,17 @@
+-- Add up migration script here
+
+-- Links a user to an external sign-in identity. Providers identify users by a stable subject, which unlike the email
+-- address never changes, so sign-ins are matched on (provider, subject).
+CREATE TABLE user_identities (
+    id UUID PRIMARY KEY DEFAULT (uuid_generate_v4()),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    provider VARCHAR(50) NOT NULL,
+    subject VARCHAR(255) NOT NULL,
+    -- The email the provider reported at the last sign-in, only for display
+    email VARCHAR(255),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    last_used_at TIMESTAMP WITH TIME ZONE,
+    UNIQUE (provider, subject)
+);
+
+CREATE INDEX user_identities_user_id_idx ON user_identities (user_id);
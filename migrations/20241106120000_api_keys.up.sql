@@ -0,0 +1,15 @@
+-- Add up migration script here
+
+-- Long-lived keys for integrations like a studio website or a check-in kiosk, which can't log in. Only a hash of the key
+-- is stored; the key itself is shown once when it is issued. Keys act for the admin who issued them and can only do
+-- what their scopes allow.
+CREATE TABLE api_keys (
+    id UUID PRIMARY KEY DEFAULT (uuid_generate_v4()),
+    name VARCHAR(100) NOT NULL,
+    key_hash VARCHAR(255) NOT NULL,
+    scopes TEXT[] NOT NULL,
+    created_by UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    last_used_at TIMESTAMP WITH TIME ZONE,
+    revoked_at TIMESTAMP WITH TIME ZONE
+);
@@ -0,0 +1,23 @@
+-- Add up migration script here
+
+-- Results emails waiting to be sent. post_test_form writes a job instead of sending the email itself, and a background
+-- worker sends it, retrying failures with exponential backoff. Jobs that run out of attempts are dead lettered so that
+-- an admin can see why and send them again. The rows double as the email log.
+CREATE TABLE email_jobs (
+    id UUID PRIMARY KEY DEFAULT (uuid_generate_v4()),
+    testee_id UUID NOT NULL REFERENCES testees(id) ON DELETE CASCADE,
+    -- The test whose score report and certificate are attached, if any
+    attached_test_id UUID REFERENCES tests(id) ON DELETE SET NULL,
+    -- The links in the email point here
+    server_root_url TEXT NOT NULL,
+    status VARCHAR(20) NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'sent', 'dead_letter')),
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    last_attempt_at TIMESTAMP WITH TIME ZONE,
+    last_error TEXT,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    sent_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX email_jobs_due_idx ON email_jobs (next_attempt_at) WHERE status = 'pending';
+CREATE INDEX email_jobs_created_at_idx ON email_jobs (created_at);
@@ -0,0 +1,5 @@
+-- Add down migration script here
+
+DROP INDEX IF EXISTS email_jobs_requested_by_idx;
+DROP INDEX IF EXISTS email_jobs_testee_idx;
+ALTER TABLE email_jobs DROP COLUMN IF EXISTS requested_by;
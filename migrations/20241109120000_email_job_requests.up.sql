@@ -0,0 +1,8 @@
+-- Add up migration script here
+
+-- Results emails sent again by hand record who asked for them, so the email log doubles as the audit trail of manual
+-- resends. Emails queued when a test is saved leave this empty. The indexes back the resend rate limits.
+ALTER TABLE email_jobs ADD COLUMN requested_by UUID REFERENCES users(id) ON DELETE SET NULL;
+
+CREATE INDEX email_jobs_testee_idx ON email_jobs (testee_id, created_at);
+CREATE INDEX email_jobs_requested_by_idx ON email_jobs (requested_by, created_at) WHERE requested_by IS NOT NULL;
@@ -0,0 +1,7 @@
+-- Add down migration script here
+
+ALTER TABLE test_sections DROP COLUMN IF EXISTS max_score;
+ALTER TABLE test_sections DROP COLUMN IF EXISTS minimum_percent;
+
+ALTER TABLE test_tables DROP COLUMN IF EXISTS max_score;
+ALTER TABLE test_tables DROP COLUMN IF EXISTS minimum_percent;
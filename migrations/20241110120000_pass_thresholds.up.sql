@@ -0,0 +1,9 @@
+-- Add up migration script here
+
+-- Sections and tables can have their own minimum percent to pass, checked on top of the test's. They are kept with
+-- graded tests so the results page shows the thresholds the test was graded against. Both are empty when not set.
+ALTER TABLE test_tables ADD COLUMN minimum_percent REAL;
+ALTER TABLE test_tables ADD COLUMN max_score INTEGER;
+
+ALTER TABLE test_sections ADD COLUMN minimum_percent REAL;
+ALTER TABLE test_sections ADD COLUMN max_score INTEGER;
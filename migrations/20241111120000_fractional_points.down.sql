@@ -0,0 +1,14 @@
+-- Add down migration script here
+
+-- Fractional points are rounded to the nearest whole point
+ALTER TABLE bonus_items ALTER COLUMN score TYPE INTEGER USING round(score);
+
+ALTER TABLE competencies DROP COLUMN IF EXISTS weight;
+
+ALTER TABLE test_sections DROP COLUMN IF EXISTS weight;
+ALTER TABLE test_sections ALTER COLUMN max_score TYPE INTEGER USING round(max_score);
+
+ALTER TABLE test_tables ALTER COLUMN max_score TYPE INTEGER USING round(max_score);
+
+ALTER TABLE test_metadata ALTER COLUMN achieved_score TYPE INTEGER USING round(achieved_score);
+ALTER TABLE test_metadata ALTER COLUMN max_score TYPE INTEGER USING round(max_score);
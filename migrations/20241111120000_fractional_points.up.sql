@@ -0,0 +1,16 @@
+-- Add up migration script here
+
+-- Scores can be half points and can be weighted per competency and per section, so every column holding points is an
+-- exact NUMERIC instead of an INTEGER. Points are multiples of 0.00001. The weights are kept with graded tests so that
+-- they can be shown and regraded the way they were graded. Both weights are empty for a weight of 1.
+ALTER TABLE test_metadata ALTER COLUMN max_score TYPE NUMERIC(14, 5);
+ALTER TABLE test_metadata ALTER COLUMN achieved_score TYPE NUMERIC(14, 5);
+
+ALTER TABLE test_tables ALTER COLUMN max_score TYPE NUMERIC(14, 5);
+
+ALTER TABLE test_sections ALTER COLUMN max_score TYPE NUMERIC(14, 5);
+ALTER TABLE test_sections ADD COLUMN weight NUMERIC(14, 5);
+
+ALTER TABLE competencies ADD COLUMN weight NUMERIC(14, 5);
+
+ALTER TABLE bonus_items ALTER COLUMN score TYPE NUMERIC(14, 5);
@@ -0,0 +1,752 @@
+use std::sync::Arc;
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+        Path, Query, State,
+    },
+    Extension,
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_extra::extract::{CookieJar, WithRejection};
+use serde::{Deserialize, Serialize};
+use utoipa::{
+    openapi::{
+        security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+        OpenApi as OpenApiDocument,
+    },
+    IntoParams, Modify, OpenApi, ToSchema,
+};
+use uuid::Uuid;
+
+use crate::{
+    auth::{
+        middleware::{check_auth_utility, AuthError, AuthStatus},
+        model::ApiKeyScope,
+    },
+    emails::{enqueue_results_email, request_results_email, ResendEmailError},
+    exam::{
+        handlers::{create_testee, dequeue_testee, enqueue_testee, fetch_test_definition, fetch_test_results_by_id, fetch_testee_by_id, fetch_testee_tests_by_id, fetch_tests_by_status, fetch_unique_test_names, retrieve_queue, save_test_to_database, search_for_testee, TestError},
+        models::{FullTestSummary, Proctor, QueueItem, SubmissionError, Test, TestGradeSummary, TestListItem, Testee},
+        submission::{grade_submission, TestSubmission},
+    },
+    AppState,
+};
+
+// #######################################################################################################################################################
+// Errors
+// #######################################################################################################################################################
+
+/// Every API error is returned as `{"error": message}` with a status code that matches the problem.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> ApiError {
+        ApiError { status, message: message.into() }
+    }
+
+    pub fn not_found(message: impl Into<String>) -> ApiError {
+        ApiError::new(StatusCode::NOT_FOUND, message)
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> ApiError {
+        ApiError::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> ApiError {
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, message)
+    }
+}
+
+/// The body of every error response
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ErrorBody {
+    pub error: String,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(ErrorBody { error: self.message })).into_response()
+    }
+}
+
+impl From<TestError> for ApiError {
+    fn from(error: TestError) -> ApiError {
+        match error {
+            TestError::InternalServerError(e) => ApiError::internal(e),
+            TestError::InvalidTestDefinition(errors) => ApiError::internal(
+                errors.iter().map(|e| e.to_string()).collect::<Vec<_>>().join(" "),
+            ),
+            TestError::InvalidSubmission(e) => e.into(),
+        }
+    }
+}
+
+impl From<SubmissionError> for ApiError {
+    fn from(error: SubmissionError) -> ApiError {
+        ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, error.to_string())
+    }
+}
+
+impl From<ResendEmailError> for ApiError {
+    fn from(error: ResendEmailError) -> ApiError {
+        let status = match error {
+            ResendEmailError::TesteeNotFound | ResendEmailError::TestNotFound => StatusCode::NOT_FOUND,
+            ResendEmailError::TesteeRecentlyEmailed | ResendEmailError::TooManyResends => StatusCode::TOO_MANY_REQUESTS,
+            ResendEmailError::InternalServerError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        ApiError::new(status, error.to_string())
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> ApiError {
+        ApiError::new(rejection.status(), rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> ApiError {
+        ApiError::new(rejection.status(), rejection.body_text())
+    }
+}
+
+impl From<axum_extra::extract::QueryRejection> for ApiError {
+    fn from(rejection: axum_extra::extract::QueryRejection) -> ApiError {
+        ApiError::bad_request(format!("Failed to deserialize query string: {}", rejection))
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> ApiError {
+        ApiError::new(rejection.status(), rejection.body_text())
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(error: sqlx::Error) -> ApiError {
+        ApiError::internal(error.to_string())
+    }
+}
+
+impl From<AuthError> for ApiError {
+    fn from(error: AuthError) -> ApiError {
+        match error {
+            AuthError::NotLoggedIn => ApiError::new(StatusCode::UNAUTHORIZED, "Missing bearer token."),
+            AuthError::InvalidToken => ApiError::new(StatusCode::UNAUTHORIZED, "Invalid bearer token."),
+            AuthError::ExpiredSession => ApiError::new(StatusCode::UNAUTHORIZED, "The token has expired or was revoked. Refresh it with POST /auth/refresh."),
+            AuthError::InvalidUser => ApiError::new(StatusCode::UNAUTHORIZED, "The token's user no longer exists."),
+            AuthError::AccountDisabled => ApiError::new(StatusCode::FORBIDDEN, "This account has been disabled."),
+            AuthError::InsufficientPermissions => ApiError::new(StatusCode::FORBIDDEN, "You do not have permission to do that."),
+            AuthError::InternalServerError(e) => ApiError::internal(e.unwrap_or_else(|| "Something unexpected went wrong.".to_string())),
+            e => ApiError::internal(format!("{:?}", e)),
+        }
+    }
+}
+
+pub type ApiResult<T> = Result<Json<T>, ApiError>;
+
+// #######################################################################################################################################################
+// Auth
+// #######################################################################################################################################################
+
+/// Like require_auth_middleware, but answers with a 401 instead of redirecting to the login page and never refreshes the
+/// session from the refresh token cookie. API clients refresh their own tokens with POST /auth/refresh, or use an API key.
+pub async fn require_api_auth_middleware(
+    cookie_jar: CookieJar,
+    State(data): State<Arc<AppState>>,
+    mut req: Request<Body>,
+    next: Next,
+) -> Response {
+    match check_auth_utility(cookie_jar, data, req.headers()).await {
+        Ok(authorized_user) => {
+            req.extensions_mut().insert(AuthStatus::Authorized(authorized_user));
+            next.run(req).await
+        },
+        Err(e) => ApiError::from(e).into_response(),
+    }
+}
+
+/// Rejects the request unless the caller has the scope. Logged in users have every scope their role allows, see
+/// ApiKeyScope::required_role.
+async fn require_api_scope(scope: ApiKeyScope, req: Request<Body>, next: Next) -> Response {
+    match req.extensions().get::<AuthStatus>() {
+        Some(AuthStatus::Authorized(authorized_user)) if authorized_user.has_scope(scope) => next.run(req).await,
+        Some(AuthStatus::Authorized(_)) => ApiError::new(StatusCode::FORBIDDEN, format!("This needs the {} scope.", scope)).into_response(),
+        _ => ApiError::from(AuthError::NotLoggedIn).into_response(),
+    }
+}
+
+pub async fn require_read_results_scope_middleware(req: Request<Body>, next: Next) -> Response {
+    require_api_scope(ApiKeyScope::ReadResults, req, next).await
+}
+
+pub async fn require_write_queue_scope_middleware(req: Request<Body>, next: Next) -> Response {
+    require_api_scope(ApiKeyScope::WriteQueue, req, next).await
+}
+
+pub async fn require_administer_tests_scope_middleware(req: Request<Body>, next: Next) -> Response {
+    require_api_scope(ApiKeyScope::AdministerTests, req, next).await
+}
+
+// #######################################################################################################################################################
+// Testees
+// #######################################################################################################################################################
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TesteeSearchParams {
+    /// A name or email to search for
+    query: String,
+}
+
+/// Searches testees by name or email, returning at most 50 of the closest matches
+#[utoipa::path(
+    get,
+    path = "/api/v1/testees",
+    tag = "testees",
+    params(TesteeSearchParams),
+    responses(
+        (status = 200, description = "The closest matches", body = Vec<Testee>),
+        (status = 400, description = "The query parameter is missing", body = ErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_api_testees(
+    State(data): State<Arc<AppState>>,
+    params: Option<Query<TesteeSearchParams>>,
+) -> ApiResult<Vec<Testee>> {
+    let Some(Query(params)) = params else {
+        return Err(ApiError::bad_request("The query parameter is required."));
+    };
+
+    let testees = search_for_testee(params.query, &data.db).await?;
+    Ok(Json(testees.unwrap_or_default()))
+}
+
+/// Fetches a testee
+#[utoipa::path(
+    get,
+    path = "/api/v1/testees/{testee_id}",
+    tag = "testees",
+    params(("testee_id" = Uuid, Path)),
+    responses(
+        (status = 200, body = Testee),
+        (status = 404, description = "No testee with that id exists", body = ErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_api_testee(
+    State(data): State<Arc<AppState>>,
+    WithRejection(Path(testee_id), _): WithRejection<Path<Uuid>, ApiError>,
+) -> ApiResult<Testee> {
+    fetch_testee_by_id(&data.db, testee_id)
+        .await?
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found("No testee with that id exists."))
+}
+
+/// Summaries of every test the testee has taken, newest first
+#[utoipa::path(
+    get,
+    path = "/api/v1/testees/{testee_id}/tests",
+    tag = "testees",
+    params(("testee_id" = Uuid, Path)),
+    responses(
+        (status = 200, body = Vec<FullTestSummary>),
+        (status = 404, description = "No testee with that id exists", body = ErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_api_testee_tests(
+    State(data): State<Arc<AppState>>,
+    WithRejection(Path(testee_id), _): WithRejection<Path<Uuid>, ApiError>,
+) -> ApiResult<Vec<FullTestSummary>> {
+    if fetch_testee_by_id(&data.db, testee_id).await?.is_none() {
+        return Err(ApiError::not_found("No testee with that id exists."));
+    }
+
+    let summaries = fetch_testee_tests_by_id(&data.db, testee_id).await?;
+    Ok(Json(summaries.unwrap_or_default()))
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct ResendEmailRequest {
+    /// The test whose score report and certificate are attached. Left out, or the whole body left out, to send the list of
+    /// tests without attachments.
+    #[serde(default)]
+    test_id: Option<Uuid>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct QueuedEmail {
+    email_job_id: Uuid,
+}
+
+/// Sends a testee their results email again, like the button on their test summaries. The email is queued and sent in
+/// the background, and the caller is recorded as having sent it. A testee is sent at most one email every few minutes,
+/// and each user can resend a limited number of emails an hour.
+#[utoipa::path(
+    post,
+    path = "/api/v1/testees/{testee_id}/email",
+    tag = "testees",
+    params(("testee_id" = Uuid, Path)),
+    request_body = Option<ResendEmailRequest>,
+    responses(
+        (status = 202, description = "The email was queued", body = QueuedEmail),
+        (status = 404, description = "No testee with that id exists, or they have not taken the given test", body = ErrorBody),
+        (status = 429, description = "The testee was emailed recently, or the caller has resent too many emails", body = ErrorBody),
+        (status = 503, description = "Email is not set up on this server", body = ErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn post_api_resend_email(
+    State(data): State<Arc<AppState>>,
+    Extension(auth_status): Extension<AuthStatus>,
+    WithRejection(Path(testee_id), _): WithRejection<Path<Uuid>, ApiError>,
+    body: Bytes,
+) -> Result<(StatusCode, Json<QueuedEmail>), ApiError> {
+    let authorized_user = match auth_status {
+        AuthStatus::Authorized(user) => user,
+        AuthStatus::Unauthorized(_) => panic!("If this happens, check your auth middleware application.")
+    };
+
+    if data.smtp_mailer.is_none() {
+        return Err(ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "Email is not set up on this server."));
+    }
+
+    let request = match body.is_empty() {
+        true => ResendEmailRequest::default(),
+        false => Json::<Option<ResendEmailRequest>>::from_bytes(&body)?.0.unwrap_or_default(),
+    };
+
+    let email_job_id = request_results_email(&data.db, testee_id, request.test_id, &data.env.public_base_url, authorized_user.user.id).await?;
+    Ok((StatusCode::ACCEPTED, Json(QueuedEmail { email_job_id })))
+}
+
+// #######################################################################################################################################################
+// Graded Tests
+// #######################################################################################################################################################
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TestListParams {
+    /// Only list tests with these names. Repeat the parameter for more than one. Every test is listed if none are given.
+    #[serde(default)]
+    test_name: Vec<String>,
+    /// Only list passing or failing tests
+    status: Option<TestStatusFilter>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TestStatusFilter {
+    Passing,
+    Failing,
+}
+
+/// Lists who passed or failed each test, with each testee's most recent attempt at each test. The same list as the pass/fail
+/// page.
+#[utoipa::path(
+    get,
+    path = "/api/v1/tests",
+    tag = "tests",
+    params(TestListParams),
+    responses(
+        (status = 200, body = Vec<TestListItem>),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_api_tests(
+    State(data): State<Arc<AppState>>,
+    WithRejection(axum_extra::extract::Query(params), _): WithRejection<axum_extra::extract::Query<TestListParams>, ApiError>,
+) -> ApiResult<Vec<TestListItem>> {
+    let test_names = match params.test_name.is_empty() {
+        true => fetch_unique_test_names(&data.db).await?,
+        false => params.test_name,
+    };
+    let is_passing_filter = params.status.map(|status| matches!(status, TestStatusFilter::Passing));
+
+    Ok(Json(fetch_tests_by_status(&data.db, &test_names, is_passing_filter).await?))
+}
+
+/// Fetches a graded test
+#[utoipa::path(
+    get,
+    path = "/api/v1/tests/{test_id}",
+    tag = "tests",
+    params(("test_id" = Uuid, Path)),
+    responses(
+        (status = 200, body = Test),
+        (status = 404, description = "No test with that id exists", body = ErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_api_test(
+    State(data): State<Arc<AppState>>,
+    WithRejection(Path(test_id), _): WithRejection<Path<Uuid>, ApiError>,
+) -> ApiResult<Test> {
+    fetch_test_results_by_id(&data.db, test_id)
+        .await?
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found("No test with that id exists."))
+}
+
+/// Grades a submission against the test definition it was made for
+async fn grade_api_submission(
+    data: &AppState,
+    test_definition_id: Uuid,
+    submission: TestSubmission,
+    proctor: Option<Proctor>,
+) -> Result<Test, ApiError> {
+    let test_definition = fetch_test_definition(data, test_definition_id, submission.test_definition_version)
+        .await?
+        .ok_or_else(|| ApiError::not_found("No test definition with that id exists."))?;
+
+    grade_submission(submission, test_definition, proctor).map_err(ApiError::from)
+}
+
+/// Grades and saves a test, recording the caller as the proctor. Requests made with an API key are recorded as
+/// proctored by the admin who issued it. The testee is emailed their results when the submission asks for it and email
+/// is set up.
+#[utoipa::path(
+    post,
+    path = "/api/v1/test-definitions/{test_definition_id}/tests",
+    tag = "tests",
+    params(("test_definition_id" = Uuid, Path)),
+    request_body(content = TestSubmission, description = SUBMISSION_DESCRIPTION),
+    responses(
+        (status = 201, description = "The graded test", body = Test),
+        (status = 404, description = "No test definition with that id exists", body = ErrorBody),
+        (status = 422, description = "The submission is incomplete or doesn't match the test definition", body = ErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn post_api_test(
+    State(data): State<Arc<AppState>>,
+    Extension(auth_status): Extension<AuthStatus>,
+    WithRejection(Path(test_definition_id), _): WithRejection<Path<Uuid>, ApiError>,
+    submission: Result<TestSubmission, SubmissionError>,
+) -> Result<(StatusCode, Json<Test>), ApiError> {
+    let authorized_user = match auth_status {
+        AuthStatus::Authorized(user) => user,
+        AuthStatus::Unauthorized(_) => panic!("If this happens, check your auth middleware application.")
+    };
+    let proctor = Proctor {
+        id: authorized_user.user.id,
+        first_name: authorized_user.user.first_name,
+        last_name: authorized_user.user.last_name,
+    };
+
+    let submission = submission?;
+    let (send_email_results, attach_pdf_results) = (submission.send_email_results, submission.attach_pdf_results);
+
+    let graded_test = grade_api_submission(&data, test_definition_id, submission, Some(proctor)).await?;
+    let (testee_id, test_id) = save_test_to_database(&data.db, graded_test.clone()).await?;
+
+    if data.smtp_mailer.is_some() && send_email_results {
+        // The test is already saved, so a failure to queue the email is logged rather than returned
+        if let Err(e) = enqueue_results_email(&data.db, testee_id, attach_pdf_results.then_some(test_id), &data.env.public_base_url).await {
+            eprintln!("Failed to queue the results email: {:?}", e);
+        }
+    }
+
+    Ok((StatusCode::CREATED, Json(graded_test)))
+}
+
+/// Grades a test without saving it, like the live grading on the test page
+#[utoipa::path(
+    post,
+    path = "/api/v1/test-definitions/{test_definition_id}/grade",
+    tag = "tests",
+    params(("test_definition_id" = Uuid, Path)),
+    request_body(content = TestSubmission, description = SUBMISSION_DESCRIPTION),
+    responses(
+        (status = 200, body = TestGradeSummary),
+        (status = 404, description = "No test definition with that id exists", body = ErrorBody),
+        (status = 422, description = "The submission is incomplete or doesn't match the test definition", body = ErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn post_api_grade_test(
+    State(data): State<Arc<AppState>>,
+    WithRejection(Path(test_definition_id), _): WithRejection<Path<Uuid>, ApiError>,
+    submission: Result<TestSubmission, SubmissionError>,
+) -> ApiResult<TestGradeSummary> {
+    let graded_test = grade_api_submission(&data, test_definition_id, submission?, None).await?;
+    graded_test.grade_summary().map(Json).map_err(ApiError::internal)
+}
+
+const SUBMISSION_DESCRIPTION: &str = "The score label picked for every competency. It can also be sent form encoded, like the \
+grading form, with each score as a `score.<table>.<section>.<competency>.<scoring category>` field holding the score label index.";
+
+// #######################################################################################################################################################
+// Queue
+// #######################################################################################################################################################
+
+/// Lists the testees waiting to take a test, longest waiting first
+#[utoipa::path(
+    get,
+    path = "/api/v1/queue",
+    tag = "queue",
+    responses(
+        (status = 200, body = Vec<QueueItem>),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_api_queue(State(data): State<Arc<AppState>>) -> ApiResult<Vec<QueueItem>> {
+    Ok(Json(retrieve_queue(&data.db).await?))
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct EnqueueRequest {
+    first_name: String,
+    last_name: String,
+    email: String,
+    test_definition_id: Uuid,
+}
+
+/// Adds a testee to the queue, creating them if their email is new. Unlike the public queue form, no sign-up key is
+/// needed since the caller is already authenticated.
+#[utoipa::path(
+    post,
+    path = "/api/v1/queue",
+    tag = "queue",
+    request_body = EnqueueRequest,
+    responses(
+        (status = 201, body = QueueItem),
+        (status = 422, description = "No test definition with that id exists", body = ErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn post_api_queue(
+    State(data): State<Arc<AppState>>,
+    WithRejection(Json(request), _): WithRejection<Json<EnqueueRequest>, ApiError>,
+) -> Result<(StatusCode, Json<QueueItem>), ApiError> {
+    let test_definition = data
+        .test_configurations()
+        .get_by_id(request.test_definition_id)
+        .cloned()
+        .ok_or_else(|| ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "No test definition with that id exists."))?;
+
+    let testee = create_testee(&data.db, &request.first_name, &request.last_name, &request.email).await?;
+    // Create testee 100% returns a testee with a testee id
+    enqueue_testee(&data.db, testee.id.unwrap(), request.test_definition_id).await?;
+
+    let queue_item = QueueItem {
+        testee,
+        test_definition_id: request.test_definition_id,
+        test_name: test_definition.metadata.test_name,
+    };
+    Ok((StatusCode::CREATED, Json(queue_item)))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ApiDequeueParams {
+    /// The testee to remove. The testee who has waited longest is removed if this is left out.
+    testee_id: Option<Uuid>,
+    /// Which of the testee's tests to remove them from, if they are queued for more than one
+    test_definition_id: Option<Uuid>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DequeuedTestee {
+    testee: Testee,
+    test_definition_id: Uuid,
+}
+
+/// Removes the given testee from the queue, or the testee who has waited longest if none is given
+#[utoipa::path(
+    delete,
+    path = "/api/v1/queue",
+    tag = "queue",
+    params(ApiDequeueParams),
+    responses(
+        (status = 200, body = DequeuedTestee),
+        (status = 400, description = "A test_definition_id was given without a testee_id", body = ErrorBody),
+        (status = 404, description = "No matching testee is in the queue", body = ErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn delete_api_queue(
+    State(data): State<Arc<AppState>>,
+    WithRejection(Query(params), _): WithRejection<Query<ApiDequeueParams>, ApiError>,
+) -> ApiResult<DequeuedTestee> {
+    if params.testee_id.is_none() && params.test_definition_id.is_some() {
+        return Err(ApiError::bad_request("A test_definition_id can only be given along with a testee_id."));
+    }
+
+    match dequeue_testee(&data.db, params.testee_id, params.test_definition_id).await? {
+        Some((testee, test_definition_id)) => Ok(Json(DequeuedTestee { testee, test_definition_id })),
+        None => Err(ApiError::not_found("No matching testee is in the queue.")),
+    }
+}
+
+// #######################################################################################################################################################
+// Test Definitions
+// #######################################################################################################################################################
+
+/// The currently loaded test definitions
+#[utoipa::path(
+    get,
+    path = "/api/v1/test-definitions",
+    tag = "test definitions",
+    responses(
+        (status = 200, body = Vec<Test>),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_api_test_definitions(State(data): State<Arc<AppState>>) -> ApiResult<Vec<Test>> {
+    Ok(Json(data.test_configurations().tests.clone()))
+}
+
+/// Fetches one of the currently loaded test definitions
+#[utoipa::path(
+    get,
+    path = "/api/v1/test-definitions/{test_definition_id}",
+    tag = "test definitions",
+    params(("test_definition_id" = Uuid, Path)),
+    responses(
+        (status = 200, body = Test),
+        (status = 404, description = "No test definition with that id exists", body = ErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_api_test_definition(
+    State(data): State<Arc<AppState>>,
+    WithRejection(Path(test_definition_id), _): WithRejection<Path<Uuid>, ApiError>,
+) -> ApiResult<Test> {
+    data.test_configurations()
+        .get_by_id(test_definition_id)
+        .cloned()
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found("No test definition with that id exists."))
+}
+
+/// Requests that don't match an API route get a JSON 404
+pub async fn api_fallback() -> ApiError {
+    ApiError::not_found("No such API endpoint.")
+}
+
+// #######################################################################################################################################################
+// OpenAPI Document
+// #######################################################################################################################################################
+
+// The document is generated from the annotations on the handlers above. A copy is checked in as openapi.json for
+// integration partners, and a test fails when the two disagree.
+
+const API_DESCRIPTION: &str = "Every endpoint takes an access token or an API key as a bearer token. \
+Test definitions are available to every caller. The queue needs the write-queue scope, testees and graded tests need the \
+read-results scope, and grading and resending results emails need the administer-tests scope. Logged in users have the scopes their role allows: \
+front desk staff have write-queue, and proctors and admins have all three.";
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Dancexam API", description = API_DESCRIPTION, license(name = "GPL-3.0", identifier = "GPL-3.0-only")),
+    paths(
+        get_api_testees,
+        get_api_testee,
+        get_api_testee_tests,
+        post_api_resend_email,
+        get_api_tests,
+        get_api_test,
+        post_api_test,
+        post_api_grade_test,
+        get_api_queue,
+        post_api_queue,
+        delete_api_queue,
+        get_api_test_definitions,
+        get_api_test_definition,
+    ),
+    components(schemas(TestStatusFilter)),
+    modifiers(&BearerSecurity),
+)]
+pub struct ApiDoc;
+
+struct BearerSecurity;
+
+impl Modify for BearerSecurity {
+    fn modify(&self, openapi: &mut OpenApiDocument) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).description(Some("An access token or an API key")).build()),
+        );
+    }
+}
+
+/// Serves the OpenAPI document. It is public so that partners can read the contract before they have a key.
+pub async fn get_openapi_document() -> Json<OpenApiDocument> {
+    Json(ApiDoc::openapi())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::FromRequest, http::header};
+
+    #[test]
+    fn auth_errors_map_to_matching_status_codes() {
+        assert_eq!(ApiError::from(AuthError::NotLoggedIn).status, StatusCode::UNAUTHORIZED);
+        assert_eq!(ApiError::from(AuthError::ExpiredSession).status, StatusCode::UNAUTHORIZED);
+        assert_eq!(ApiError::from(AuthError::AccountDisabled).status, StatusCode::FORBIDDEN);
+        assert_eq!(ApiError::from(AuthError::InsufficientPermissions).status, StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn only_invalid_submissions_are_unprocessable() {
+        let invalid_submission = ApiError::from(TestError::InvalidSubmission(SubmissionError::UnsupportedVersion { version: 2 }));
+        assert_eq!(invalid_submission.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(invalid_submission.message, SubmissionError::UnsupportedVersion { version: 2 }.to_string());
+
+        let database_error = ApiError::from(TestError::from(sqlx::Error::PoolTimedOut));
+        assert_eq!(database_error.status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    /// Regenerate openapi.json with `UPDATE_OPENAPI=1 cargo test openapi` after changing the API on purpose.
+    #[test]
+    fn openapi_json_matches_the_api() {
+        let generated = ApiDoc::openapi().to_pretty_json().unwrap() + "\n";
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/openapi.json");
+
+        if std::env::var("UPDATE_OPENAPI").is_ok() {
+            std::fs::write(path, &generated).unwrap();
+        }
+
+        let checked_in = std::fs::read_to_string(path).unwrap_or_default();
+        assert!(generated == checked_in, "openapi.json is out of date with the API. If the change is intended, regenerate it with `UPDATE_OPENAPI=1 cargo test openapi`.");
+    }
+
+    #[tokio::test]
+    async fn errors_are_returned_as_json() {
+        let response = ApiError::not_found("No test with that id exists.").into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: ErrorBody = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body.error, "No test with that id exists.");
+    }
+
+    #[tokio::test]
+    async fn malformed_requests_are_rejected_with_api_errors() {
+        let request = Request::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"first_name": 1}"#))
+            .unwrap();
+        let rejection = WithRejection::<Json<EnqueueRequest>, ApiError>::from_request(request, &()).await.err().unwrap();
+        assert_eq!(rejection.status, StatusCode::UNPROCESSABLE_ENTITY);
+
+        let request = Request::builder().uri("/api/v1/queue?testee_id=not-a-uuid").body(Body::empty()).unwrap();
+        let rejection = WithRejection::<Query<ApiDequeueParams>, ApiError>::from_request(request, &()).await.err().unwrap();
+        assert_eq!(rejection.status, StatusCode::BAD_REQUEST);
+    }
+}
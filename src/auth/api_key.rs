@@ -0,0 +1,201 @@
+use std::str::FromStr;
+
+use base64::{engine::general_purpose, Engine};
+use chrono::{DateTime, Utc};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Postgres};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+use crate::auth::{
+    middleware::AuthError,
+    model::{ApiKey, ApiKeyScope},
+};
+
+// #######################################################################################################################################################
+// API Keys
+// #######################################################################################################################################################
+
+// Keys look like dxk_<key id>_<secret>. The id lets a key's hash be found without hashing every stored key, and the
+// prefix lets check_auth_utility tell keys apart from access tokens.
+
+pub const API_KEY_PREFIX: &str = "dxk_";
+
+/// Uses are only recorded once a minute so that a busy kiosk doesn't write to the database on every request
+const LAST_USED_RESOLUTION_SECONDS: f64 = 60.0;
+
+fn database_error(e: sqlx::Error) -> AuthError {
+    AuthError::InternalServerError(Some(format!("Database error: {}", e)))
+}
+
+/// Scopes are stored as text so that adding one doesn't need a migration. Unknown scopes are dropped rather than failing
+/// the whole key.
+fn parse_scopes(scopes: Vec<String>) -> Vec<ApiKeyScope> {
+    scopes.iter().filter_map(|scope| ApiKeyScope::from_str(scope).ok()).collect()
+}
+
+/// Keys carry 256 random bits, so a plain SHA-256 digest is as hard to reverse as a slow password hash and, unlike one,
+/// is cheap enough to check on every request.
+fn hash_api_key(api_key: &str) -> String {
+    hex::encode(Sha256::digest(api_key.as_bytes()))
+}
+
+/// Splits a key into its id and secret, or returns None if it isn't shaped like a key
+fn split_api_key(api_key: &str) -> Option<(Uuid, &str)> {
+    let (key_id, secret) = api_key.strip_prefix(API_KEY_PREFIX)?.split_once('_')?;
+    let key_id = Uuid::try_parse(key_id).ok()?;
+    Some((key_id, secret))
+}
+
+struct ApiKeyRow {
+    id: Uuid,
+    name: String,
+    key_hash: String,
+    scopes: Vec<String>,
+    created_by: Uuid,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+    revoked_at: Option<DateTime<Utc>>,
+}
+
+impl From<ApiKeyRow> for ApiKey {
+    fn from(row: ApiKeyRow) -> ApiKey {
+        ApiKey {
+            id: row.id,
+            name: row.name,
+            scopes: parse_scopes(row.scopes),
+            created_by: row.created_by,
+            created_at: row.created_at,
+            last_used_at: row.last_used_at,
+            revoked_at: row.revoked_at,
+        }
+    }
+}
+
+/// Issues a new key. Returns the stored key along with the key itself, which can't be recovered later.
+pub async fn create_api_key(
+    name: &str,
+    scopes: &[ApiKeyScope],
+    created_by: Uuid,
+    db: &Pool<Postgres>,
+) -> Result<(ApiKey, String), AuthError> {
+    let key_id = Uuid::new_v4();
+    let mut secret_bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut secret_bytes);
+    let api_key = format!("{}{}_{}", API_KEY_PREFIX, key_id.simple(), general_purpose::URL_SAFE_NO_PAD.encode(secret_bytes));
+
+    let key_hash = hash_api_key(&api_key);
+    let scopes: Vec<String> = scopes.iter().map(|scope| scope.to_string()).collect();
+
+    let row = sqlx::query_as!(
+        ApiKeyRow,
+        "INSERT INTO api_keys (id, name, key_hash, scopes, created_by) VALUES ($1, $2, $3, $4, $5)
+        RETURNING id, name, key_hash, scopes, created_by, created_at, last_used_at, revoked_at",
+        key_id,
+        name,
+        key_hash,
+        &scopes,
+        created_by
+    )
+    .fetch_one(db)
+    .await
+    .map_err(database_error)?;
+
+    Ok((row.into(), api_key))
+}
+
+/// Checks the key against its stored hash and records the use. Unknown, malformed and revoked keys are all rejected as
+/// invalid so that callers can't tell them apart.
+pub async fn verify_api_key(api_key: &str, db: &Pool<Postgres>) -> Result<ApiKey, AuthError> {
+    let (key_id, _) = split_api_key(api_key).ok_or(AuthError::InvalidToken)?;
+
+    let row = sqlx::query_as!(
+        ApiKeyRow,
+        "SELECT id, name, key_hash, scopes, created_by, created_at, last_used_at, revoked_at FROM api_keys WHERE id = $1 AND revoked_at IS NULL",
+        key_id
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(database_error)?
+    .ok_or(AuthError::InvalidToken)?;
+
+    if !bool::from(hash_api_key(api_key).as_bytes().ct_eq(row.key_hash.as_bytes())) {
+        return Err(AuthError::InvalidToken);
+    }
+
+    sqlx::query!(
+        "UPDATE api_keys SET last_used_at = NOW()
+        WHERE id = $1 AND (last_used_at IS NULL OR last_used_at < NOW() - make_interval(secs => $2))",
+        key_id,
+        LAST_USED_RESOLUTION_SECONDS
+    )
+    .execute(db)
+    .await
+    .map_err(database_error)?;
+
+    Ok(row.into())
+}
+
+/// Lists every key, including revoked ones, newest first
+pub async fn fetch_api_keys(db: &Pool<Postgres>) -> Result<Vec<ApiKey>, AuthError> {
+    let rows = sqlx::query_as!(
+        ApiKeyRow,
+        "SELECT id, name, key_hash, scopes, created_by, created_at, last_used_at, revoked_at FROM api_keys ORDER BY created_at DESC"
+    )
+    .fetch_all(db)
+    .await
+    .map_err(database_error)?;
+
+    Ok(rows.into_iter().map(ApiKey::from).collect())
+}
+
+/// Revokes the key, which takes effect on its next use. Returns the revoked key, or None if it doesn't exist or was
+/// already revoked.
+pub async fn revoke_api_key(key_id: Uuid, db: &Pool<Postgres>) -> Result<Option<ApiKey>, AuthError> {
+    let row = sqlx::query_as!(
+        ApiKeyRow,
+        "UPDATE api_keys SET revoked_at = NOW() WHERE id = $1 AND revoked_at IS NULL
+        RETURNING id, name, key_hash, scopes, created_by, created_at, last_used_at, revoked_at",
+        key_id
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(database_error)?;
+
+    Ok(row.map(ApiKey::from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_well_formed_keys() {
+        let key_id = Uuid::new_v4();
+        let api_key = format!("{}{}_c2VjcmV0_with-underscore", API_KEY_PREFIX, key_id.simple());
+        assert_eq!(split_api_key(&api_key), Some((key_id, "c2VjcmV0_with-underscore")));
+    }
+
+    #[test]
+    fn rejects_malformed_keys() {
+        assert_eq!(split_api_key("not-a-key"), None);
+        assert_eq!(split_api_key("dxk_not-a-uuid_secret"), None);
+        assert_eq!(split_api_key(&format!("{}{}", API_KEY_PREFIX, Uuid::new_v4().simple())), None);
+    }
+
+    #[test]
+    fn hashes_keys_with_sha256() {
+        let api_key = format!("{}{}_c2VjcmV0", API_KEY_PREFIX, Uuid::new_v4().simple());
+        let key_hash = hash_api_key(&api_key);
+        assert_eq!(key_hash.len(), 64);
+        assert_eq!(key_hash, hash_api_key(&api_key));
+        assert_ne!(key_hash, hash_api_key(&format!("{}x", api_key)));
+    }
+
+    #[test]
+    fn parses_known_scopes() {
+        let scopes = parse_scopes(vec!["read-results".to_string(), "write-queue".to_string(), "retired-scope".to_string()]);
+        assert_eq!(scopes, vec![ApiKeyScope::ReadResults, ApiKeyScope::WriteQueue]);
+    }
+}
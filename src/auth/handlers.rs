@@ -1,6 +1,7 @@
 use std::sync::Arc;
 
 use argon2::{password_hash::SaltString, Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use askama::Template;
 use askama_axum::Response;
 use axum::{
     http::{header, HeaderMap},
@@ -10,17 +11,22 @@ use axum_extra::extract::{
     cookie::{Cookie, SameSite},
     CookieJar,
 };
+use base64::{engine::general_purpose, Engine as _};
+use lettre::{message::header::ContentType, AsyncTransport, Message};
 use oauth2::{basic::BasicClient, AuthorizationCode, CsrfToken, PkceCodeChallenge, PkceCodeVerifier, Scope, TokenResponse};
-use rand_core::OsRng;
-use serde::Deserialize;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
 use sqlx::{Pool, Postgres};
 
 use crate::{
     auth::{
-        model::User,
+        identity::{fetch_user_by_identity, link_identity, GOOGLE_PROVIDER},
+        model::{Role, User},
         token::{TokenDetails, generate_jwt_token, verify_jwt_token},
-        middleware::{AuthorizedUser, AuthError}
+        middleware::{fetch_active_user, AuthorizedUser, AuthError},
+        session::{create_session, delete_user_sessions, revoke_session, update_session_tokens, SessionContext},
     },
+    config::AutoProvisionConfig,
     AppState,
 };
 
@@ -48,19 +54,23 @@ pub async fn register_user_handler(
         return Err(AuthError::DuplicateEmail);
     }
 
-    let salt = SaltString::generate(&mut OsRng);
-    let hashed_password = Argon2::default()
-        .hash_password(password.as_bytes(), &salt)
-        .map_err(|e| AuthError::InternalServerError(Some(format!("Error while hashing password: {}", e))))
-        .map(|hash| hash.to_string())?;
+    let hashed_password = hash_password(&password)?;
+
+    // The first account to sign up has nobody to promote it, so it becomes the admin
+    let is_first_user = !sqlx::query_scalar!(r#"SELECT EXISTS(SELECT 1 FROM users) AS "exists!""#)
+        .fetch_one(&data.db)
+        .await
+        .map_err(|e| AuthError::InternalServerError(Some(format!("Database error: {}", e))))?;
+    let role = if is_first_user { Role::Admin } else { Role::Proctor };
 
     sqlx::query_as!(
         User,
-        "INSERT INTO users (first_name,last_name,email,password) VALUES ($1, $2, $3, $4)",
+        "INSERT INTO users (first_name,last_name,email,password,role) VALUES ($1, $2, $3, $4, $5)",
         first_name,
         last_name,
         email.to_ascii_lowercase(),
-        hashed_password
+        hashed_password,
+        role as Role
     )
     .execute(&data.db)
     .await
@@ -79,6 +89,7 @@ pub async fn login_user_handler(
     cookie_jar: CookieJar,
     email: String,
     password: String,
+    session_context: SessionContext,
 ) -> Result<impl IntoResponse, AuthError> {
 
     let user = get_user(&email, &data.db)
@@ -96,19 +107,162 @@ pub async fn login_user_handler(
         return Err(AuthError::InvalidEmailOrPassword);
     }
 
-    let jar = login_user(user, &data, cookie_jar).await?;
+    let jar = login_user(user, &data, cookie_jar, session_context).await?;
 
     Ok((jar, Redirect::to("/dashboard")))
 }
 
+// #######################################################################################################################################################
+// External Sign In (shared by Google and OpenID Connect providers)
+// #######################################################################################################################################################
+
+/// What the user was doing when they started signing in with an external provider. It is saved to Redis under the CSRF
+/// token so that the callback can't be tricked into linking an external account to somebody else.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum OAuthIntent {
+    SignIn { licensing_key_supplied: bool },
+    Link { user_id: uuid::Uuid },
+}
+
+impl OAuthIntent {
+    /// Supplying the licensing key lets an unknown external account have a proctor account created for it, if auto
+    /// provisioning is enabled for the provider.
+    pub fn sign_in(data: &AppState, licensing_key: Option<String>) -> Result<OAuthIntent, AuthError> {
+        let licensing_key_supplied = match licensing_key.as_deref().map(str::trim) {
+            None | Some("") => false,
+            Some(licensing_key) if licensing_key == data.env.signup_licensing_key => true,
+            Some(_) => return Err(AuthError::InvalidLicensingKey),
+        };
+
+        Ok(OAuthIntent::SignIn { licensing_key_supplied })
+    }
+}
+
+/// The account an external provider says the user signed in with
+#[derive(Debug, Clone)]
+pub struct ExternalProfile {
+    /// The provider id that identities are linked under
+    pub provider: String,
+    /// The provider's name as shown to users
+    pub provider_name: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub given_name: Option<String>,
+    pub family_name: Option<String>,
+    pub name: Option<String>,
+}
+
+/// Finishes an external sign in once the provider has vouched for the profile, either linking it to the user that started
+/// the flow or logging in as the user it is linked to.
+pub async fn complete_external_sign_in(
+    data: &Arc<AppState>,
+    cookie_jar: CookieJar,
+    session_context: SessionContext,
+    intent: OAuthIntent,
+    profile: ExternalProfile,
+    auto_provision: &AutoProvisionConfig,
+) -> Result<Response, AuthError> {
+    match intent {
+        OAuthIntent::Link { user_id } => {
+            link_identity(user_id, &profile.provider, &profile.subject, profile.email.as_deref(), &data.db).await?;
+            Ok(Redirect::to("/linked-accounts").into_response())
+        }
+        OAuthIntent::SignIn { licensing_key_supplied } => {
+            let user = match fetch_user_by_identity(&profile.provider, &profile.subject, &data.db).await? {
+                Some(user) => user,
+                None => find_or_provision_external_user(data, &profile, auto_provision, licensing_key_supplied).await?,
+            };
+
+            let jar = login_user(user.clone(), data, cookie_jar, session_context).await?;
+            // Links the external account the first time it is used, and keeps the email shown on the linked accounts page current
+            link_identity(user.id, &profile.provider, &profile.subject, profile.email.as_deref(), &data.db).await?;
+
+            Ok((jar, Redirect::to("/dashboard")).into_response())
+        }
+    }
+}
+
+/// Finds the account for an external account that isn't linked to anyone yet by its email, creating a proctor account for it
+/// if auto provisioning allows it.
+async fn find_or_provision_external_user(
+    data: &Arc<AppState>,
+    profile: &ExternalProfile,
+    auto_provision: &AutoProvisionConfig,
+    licensing_key_supplied: bool,
+) -> Result<User, AuthError> {
+    let email = profile.email.as_ref().ok_or_else(|| {
+        AuthError::OAuthError(Some(format!("{} did not return an email address when signing in. The app cannot handle this. Try signing in without {} or try again later.", profile.provider_name, profile.provider_name)))
+    })?;
+
+    // Only a verified email shows that the external account belongs to whoever owns the account with that email
+    if !profile.email_verified {
+        return Err(AuthError::OAuthError(Some(format!("Your {} account's email address is not verified. Verify it with {}, or log in with your password and link your {} account from the Linked Accounts page.", profile.provider_name, profile.provider_name, profile.provider_name))));
+    }
+
+    if let Some(user) = get_user(email, &data.db).await? {
+        return Ok(user);
+    }
+
+    if !auto_provision.allows(email, licensing_key_supplied) {
+        return Err(AuthError::AccountNotFound);
+    }
+
+    // Nobody knows the password, the user can set one with the forgot password flow if they want to log in with a password
+    let mut password_bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut password_bytes);
+    let hashed_password = hash_password(&general_purpose::URL_SAFE_NO_PAD.encode(password_bytes))?;
+
+    sqlx::query_as!(
+        User,
+        r#"INSERT INTO users (first_name,last_name,email,password,role) VALUES ($1, $2, $3, $4, $5)
+        RETURNING id, first_name, last_name, email, password, created_at, updated_at, role AS "role: Role", disabled_at"#,
+        profile.given_name.clone().or_else(|| profile.name.clone()).unwrap_or_default(),
+        profile.family_name.clone().unwrap_or_default(),
+        email.to_ascii_lowercase(),
+        hashed_password,
+        Role::Proctor as Role
+    )
+    .fetch_one(&data.db)
+    .await
+    .map_err(|e| AuthError::InternalServerError(Some(format!("Database error: {}", e))))
+}
+
 // #######################################################################################################################################################
 // Google OAuth Initialize Login Flow Handler
 // #######################################################################################################################################################
 
+const GOOGLE_OAUTH_FLOW_MAX_AGE_MINUTES: i64 = 5;
+
+fn google_oauth_intent_key(csrf_token: &str) -> String {
+    format!("google_oauth_intent:{}", csrf_token)
+}
+
+/// Starts signing in with Google
 pub async fn google_oauth_init_flow_handler(
     data: Arc<AppState>,
     cookie_jar: CookieJar,
+    licensing_key: Option<String>,
+) -> Result<impl IntoResponse, AuthError> {
+    let intent = OAuthIntent::sign_in(&data, licensing_key)?;
+    start_google_oauth_flow(data, cookie_jar, intent).await
+}
+
+/// Starts linking a Google account to the logged in user
+pub async fn google_oauth_link_flow_handler(
+    data: Arc<AppState>,
+    cookie_jar: CookieJar,
+    authorized_user: AuthorizedUser,
 ) -> Result<impl IntoResponse, AuthError> {
+    start_google_oauth_flow(data, cookie_jar, OAuthIntent::Link { user_id: authorized_user.user.id }).await
+}
+
+async fn start_google_oauth_flow(
+    data: Arc<AppState>,
+    cookie_jar: CookieJar,
+    intent: OAuthIntent,
+) -> Result<(CookieJar, Redirect), AuthError> {
     let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
 
     if let Some(config) = &data.google_oauth_config {
@@ -125,19 +279,31 @@ pub async fn google_oauth_init_flow_handler(
             .set_pkce_challenge(pkce_challenge)
             .url();
 
+        let intent_json = serde_json::to_string(&intent)
+            .map_err(|e| AuthError::InternalServerError(Some(format!("Error serializing the OAuth flow: {}", e))))?;
+        let mut redis_client = data
+            .redis_client
+            .get_multiplexed_async_connection()
+            .await
+            .map_err(|e| AuthError::InternalServerError(Some(format!("Redis error: {}", e))))?;
+        redis_client
+            .set_ex::<_, _, ()>(google_oauth_intent_key(csrf_token.secret()), intent_json, (GOOGLE_OAUTH_FLOW_MAX_AGE_MINUTES * 60) as u64)
+            .await
+            .map_err(|e| AuthError::InternalServerError(Some(format!("Redis error: {}", e))))?;
+
         let csrf_cookie = Cookie::build(("oauth_csrf", csrf_token.secret().clone()))
             .path("/")
             .http_only(true)
             .same_site(SameSite::Lax)
             .secure(true)
-            .max_age(time::Duration::minutes(5));
+            .max_age(time::Duration::minutes(GOOGLE_OAUTH_FLOW_MAX_AGE_MINUTES));
         
         let pkce_cookie =  Cookie::build(("oauth_pkce_verifier", pkce_verifier.secret().clone()))
             .path("/")
             .http_only(true)
             .same_site(SameSite::Lax)
             .secure(true)
-            .max_age(time::Duration::minutes(5));
+            .max_age(time::Duration::minutes(GOOGLE_OAUTH_FLOW_MAX_AGE_MINUTES));
 
         let jar = cookie_jar.add(csrf_cookie).add(pkce_cookie);
 
@@ -175,6 +341,7 @@ pub async fn google_oauth_callback_handler(
     data: Arc<AppState>,
     cookie_jar: CookieJar,
     callback_params: GoogleOAuthCallbackParams,
+    session_context: SessionContext,
 ) -> Result<impl IntoResponse, AuthError> {
     let csrf_cookie = cookie_jar
         .get("oauth_csrf")
@@ -188,6 +355,20 @@ pub async fn google_oauth_callback_handler(
         return Err(AuthError::CSRFTokenMismatch);
     }
 
+    let mut redis_client = data
+        .redis_client
+        .get_multiplexed_async_connection()
+        .await
+        .map_err(|e| AuthError::InternalServerError(Some(format!("Redis error: {}", e))))?;
+    let intent_json: Option<String> = redis_client
+        .get_del(google_oauth_intent_key(&callback_params.state))
+        .await
+        .map_err(|e| AuthError::InternalServerError(Some(format!("Redis error: {}", e))))?;
+    // The intent only expires along with the CSRF cookie, so a missing intent means the flow was already completed
+    let intent = intent_json
+        .and_then(|intent_json| serde_json::from_str::<OAuthIntent>(&intent_json).ok())
+        .ok_or(AuthError::CSRFTokenMismatch)?;
+
     let config = data
         .google_oauth_config
         .as_ref()
@@ -255,19 +436,18 @@ pub async fn google_oauth_callback_handler(
         .await
         .map_err(|e| AuthError::OAuthError(Some(e.to_string())))?;
 
-    let email = user_info.email.as_ref().ok_or_else(|| {
-        AuthError::OAuthError(Some("Google did not return an email address when signing in. The app cannot handle this. Try signing in without Google or try again later.".to_string()))
-    })?;
-
-    let user = get_user(email, &data.db).await?;
+    let profile = ExternalProfile {
+        provider: GOOGLE_PROVIDER.to_string(),
+        provider_name: "Google".to_string(),
+        subject: user_info.sub,
+        email: user_info.email,
+        email_verified: user_info.email_verified == Some(true),
+        given_name: user_info.given_name,
+        family_name: user_info.family_name,
+        name: user_info.name,
+    };
 
-    match user {
-        Some(user) => {
-            let jar = login_user(user, &data, cookie_jar).await?;
-            Ok((jar, Redirect::to("/dashboard")).into_response())
-        }
-        None => Err(AuthError::AccountNotFound),
-    }
+    complete_external_sign_in(&data, cookie_jar, session_context, intent, profile, &config.auto_provision).await
 }
 // #######################################################################################################################################################
 // Logout
@@ -305,6 +485,10 @@ pub async fn logout_handler(
             AuthError::InternalServerError(Some(format!("Redis error: {}", e)))
         })?;
 
+    if let Some(session_id) = authorized_user.session_id {
+        revoke_session(&data, authorized_user.user.id, session_id).await?;
+    }
+
     let access_cookie = Cookie::build(("access_token", ""))
         .path("/")
         .max_age(time::Duration::minutes(-1))
@@ -341,10 +525,420 @@ pub async fn logout_handler(
     Ok(response)
 }
 
+// #######################################################################################################################################################
+// Refresh Token Rotation
+// #######################################################################################################################################################
+
+/// How long a refresh token that was just rotated is still accepted for. The dashboard loads several widgets at once, so when
+/// the access token has expired they all try to refresh with the same refresh token and only the first one can rotate it.
+const REFRESH_TOKEN_REUSE_GRACE_SECONDS: i64 = 10;
+
+/// Recorded in place of a refresh token once it has been rotated, so that reuse of it can be told apart from an expired session.
+#[derive(Debug, Serialize, Deserialize)]
+struct RotatedRefreshToken {
+    user_id: uuid::Uuid,
+    access_token_uuid: uuid::Uuid,
+    session_id: uuid::Uuid,
+    rotated_at: i64,
+}
+
+pub(crate) fn rotated_refresh_token_key(token_uuid: uuid::Uuid) -> String {
+    format!("rotated_refresh_token:{}", token_uuid)
+}
+
+/// Atomically swaps a refresh token for its rotated record so that two requests can't both rotate the same refresh token.
+/// Returns ["rotated"] if this call rotated the token, ["already_rotated", record] if it had already been rotated, or [] if the
+/// token doesn't exist (it expired or the user logged out).
+const ROTATE_REFRESH_TOKEN_SCRIPT: &str = r#"
+if redis.call('GET', KEYS[1]) then
+    redis.call('DEL', KEYS[1])
+    redis.call('SET', KEYS[2], ARGV[1], 'EX', ARGV[2])
+    return {'rotated'}
+end
+local rotated = redis.call('GET', KEYS[2])
+if rotated then
+    return {'already_rotated', rotated}
+end
+return {}
+"#;
+
+pub enum RefreshedSession {
+    /// New tokens were issued and need to be sent back to the client.
+    Rotated {
+        user: User,
+        access_token: TokenDetails,
+        refresh_token: TokenDetails,
+    },
+    /// Another request rotated the refresh token moments ago. This request is authorized as the access token issued then.
+    ConcurrentlyRotated {
+        user: User,
+        access_token_uuid: uuid::Uuid,
+        session_id: uuid::Uuid,
+    },
+}
+
+/// Exchanges a refresh token for a new access and refresh token pair and revokes the old refresh token. A refresh token can
+/// only be used once, so if one that was rotated out is used again after the grace period it has probably been stolen, and
+/// every token belonging to the user is revoked. The session context is only used for refresh tokens that were issued before
+/// sessions were tracked, which start a new session.
+pub async fn refresh_session(
+    data: &Arc<AppState>,
+    refresh_token: &str,
+    session_context: SessionContext,
+) -> Result<RefreshedSession, AuthError> {
+    let refresh_token_details = verify_jwt_token(data.env.refresh_token_public_key.to_owned(), refresh_token)
+        .map_err(|_| AuthError::InvalidToken)?;
+
+    let user = fetch_active_user(data, refresh_token_details.user_id).await?;
+    let session_id = refresh_token_details.session_id.unwrap_or_else(uuid::Uuid::new_v4);
+
+    // Generated and saved up front so that the rotated record can point at the new access token, and so that a concurrent
+    // request that finds the refresh token already rotated can check that the access token is live
+    let (access_token_details, new_refresh_token_details) = generate_token_pair(refresh_token_details.user_id, session_id, data)?;
+    save_token_pair_to_redis(data, &access_token_details, &new_refresh_token_details).await?;
+
+    let rotated_refresh_token = serde_json::to_string(&RotatedRefreshToken {
+        user_id: refresh_token_details.user_id,
+        access_token_uuid: access_token_details.token_uuid,
+        session_id,
+        rotated_at: chrono::Utc::now().timestamp(),
+    }).map_err(|e| AuthError::InternalServerError(Some(format!("Error serializing rotated refresh token: {}", e))))?;
+
+    let mut redis_client = data
+        .redis_client
+        .get_multiplexed_async_connection()
+        .await
+        .map_err(|e: RedisError| AuthError::InternalServerError(Some(format!("Redis error: {}", e))))?;
+
+    let result: Vec<String> = redis::Script::new(ROTATE_REFRESH_TOKEN_SCRIPT)
+        .key(refresh_token_details.token_uuid.to_string())
+        .key(rotated_refresh_token_key(refresh_token_details.token_uuid))
+        .arg(rotated_refresh_token)
+        .arg(data.env.refresh_token_max_age * 60)
+        .invoke_async(&mut redis_client)
+        .await
+        .map_err(|e: RedisError| AuthError::InternalServerError(Some(format!("Redis error: {}", e))))?;
+
+    let is_rotated = matches!(result.as_slice(), [outcome] if outcome == "rotated");
+    if !is_rotated {
+        redis_client
+            .del::<_, ()>(&[access_token_details.token_uuid.to_string(), new_refresh_token_details.token_uuid.to_string()])
+            .await
+            .map_err(|e: RedisError| AuthError::InternalServerError(Some(format!("Redis error: {}", e))))?;
+    }
+
+    match result.as_slice() {
+        [_] if is_rotated => {
+            match refresh_token_details.session_id {
+                Some(_) => update_session_tokens(data, &access_token_details, &new_refresh_token_details).await?,
+                None => { create_session(data, session_context, &access_token_details, &new_refresh_token_details).await?; },
+            }
+
+            Ok(RefreshedSession::Rotated { user, access_token: access_token_details, refresh_token: new_refresh_token_details })
+        },
+        [outcome, rotated_refresh_token] if outcome == "already_rotated" => {
+            let rotated_refresh_token: RotatedRefreshToken = serde_json::from_str(rotated_refresh_token)
+                .map_err(|e| AuthError::InternalServerError(Some(format!("Error deserializing rotated refresh token: {}", e))))?;
+
+            if chrono::Utc::now().timestamp() - rotated_refresh_token.rotated_at > REFRESH_TOKEN_REUSE_GRACE_SECONDS {
+                eprintln!("🔥 Refresh token reuse detected for user {}, revoking all of their tokens.", rotated_refresh_token.user_id);
+                revoke_user_tokens(data, rotated_refresh_token.user_id).await?;
+                return Err(AuthError::RefreshTokenReused);
+            }
+
+            // The session may have been logged out or revoked since it was rotated, which deletes the access token
+            let is_access_token_live: bool = redis_client
+                .exists(rotated_refresh_token.access_token_uuid.to_string())
+                .await
+                .map_err(|e: RedisError| AuthError::InternalServerError(Some(format!("Redis error: {}", e))))?;
+            if !is_access_token_live {
+                return Err(AuthError::ExpiredSession);
+            }
+
+            Ok(RefreshedSession::ConcurrentlyRotated {
+                user,
+                access_token_uuid: rotated_refresh_token.access_token_uuid,
+                session_id: rotated_refresh_token.session_id,
+            })
+        },
+        _ => Err(AuthError::ExpiredSession),
+    }
+}
+
+// #######################################################################################################################################################
+// Forgot Password
+// #######################################################################################################################################################
+
+/// How long a password reset link works for.
+pub const PASSWORD_RESET_TOKEN_MAX_AGE_MINUTES: i64 = 30;
+
+#[derive(Template)]
+#[template(path = "./auth_templates/password_reset_email.html")]
+struct PasswordResetEmailTemplate<'a> {
+    first_name: &'a str,
+    public_base_url: &'a str,
+    token: &'a str,
+    max_age_minutes: i64,
+}
+
+fn password_reset_key(token: &str) -> String {
+    format!("password_reset:{}", token)
+}
+
+/// Emails the user a single use link to reset their password. The account is looked up and the email sent in the background,
+/// so that the forgot password form answers the same way, in the same time, whether or not there is an account with that email.
+pub async fn request_password_reset_handler(
+    data: Arc<AppState>,
+    email: String,
+) -> Result<(), AuthError> {
+    if data.smtp_config.is_none() || data.smtp_mailer.is_none() {
+        return Err(AuthError::InternalServerError(Some("SMTP is not set up, so password reset emails can't be sent. Ask an admin to reset your password.".to_string())));
+    }
+
+    tokio::spawn(async move {
+        if let Err(e) = send_password_reset_email(data, email).await {
+            eprintln!("Failed to send a password reset email: {:?}", e);
+        }
+    });
+
+    Ok(())
+}
+
+/// Does nothing if there is no active account with that email.
+async fn send_password_reset_email(
+    data: Arc<AppState>,
+    email: String,
+) -> Result<(), AuthError> {
+    let (smtp_config, smtp_mailer) = match (&data.smtp_config, &data.smtp_mailer) {
+        (Some(smtp_config), Some(smtp_mailer)) => (smtp_config, smtp_mailer),
+        _ => return Ok(()),
+    };
+
+    let user = match get_user(&email, &data.db).await? {
+        Some(user) if user.disabled_at.is_none() => user,
+        _ => return Ok(()),
+    };
+
+    let mut token_bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut token_bytes);
+    let token = general_purpose::URL_SAFE_NO_PAD.encode(token_bytes);
+
+    let mut redis_client = data
+        .redis_client
+        .get_multiplexed_async_connection()
+        .await
+        .map_err(|e: RedisError| AuthError::InternalServerError(Some(format!("Redis error: {}", e))))?;
+
+    redis_client
+        .set_ex::<_, _, ()>(password_reset_key(&token), user.id.to_string(), (PASSWORD_RESET_TOKEN_MAX_AGE_MINUTES * 60) as u64)
+        .await
+        .map_err(|e: RedisError| AuthError::InternalServerError(Some(format!("Redis error: {}", e))))?;
+
+    let email_body = PasswordResetEmailTemplate {
+        first_name: &user.first_name,
+        public_base_url: &data.env.public_base_url,
+        token: &token,
+        max_age_minutes: PASSWORD_RESET_TOKEN_MAX_AGE_MINUTES,
+    }
+    .render()
+    .map_err(|e| AuthError::InternalServerError(Some(format!("Error rendering email template: {}", e))))?;
+
+    let email = Message::builder()
+        .from(smtp_config.user_email.parse().map_err(|e| AuthError::InternalServerError(Some(format!("Error: Unable to parse SMTP config user_email \"{}\": {}", smtp_config.user_email, e))))?)
+        .to(user.email.parse().map_err(|e| AuthError::InternalServerError(Some(format!("Error: Unable to parse user email \"{}\": {}", user.email, e))))?)
+        .subject("Reset Your Dancexam Password")
+        .header(ContentType::TEXT_HTML)
+        .body(email_body)
+        .map_err(|e| AuthError::InternalServerError(Some(format!("Error: Unable to create email: {}", e))))?;
+
+    smtp_mailer.send(email)
+        .await
+        .map_err(|e| AuthError::InternalServerError(Some(format!("Error: Unable to send email: {}", e))))?;
+
+    Ok(())
+}
+
+/// Whether the password reset token exists and hasn't expired, without using it up.
+pub async fn is_password_reset_token_valid(
+    data: &Arc<AppState>,
+    token: &str,
+) -> Result<bool, AuthError> {
+    let mut redis_client = data
+        .redis_client
+        .get_multiplexed_async_connection()
+        .await
+        .map_err(|e: RedisError| AuthError::InternalServerError(Some(format!("Redis error: {}", e))))?;
+
+    redis_client
+        .exists(password_reset_key(token))
+        .await
+        .map_err(|e: RedisError| AuthError::InternalServerError(Some(format!("Redis error: {}", e))))
+}
+
+/// Uses up the password reset token and sets the new password. The token is deleted as it is read so that it can only be used once.
+pub async fn reset_forgotten_password_handler(
+    data: Arc<AppState>,
+    token: String,
+    password: String,
+) -> Result<(), AuthError> {
+    let mut redis_client = data
+        .redis_client
+        .get_multiplexed_async_connection()
+        .await
+        .map_err(|e: RedisError| AuthError::InternalServerError(Some(format!("Redis error: {}", e))))?;
+
+    let user_id: Option<String> = redis_client
+        .get_del(password_reset_key(&token))
+        .await
+        .map_err(|e: RedisError| AuthError::InternalServerError(Some(format!("Redis error: {}", e))))?;
+
+    let user_id = user_id
+        .and_then(|user_id| uuid::Uuid::parse_str(&user_id).ok())
+        .ok_or(AuthError::InvalidPasswordResetToken)?;
+
+    reset_user_password_handler(data, user_id, password).await
+}
+
+// #######################################################################################################################################################
+// User Management
+// #######################################################################################################################################################
+
+/// Lists every user, disabled or not, for the admin user management pages.
+pub async fn fetch_users(db: &Pool<Postgres>) -> Result<Vec<User>, AuthError> {
+    sqlx::query_as!(
+        User,
+        r#"SELECT id, first_name, last_name, email, password, created_at, updated_at, role AS "role: Role", disabled_at FROM users ORDER BY last_name, first_name"#
+    )
+        .fetch_all(db)
+        .await
+        .map_err(|e| AuthError::InternalServerError(Some(format!("Database error: {}", e))))
+}
+
+pub async fn fetch_user_by_id(
+    user_id: uuid::Uuid,
+    db: &Pool<Postgres>,
+) -> Result<Option<User>, AuthError> {
+    sqlx::query_as!(
+        User,
+        r#"SELECT id, first_name, last_name, email, password, created_at, updated_at, role AS "role: Role", disabled_at FROM users WHERE id = $1"#,
+        user_id
+    )
+        .fetch_optional(db)
+        .await
+        .map_err(|e| AuthError::InternalServerError(Some(format!("Database error: {}", e))))
+}
+
+/// Updates a user's name, email, and role. Admins can't change their own role so that there is always someone left to manage users.
+pub async fn update_user_handler(
+    data: Arc<AppState>,
+    acting_user: &AuthorizedUser,
+    user_id: uuid::Uuid,
+    first_name: String,
+    last_name: String,
+    email: String,
+    role: Role,
+) -> Result<(), AuthError> {
+
+    if acting_user.user.id == user_id && role != acting_user.user.role {
+        return Err(AuthError::CannotRemoveOwnAdminAccess);
+    }
+
+    if get_user(&email, &data.db).await?.is_some_and(|user| user.id != user_id) {
+        return Err(AuthError::DuplicateEmail);
+    }
+
+    let result = sqlx::query!(
+        "UPDATE users SET first_name = $1, last_name = $2, email = $3, role = $4, updated_at = NOW() WHERE id = $5",
+        first_name,
+        last_name,
+        email.to_ascii_lowercase(),
+        role as Role,
+        user_id
+    )
+    .execute(&data.db)
+    .await
+    .map_err(|e| AuthError::InternalServerError(Some(format!("Database error: {}", e))))?;
+
+    if result.rows_affected() == 0 {
+        return Err(AuthError::InvalidUser);
+    }
+    Ok(())
+}
+
+/// Sets a new password for a user and logs them out everywhere, since whoever knew the old password may still be logged in.
+pub async fn reset_user_password_handler(
+    data: Arc<AppState>,
+    user_id: uuid::Uuid,
+    password: String,
+) -> Result<(), AuthError> {
+
+    let hashed_password = hash_password(&password)?;
+
+    let result = sqlx::query!(
+        "UPDATE users SET password = $1, updated_at = NOW() WHERE id = $2",
+        hashed_password,
+        user_id
+    )
+    .execute(&data.db)
+    .await
+    .map_err(|e| AuthError::InternalServerError(Some(format!("Database error: {}", e))))?;
+
+    if result.rows_affected() == 0 {
+        return Err(AuthError::InvalidUser);
+    }
+
+    revoke_user_tokens(&data, user_id).await
+}
+
+/// Disables or re-enables a user. Disabling revokes all of the user's tokens so that they are logged out immediately.
+pub async fn set_user_disabled_handler(
+    data: Arc<AppState>,
+    acting_user: &AuthorizedUser,
+    user_id: uuid::Uuid,
+    disabled: bool,
+) -> Result<(), AuthError> {
+
+    if acting_user.user.id == user_id && disabled {
+        return Err(AuthError::CannotRemoveOwnAdminAccess);
+    }
+
+    let result = sqlx::query!(
+        "UPDATE users SET disabled_at = CASE WHEN $1 THEN COALESCE(disabled_at, NOW()) ELSE NULL END, updated_at = NOW() WHERE id = $2",
+        disabled,
+        user_id
+    )
+    .execute(&data.db)
+    .await
+    .map_err(|e| AuthError::InternalServerError(Some(format!("Database error: {}", e))))?;
+
+    if result.rows_affected() == 0 {
+        return Err(AuthError::InvalidUser);
+    }
+
+    if disabled {
+        revoke_user_tokens(&data, user_id).await?;
+    }
+    Ok(())
+}
+
 // #######################################################################################################################################################
 // Utility Functions
 // #######################################################################################################################################################
 
+/// Hashes a password with Argon2 and a random salt for storage in the users table.
+pub(crate) fn hash_password(password: &str) -> Result<String, AuthError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| AuthError::InternalServerError(Some(format!("Error while hashing password: {}", e))))
+        .map(|hash| hash.to_string())
+}
+
+/// The Redis set holding every token uuid issued to a user, so that they can all be revoked at once.
+fn user_tokens_key(user_id: uuid::Uuid) -> String {
+    format!("user_tokens:{}", user_id)
+}
+
 async fn save_token_data_to_redis(
     data: &Arc<AppState>,
     token_details: &TokenDetails,
@@ -361,9 +955,53 @@ async fn save_token_data_to_redis(
             (max_age * 60) as u64,
         )
         .await?;
+
+    // The set lives as long as the longest lived token in it. Expired token uuids left in the set are harmless.
+    let user_tokens_key = user_tokens_key(token_details.user_id);
+    redis_client
+        .sadd::<_, _, ()>(&user_tokens_key, token_details.token_uuid.to_string())
+        .await?;
+    let ttl: i64 = redis_client.ttl(&user_tokens_key).await?;
+    if ttl < max_age * 60 {
+        redis_client.expire::<_, ()>(&user_tokens_key, max_age * 60).await?;
+    }
     Ok(())
 }
 
+/// Deletes every access and refresh token issued to the user so that check_auth_utility rejects them on their next request.
+pub async fn revoke_user_tokens(
+    data: &Arc<AppState>,
+    user_id: uuid::Uuid,
+) -> Result<(), AuthError> {
+    let mut redis_client = data
+        .redis_client
+        .get_multiplexed_async_connection()
+        .await
+        .map_err(|e: RedisError| AuthError::InternalServerError(Some(format!("Redis error: {}", e))))?;
+
+    let user_tokens_key = user_tokens_key(user_id);
+    let token_uuids: Vec<String> = redis_client
+        .smembers(&user_tokens_key)
+        .await
+        .map_err(|e: RedisError| AuthError::InternalServerError(Some(format!("Redis error: {}", e))))?;
+
+    // The rotated records too, so that their grace period can't be used to get back in
+    let mut keys: Vec<String> = token_uuids
+        .iter()
+        .filter_map(|token_uuid| uuid::Uuid::parse_str(token_uuid).ok())
+        .map(rotated_refresh_token_key)
+        .collect();
+    keys.extend(token_uuids);
+    keys.push(user_tokens_key);
+
+    redis_client
+        .del::<_, ()>(keys)
+        .await
+        .map_err(|e: RedisError| AuthError::InternalServerError(Some(format!("Redis error: {}", e))))?;
+
+    delete_user_sessions(data, user_id).await
+}
+
 /// Gets a user from the database
 pub async fn get_user(
     email: &str,
@@ -371,7 +1009,7 @@ pub async fn get_user(
 ) -> Result<Option<User>, AuthError> {
     sqlx::query_as!(
         User,
-        "SELECT * FROM users WHERE email = $1",
+        r#"SELECT id, first_name, last_name, email, password, created_at, updated_at, role AS "role: Role", disabled_at FROM users WHERE email = $1"#,
         email.to_ascii_lowercase()
     )
         .fetch_optional(db)
@@ -379,13 +1017,32 @@ pub async fn get_user(
         .map_err(|e| AuthError::InternalServerError(Some(format!("Database error: {}", e))))
 }
 
+/// Starts a new session for the user and adds its tokens to the cookie jar.
 async fn login_user(
     user: User,
     data: &Arc<AppState>,
     cookie_jar: CookieJar,
+    session_context: SessionContext,
 ) -> Result<CookieJar, AuthError> {
+    if user.disabled_at.is_some() {
+        return Err(AuthError::AccountDisabled);
+    }
+
+    let (access_token_details, refresh_token_details) = generate_token_pair(user.id, uuid::Uuid::new_v4(), data)?;
+    save_token_pair_to_redis(data, &access_token_details, &refresh_token_details).await?;
+    create_session(data, session_context, &access_token_details, &refresh_token_details).await?;
+
+    Ok(add_token_cookies(cookie_jar, data, access_token_details, refresh_token_details))
+}
+
+fn generate_token_pair(
+    user_id: uuid::Uuid,
+    session_id: uuid::Uuid,
+    data: &Arc<AppState>,
+) -> Result<(TokenDetails, TokenDetails), AuthError> {
     let access_token_details = generate_jwt_token(
-        user.id,
+        user_id,
+        session_id,
         data.env.access_token_max_age,
         data.env.access_token_private_key.to_owned()
     ).map_err(|e: jsonwebtoken::errors::Error| {
@@ -393,31 +1050,48 @@ async fn login_user(
     })?;
 
     let refresh_token_details = generate_jwt_token(
-        user.id,
+        user_id,
+        session_id,
         data.env.refresh_token_max_age,
         data.env.refresh_token_private_key.to_owned(),
     ).map_err(|e: jsonwebtoken::errors::Error| {
         AuthError::InternalServerError(Some(format!("JWT error: {}", e)))
     })?;
 
-    save_token_data_to_redis(&data, &access_token_details, data.env.access_token_max_age).await
+    Ok((access_token_details, refresh_token_details))
+}
+
+async fn save_token_pair_to_redis(
+    data: &Arc<AppState>,
+    access_token_details: &TokenDetails,
+    refresh_token_details: &TokenDetails,
+) -> Result<(), AuthError> {
+    save_token_data_to_redis(data, access_token_details, data.env.access_token_max_age).await
         .map_err(|e: RedisError| {
         AuthError::InternalServerError(Some(format!("Redis error: {}", e)))
         })?;
 
     save_token_data_to_redis(
-        &data,
-        &refresh_token_details,
+        data,
+        refresh_token_details,
         data.env.refresh_token_max_age,
     )
         .await    
         .map_err(|e: RedisError| {
             AuthError::InternalServerError(Some(format!("Redis error: {}", e)))
-        })?;
+        })
+}
 
+/// Adds the access and refresh token cookies, along with the logged_in cookie that the frontend can read, to the cookie jar.
+pub fn add_token_cookies(
+    cookie_jar: CookieJar,
+    data: &AppState,
+    access_token_details: TokenDetails,
+    refresh_token_details: TokenDetails,
+) -> CookieJar {
     let access_cookie = Cookie::build(
         ("access_token",
-        access_token_details.token.clone().unwrap_or_default()),
+        access_token_details.token.unwrap_or_default()),
     )
         .path("/")
         .max_age(time::Duration::minutes(data.env.access_token_max_age * 60))
@@ -439,8 +1113,271 @@ async fn login_user(
         .same_site(SameSite::Lax)
         .http_only(false);
 
-    Ok(cookie_jar
+    cookie_jar
         .add(access_cookie)
         .add(refresh_cookie)
-        .add(logged_in_cookie))
-}
\ No newline at end of file
+        .add(logged_in_cookie)
+}
+/// Expires the cookies set by add_token_cookies.
+pub fn remove_token_cookies(cookie_jar: CookieJar) -> CookieJar {
+    ["access_token", "refresh_token", "logged_in"]
+        .into_iter()
+        .fold(cookie_jar, |cookie_jar, name| cookie_jar.remove(Cookie::build(name).path("/")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::{middleware::check_auth_utility, session::{fetch_session, fetch_user_sessions}},
+        testing::{create_test_user, spawn_smtp_sink, test_app_state, test_app_state_with_smtp},
+    };
+    use sqlx::PgPool;
+
+    fn session_context() -> SessionContext {
+        SessionContext { user_agent: "Test Browser".to_string(), ip_address: "127.0.0.1".to_string() }
+    }
+
+    fn acting_as(user: User) -> AuthorizedUser {
+        AuthorizedUser { user, access_token_uuid: uuid::Uuid::nil(), session_id: None, api_key: None }
+    }
+
+    /// Logs the user in, returning their access and refresh tokens
+    async fn log_in(data: &Arc<AppState>, user: &User) -> (String, String) {
+        let jar = login_user(user.clone(), data, CookieJar::new(), session_context()).await.unwrap();
+        (jar.get("access_token").unwrap().value().to_string(), jar.get("refresh_token").unwrap().value().to_string())
+    }
+
+    fn refresh_token_details(data: &AppState, refresh_token: &str) -> TokenDetails {
+        verify_jwt_token(data.env.refresh_token_public_key.clone(), refresh_token).unwrap()
+    }
+
+    async fn is_in_redis(data: &AppState, key: String) -> bool {
+        let mut redis_client = data.redis_client.get_multiplexed_async_connection().await.unwrap();
+        redis_client.exists(key).await.unwrap()
+    }
+
+    async fn is_logged_in(data: &Arc<AppState>, access_token: &str) -> bool {
+        let jar = CookieJar::new().add(Cookie::new("access_token", access_token.to_string()));
+        check_auth_utility(jar, data.clone(), &HeaderMap::new()).await.is_ok()
+    }
+
+    async fn rotate(data: &Arc<AppState>, refresh_token: &str) -> (TokenDetails, TokenDetails) {
+        match refresh_session(data, refresh_token, session_context()).await {
+            Ok(RefreshedSession::Rotated { access_token, refresh_token, .. }) => (access_token, refresh_token),
+            Ok(RefreshedSession::ConcurrentlyRotated { .. }) => panic!("The refresh token was already rotated"),
+            Err(e) => panic!("Refreshing failed: {:?}", e),
+        }
+    }
+
+    #[sqlx::test]
+    async fn refresh_tokens_are_rotated(pool: PgPool) {
+        let data = test_app_state(pool.clone());
+        let user = create_test_user(&pool, Role::Proctor, "proctor@example.com").await;
+        let (_, refresh_token) = log_in(&data, &user).await;
+        let old_refresh_token = refresh_token_details(&data, &refresh_token);
+
+        let (access_token, new_refresh_token) = rotate(&data, &refresh_token).await;
+
+        assert_ne!(new_refresh_token.token_uuid, old_refresh_token.token_uuid);
+        assert_eq!(new_refresh_token.session_id, old_refresh_token.session_id);
+        assert!(!is_in_redis(&data, old_refresh_token.token_uuid.to_string()).await);
+        assert!(is_logged_in(&data, access_token.token.as_deref().unwrap()).await);
+
+        let session = fetch_session(&data, old_refresh_token.session_id.unwrap()).await.unwrap().unwrap();
+        assert_eq!(session.refresh_token_uuid, new_refresh_token.token_uuid);
+        assert_eq!(session.previous_refresh_token_uuid, old_refresh_token.token_uuid);
+    }
+
+    #[sqlx::test]
+    async fn concurrent_refreshes_share_the_rotated_tokens(pool: PgPool) {
+        let data = test_app_state(pool.clone());
+        let user = create_test_user(&pool, Role::Proctor, "proctor@example.com").await;
+        let (_, refresh_token) = log_in(&data, &user).await;
+        let session_id = refresh_token_details(&data, &refresh_token).session_id.unwrap();
+
+        let (access_token, _) = rotate(&data, &refresh_token).await;
+
+        match refresh_session(&data, &refresh_token, session_context()).await {
+            Ok(RefreshedSession::ConcurrentlyRotated { access_token_uuid, session_id: rotated_session_id, .. }) => {
+                assert_eq!(access_token_uuid, access_token.token_uuid);
+                assert_eq!(rotated_session_id, session_id);
+            },
+            Ok(RefreshedSession::Rotated { .. }) => panic!("The refresh token was rotated twice"),
+            Err(e) => panic!("Refreshing failed: {:?}", e),
+        }
+
+        // Logging out deletes the access token, so the grace period can't be used to get back in
+        revoke_session(&data, user.id, session_id).await.unwrap();
+        assert!(matches!(refresh_session(&data, &refresh_token, session_context()).await, Err(AuthError::ExpiredSession)));
+    }
+
+    #[sqlx::test]
+    async fn reusing_a_refresh_token_revokes_every_session(pool: PgPool) {
+        let data = test_app_state(pool.clone());
+        let user = create_test_user(&pool, Role::Proctor, "proctor@example.com").await;
+        let (_, stolen_refresh_token) = log_in(&data, &user).await;
+        let (other_access_token, other_refresh_token) = log_in(&data, &user).await;
+
+        let (access_token, _) = rotate(&data, &stolen_refresh_token).await;
+
+        // Move the rotation back past the grace period
+        let rotated_key = rotated_refresh_token_key(refresh_token_details(&data, &stolen_refresh_token).token_uuid);
+        let mut redis_client = data.redis_client.get_multiplexed_async_connection().await.unwrap();
+        let rotated_json: String = redis_client.get(&rotated_key).await.unwrap();
+        let mut rotated: RotatedRefreshToken = serde_json::from_str(&rotated_json).unwrap();
+        rotated.rotated_at -= REFRESH_TOKEN_REUSE_GRACE_SECONDS + 1;
+        redis_client.set_ex::<_, _, ()>(&rotated_key, serde_json::to_string(&rotated).unwrap(), 60).await.unwrap();
+
+        assert!(matches!(refresh_session(&data, &stolen_refresh_token, session_context()).await, Err(AuthError::RefreshTokenReused)));
+
+        assert!(!is_logged_in(&data, access_token.token.as_deref().unwrap()).await);
+        assert!(!is_logged_in(&data, &other_access_token).await);
+        assert!(matches!(refresh_session(&data, &other_refresh_token, session_context()).await, Err(AuthError::ExpiredSession)));
+        assert!(fetch_user_sessions(&data, user.id).await.unwrap().is_empty());
+    }
+
+    #[sqlx::test]
+    async fn disabling_a_user_logs_them_out_everywhere(pool: PgPool) {
+        let data = test_app_state(pool.clone());
+        let admin = create_test_user(&pool, Role::Admin, "admin@example.com").await;
+        let user = create_test_user(&pool, Role::Proctor, "proctor@example.com").await;
+        let (access_token, refresh_token) = log_in(&data, &user).await;
+
+        set_user_disabled_handler(data.clone(), &acting_as(admin), user.id, true).await.unwrap();
+
+        assert!(!is_logged_in(&data, &access_token).await);
+        assert!(!is_in_redis(&data, refresh_token_details(&data, &refresh_token).token_uuid.to_string()).await);
+        assert!(fetch_user_sessions(&data, user.id).await.unwrap().is_empty());
+        let user = fetch_user_by_id(user.id, &data.db).await.unwrap().unwrap();
+        assert!(matches!(login_user(user, &data, CookieJar::new(), session_context()).await, Err(AuthError::AccountDisabled)));
+    }
+
+    #[sqlx::test]
+    async fn resetting_a_password_logs_the_user_out_everywhere(pool: PgPool) {
+        let data = test_app_state(pool.clone());
+        let user = create_test_user(&pool, Role::Proctor, "proctor@example.com").await;
+        let (access_token, refresh_token) = log_in(&data, &user).await;
+
+        reset_user_password_handler(data.clone(), user.id, "a new password".to_string()).await.unwrap();
+
+        assert!(!is_logged_in(&data, &access_token).await);
+        assert!(matches!(refresh_session(&data, &refresh_token, session_context()).await, Err(AuthError::ExpiredSession)));
+        assert!(fetch_user_sessions(&data, user.id).await.unwrap().is_empty());
+    }
+
+    #[sqlx::test]
+    async fn admins_cannot_remove_their_own_admin_access(pool: PgPool) {
+        let data = test_app_state(pool.clone());
+        let admin = create_test_user(&pool, Role::Admin, "admin@example.com").await;
+
+        let disabled = set_user_disabled_handler(data.clone(), &acting_as(admin.clone()), admin.id, true).await;
+        assert!(matches!(disabled, Err(AuthError::CannotRemoveOwnAdminAccess)));
+
+        let demoted = update_user_handler(
+            data.clone(), &acting_as(admin.clone()), admin.id, admin.first_name.clone(), admin.last_name.clone(), admin.email.clone(), Role::Proctor,
+        ).await;
+        assert!(matches!(demoted, Err(AuthError::CannotRemoveOwnAdminAccess)));
+
+        let admin = fetch_active_user(&data, admin.id).await.unwrap();
+        assert_eq!(admin.role, Role::Admin);
+    }
+
+    /// Asks for a password reset link and returns the token from the email that is sent
+    async fn request_reset_token(pool: &PgPool) -> (Arc<AppState>, User, String) {
+        let (port, mut received) = spawn_smtp_sink(true).await;
+        let data = test_app_state_with_smtp(pool.clone(), port);
+        let user = create_test_user(pool, Role::Proctor, "proctor@example.com").await;
+
+        request_password_reset_handler(data.clone(), user.email.clone()).await.unwrap();
+
+        // The body is quoted-printable
+        let email = received.recv().await.unwrap().replace("=\n", "").replace("=3D", "=");
+        let token = email
+            .split("reset-password?token=")
+            .nth(1)
+            .and_then(|link| link.split('"').next())
+            .expect("The email should link to the reset page")
+            .to_string();
+        (data, user, token)
+    }
+
+    #[sqlx::test]
+    async fn password_reset_tokens_work_once(pool: PgPool) {
+        let (data, user, token) = request_reset_token(&pool).await;
+        assert!(is_password_reset_token_valid(&data, &token).await.unwrap());
+
+        reset_forgotten_password_handler(data.clone(), token.clone(), "a new password".to_string()).await.unwrap();
+
+        let password_hash = get_user(&user.email, &data.db).await.unwrap().unwrap().password;
+        assert!(Argon2::default().verify_password(b"a new password", &PasswordHash::new(&password_hash).unwrap()).is_ok());
+
+        assert!(!is_password_reset_token_valid(&data, &token).await.unwrap());
+        let reused = reset_forgotten_password_handler(data.clone(), token, "another password".to_string()).await;
+        assert!(matches!(reused, Err(AuthError::InvalidPasswordResetToken)));
+    }
+
+    #[sqlx::test]
+    async fn password_reset_tokens_expire(pool: PgPool) {
+        let (data, _, token) = request_reset_token(&pool).await;
+
+        let mut redis_client = data.redis_client.get_multiplexed_async_connection().await.unwrap();
+        let ttl: i64 = redis_client.ttl(password_reset_key(&token)).await.unwrap();
+        assert!(ttl > 0 && ttl <= PASSWORD_RESET_TOKEN_MAX_AGE_MINUTES * 60);
+
+        redis_client.pexpire::<_, ()>(password_reset_key(&token), 1).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+
+        assert!(!is_password_reset_token_valid(&data, &token).await.unwrap());
+        let expired = reset_forgotten_password_handler(data.clone(), token, "a new password".to_string()).await;
+        assert!(matches!(expired, Err(AuthError::InvalidPasswordResetToken)));
+    }
+
+    fn google_profile(subject: &str, email: &str) -> ExternalProfile {
+        ExternalProfile {
+            provider: GOOGLE_PROVIDER.to_string(),
+            provider_name: "Google".to_string(),
+            subject: subject.to_string(),
+            email: Some(email.to_string()),
+            email_verified: true,
+            given_name: Some("Grace".to_string()),
+            family_name: Some("Hopper".to_string()),
+            name: None,
+        }
+    }
+
+    #[sqlx::test]
+    async fn unknown_external_accounts_are_provisioned_as_proctors_when_allowed(pool: PgPool) {
+        let data = test_app_state(pool.clone());
+        let auto_provision = AutoProvisionConfig { enabled: true, domains: vec!["example.com".to_string()] };
+
+        let user = find_or_provision_external_user(&data, &google_profile("1", "grace@example.com"), &auto_provision, false).await.unwrap();
+        assert_eq!(user.role, Role::Proctor);
+        assert_eq!((user.first_name.as_str(), user.last_name.as_str()), ("Grace", "Hopper"));
+
+        let outside_domain = find_or_provision_external_user(&data, &google_profile("2", "grace@elsewhere.com"), &auto_provision, false).await;
+        assert!(matches!(outside_domain, Err(AuthError::AccountNotFound)));
+
+        let with_licensing_key = find_or_provision_external_user(&data, &google_profile("3", "grace@elsewhere.com"), &auto_provision, true).await;
+        assert!(with_licensing_key.is_ok());
+
+        let disabled = AutoProvisionConfig { enabled: false, domains: vec!["example.com".to_string()] };
+        let not_provisioned = find_or_provision_external_user(&data, &google_profile("4", "ada@example.com"), &disabled, true).await;
+        assert!(matches!(not_provisioned, Err(AuthError::AccountNotFound)));
+    }
+
+    #[sqlx::test]
+    async fn external_accounts_are_matched_to_users_by_verified_email(pool: PgPool) {
+        let data = test_app_state(pool.clone());
+        let user = create_test_user(&pool, Role::FrontDesk, "grace@example.com").await;
+        let no_provisioning = AutoProvisionConfig { enabled: false, domains: Vec::new() };
+
+        let found = find_or_provision_external_user(&data, &google_profile("1", "Grace@Example.com"), &no_provisioning, false).await.unwrap();
+        assert_eq!(found.id, user.id);
+
+        let mut unverified = google_profile("1", "grace@example.com");
+        unverified.email_verified = false;
+        let unverified = find_or_provision_external_user(&data, &unverified, &no_provisioning, false).await;
+        assert!(matches!(unverified, Err(AuthError::OAuthError(_))));
+    }
+}
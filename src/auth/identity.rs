@@ -0,0 +1,120 @@
+use sqlx::{Pool, Postgres};
+use uuid::Uuid;
+
+use crate::auth::{
+    middleware::AuthError,
+    model::{Role, User, UserIdentity},
+};
+
+// #######################################################################################################################################################
+// Identity Links
+// #######################################################################################################################################################
+
+// External sign-ins are matched on the provider's stable subject rather than the email address, which can change at the provider.
+// An identity belongs to at most one user, but a user can link any number of identities.
+
+pub const GOOGLE_PROVIDER: &str = "google";
+
+fn database_error(e: sqlx::Error) -> AuthError {
+    AuthError::InternalServerError(Some(format!("Database error: {}", e)))
+}
+
+/// Finds the user an identity is linked to
+pub async fn fetch_user_by_identity(
+    provider: &str,
+    subject: &str,
+    db: &Pool<Postgres>,
+) -> Result<Option<User>, AuthError> {
+    sqlx::query_as!(
+        User,
+        r#"SELECT users.id, first_name, last_name, users.email, password, users.created_at, updated_at, role AS "role: Role", disabled_at
+        FROM users
+        JOIN user_identities ON user_identities.user_id = users.id
+        WHERE user_identities.provider = $1 AND user_identities.subject = $2"#,
+        provider,
+        subject
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(database_error)
+}
+
+/// Links an identity to the user, or records a sign-in if it is already linked to them. Fails if the identity is linked
+/// to somebody else.
+pub async fn link_identity(
+    user_id: Uuid,
+    provider: &str,
+    subject: &str,
+    email: Option<&str>,
+    db: &Pool<Postgres>,
+) -> Result<(), AuthError> {
+    // The WHERE clause skips the update, and so returns no row, when the identity belongs to another user
+    let linked = sqlx::query_scalar!(
+        "INSERT INTO user_identities (user_id, provider, subject, email, last_used_at) VALUES ($1, $2, $3, $4, NOW())
+        ON CONFLICT (provider, subject) DO UPDATE SET email = EXCLUDED.email, last_used_at = NOW()
+        WHERE user_identities.user_id = EXCLUDED.user_id
+        RETURNING id",
+        user_id,
+        provider,
+        subject,
+        email
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(database_error)?;
+
+    match linked {
+        Some(_) => Ok(()),
+        None => Err(AuthError::IdentityLinkedToAnotherUser),
+    }
+}
+
+/// Lists the identities linked to the user, oldest first
+pub async fn fetch_user_identities(user_id: Uuid, db: &Pool<Postgres>) -> Result<Vec<UserIdentity>, AuthError> {
+    sqlx::query_as!(
+        UserIdentity,
+        "SELECT id, user_id, provider, subject, email, created_at, last_used_at FROM user_identities WHERE user_id = $1 ORDER BY created_at",
+        user_id
+    )
+    .fetch_all(db)
+    .await
+    .map_err(database_error)
+}
+
+/// Unlinks one of the user's identities. Returns whether an identity was unlinked.
+pub async fn unlink_identity(user_id: Uuid, identity_id: Uuid, db: &Pool<Postgres>) -> Result<bool, AuthError> {
+    let result = sqlx::query!(
+        "DELETE FROM user_identities WHERE id = $1 AND user_id = $2",
+        identity_id,
+        user_id
+    )
+    .execute(db)
+    .await
+    .map_err(database_error)?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::create_test_user;
+
+    #[sqlx::test]
+    async fn identities_belong_to_one_user(pool: Pool<Postgres>) {
+        let grace = create_test_user(&pool, Role::Proctor, "grace@example.com").await;
+        let ada = create_test_user(&pool, Role::Proctor, "ada@example.com").await;
+
+        link_identity(grace.id, GOOGLE_PROVIDER, "1", Some("grace@example.com"), &pool).await.unwrap();
+        // Signing in again with a linked identity is fine
+        link_identity(grace.id, GOOGLE_PROVIDER, "1", Some("grace@example.com"), &pool).await.unwrap();
+
+        let taken = link_identity(ada.id, GOOGLE_PROVIDER, "1", Some("ada@example.com"), &pool).await;
+        assert!(matches!(taken, Err(AuthError::IdentityLinkedToAnotherUser)));
+
+        let owner = fetch_user_by_identity(GOOGLE_PROVIDER, "1", &pool).await.unwrap().unwrap();
+        assert_eq!(owner.id, grace.id);
+        assert_eq!(fetch_user_identities(grace.id, &pool).await.unwrap()[0].email.as_deref(), Some("grace@example.com"));
+        assert!(fetch_user_identities(ada.id, &pool).await.unwrap().is_empty());
+    }
+}
@@ -1,7 +1,7 @@
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
 
 use axum::{
-    body::Body, extract::State, http::{header, HeaderMap, Request}, middleware::Next, response::{IntoResponse, Redirect},
+    body::Body, extract::{ConnectInfo, State}, http::{header, HeaderMap, Request, StatusCode}, middleware::Next, response::{Html, IntoResponse, Redirect, Response},
 };
 
 use axum_extra::extract::cookie::CookieJar;
@@ -9,7 +9,10 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     auth::{
-        model::User, 
+        api_key::{verify_api_key, API_KEY_PREFIX},
+        handlers::{add_token_cookies, refresh_session, RefreshedSession},
+        model::{ApiKey, ApiKeyScope, Role, User}, 
+        session::SessionContext,
         token,
      },
      AppState,
@@ -20,6 +23,20 @@ use redis::AsyncCommands;
 pub struct AuthorizedUser {
     pub user: User,
     pub access_token_uuid: uuid::Uuid,
+    /// None for tokens issued before sessions were tracked
+    pub session_id: Option<uuid::Uuid>,
+    /// Set when the request was made with an API key instead of an access token. The user is then the admin who issued the key.
+    pub api_key: Option<Box<ApiKey>>,
+}
+
+impl AuthorizedUser {
+    /// API keys are limited to their scopes, while logged in users get every scope their role allows.
+    pub fn has_scope(&self, scope: ApiKeyScope) -> bool {
+        match &self.api_key {
+            Some(api_key) => api_key.scopes.contains(&scope),
+            None => self.user.role.permits(scope.required_role()),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -35,6 +52,12 @@ pub enum AuthError {
     CSRFTokenMismatch,
     OAuthError(Option<String>),
     AccountNotFound,
+    InsufficientPermissions,
+    AccountDisabled,
+    CannotRemoveOwnAdminAccess,
+    InvalidPasswordResetToken,
+    RefreshTokenReused,
+    IdentityLinkedToAnotherUser,
 }
 
 // impl AuthError {
@@ -92,8 +115,13 @@ pub async fn check_auth_utility(
 
         let access_token = access_token.ok_or(AuthError::NotLoggedIn)?;
 
+        if access_token.starts_with(API_KEY_PREFIX) {
+            return check_api_key(&data, &access_token).await;
+        }
+
+        // An expired access token is expected, the session can still be refreshed with the refresh token
         let access_token_details = token::verify_jwt_token(data.env.access_token_public_key.to_owned(), &access_token)
-        .map_err(|e| AuthError::InternalServerError(Some(format!("{:?}", e))))?;
+        .map_err(|_| AuthError::InvalidToken)?;
 
         let access_token_uuid = uuid::Uuid::parse_str(&access_token_details.token_uuid.to_string())
         .map_err(|_| AuthError::InvalidToken)?;
@@ -111,20 +139,97 @@ pub async fn check_auth_utility(
 
         let user_id_uuid = uuid::Uuid::parse_str(&redis_token_user_id).map_err(|_| AuthError::ExpiredSession)?;
     
-        let user = sqlx::query_as!(User, "SELECT * FROM users WHERE id = $1", user_id_uuid)
-            .fetch_optional(&data.db)
-            .await
-            .map_err(|e| AuthError::InternalServerError(Some(format!("Error fetching user from database (this shouldn't happen, try again or contact the server administrator): {}", e))))?;
-    
-        let user = user.ok_or_else(|| AuthError::InvalidUser)?;
+        let user = fetch_active_user(&data, user_id_uuid).await?;
 
         Ok(AuthorizedUser {
             user,
             access_token_uuid,
+            session_id: access_token_details.session_id,
+            api_key: None,
         })
 
 }
 
+/// Authorizes a request made with an API key. Keys stop working when the admin who issued them is disabled or is no
+/// longer an admin.
+async fn check_api_key(data: &AppState, api_key: &str) -> Result<AuthorizedUser, AuthError> {
+    let api_key = verify_api_key(api_key, &data.db).await?;
+    let user = fetch_active_user(data, api_key.created_by).await?;
+
+    if !user.role.is_admin() {
+        return Err(AuthError::InvalidToken);
+    }
+
+    Ok(AuthorizedUser {
+        user,
+        // Keys aren't backed by an access token, and are rejected by the pages that revoke tokens
+        access_token_uuid: uuid::Uuid::nil(),
+        session_id: None,
+        api_key: Some(Box::new(api_key)),
+    })
+}
+
+/// Fetches the user a token belongs to, rejecting users that no longer exist or have been disabled.
+pub async fn fetch_active_user(
+    data: &AppState,
+    user_id: uuid::Uuid,
+) -> Result<User, AuthError> {
+    let user = sqlx::query_as!(
+        User,
+        r#"SELECT id, first_name, last_name, email, password, created_at, updated_at, role AS "role: Role", disabled_at FROM users WHERE id = $1"#,
+        user_id
+    )
+        .fetch_optional(&data.db)
+        .await
+        .map_err(|e| AuthError::InternalServerError(Some(format!("Error fetching user from database (this shouldn't happen, try again or contact the server administrator): {}", e))))?;
+
+    let user = user.ok_or(AuthError::InvalidUser)?;
+
+    // Disabling an account revokes its tokens, but check anyway in case the revocation failed partway through
+    if user.disabled_at.is_some() {
+        return Err(AuthError::AccountDisabled);
+    }
+
+    Ok(user)
+}
+
+/// Authorizes the request with its access token. If the access token is missing or expired, the refresh token cookie is rotated
+/// instead and the new cookies are returned so that they can be set on the response.
+async fn check_auth_or_refresh(
+    cookie_jar: CookieJar,
+    data: Arc<AppState>,
+    request_headers: &HeaderMap,
+    peer_address: Option<SocketAddr>,
+) -> Result<(AuthorizedUser, Option<CookieJar>), AuthError> {
+    let auth_error = match check_auth_utility(cookie_jar.clone(), data.clone(), request_headers).await {
+        // API keys are only for the JSON API, the pages act on behalf of a person
+        Ok(authorized_user) if authorized_user.api_key.is_some() => return Err(AuthError::InsufficientPermissions),
+        Ok(authorized_user) => return Ok((authorized_user, None)),
+        Err(auth_error @ (AuthError::NotLoggedIn | AuthError::InvalidToken | AuthError::ExpiredSession)) => auth_error,
+        Err(auth_error) => return Err(auth_error),
+    };
+
+    let refresh_token = match cookie_jar.get("refresh_token") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return Err(auth_error),
+    };
+
+    let session_context = SessionContext::from_request(request_headers, peer_address);
+
+    match refresh_session(&data, &refresh_token, session_context).await? {
+        RefreshedSession::Rotated { user, access_token, refresh_token } => {
+            let access_token_uuid = access_token.token_uuid;
+            let session_id = access_token.session_id;
+            let cookie_jar = add_token_cookies(cookie_jar, &data, access_token, refresh_token);
+            Ok((AuthorizedUser { user, access_token_uuid, session_id, api_key: None }, Some(cookie_jar)))
+        },
+        // The response to the request that rotated the token is setting the new cookies
+        RefreshedSession::ConcurrentlyRotated { user, access_token_uuid, session_id } => {
+            Ok((AuthorizedUser { user, access_token_uuid, session_id: Some(session_id), api_key: None }, None))
+        },
+    }
+}
+
 /// Inserts the auth status into the request
 pub async fn check_auth_middleware(
     cookie_jar: CookieJar,
@@ -133,15 +238,17 @@ pub async fn check_auth_middleware(
     next: Next,
 ) -> impl IntoResponse {
 
-    match check_auth_utility(cookie_jar, data, req.headers()).await {
-        Ok(auth_data) => {
+    let peer_address = req.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(address)| *address);
+    match check_auth_or_refresh(cookie_jar, data, req.headers(), peer_address).await {
+        Ok((auth_data, refreshed_cookies)) => {
             req.extensions_mut().insert(AuthStatus::Authorized(auth_data));
+            (refreshed_cookies, next.run(req).await).into_response()
         },
         Err(auth_error) => {
             req.extensions_mut().insert(AuthStatus::Unauthorized(auth_error));
+            next.run(req).await
         }
     }
-    next.run(req).await
 }
 
 /// Redirect to login page if auth failed, otherwise continue
@@ -151,12 +258,139 @@ pub async fn require_auth_middleware(
     mut req: Request<Body>,
     next: Next,
 ) -> impl IntoResponse {
-    match check_auth_utility(cookie_jar, data, req.headers()).await {
-        Ok(auth_data) => {
+    let peer_address = req.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(address)| *address);
+    match check_auth_or_refresh(cookie_jar, data, req.headers(), peer_address).await {
+        Ok((auth_data, refreshed_cookies)) => {
             req.extensions_mut().insert(AuthStatus::Authorized(auth_data));
-            return next.run(req).await
+            (refreshed_cookies, next.run(req).await).into_response()
         },
         // Purposefully leaving auth_error here so that I can remember to eventually handle the error itself
         Err(auth_error) => return Redirect::to("/login").into_response(),
     }
 }
+
+/// Rejects the request unless the logged in user has at least the required role. Must be layered inside
+/// require_auth_middleware, which inserts the AuthStatus this reads.
+async fn require_role(
+    required_role: Role,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    match req.extensions().get::<AuthStatus>() {
+        Some(AuthStatus::Authorized(authorized_user)) if authorized_user.user.role.permits(required_role) => next.run(req).await,
+        Some(AuthStatus::Authorized(_)) => {
+            // OK instead of FORBIDDEN so that HTMX swaps the message in
+            (StatusCode::OK, Html("<h1 id=\"primary-content\">You do not have permission to do that. Ask an admin if you need access.</h1>")).into_response()
+        },
+        _ => Redirect::to("/login").into_response(),
+    }
+}
+
+/// Only lets admins through. Used for managing users and test definitions.
+pub async fn require_admin_middleware(req: Request<Body>, next: Next) -> Response {
+    require_role(Role::Admin, req, next).await
+}
+
+/// Lets proctors and admins through. Used for administering tests and looking up testees' results.
+pub async fn require_proctor_middleware(req: Request<Body>, next: Next) -> Response {
+    require_role(Role::Proctor, req, next).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{middleware::from_fn, routing::get, Extension, Router};
+    use chrono::Utc;
+    use tower::ServiceExt;
+
+    fn authorized_user(role: Role, api_key_scopes: Option<Vec<ApiKeyScope>>) -> AuthorizedUser {
+        let user = User {
+            id: uuid::Uuid::new_v4(),
+            first_name: "Kiosk".to_string(),
+            last_name: "Admin".to_string(),
+            email: "admin@example.com".to_string(),
+            password: String::new(),
+            created_at: None,
+            updated_at: None,
+            role,
+            disabled_at: None,
+        };
+        let api_key = api_key_scopes.map(|scopes| Box::new(ApiKey {
+            id: uuid::Uuid::new_v4(),
+            name: "Kiosk".to_string(),
+            scopes,
+            created_by: user.id,
+            created_at: Utc::now(),
+            last_used_at: None,
+            revoked_at: None,
+        }));
+        AuthorizedUser { user, access_token_uuid: uuid::Uuid::nil(), session_id: None, api_key }
+    }
+
+    /// Sends a request through the role middleware, as require_auth_middleware would after checking the request
+    async fn request_as(auth_status: Option<AuthStatus>, admin_only: bool) -> Response {
+        let router = Router::new().route("/", get(|| async { "Let through" }));
+        let router = if admin_only {
+            router.layer(from_fn(require_admin_middleware))
+        } else {
+            router.layer(from_fn(require_proctor_middleware))
+        };
+        let router = match auth_status {
+            Some(auth_status) => router.layer(Extension(auth_status)),
+            None => router,
+        };
+        router.oneshot(Request::new(Body::empty())).await.unwrap()
+    }
+
+    async fn is_let_through(role: Role, admin_only: bool) -> bool {
+        let response = request_as(Some(AuthStatus::Authorized(authorized_user(role, None))), admin_only).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        body.as_ref() == b"Let through"
+    }
+
+    #[tokio::test]
+    async fn roles_are_enforced() {
+        assert!(!is_let_through(Role::FrontDesk, false).await);
+        assert!(is_let_through(Role::Proctor, false).await);
+        assert!(is_let_through(Role::Admin, false).await);
+
+        assert!(!is_let_through(Role::FrontDesk, true).await);
+        assert!(!is_let_through(Role::Proctor, true).await);
+        assert!(is_let_through(Role::Admin, true).await);
+    }
+
+    #[tokio::test]
+    async fn unauthorized_requests_are_sent_to_login() {
+        for auth_status in [None, Some(AuthStatus::Unauthorized(AuthError::NotLoggedIn))] {
+            let response = request_as(auth_status, false).await;
+            assert!(response.status().is_redirection());
+            assert_eq!(response.headers()[header::LOCATION], "/login");
+        }
+    }
+
+    #[test]
+    fn api_keys_are_limited_to_their_scopes() {
+        let kiosk = authorized_user(Role::Admin, Some(vec![ApiKeyScope::WriteQueue]));
+        assert!(kiosk.has_scope(ApiKeyScope::WriteQueue));
+        assert!(!kiosk.has_scope(ApiKeyScope::ReadResults));
+        assert!(!kiosk.has_scope(ApiKeyScope::AdministerTests));
+    }
+
+    #[test]
+    fn users_have_the_scopes_their_role_allows() {
+        let front_desk = authorized_user(Role::FrontDesk, None);
+        assert!(front_desk.has_scope(ApiKeyScope::WriteQueue));
+        assert!(!front_desk.has_scope(ApiKeyScope::ReadResults));
+        assert!(!front_desk.has_scope(ApiKeyScope::AdministerTests));
+
+        let proctor = authorized_user(Role::Proctor, None);
+        assert!(proctor.has_scope(ApiKeyScope::ReadResults));
+        assert!(proctor.has_scope(ApiKeyScope::AdministerTests));
+
+        let admin = authorized_user(Role::Admin, None);
+        assert!(admin.has_scope(ApiKeyScope::WriteQueue));
+        assert!(admin.has_scope(ApiKeyScope::ReadResults));
+        assert!(admin.has_scope(ApiKeyScope::AdministerTests));
+    }
+}
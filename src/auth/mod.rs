@@ -1,4 +1,8 @@
+pub mod api_key;
 pub mod handlers;
+pub mod identity;
 pub mod middleware;
 pub mod model;
-pub mod token;
\ No newline at end of file
+pub mod oidc;
+pub mod session;
+pub mod token;
@@ -13,6 +13,37 @@ pub struct User {
     pub created_at: Option<DateTime<Utc>>,
     #[serde(rename = "updatedAt")]
     pub updated_at: Option<DateTime<Utc>>,
+    pub role: Role,
+    /// Set when an admin disables the account. Disabled users can't log in and any existing sessions are revoked.
+    pub disabled_at: Option<DateTime<Utc>>,
+}
+
+/// What a user is allowed to do. Roles are ordered so that each role can do everything the roles below it can: front desk
+/// staff manage the queue and view pass/fail lists, proctors also administer tests and view results, and admins also manage
+/// users and test definitions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize, sqlx::Type, strum_macros::Display, strum_macros::EnumString, strum_macros::EnumIter)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "varchar", rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Role {
+    FrontDesk,
+    Proctor,
+    Admin,
+}
+
+impl Role {
+    /// Whether this role has at least the powers of the required role.
+    pub fn permits(&self, required_role: Role) -> bool {
+        *self >= required_role
+    }
+
+    pub fn can_administer_tests(&self) -> bool {
+        self.permits(Role::Proctor)
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.permits(Role::Admin)
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -27,3 +58,53 @@ pub struct LoginUserSchema {
     pub email: String,
     pub password: String,
 }
+
+/// An external sign-in identity, like a Google account, linked to a user.
+#[derive(Debug, Deserialize, sqlx::FromRow, Serialize, Clone)]
+pub struct UserIdentity {
+    pub id: uuid::Uuid,
+    pub user_id: uuid::Uuid,
+    pub provider: String,
+    /// The provider's stable id for the account
+    pub subject: String,
+    pub email: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+/// What an API key is allowed to do. Unlike roles, scopes aren't ordered, so a key can be given any combination of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, strum_macros::Display, strum_macros::EnumString, strum_macros::EnumIter)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum ApiKeyScope {
+    /// Search testees and read their graded tests
+    ReadResults,
+    /// View the queue and add or remove testees
+    WriteQueue,
+    /// Submit tests for grading
+    AdministerTests,
+}
+
+impl ApiKeyScope {
+    /// The role a logged in user needs for the same access, so that the API can check users and keys alike.
+    pub fn required_role(&self) -> Role {
+        match self {
+            ApiKeyScope::ReadResults => Role::Proctor,
+            ApiKeyScope::WriteQueue => Role::FrontDesk,
+            ApiKeyScope::AdministerTests => Role::Proctor,
+        }
+    }
+}
+
+/// A long-lived key for an integration. The key itself is only shown when it is issued, the database keeps a hash of it.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ApiKey {
+    pub id: uuid::Uuid,
+    pub name: String,
+    pub scopes: Vec<ApiKeyScope>,
+    /// The admin who issued the key. Requests made with the key act on their behalf.
+    pub created_by: uuid::Uuid,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
@@ -0,0 +1,231 @@
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header, request::Parts, HeaderMap},
+};
+use chrono::{DateTime, Utc};
+use redis::{AsyncCommands, RedisError};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    auth::{handlers::rotated_refresh_token_key, middleware::AuthError, token::TokenDetails},
+    AppState,
+};
+
+// #######################################################################################################################################################
+// Session Index
+// #######################################################################################################################################################
+
+// A session is everything that happens between a login and its logout. The access and refresh tokens change every time the
+// refresh token is rotated, but they keep the session id they were issued with in their claims. Each session is stored in
+// Redis as `session:{session_id}` with a TTL matching its refresh token, and `user_sessions:{user_id}` indexes a user's sessions.
+
+/// Where a login came from, recorded when the session is created so that users can recognize their sessions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionContext {
+    pub user_agent: String,
+    pub ip_address: String,
+}
+
+impl SessionContext {
+    /// Reads the user agent and client ip address from the request. The server usually sits behind a reverse proxy, so the
+    /// forwarded headers are preferred over the address of the connection.
+    pub fn from_request(headers: &HeaderMap, peer_address: Option<SocketAddr>) -> SessionContext {
+        let header_value = |name: &str| headers.get(name).and_then(|value| value.to_str().ok()).map(|value| value.trim().to_string());
+
+        let ip_address = header_value("x-forwarded-for")
+            .and_then(|forwarded_for| forwarded_for.split(',').next().map(|ip| ip.trim().to_string()))
+            .or_else(|| header_value("x-real-ip"))
+            .or_else(|| peer_address.map(|address| address.ip().to_string()))
+            .unwrap_or_else(|| "Unknown".to_string());
+
+        SessionContext {
+            user_agent: header_value(header::USER_AGENT.as_str()).unwrap_or_else(|| "Unknown".to_string()),
+            ip_address,
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for SessionContext {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let peer_address = parts.extensions.get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(address)| *address);
+        Ok(SessionContext::from_request(&parts.headers, peer_address))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub session_id: Uuid,
+    pub user_id: Uuid,
+    pub context: SessionContext,
+    pub created_at: DateTime<Utc>,
+    pub last_refreshed_at: Option<DateTime<Utc>>,
+    pub access_token_uuid: Uuid,
+    pub refresh_token_uuid: Uuid,
+    /// The refresh token this session was last rotated from, whose rotated record has to be deleted along with the session.
+    /// The same as refresh_token_uuid until the session is first refreshed.
+    pub previous_refresh_token_uuid: Uuid,
+}
+
+fn session_key(session_id: Uuid) -> String {
+    format!("session:{}", session_id)
+}
+
+fn user_sessions_key(user_id: Uuid) -> String {
+    format!("user_sessions:{}", user_id)
+}
+
+fn redis_error(e: RedisError) -> AuthError {
+    AuthError::InternalServerError(Some(format!("Redis error: {}", e)))
+}
+
+/// Records a new session for a freshly issued token pair.
+pub async fn create_session(
+    data: &Arc<AppState>,
+    context: SessionContext,
+    access_token_details: &TokenDetails,
+    refresh_token_details: &TokenDetails,
+) -> Result<Session, AuthError> {
+    let session = Session {
+        session_id: refresh_token_details.session_id.ok_or_else(|| AuthError::InternalServerError(Some("Tokens were issued without a session id.".to_string())))?,
+        user_id: refresh_token_details.user_id,
+        context,
+        created_at: Utc::now(),
+        last_refreshed_at: None,
+        access_token_uuid: access_token_details.token_uuid,
+        refresh_token_uuid: refresh_token_details.token_uuid,
+        previous_refresh_token_uuid: refresh_token_details.token_uuid,
+    };
+
+    save_session(data, &session).await?;
+    Ok(session)
+}
+
+/// Points the session at the token pair it was rotated to. Sessions that don't exist anymore (ie, that were revoked between
+/// the refresh token being checked and now) are left alone.
+pub async fn update_session_tokens(
+    data: &Arc<AppState>,
+    access_token_details: &TokenDetails,
+    refresh_token_details: &TokenDetails,
+) -> Result<(), AuthError> {
+    let Some(session_id) = refresh_token_details.session_id else {
+        return Ok(());
+    };
+
+    if let Some(mut session) = fetch_session(data, session_id).await? {
+        session.last_refreshed_at = Some(Utc::now());
+        session.previous_refresh_token_uuid = session.refresh_token_uuid;
+        session.access_token_uuid = access_token_details.token_uuid;
+        session.refresh_token_uuid = refresh_token_details.token_uuid;
+        save_session(data, &session).await?;
+    }
+    Ok(())
+}
+
+async fn save_session(data: &Arc<AppState>, session: &Session) -> Result<(), AuthError> {
+    let mut redis_client = data.redis_client.get_multiplexed_async_connection().await.map_err(redis_error)?;
+
+    let session_json = serde_json::to_string(session)
+        .map_err(|e| AuthError::InternalServerError(Some(format!("Error serializing session: {}", e))))?;
+    let max_age_seconds = data.env.refresh_token_max_age * 60;
+
+    redis_client
+        .set_ex::<_, _, ()>(session_key(session.session_id), session_json, max_age_seconds as u64)
+        .await
+        .map_err(redis_error)?;
+
+    let user_sessions_key = user_sessions_key(session.user_id);
+    redis_client
+        .sadd::<_, _, ()>(&user_sessions_key, session.session_id.to_string())
+        .await
+        .map_err(redis_error)?;
+    redis_client
+        .expire::<_, ()>(&user_sessions_key, max_age_seconds)
+        .await
+        .map_err(redis_error)
+}
+
+pub async fn fetch_session(data: &Arc<AppState>, session_id: Uuid) -> Result<Option<Session>, AuthError> {
+    let mut redis_client = data.redis_client.get_multiplexed_async_connection().await.map_err(redis_error)?;
+
+    let session_json: Option<String> = redis_client.get(session_key(session_id)).await.map_err(redis_error)?;
+
+    session_json
+        .map(|session_json| serde_json::from_str(&session_json))
+        .transpose()
+        .map_err(|e| AuthError::InternalServerError(Some(format!("Error deserializing session: {}", e))))
+}
+
+/// Lists the user's sessions, newest first. Sessions that expired are pruned from the index along the way.
+pub async fn fetch_user_sessions(data: &Arc<AppState>, user_id: Uuid) -> Result<Vec<Session>, AuthError> {
+    let mut redis_client = data.redis_client.get_multiplexed_async_connection().await.map_err(redis_error)?;
+
+    let user_sessions_key = user_sessions_key(user_id);
+    let session_ids: Vec<String> = redis_client.smembers(&user_sessions_key).await.map_err(redis_error)?;
+
+    let mut sessions = Vec::new();
+    for session_id in session_ids {
+        match Uuid::parse_str(&session_id).ok() {
+            Some(parsed_session_id) => match fetch_session(data, parsed_session_id).await? {
+                Some(session) => sessions.push(session),
+                None => redis_client.srem::<_, _, ()>(&user_sessions_key, &session_id).await.map_err(redis_error)?,
+            },
+            None => redis_client.srem::<_, _, ()>(&user_sessions_key, &session_id).await.map_err(redis_error)?,
+        }
+    }
+
+    sessions.sort_by_key(|session| std::cmp::Reverse(session.created_at));
+    Ok(sessions)
+}
+
+/// Logs a single session out by deleting its tokens. Only revokes the session if it belongs to the given user, so that users
+/// can't revoke each other's sessions by guessing ids. Returns whether a session was revoked.
+pub async fn revoke_session(data: &Arc<AppState>, user_id: Uuid, session_id: Uuid) -> Result<bool, AuthError> {
+    let session = match fetch_session(data, session_id).await? {
+        Some(session) if session.user_id == user_id => session,
+        _ => return Ok(false),
+    };
+
+    let mut redis_client = data.redis_client.get_multiplexed_async_connection().await.map_err(redis_error)?;
+
+    let keys = [
+        session.access_token_uuid.to_string(),
+        session.refresh_token_uuid.to_string(),
+        rotated_refresh_token_key(session.previous_refresh_token_uuid),
+        session_key(session_id),
+    ];
+
+    redis_client
+        .del::<_, ()>(&keys)
+        .await
+        .map_err(redis_error)?;
+    redis_client
+        .srem::<_, _, ()>(user_sessions_key(user_id), session_id.to_string())
+        .await
+        .map_err(redis_error)?;
+
+    Ok(true)
+}
+
+/// Deletes the session records of every session the user has. The tokens themselves are revoked by revoke_user_tokens.
+pub async fn delete_user_sessions(data: &Arc<AppState>, user_id: Uuid) -> Result<(), AuthError> {
+    let mut redis_client = data.redis_client.get_multiplexed_async_connection().await.map_err(redis_error)?;
+
+    let user_sessions_key = user_sessions_key(user_id);
+    let session_ids: Vec<String> = redis_client.smembers(&user_sessions_key).await.map_err(redis_error)?;
+
+    let mut keys: Vec<String> = session_ids
+        .iter()
+        .filter_map(|session_id| Uuid::parse_str(session_id).ok())
+        .map(session_key)
+        .collect();
+    keys.push(user_sessions_key);
+
+    redis_client.del::<_, ()>(keys).await.map_err(redis_error)
+}
@@ -7,6 +7,7 @@ pub struct TokenDetails {
     pub token: Option<String>,
     pub token_uuid: uuid::Uuid,
     pub user_id: uuid::Uuid,
+    pub session_id: Option<uuid::Uuid>,
     pub expires_in: Option<i64>,
 }
 
@@ -14,6 +15,9 @@ pub struct TokenDetails {
 pub struct TokenClaims {
     pub sub: String,
     pub token_uuid: String,
+    /// Tokens issued before sessions were tracked don't have a session id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
     pub exp: i64,
     pub iat: i64,
     pub nbf: i64,
@@ -21,6 +25,7 @@ pub struct TokenClaims {
 
 pub fn generate_jwt_token(
     user_id: uuid::Uuid,
+    session_id: uuid::Uuid,
     ttl: i64,
     private_key: String,
 ) -> Result<TokenDetails, jsonwebtoken::errors::Error> {
@@ -30,6 +35,7 @@ pub fn generate_jwt_token(
     let now = chrono::Utc::now();
     let mut token_details = TokenDetails {
         user_id,
+        session_id: Some(session_id),
         token_uuid: Uuid::new_v4(),
         expires_in: Some((now + chrono::Duration::minutes(ttl)).timestamp()),
         token: None,
@@ -38,6 +44,7 @@ pub fn generate_jwt_token(
     let claims = TokenClaims {
         sub: token_details.user_id.to_string(),
         token_uuid: token_details.token_uuid.to_string(),
+        sid: Some(session_id.to_string()),
         exp: token_details.expires_in.unwrap(),
         iat: now.timestamp(),
         nbf: now.timestamp(),
@@ -70,11 +77,13 @@ pub fn verify_jwt_token(
 
     let user_id = Uuid::parse_str(decoded.claims.sub.as_str()).unwrap();
     let token_uuid = Uuid::parse_str(decoded.claims.token_uuid.as_str()).unwrap();
+    let session_id = decoded.claims.sid.and_then(|sid| Uuid::parse_str(&sid).ok());
 
     Ok(TokenDetails {
         token: None,
         token_uuid,
         user_id,
+        session_id,
         expires_in: None,
     })
 }
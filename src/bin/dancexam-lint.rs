@@ -0,0 +1,216 @@
+//! Checks a test definitions file without running the server so that test writers can catch mistakes before deploying.
+//! Every problem in the file is reported with its line number, the computed max score of each test is printed so that
+//! `max_score` can be filled in, and the exit code is nonzero if any problems were found (for use in pre-commit hooks).
+//!
+//! Usage: dancexam-lint [test_definitions.yaml ...]
+
+use std::{collections::HashMap, process::ExitCode};
+
+use dancer_test::exam::{
+    handlers::{parse_test_definition_from_str, TEST_DEFINITIONS_FILE_PATH},
+    models::{DefinitionError, DefinitionLocation},
+};
+use yaml_rust2::{parser::{Event, MarkedEventReceiver, Parser}, scanner::Marker};
+
+fn main() -> ExitCode {
+    let mut file_paths: Vec<String> = std::env::args().skip(1).collect();
+    if file_paths.is_empty() {
+        file_paths.push(TEST_DEFINITIONS_FILE_PATH.to_string());
+    }
+
+    let problem_count: usize = file_paths.iter().map(|file_path| lint_file(file_path)).sum();
+
+    if problem_count == 0 {
+        println!("No problems found.");
+        ExitCode::SUCCESS
+    } else {
+        println!("{} problem(s) found.", problem_count);
+        ExitCode::FAILURE
+    }
+}
+
+/// Prints every problem in the file as `file:line: error: message` and returns the number of problems found.
+fn lint_file(file_path: &str) -> usize {
+    let yaml_string = match std::fs::read_to_string(file_path) {
+        Ok(yaml_string) => yaml_string,
+        Err(e) => {
+            println!("{}: error: Couldn't read file: {}", file_path, e);
+            return 1;
+        }
+    };
+
+    // Serde stops at the first parsing error, so there is nothing more to report until it is fixed
+    let tests = match parse_test_definition_from_str(&yaml_string) {
+        Ok(tests) => tests,
+        Err(e) => {
+            match e.location() {
+                Some(location) => println!("{}:{}:{}: error: {}", file_path, location.line(), location.column(), e),
+                None => println!("{}: error: {}", file_path, e),
+            }
+            return 1;
+        }
+    };
+
+    let line_index = YamlLineIndex::new(&yaml_string);
+    let mut problem_count = 0;
+
+    for (test_index, test) in tests.tests.iter().enumerate() {
+        let mut errors = test.validate().err().unwrap_or_default();
+
+        // Test names must be unique across the file, which only shows up when validating the whole file
+        if tests.tests[..test_index].iter().any(|other| other.metadata.test_name == test.metadata.test_name) {
+            errors.insert(0, DefinitionError::DuplicateTestName { test_name: test.metadata.test_name.clone() });
+        }
+
+        for error in &errors {
+            println!("{}:{}: error: {}", file_path, line_index.line_of(test_index, &error.location()), error);
+        }
+        problem_count += errors.len();
+
+        println!(
+            "{}: '{}' has a computed max score of {} (max_score is set to {}).",
+            file_path, test.metadata.test_name, test.calculate_max_score(), test.metadata.max_score
+        );
+    }
+
+    problem_count
+}
+
+// #######################################################################################################################################################
+// Line Lookup
+// #######################################################################################################################################################
+
+/// Maps the path of every node in a yaml document (ie, `tests[0].tables[1].sections[0].name`) to the line it starts on.
+/// serde_yaml doesn't expose where a value came from, so the document is walked a second time with yaml_rust2's marked events.
+struct YamlLineIndex {
+    lines: HashMap<String, usize>,
+}
+
+impl YamlLineIndex {
+    fn new(yaml_string: &str) -> YamlLineIndex {
+        let mut builder = YamlLineIndexBuilder { lines: HashMap::new(), containers: Vec::new() };
+
+        // The file already parsed with serde, so a scan error here would be surprising. Worst case, lines fall back to 1.
+        let _ = Parser::new_from_str(yaml_string).load(&mut builder, false);
+
+        YamlLineIndex { lines: builder.lines }
+    }
+
+    /// Finds the line of the most specific node the location points to that exists in the document.
+    fn line_of(&self, test_index: usize, location: &DefinitionLocation) -> usize {
+        let mut path = format!("tests[{}]", test_index);
+        let mut candidates = vec![path.clone()];
+
+        match (location.rule_index, location.table_index, location.field) {
+            (Some(rule_index), _, field) => {
+                path.push_str(&format!(".rules[{}]", rule_index));
+                candidates.push(path.clone());
+
+                if let Some(field) = field {
+                    candidates.push(format!("{}.{}", path, field));
+                }
+            }
+            // Fields that aren't on a table belong to the test's metadata
+            (None, None, Some(field)) => candidates.push(format!("{}.metadata.{}", path, field)),
+            (None, Some(table_index), _) => {
+                path.push_str(&format!(".tables[{}]", table_index));
+                candidates.push(path.clone());
+
+                if let Some(section_index) = location.section_index {
+                    path.push_str(&format!(".sections[{}]", section_index));
+                    candidates.push(path.clone());
+                }
+                if let Some(competency_index) = location.competency_index {
+                    path.push_str(&format!(".competencies[{}]", competency_index));
+                    candidates.push(path.clone());
+                }
+                if let Some(field) = location.field {
+                    candidates.push(format!("{}.{}", path, field));
+                }
+            }
+            (None, None, None) => (),
+        }
+
+        candidates
+            .iter()
+            .rev()
+            .find_map(|candidate| self.lines.get(candidate).copied())
+            .unwrap_or(1)
+    }
+}
+
+enum Container {
+    Sequence { path: String, next_index: usize },
+    Mapping { path: String, key: Option<String> },
+}
+
+struct YamlLineIndexBuilder {
+    lines: HashMap<String, usize>,
+    containers: Vec<Container>,
+}
+
+impl YamlLineIndexBuilder {
+    /// Records where a node starts and returns its path, or None if the node is a mapping key.
+    fn start_node(&mut self, scalar: Option<&str>, mark: Marker) -> Option<String> {
+        let path = match self.containers.last_mut() {
+            None => String::new(),
+            Some(Container::Sequence { path, next_index }) => format!("{}[{}]", path, next_index),
+            Some(Container::Mapping { path, key: Some(key) }) => join_key(path, key),
+            Some(Container::Mapping { path, key }) => {
+                // Keys are recorded instead of their values since block sequences start on the line after their key
+                let key_name = scalar.unwrap_or_default().to_string();
+                self.lines.insert(join_key(path, &key_name), mark.line());
+                *key = Some(key_name);
+                return None;
+            }
+        };
+        self.lines.entry(path.clone()).or_insert(mark.line());
+        Some(path)
+    }
+
+    /// Scalars and aliases start and end in the same event.
+    fn leaf_node(&mut self, scalar: Option<&str>, mark: Marker) {
+        if self.start_node(scalar, mark).is_some() {
+            self.end_node();
+        }
+    }
+
+    /// Moves the parent container on to its next item once a value node has been fully read.
+    fn end_node(&mut self) {
+        match self.containers.last_mut() {
+            Some(Container::Sequence { next_index, .. }) => *next_index += 1,
+            Some(Container::Mapping { key, .. }) => *key = None,
+            None => (),
+        }
+    }
+}
+
+impl MarkedEventReceiver for YamlLineIndexBuilder {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        match event {
+            Event::Scalar(value, ..) => self.leaf_node(Some(&value), mark),
+            Event::Alias(_) => self.leaf_node(None, mark),
+            Event::SequenceStart(..) => {
+                let path = self.start_node(None, mark).unwrap_or_default();
+                self.containers.push(Container::Sequence { path, next_index: 0 });
+            }
+            Event::MappingStart(..) => {
+                let path = self.start_node(None, mark).unwrap_or_default();
+                self.containers.push(Container::Mapping { path, key: None });
+            }
+            Event::SequenceEnd | Event::MappingEnd => {
+                self.containers.pop();
+                self.end_node();
+            }
+            _ => (),
+        }
+    }
+}
+
+fn join_key(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
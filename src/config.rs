@@ -12,6 +12,7 @@ pub struct GoogleOAuthConfig {
     pub auth_uri: AuthUrl,
     pub token_uri: TokenUrl,
     pub redirect_uri: RedirectUrl,
+    pub auto_provision: AutoProvisionConfig,
 }
 impl GoogleOAuthConfig {
     pub fn init() -> Option<GoogleOAuthConfig> {
@@ -20,6 +21,7 @@ impl GoogleOAuthConfig {
         let auth_uri = get_env_var("GOOGLE_OAUTH_AUTH_URI");
         let token_uri = get_env_var("GOOGLE_OAUTH_TOKEN_URI");
         let redirect_uri = get_env_var("GOOGLE_OAUTH_REDIRECT_URI");
+        let auto_provision = AutoProvisionConfig::init("GOOGLE_OAUTH");
 
         match (
             client_id.as_str(),
@@ -63,6 +65,7 @@ impl GoogleOAuthConfig {
                     auth_uri: AuthUrl::new(auth_uri.to_string()).expect("Unable to parse GOOGLE_OAUTH_AUTH_URI."),
                     token_uri: TokenUrl::new(token_uri.to_string()).expect("Unable to parse GOOGLE_OAUTH_TOKEN_URI."),
                     redirect_uri: RedirectUrl::new(redirect_uri.to_string()).expect("Unable to parse GOOGLE_OAUTH_REDIRECT_URI."),
+                    auto_provision,
                 })
             }
         }
@@ -70,6 +73,91 @@ impl GoogleOAuthConfig {
 }
 
 
+/// Whether signing in with an unknown external account creates a proctor account for it, as long as its email is in one of
+/// the domains or the licensing key was supplied when starting the sign-in.
+#[derive(Debug, Clone)]
+pub struct AutoProvisionConfig {
+    pub enabled: bool,
+    /// Lowercase email domains, without the @
+    pub domains: Vec<String>,
+}
+impl AutoProvisionConfig {
+    /// Reads {prefix}_AUTO_PROVISION and {prefix}_AUTO_PROVISION_DOMAINS
+    pub fn init(prefix: &str) -> AutoProvisionConfig {
+        let enabled_var = format!("{}_AUTO_PROVISION", prefix);
+        let enabled = get_env_var(&enabled_var).to_lowercase().trim().parse().unwrap_or_else(|_| panic!("{} should be TRUE or FALSE.", enabled_var));
+        let domains = get_env_var(&format!("{}_AUTO_PROVISION_DOMAINS", prefix))
+            .split(',')
+            .map(|domain| domain.trim().trim_start_matches('@').to_ascii_lowercase())
+            .filter(|domain| !domain.is_empty())
+            .collect();
+
+        AutoProvisionConfig { enabled, domains }
+    }
+
+    /// Whether an unknown external account with this (verified) email can have an account created for it.
+    pub fn allows(&self, email: &str, licensing_key_supplied: bool) -> bool {
+        if !self.enabled {
+            return false;
+        }
+
+        let domain = email.rsplit_once('@').map(|(_, domain)| domain.to_ascii_lowercase());
+        licensing_key_supplied || domain.is_some_and(|domain| self.domains.contains(&domain))
+    }
+}
+
+/// An OpenID Connect provider, like Microsoft or a self-hosted Keycloak. Providers are listed by id in OIDC_PROVIDERS, and each
+/// one is configured with OIDC_<ID>_* environment variables. Its endpoints are discovered from the issuer when the server starts.
+#[derive(Debug, Clone)]
+pub struct OidcProviderConfig {
+    /// Used in URLs and to tell linked identities apart, ie "keycloak"
+    pub id: String,
+    pub display_name: String,
+    pub issuer_url: String,
+    pub client_id: ClientId,
+    pub client_secret: ClientSecret,
+    /// Must be this server's /auth/oidc/<id>/callback
+    pub redirect_uri: RedirectUrl,
+    pub auto_provision: AutoProvisionConfig,
+}
+impl OidcProviderConfig {
+    pub fn init() -> Vec<OidcProviderConfig> {
+        let provider_ids = get_env_var("OIDC_PROVIDERS");
+
+        let providers: Vec<OidcProviderConfig> = provider_ids
+            .split(',')
+            .map(|id| id.trim().to_ascii_lowercase())
+            .filter(|id| !id.is_empty())
+            .map(|id| {
+                if !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                    panic!("OIDC provider id \"{}\" should only contain letters, numbers, dashes and underscores.", id);
+                }
+
+                let prefix = format!("OIDC_{}", id.to_ascii_uppercase().replace('-', "_"));
+                let redirect_uri_var = format!("{}_REDIRECT_URI", prefix);
+
+                OidcProviderConfig {
+                    display_name: get_env_var(&format!("{}_DISPLAY_NAME", prefix)),
+                    issuer_url: get_env_var(&format!("{}_ISSUER_URL", prefix)).trim_end_matches('/').to_string(),
+                    client_id: ClientId::new(get_env_var(&format!("{}_CLIENT_ID", prefix))),
+                    client_secret: ClientSecret::new(get_env_var(&format!("{}_CLIENT_SECRET", prefix))),
+                    redirect_uri: RedirectUrl::new(get_env_var(&redirect_uri_var)).unwrap_or_else(|_| panic!("Unable to parse {}.", redirect_uri_var)),
+                    auto_provision: AutoProvisionConfig::init(&prefix),
+                    id,
+                }
+            })
+            .collect();
+
+        match providers.len() {
+            0 => println!("\nOpenID Connect functionality disabled since OIDC_PROVIDERS was left blank."),
+            _ => println!("\nOpenID Connect functionality is enabled for: {}.", providers.iter().map(|provider| provider.id.as_str()).collect::<Vec<_>>().join(", ")),
+        }
+
+        providers
+    }
+}
+
+
 #[derive(Debug, Clone)]
 pub struct SecretsConfig {
     pub is_demo_mode: bool,
@@ -78,6 +166,9 @@ pub struct SecretsConfig {
     pub server_port: i64,
     pub database_url: String,
     pub redis_url: String,
+    /// Where users reach the site, like "https://dancer-test.example.com", without a trailing slash. Links in emails are
+    /// built from this rather than the request's Host header, which the client controls.
+    pub public_base_url: String,
 
     pub access_token_private_key: String,
     pub access_token_public_key: String,
@@ -98,6 +189,10 @@ impl SecretsConfig {
         let server_port = get_env_var("SERVER_PORT").parse::<i64>().expect("Server port (ENV_VAR=SERVER_PORT) should be an integer.");
         let database_url = get_env_var("DATABASE_URL");
         let redis_url = get_env_var("REDIS_URL");
+        let public_base_url = get_env_var("PUBLIC_BASE_URL").trim().trim_end_matches('/').to_string();
+        if !public_base_url.starts_with("https://") && !public_base_url.starts_with("http://") {
+            panic!("PUBLIC_BASE_URL should be the site's URL, starting with https://");
+        }
 
         let access_token_private_key = get_env_var("ACCESS_TOKEN_PRIVATE_KEY");
         let access_token_public_key = get_env_var("ACCESS_TOKEN_PUBLIC_KEY");
@@ -116,6 +211,7 @@ impl SecretsConfig {
             server_port,
             database_url,
             redis_url,
+            public_base_url,
             access_token_private_key,
             access_token_public_key,
             refresh_token_private_key,
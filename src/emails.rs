@@ -0,0 +1,427 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use lettre::{AsyncSmtpTransport, Tokio1Executor};
+use serde::Serialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    exam::{handlers::{send_email, TestError}, models::SMTPConfig},
+    outbox::{run_outbox, AttemptOutcome, Outbox, OutboxPolicy, RecordedAttempt},
+    AppState,
+};
+
+// #######################################################################################################################################################
+// Email Jobs
+// #######################################################################################################################################################
+
+// Results emails are written to the email_jobs table by the code that wants them sent, and deliver_emails sends them in
+// the background so that a slow or flaky SMTP server never holds up a request or silently drops an email.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, sqlx::Type, strum_macros::Display)]
+#[sqlx(type_name = "varchar", rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum EmailJobStatus {
+    /// Waiting for its first attempt or a retry
+    Pending,
+    Sent,
+    /// Gave up after EMAIL_POLICY.max_attempts and is waiting for an admin to resend it
+    DeadLetter,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EmailJob {
+    pub id: Uuid,
+    pub testee_id: Uuid,
+    pub testee_first_name: String,
+    pub testee_last_name: String,
+    pub testee_email: String,
+    pub attached_test_id: Option<Uuid>,
+    pub status: EmailJobStatus,
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_attempt_at: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// Who sent the email again by hand. Empty for emails queued when a test was saved.
+    pub requested_by_first_name: Option<String>,
+    pub requested_by_last_name: Option<String>,
+}
+
+/// Queues the results email for a testee, with the score report and certificate of the attached test
+pub async fn enqueue_results_email(
+    pool: &PgPool,
+    testee_id: Uuid,
+    attached_test_id: Option<Uuid>,
+    server_root_url: &str,
+) -> Result<Uuid, sqlx::Error> {
+    let row = sqlx::query!(
+        "INSERT INTO email_jobs (testee_id, attached_test_id, server_root_url) VALUES ($1, $2, $3) RETURNING id",
+        testee_id,
+        attached_test_id,
+        server_root_url
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(row.id)
+}
+
+/// The most recent emails, newest first
+pub async fn fetch_email_jobs(pool: &PgPool, limit: i64) -> Result<Vec<EmailJob>, sqlx::Error> {
+    sqlx::query_as!(
+        EmailJob,
+        r#"SELECT j.id, j.testee_id, t.first_name AS testee_first_name, t.last_name AS testee_last_name, t.email AS testee_email,
+            j.attached_test_id, j.status AS "status: EmailJobStatus", j.attempts, j.next_attempt_at, j.last_attempt_at, j.last_error, j.created_at,
+            u.first_name AS "requested_by_first_name?", u.last_name AS "requested_by_last_name?"
+        FROM email_jobs j
+        JOIN testees t ON t.id = j.testee_id
+        LEFT JOIN users u ON u.id = j.requested_by
+        ORDER BY j.created_at DESC
+        LIMIT $1"#,
+        limit
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Sends a dead lettered email again, with a fresh set of attempts
+pub async fn resend_email_job(pool: &PgPool, job_id: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE email_jobs SET status = 'pending', attempts = 0, next_attempt_at = NOW() WHERE id = $1 AND status = 'dead_letter'",
+        job_id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+// #######################################################################################################################################################
+// Manual Resends
+// #######################################################################################################################################################
+
+/// A testee is sent at most one results email in this long, however it was queued
+const RESEND_COOLDOWN_MINUTES: i32 = 5;
+/// How many results emails one user can send again by hand in an hour
+const RESENDS_PER_USER_PER_HOUR: i64 = 30;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResendEmailError {
+    TesteeNotFound,
+    /// The test doesn't exist or belongs to another testee
+    TestNotFound,
+    TesteeRecentlyEmailed,
+    TooManyResends,
+    InternalServerError(String),
+}
+
+impl std::fmt::Display for ResendEmailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResendEmailError::TesteeNotFound => write!(f, "No testee with that id exists."),
+            ResendEmailError::TestNotFound => write!(f, "The testee has not taken a test with that id."),
+            ResendEmailError::TesteeRecentlyEmailed => write!(f, "This testee was sent an email in the last {} minutes. Try again later.", RESEND_COOLDOWN_MINUTES),
+            ResendEmailError::TooManyResends => write!(f, "You have resent {} emails in the last hour. Try again later.", RESENDS_PER_USER_PER_HOUR),
+            ResendEmailError::InternalServerError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for ResendEmailError {
+    fn from(error: sqlx::Error) -> Self {
+        ResendEmailError::InternalServerError(error.to_string())
+    }
+}
+
+/// Queues the results email for a testee again on behalf of a user, with the PDFs of the given test attached. The job
+/// records who asked for it, which is the audit trail shown on the Emails page.
+pub async fn request_results_email(
+    pool: &PgPool,
+    testee_id: Uuid,
+    attached_test_id: Option<Uuid>,
+    server_root_url: &str,
+    requested_by: Uuid,
+) -> Result<Uuid, ResendEmailError> {
+    let testee_exists = sqlx::query_scalar!(r#"SELECT EXISTS(SELECT 1 FROM testees WHERE id = $1) AS "exists!""#, testee_id)
+        .fetch_one(pool)
+        .await?;
+    if !testee_exists {
+        return Err(ResendEmailError::TesteeNotFound);
+    }
+
+    if let Some(test_id) = attached_test_id {
+        let test_belongs_to_testee = sqlx::query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM test_metadata WHERE test_id = $1 AND testee_id = $2) AS "exists!""#,
+            test_id,
+            testee_id
+        )
+        .fetch_one(pool)
+        .await?;
+        if !test_belongs_to_testee {
+            return Err(ResendEmailError::TestNotFound);
+        }
+    }
+
+    // Hold locks on the testee and the user until the job is inserted, so that requests made at the same time can't
+    // all pass the checks below. Every request takes them in the same order.
+    let mut transaction = pool.begin().await?;
+    sqlx::query!("SELECT pg_advisory_xact_lock(hashtextextended('email_jobs testee ' || $1::uuid::text, 0))", testee_id)
+        .execute(&mut *transaction)
+        .await?;
+    sqlx::query!("SELECT pg_advisory_xact_lock(hashtextextended('email_jobs requested_by ' || $1::uuid::text, 0))", requested_by)
+        .execute(&mut *transaction)
+        .await?;
+
+    let recently_emailed = sqlx::query_scalar!(
+        r#"SELECT EXISTS(SELECT 1 FROM email_jobs WHERE testee_id = $1 AND created_at > NOW() - make_interval(mins => $2)) AS "exists!""#,
+        testee_id,
+        RESEND_COOLDOWN_MINUTES
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+    if recently_emailed {
+        return Err(ResendEmailError::TesteeRecentlyEmailed);
+    }
+
+    let resends_in_last_hour = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM email_jobs WHERE requested_by = $1 AND created_at > NOW() - INTERVAL '1 hour'"#,
+        requested_by
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+    if resends_in_last_hour >= RESENDS_PER_USER_PER_HOUR {
+        return Err(ResendEmailError::TooManyResends);
+    }
+
+    let row = sqlx::query!(
+        "INSERT INTO email_jobs (testee_id, attached_test_id, server_root_url, requested_by) VALUES ($1, $2, $3, $4) RETURNING id",
+        testee_id,
+        attached_test_id,
+        server_root_url,
+        requested_by
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+
+    transaction.commit().await?;
+
+    Ok(row.id)
+}
+
+// #######################################################################################################################################################
+// Delivery
+// #######################################################################################################################################################
+
+/// With the backoff, the last attempt is made a little over two hours after the first
+const EMAIL_POLICY: OutboxPolicy = OutboxPolicy {
+    items_per_poll: 10,
+    // lettre's default SMTP timeout
+    send_timeout: Duration::from_secs(60),
+    max_attempts: 8,
+    first_retry_delay_seconds: 60,
+    max_retry_delay_seconds: 2 * 60 * 60,
+};
+
+/// A job that is due
+struct DueEmailJob {
+    id: Uuid,
+    testee_id: Uuid,
+    attached_test_id: Option<Uuid>,
+    server_root_url: String,
+    attempts: i32,
+}
+
+/// The email_jobs table
+struct EmailOutbox {
+    pool: PgPool,
+    smtp_mailer: AsyncSmtpTransport<Tokio1Executor>,
+    smtp_config: SMTPConfig,
+}
+
+impl Outbox for EmailOutbox {
+    type Item = DueEmailJob;
+    /// The error, if the email wasn't sent
+    type Attempt = Option<String>;
+
+    const POLICY: OutboxPolicy = EMAIL_POLICY;
+    const NAME: &'static str = "emails";
+
+    async fn claim_due(&self) -> Result<Vec<DueEmailJob>, sqlx::Error> {
+        sqlx::query_as!(
+            DueEmailJob,
+            "UPDATE email_jobs SET next_attempt_at = NOW() + make_interval(secs => $1)
+            WHERE id IN (
+                SELECT id FROM email_jobs WHERE status = 'pending' AND next_attempt_at <= NOW()
+                ORDER BY next_attempt_at LIMIT $2 FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, testee_id, attached_test_id, server_root_url, attempts",
+            Self::POLICY.claim_lease_seconds(),
+            Self::POLICY.items_per_poll
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    fn previous_attempts(job: &DueEmailJob) -> i32 {
+        job.attempts
+    }
+
+    async fn send(&self, job: &DueEmailJob) -> Option<String> {
+        let error = send_email(&self.pool, &self.smtp_mailer, self.smtp_config.clone(), job.testee_id, job.server_root_url.clone(), job.attached_test_id)
+            .await
+            .err()
+            .map(|e| match e {
+                TestError::InternalServerError(message) => message,
+                other => format!("{:?}", other),
+            });
+
+        if let Some(error) = &error {
+            eprintln!("Failed to send email job {} (attempt {}): {}", job.id, job.attempts + 1, error);
+        }
+        error
+    }
+
+    fn succeeded(error: &Option<String>) -> bool {
+        error.is_none()
+    }
+
+    async fn record_attempt(&self, job: &DueEmailJob, error: Option<String>, recorded: RecordedAttempt) -> Result<(), sqlx::Error> {
+        let status = match recorded.outcome {
+            AttemptOutcome::Succeeded => EmailJobStatus::Sent,
+            AttemptOutcome::Retrying => EmailJobStatus::Pending,
+            AttemptOutcome::GaveUp => EmailJobStatus::DeadLetter,
+        };
+
+        sqlx::query!(
+            "UPDATE email_jobs SET
+                status = $2::varchar, attempts = $3, next_attempt_at = $4, last_attempt_at = NOW(), last_error = $5,
+                sent_at = CASE WHEN $2::varchar = 'sent' THEN NOW() END
+            WHERE id = $1",
+            job.id,
+            status as EmailJobStatus,
+            recorded.attempts,
+            recorded.next_attempt_at,
+            error
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// Runs for the life of the server, sending queued emails. Does nothing when email isn't set up, since nothing is
+/// queued then.
+pub async fn deliver_emails(data: Arc<AppState>) {
+    let (smtp_mailer, smtp_config) = match (&data.smtp_mailer, &data.smtp_config) {
+        (Some(smtp_mailer), Some(smtp_config)) => (smtp_mailer.clone(), smtp_config.clone()),
+        _ => return,
+    };
+
+    run_outbox(EmailOutbox { pool: data.db.clone(), smtp_mailer, smtp_config }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        exam::handlers::create_testee,
+        outbox::deliver_due,
+        testing::{local_smtp_config, spawn_smtp_sink},
+    };
+
+    fn email_outbox(pool: PgPool, port: u16) -> EmailOutbox {
+        let smtp_config = local_smtp_config(port);
+        EmailOutbox { pool, smtp_mailer: smtp_config.build_transport().unwrap(), smtp_config }
+    }
+
+    async fn fetch_only_email_job(pool: &PgPool) -> EmailJob {
+        let mut jobs = fetch_email_jobs(pool, 10).await.unwrap();
+        assert_eq!(jobs.len(), 1);
+        jobs.remove(0)
+    }
+
+    #[sqlx::test]
+    async fn due_emails_are_sent_once(pool: PgPool) {
+        let (port, mut received) = spawn_smtp_sink(true).await;
+        let outbox = email_outbox(pool.clone(), port);
+        let testee = create_testee(&pool, "Jane", "Doe", "jane@example.com").await.unwrap();
+        enqueue_results_email(&pool, testee.id.unwrap(), None, "https://dancexam.example.com").await.unwrap();
+
+        assert_eq!(deliver_due(&outbox).await.unwrap(), 1);
+
+        let data = received.recv().await.unwrap();
+        assert!(data.contains("Subject: Your Dancexam Results"));
+        let job = fetch_only_email_job(&pool).await;
+        assert_eq!(job.status, EmailJobStatus::Sent);
+        assert_eq!(job.attempts, 1);
+        assert_eq!(job.last_error, None);
+
+        assert_eq!(deliver_due(&outbox).await.unwrap(), 0);
+    }
+
+    #[sqlx::test]
+    async fn rejected_emails_back_off_then_are_dead_lettered(pool: PgPool) {
+        let (port, _received) = spawn_smtp_sink(false).await;
+        let outbox = email_outbox(pool.clone(), port);
+        let testee = create_testee(&pool, "Jane", "Doe", "jane@example.com").await.unwrap();
+        enqueue_results_email(&pool, testee.id.unwrap(), None, "https://dancexam.example.com").await.unwrap();
+
+        let before_attempt = Utc::now();
+        assert_eq!(deliver_due(&outbox).await.unwrap(), 1);
+
+        let job = fetch_only_email_job(&pool).await;
+        assert_eq!(job.status, EmailJobStatus::Pending);
+        assert_eq!(job.attempts, 1);
+        assert!(job.last_error.is_some());
+        assert!(job.next_attempt_at >= before_attempt + EMAIL_POLICY.retry_delay(1));
+        assert!(job.next_attempt_at < before_attempt + EMAIL_POLICY.retry_delay(2));
+
+        // Not due again until the backoff has passed
+        assert_eq!(deliver_due(&outbox).await.unwrap(), 0);
+
+        sqlx::query("UPDATE email_jobs SET attempts = $1, next_attempt_at = NOW()")
+            .bind(EMAIL_POLICY.max_attempts - 1)
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(deliver_due(&outbox).await.unwrap(), 1);
+
+        let job = fetch_only_email_job(&pool).await;
+        assert_eq!(job.status, EmailJobStatus::DeadLetter);
+        assert_eq!(job.attempts, EMAIL_POLICY.max_attempts);
+        assert_eq!(deliver_due(&outbox).await.unwrap(), 0);
+    }
+
+    #[sqlx::test]
+    async fn simultaneous_resends_are_rate_limited_together(pool: PgPool) {
+        let testee = create_testee(&pool, "Jane", "Doe", "jane@example.com").await.unwrap();
+        let user_id: Uuid = sqlx::query_scalar("INSERT INTO users (first_name, last_name, email, password) VALUES ('Ada', 'Admin', 'ada@example.com', 'unused') RETURNING id")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+
+        let mut requests = tokio::task::JoinSet::new();
+        for _ in 0..5 {
+            let pool = pool.clone();
+            requests.spawn(async move { request_results_email(&pool, testee.id.unwrap(), None, "https://dancexam.example.com", user_id).await });
+        }
+        let mut results = Vec::new();
+        while let Some(result) = requests.join_next().await {
+            results.push(result.unwrap());
+        }
+
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+        assert!(results.iter().filter_map(|result| result.as_ref().err()).all(|e| *e == ResendEmailError::TesteeRecentlyEmailed));
+    }
+
+    #[test]
+    fn retries_back_off_exponentially() {
+        assert_eq!(EMAIL_POLICY.retry_delay(1), chrono::Duration::seconds(60));
+        assert_eq!(EMAIL_POLICY.retry_delay(2), chrono::Duration::seconds(120));
+        assert_eq!(EMAIL_POLICY.retry_delay(5), chrono::Duration::seconds(960));
+        assert_eq!(EMAIL_POLICY.retry_delay(20), chrono::Duration::seconds(EMAIL_POLICY.max_retry_delay_seconds));
+    }
+}
@@ -0,0 +1,421 @@
+use chrono::NaiveDateTime;
+use rust_xlsxwriter::{Format, Workbook};
+use serde::Deserialize;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::exam::handlers::{fetch_test_results_by_id, fetch_tests_by_status, fetch_unique_test_names, TestError};
+use crate::exam::models::{Test, TestListItem};
+use crate::exam::points::Points;
+
+
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+// Export Options
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Csv,
+    Xlsx,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Xlsx => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Xlsx => "xlsx",
+        }
+    }
+}
+
+/// Results has one row per test like the broad test results page, competencies has one row per graded scoring category.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportReport {
+    #[default]
+    Results,
+    Competencies,
+}
+
+impl ExportReport {
+    pub fn file_stem(&self) -> &'static str {
+        match self {
+            ExportReport::Results => "test-results",
+            ExportReport::Competencies => "competency-breakdown",
+        }
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+// Export Table
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+
+/// A single value in an export. Numbers are kept as numbers so that spreadsheets can sort and sum them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExportCell {
+    Text(String),
+    Points(Points),
+    /// A fraction, so 0.6 is 60%
+    Percent(f32),
+}
+
+impl ExportCell {
+    fn to_csv_field(&self) -> String {
+        match self {
+            ExportCell::Text(text) => text.clone(),
+            ExportCell::Points(points) => points.to_string(),
+            ExportCell::Percent(fraction) => format!("{:.1}%", fraction * 100.0),
+        }
+    }
+}
+
+impl From<String> for ExportCell {
+    fn from(text: String) -> Self {
+        ExportCell::Text(text)
+    }
+}
+
+impl From<&str> for ExportCell {
+    fn from(text: &str) -> Self {
+        ExportCell::Text(text.to_string())
+    }
+}
+
+/// The rows of an export in a format independent form, rendered with to_csv or to_xlsx.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportTable {
+    pub headers: Vec<&'static str>,
+    pub rows: Vec<Vec<ExportCell>>,
+}
+
+impl ExportTable {
+    pub fn render(&self, format: ExportFormat, sheet_name: &str) -> Result<Vec<u8>, TestError> {
+        match format {
+            ExportFormat::Csv => self.to_csv(),
+            ExportFormat::Xlsx => self.to_xlsx(sheet_name),
+        }
+    }
+
+    pub fn to_csv(&self) -> Result<Vec<u8>, TestError> {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+
+        writer.write_record(&self.headers)
+            .map_err(|e| TestError::InternalServerError(format!("Couldn't write the export headers: {}", e)))?;
+        for row in &self.rows {
+            writer.write_record(row.iter().map(|cell| cell.to_csv_field()))
+                .map_err(|e| TestError::InternalServerError(format!("Couldn't write an export row: {}", e)))?;
+        }
+
+        writer.into_inner()
+            .map_err(|e| TestError::InternalServerError(format!("Couldn't finish the export: {}", e)))
+    }
+
+    pub fn to_xlsx(&self, sheet_name: &str) -> Result<Vec<u8>, TestError> {
+        let xlsx_error = |e: rust_xlsxwriter::XlsxError| TestError::InternalServerError(format!("Couldn't build the spreadsheet: {}", e));
+
+        let mut workbook = Workbook::new();
+        let header_format = Format::new().set_bold();
+        let percent_format = Format::new().set_num_format("0.0%");
+
+        let worksheet = workbook.add_worksheet();
+        worksheet.set_name(sheet_name).map_err(xlsx_error)?;
+
+        for (col, header) in self.headers.iter().enumerate() {
+            worksheet.write_string_with_format(0, col as u16, *header, &header_format).map_err(xlsx_error)?;
+        }
+
+        for (row_index, row) in self.rows.iter().enumerate() {
+            let row_number = row_index as u32 + 1;
+            for (col, cell) in row.iter().enumerate() {
+                let col = col as u16;
+                match cell {
+                    ExportCell::Text(text) => worksheet.write_string(row_number, col, text),
+                    ExportCell::Points(points) => worksheet.write_number(row_number, col, points.to_f64()),
+                    ExportCell::Percent(fraction) => worksheet.write_number_with_format(row_number, col, *fraction, &percent_format),
+                }.map_err(xlsx_error)?;
+            }
+        }
+
+        worksheet.set_freeze_panes(1, 0).map_err(xlsx_error)?;
+        worksheet.autofit();
+
+        workbook.save_to_buffer().map_err(xlsx_error)
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+// Failure Explanations
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+
+/// Turns a stored failure explanation into the same sentence the test grade page shows. They are stored as "-.-." delimited
+/// parts, see the test_grade.html template.
+pub fn describe_failure_explanation(failure_explanation: &str) -> String {
+    let parts: Vec<&str> = failure_explanation.split("-.-.").collect();
+
+    match parts.as_slice() {
+        // Grading rules explain themselves
+        [message] => message.to_string(),
+        [achieved, minimum] => format!("Your score of {} is lower than the minimum passing score of {}", achieved, minimum),
+        [competency, label, failing_labels] => format!(
+            "Competency '{}' is failing because a label of '{}' was achieved, and the label(s) '{}' fail the test.",
+            competency, label, failing_labels
+        ),
+        [competency, label, category, failing_labels] => format!(
+            "Competency '{}' is failing because a label of '{}' was achieved for the '{}' category, and the label(s) '{}' fail the test.",
+            competency, label, category, failing_labels
+        ),
+        [scope, name, score, achieved, minimum] => format!(
+            "Your score of {} = {} on the {} '{}' is lower than its minimum passing score of {}",
+            score, achieved, scope, name, minimum
+        ),
+        _ => failure_explanation.to_string(),
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+// Results Export
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+
+/// The grading details that the broad test results list doesn't carry
+struct TestExportDetails {
+    achieved_score: Points,
+    max_score: Points,
+    minimum_percent: f32,
+    failure_explanation: Option<Vec<String>>,
+    proctor_name: String,
+}
+
+async fn fetch_test_export_details(pool: &PgPool, test_ids: &[Uuid]) -> Result<HashMap<Uuid, TestExportDetails>, TestError> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT tm.test_id, tm.achieved_score::TEXT AS "achieved_score!: Points", tm.max_score::TEXT AS "max_score!: Points",
+               tm.minimum_percent, tm.failure_explanation,
+               u.first_name as proctor_first_name, u.last_name as proctor_last_name
+        FROM test_metadata tm
+        JOIN users u ON tm.proctor_id = u.id
+        WHERE tm.test_id = ANY($1)
+        "#,
+        test_ids
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.into_iter().map(|row| (row.test_id, TestExportDetails {
+        achieved_score: row.achieved_score,
+        max_score: row.max_score,
+        minimum_percent: row.minimum_percent,
+        failure_explanation: row.failure_explanation,
+        proctor_name: format!("{} {}", row.proctor_first_name, row.proctor_last_name),
+    })).collect())
+}
+
+/// Runs the same search as the broad test results page. No test names means every test name.
+async fn fetch_filtered_tests(pool: &PgPool, test_names: &[String], is_passing_filter: Option<bool>) -> Result<Vec<TestListItem>, TestError> {
+    let test_names = match test_names.is_empty() {
+        true => fetch_unique_test_names(pool).await?,
+        false => test_names.to_vec(),
+    };
+
+    Ok(fetch_tests_by_status(pool, &test_names, is_passing_filter).await?)
+}
+
+fn format_test_date(test_date: &NaiveDateTime) -> String {
+    test_date.format("%Y-%m-%d %H:%M").to_string()
+}
+
+fn pass_status(is_passing: bool) -> &'static str {
+    match is_passing {
+        true => "Pass",
+        false => "Fail",
+    }
+}
+
+const RESULTS_HEADERS: [&str; 13] = [
+    "Test Date", "Test Name", "Testee First Name", "Testee Last Name", "Testee Email", "Proctor",
+    "Score", "Max Score", "Percent", "Minimum Percent", "Result", "Failure Reasons", "Test Id",
+];
+
+/// One row per test in the broad test results search, with the score and the reasons for any failure.
+pub async fn build_results_export(pool: &PgPool, test_names: &[String], is_passing_filter: Option<bool>) -> Result<ExportTable, TestError> {
+    let test_list_items = fetch_filtered_tests(pool, test_names, is_passing_filter).await?;
+    let test_ids: Vec<Uuid> = test_list_items.iter().map(|item| item.test_id).collect();
+    let details = fetch_test_export_details(pool, &test_ids).await?;
+
+    let mut rows = Vec::with_capacity(test_list_items.len());
+    for item in test_list_items {
+        let detail = details.get(&item.test_id)
+            .ok_or_else(|| TestError::InternalServerError(format!("Test {} disappeared while it was being exported", item.test_id)))?;
+
+        let failure_reasons = detail.failure_explanation.as_deref().unwrap_or_default()
+            .iter()
+            .map(|explanation| describe_failure_explanation(explanation))
+            .collect::<Vec<String>>()
+            .join("; ");
+
+        rows.push(vec![
+            format_test_date(&item.test_date).into(),
+            item.test_name.into(),
+            item.testee_first_name.into(),
+            item.testee_last_name.into(),
+            item.testee_email.into(),
+            detail.proctor_name.clone().into(),
+            ExportCell::Points(detail.achieved_score),
+            ExportCell::Points(detail.max_score),
+            ExportCell::Percent(detail.achieved_score.fraction_of(detail.max_score)),
+            ExportCell::Percent(detail.minimum_percent),
+            pass_status(item.is_passing).into(),
+            failure_reasons.into(),
+            item.test_id.to_string().into(),
+        ]);
+    }
+
+    Ok(ExportTable { headers: RESULTS_HEADERS.to_vec(), rows })
+}
+
+
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+// Competency Breakdown Export
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+
+const COMPETENCY_HEADERS: [&str; 13] = [
+    "Test Date", "Test Name", "Testee First Name", "Testee Last Name", "Testee Email", "Proctor",
+    "Section", "Competency", "Scoring Category", "Achieved Label", "Points", "Test Result", "Test Id",
+];
+
+/// Flattens a graded test into one row per scoring category of every competency, followed by a row per bonus item.
+fn competency_rows(item: &TestListItem, test: &Test) -> Vec<Vec<ExportCell>> {
+    let proctor_name = test.metadata.proctor.as_ref()
+        .map(|proctor| format!("{} {}", proctor.first_name, proctor.last_name))
+        .unwrap_or_default();
+
+    let test_columns = || -> Vec<ExportCell> {
+        vec![
+            format_test_date(&item.test_date).into(),
+            item.test_name.as_str().into(),
+            item.testee_first_name.as_str().into(),
+            item.testee_last_name.as_str().into(),
+            item.testee_email.as_str().into(),
+            proctor_name.as_str().into(),
+        ]
+    };
+
+    let mut rows = Vec::new();
+    for section in test.tables.iter().flat_map(|table| table.sections.iter()) {
+        for competency in &section.competencies {
+            let achieved_scores = competency.achieved_scores.as_deref().unwrap_or_default();
+            let achieved_labels = competency.achieved_score_labels.as_deref().unwrap_or_default();
+
+            for (index, scoring_category) in section.scoring_categories.iter().enumerate() {
+                let label = achieved_labels.iter()
+                    .find(|label| label.scoring_category_name == scoring_category.name)
+                    .map(|label| label.value.clone())
+                    .unwrap_or_default();
+
+                let mut row = test_columns();
+                row.extend([
+                    section.name.as_str().into(),
+                    competency.name.as_str().into(),
+                    scoring_category.name.as_str().into(),
+                    label.into(),
+                    achieved_scores.get(index).map_or(ExportCell::Text(String::new()), |score| ExportCell::Points(*score)),
+                    pass_status(item.is_passing).into(),
+                    item.test_id.to_string().into(),
+                ]);
+                rows.push(row);
+            }
+        }
+    }
+
+    for bonus_item in test.bonus_items.as_deref().unwrap_or_default() {
+        let achieved = bonus_item.achieved.unwrap_or(false);
+
+        let mut row = test_columns();
+        row.extend([
+            "Bonus".into(),
+            bonus_item.name.as_str().into(),
+            "".into(),
+            match achieved { true => "Achieved", false => "Not Achieved" }.into(),
+            ExportCell::Points(match achieved { true => bonus_item.score, false => Points::ZERO }),
+            pass_status(item.is_passing).into(),
+            item.test_id.to_string().into(),
+        ]);
+        rows.push(row);
+    }
+
+    rows
+}
+
+/// Every graded scoring category of every test in the broad test results search.
+pub async fn build_competency_export(pool: &PgPool, test_names: &[String], is_passing_filter: Option<bool>) -> Result<ExportTable, TestError> {
+    let test_list_items = fetch_filtered_tests(pool, test_names, is_passing_filter).await?;
+
+    let mut rows = Vec::new();
+    for item in &test_list_items {
+        let test = fetch_test_results_by_id(pool, item.test_id).await?
+            .ok_or_else(|| TestError::InternalServerError(format!("Test {} disappeared while it was being exported", item.test_id)))?;
+
+        rows.extend(competency_rows(item, &test));
+    }
+
+    Ok(ExportTable { headers: COMPETENCY_HEADERS.to_vec(), rows })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failure_explanations_read_like_the_grade_page() {
+        assert_eq!(
+            describe_failure_explanation("40-.-.60"),
+            "Your score of 40 is lower than the minimum passing score of 60"
+        );
+        assert_eq!(
+            describe_failure_explanation("Balance-.-.Fail-.-.Technique-.-.Fail, Poor"),
+            "Competency 'Balance' is failing because a label of 'Fail' was achieved for the 'Technique' category, and the label(s) 'Fail, Poor' fail the test."
+        );
+        assert_eq!(
+            describe_failure_explanation("section-.-.Connection-.-.3 / 8-.-.37.5-.-.50.0"),
+            "Your score of 3 / 8 = 37.5 on the section 'Connection' is lower than its minimum passing score of 50.0"
+        );
+        assert_eq!(
+            describe_failure_explanation("At least 2 bonus items must be earned."),
+            "At least 2 bonus items must be earned."
+        );
+    }
+
+    #[test]
+    fn csv_export_quotes_fields_and_formats_numbers() {
+        let table = ExportTable {
+            headers: vec!["Name", "Score", "Percent"],
+            rows: vec![vec!["Doe, Jane".into(), ExportCell::Points(Points::whole(42)), ExportCell::Percent(0.6)]],
+        };
+
+        let csv = String::from_utf8(table.to_csv().unwrap()).unwrap();
+        assert_eq!(csv, "Name,Score,Percent\n\"Doe, Jane\",42,60.0%\n");
+    }
+
+    #[test]
+    fn xlsx_export_is_a_zip_archive() {
+        let table = ExportTable {
+            headers: vec!["Name", "Score"],
+            rows: vec![vec!["Jane".into(), ExportCell::Points(Points::whole(42))]],
+        };
+
+        let xlsx = table.to_xlsx("Test Results").unwrap();
+        assert!(xlsx.starts_with(b"PK"));
+    }
+}
@@ -1,13 +1,16 @@
 use askama::Template;
-use chrono::Local;
-use lettre::{message::header::ContentType, transport::smtp::authentication::Credentials, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
-use sqlx::{Error, PgPool};
+use chrono::{Local, NaiveDateTime};
+use lettre::{message::{header::ContentType, Attachment, MultiPart, SinglePart}, transport::smtp::authentication::Credentials, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use sqlx::{Error, PgConnection, PgExecutor, PgPool};
 use uuid::Uuid;
-use std::{collections::HashMap, fs::File, io::Read};
+use std::{fs::File, io::Read, sync::Arc, time::Duration};
 use crate::exam::models::{
-    AchievedScoreLabel, BonusItem, Competency, FailingScoreLabels, Metadata, ScoringCategory, Test, TestDefinitionYaml, TestSection, FullTestSummary, TestTable, Testee, TestGradeSummary, TestConfig, Proctor, SMTPConfig, TestListItem
+    AchievedScoreLabel, BonusItem, Competency, FailingScoreLabels, Metadata, ScoringCategory, Test, TestDefinitionYaml, TestSection, FullTestSummary, TestTable, Testee, TestGradeSummary, TestConfig, Proctor, SMTPConfig, TestListItem, QueueItem, DefinitionError, SubmissionError
 };
-use crate::filters;
+use crate::exam::pdf::{pdf_file_name, PdfDocumentKind};
+use crate::exam::points::Points;
+use crate::{filters, webhooks::{publish_webhook_event, WebhookEvent}, AppState};
+use serde_json::json;
 
 
 
@@ -26,7 +29,27 @@ use crate::filters;
 
 #[derive(Debug)]
 pub enum TestError {
-    InternalServerError(String)
+    InternalServerError(String),
+    InvalidTestDefinition(Vec<DefinitionError>),
+    InvalidSubmission(SubmissionError),
+}
+
+impl std::fmt::Display for TestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TestError::InternalServerError(e) => write!(f, "{}", e),
+            TestError::InvalidTestDefinition(errors) => {
+                write!(f, "{}", errors.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("; "))
+            },
+            TestError::InvalidSubmission(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<SubmissionError> for TestError {
+    fn from(error: SubmissionError) -> Self {
+        TestError::InvalidSubmission(error)
+    }
 }
 
 impl From<sqlx::Error> for TestError {
@@ -57,129 +80,193 @@ pub fn parse_test_definition_from_str(yaml_string: &str) -> Result<TestDefinitio
     serde_yaml::from_str(yaml_string)
 }
 
+pub const TEST_DEFINITIONS_FILE_PATH: &str = "test_definitions.yaml";
+
+/// Reads, parses, and validates the test definitions file. If any test in the file is invalid, none of them are returned.
+pub fn load_test_definitions_from_file(file_path: &str) -> Result<TestDefinitionYaml, TestError> {
+    let yaml_string = std::fs::read_to_string(file_path)
+        .map_err(|e| TestError::InternalServerError(format!("Couldn't read file '{}' to string: {}", file_path, e)))?;
+
+    let tests = parse_test_definition_from_str(&yaml_string)
+        .map_err(|e| TestError::InvalidTestDefinition(vec![DefinitionError::InvalidYaml { message: format!("Error parsing '{}': {}", file_path, e) }]))?;
+
+    tests.validate().map_err(TestError::InvalidTestDefinition)?;
+
+    Ok(tests)
+}
+
 
 // -------------------------------------------------------------------------------------------------------------------------------------------------------
-// Parse Test
-// -------------------------------------------------------------------------------------------------------------------------------------------------------
-
-/// Parses the test form data, which should have a format of a hashmap more or less like this
-/// 
-/// Takes in a test_template which it will then mutate, adding the results so that it is graded.
-pub fn parse_test_form_data(test: HashMap<String, String>, mut test_template: Test, proctor: Option<Proctor>) -> Result<Test, TestError> {
-
-    let mut user_info = HashMap::new();
-
-    // Sort the keys so that the graded test gets reconstructed in the same order as the test definition
-    let mut sorted_keys: Vec<&String> = test.keys().collect();
-    sorted_keys.sort(); 
-
-    for key in sorted_keys {
-        let value = &test[key];
-
-        // Build the hash map with all of the graded items
-        if key.starts_with("table_index") {
-            let key_parts: Vec<&str> = key.split("---").collect();
-            let value_parts: Vec<&str> = value.split("---").collect();
-
-            match (key_parts.len(), value_parts.len()) {
-                (8, 4) => {
-                    match (
-                        key_parts[1].parse::<usize>(), 
-                        key_parts[3].parse::<usize>(), 
-                        key_parts[5].parse::<usize>(), 
-                        key_parts[7].parse::<usize>(), 
-                        value_parts[1].parse::<usize>(), 
-                        value_parts[3].parse::<i32>()
-                    ) {
-                        (Ok(table_index), Ok(section_index), Ok(item_index), Ok(scoring_category_index), Ok(scoring_category_label_index), Ok(points)) => {
-
-                            let scoring_category_name = test_template.tables[table_index]
-                                .sections[section_index]
-                                .scoring_categories[scoring_category_index].name.clone();
-
-                            let label = test_template.tables[table_index]
-                                .sections[section_index]
-                                .scoring_categories[scoring_category_index]
-                                .values[scoring_category_label_index]
-                                .clone();
-
-                            if let Some(item) = test_template.tables[table_index]
-                            .sections[section_index]
-                            .competencies
-                            .get_mut(item_index)
-                        {
-                            item.achieved_scores.get_or_insert_with(Vec::new).push(points);
-                                               
-                            item.achieved_score_labels
-                                .get_or_insert_with(Vec::new)
-                                .push(AchievedScoreLabel {
-                                     scoring_category_name,
-                                     value: label, 
-                                    });
-                        }
-                        },
-
-                        (Err(e), _, _, _, _, _) => return Err(TestError::InternalServerError(format!("Failed to parse table index key '{}': {:?}", key, e))),
-                        (_, Err(e), _, _, _, _) => return Err(TestError::InternalServerError(format!("Failed to parse section index from key '{}': {:?}", key, e))),
-                        (_, _, Err(e), _, _, _) => return Err(TestError::InternalServerError(format!("Failed to parse item index from key'{}': {:?}", key, e))),
-                        (_, _, _, Err(e), _, _) => return Err(TestError::InternalServerError(format!("Failed to parse scoring category index from key '{}': {:?}", key, e))),
-                        (_, _, _, _, Err(e), _) => return Err(TestError::InternalServerError(format!("Failed to parse scoring category label index from value '{}': {:?}", value, e))),
-                        (_, _, _, _, _, Err(e)) => return Err(TestError::InternalServerError(format!("Failed to parse score from value '{}': {:?}", value, e))),
-                    }
-                }
-                _ => return Err(TestError::InternalServerError(format!("The key '{}' and value '{}' should be formatted as follows 'table_index---0---section_index---0---item_index---0---scoring_category_index---1': 'scoring_category_value_index---0---points---1'", key, value))),
-            }
-        } else if key.starts_with("bonus_index") {
-            if let Some(bonus_items) = &mut test_template.bonus_items {
-                let key_parts: Vec<&str> = key.split("---").collect();
-                match key_parts.len() {
-                    2 => {
-                        match (key_parts[1].parse::<usize>(), value.parse::<i64>()) {
-                            (Ok(bonus_index), Ok(_)) => {
-                                let _ = bonus_items[bonus_index].achieved.insert(true);
-                            },
-                            (Err(e), _) => return Err(TestError::InternalServerError(format!("Failed to parse bonus index from key '{}': {:?}", key, e))),
-                            (_, Err(e)) => return Err(TestError::InternalServerError(format!("Failed to parse points from value '{}': {:?}", value, e))),
-                        }
-                    }
-                    _ => return Err(TestError::InternalServerError(format!("The key '{}' should be formatted as 'bonus_index---<index>', but got '{}'", key, key))),
-                }
-            }
-        } else {
-            user_info.insert(key.clone(), value.clone());
+// Reload Test Definitions
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+
+/// Re-reads the test definitions file, syncs it to the database, and swaps it in for the running definitions. 
+/// If the file is invalid, the running definitions are left untouched and the error is returned.
+pub async fn reload_test_definitions(data: &AppState) -> Result<Arc<TestDefinitionYaml>, TestError> {
+    reload_test_definitions_from_file(data, TEST_DEFINITIONS_FILE_PATH).await
+}
+
+async fn reload_test_definitions_from_file(data: &AppState, file_path: &str) -> Result<Arc<TestDefinitionYaml>, TestError> {
+    // Reloads run one at a time, otherwise a slower reload of an older file could be swapped in last
+    let _reload_guard = data.test_definitions_reload_lock.lock().await;
+
+    let tests = load_test_definitions_from_file(file_path)?;
+    let tests = Arc::new(sync_test_definitions(&data.db, tests).await?);
+
+    *data.test_configurations
+        .write()
+        .map_err(|_| TestError::InternalServerError("Test definitions lock was poisoned.".to_string()))? = tests.clone();
+
+    Ok(tests)
+}
+
+/// Polls the test definitions file and reloads it whenever its modified time changes. Polling is used instead of filesystem
+/// events because the file is bind mounted into the container, and editors that replace the file on save break inotify watches.
+pub async fn watch_test_definitions_file(data: Arc<AppState>) {
+    let modified_time = || std::fs::metadata(TEST_DEFINITIONS_FILE_PATH).and_then(|metadata| metadata.modified()).ok();
+
+    let mut last_modified_time = modified_time();
+    let mut interval = tokio::time::interval(Duration::from_secs(5));
+
+    loop {
+        interval.tick().await;
+
+        let current_modified_time = modified_time();
+        if current_modified_time == last_modified_time {
+            continue;
         }
-    } 
-
-    // Construct the GradedTestee instance from the user_info hashmap
-    let testee: Testee = match (
-        user_info.get("first_name").cloned(),
-        user_info.get("last_name").cloned(),
-        user_info.get("email").cloned()
-    ) {
-        (Some(first_name), Some(last_name), Some(email)) => Testee {
-            id: None,
-            first_name,
-            last_name,
-            email,
-        },
-        _ => {
-           return Err(TestError::InternalServerError("Missing user information. Please ensure 'first_name', 'last_name', and 'email' are provided.".to_string()));
+        last_modified_time = current_modified_time;
+
+        match reload_test_definitions(&data).await {
+            Ok(tests) => println!("✅ Reloaded {} test definitions from {}", tests.tests.len(), TEST_DEFINITIONS_FILE_PATH),
+            Err(e) => eprintln!("🔥 Rejected changes to {}, the running test definitions were kept: {:?}", TEST_DEFINITIONS_FILE_PATH, e),
         }
-    };
+    }
+}
 
-    // Assign the testee
-    test_template.metadata.testee = Some(testee);
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+// Fetch Test Definition
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
 
-    // Grade the test
-    if let Err(e) = test_template.grade() {
-        return Err(TestError::InternalServerError(e)); // Return the error
+/// Finds the test definition that a test form was rendered from. A test that was started before the definitions were
+/// reloaded keeps being graded against the version it was started with, which is fetched from the database if needed.
+pub async fn fetch_test_definition(
+    data: &AppState,
+    test_definition_id: Uuid,
+    test_definition_version: Option<i32>,
+) -> Result<Option<Test>, TestError> {
+    let test_configurations = data.test_configurations();
+
+    match (test_configurations.get_by_id(test_definition_id), test_definition_version) {
+        (Some(test), None) => Ok(Some(test.clone())),
+        (Some(test), Some(version)) if test.metadata.test_definition_version == Some(version) => Ok(Some(test.clone())),
+        (_, Some(version)) => fetch_test_definition_version(&data.db, test_definition_id, version).await,
+        (None, None) => Ok(None),
     }
+}
+
+/// Fetch a specific version of a test definition from the database
+pub async fn fetch_test_definition_version<'e>(
+    executor: impl PgExecutor<'e>,
+    test_definition_id: Uuid,
+    test_definition_version: i32,
+) -> Result<Option<Test>, TestError> {
+    let record = sqlx::query!(
+        "SELECT definition FROM test_definition_versions
+        WHERE test_definition_id = $1 AND version = $2",
+        test_definition_id,
+        test_definition_version
+    )
+    .fetch_optional(executor)
+    .await?;
+
+    match record {
+        Some(record) => {
+            let mut test: Test = serde_json::from_value(record.definition)?;
+            test.metadata.test_definition_id = Some(test_definition_id);
+            test.metadata.test_definition_version = Some(test_definition_version);
+            Ok(Some(test))
+        },
+        None => Ok(None),
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+// Sync Test Definitions to Database
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+
+/// Stores the parsed test definitions in the database and returns them with their stable test_definition_id and 
+/// test_definition_version filled in. Definitions are matched to their stored counterpart by test name, so the yaml
+/// can be reordered freely. A new version is only written when the definition differs from the latest stored version.
+pub async fn sync_test_definitions(
+    pool: &PgPool,
+    test_definitions: TestDefinitionYaml,
+) -> Result<TestDefinitionYaml, TestError> {
+    let mut transaction = pool.begin().await?;
+    let test_definitions = store_test_definitions(&mut transaction, test_definitions).await?;
+    transaction.commit().await?;
+    Ok(test_definitions)
+}
+
+/// Does the work of sync_test_definitions without committing, so that it can be part of a larger transaction.
+pub async fn store_test_definitions(
+    connection: &mut PgConnection,
+    mut test_definitions: TestDefinitionYaml,
+) -> Result<TestDefinitionYaml, TestError> {
+
+    // Definitions are matched by name, so duplicate names would overwrite each other's versions
+    test_definitions.validate().map_err(TestError::InvalidTestDefinition)?;
+
+    for test in &mut test_definitions.tests {
+        // These are assigned here, they should never be part of the stored definition
+        test.metadata.test_definition_id = None;
+        test.metadata.test_definition_version = None;
+        let definition = serde_json::to_value(&*test)?;
+
+        let test_definition_id = sqlx::query!(
+            "INSERT INTO test_definitions (test_name)
+            VALUES ($1)
+            ON CONFLICT (test_name) DO UPDATE SET test_name = EXCLUDED.test_name
+            RETURNING id",
+            test.metadata.test_name
+        )
+        .fetch_one(&mut *connection)
+        .await?
+        .id;
+
+        let latest_version = sqlx::query!(
+            "SELECT version, definition FROM test_definition_versions
+            WHERE test_definition_id = $1
+            ORDER BY version DESC
+            LIMIT 1",
+            test_definition_id
+        )
+        .fetch_optional(&mut *connection)
+        .await?;
 
-    // Assign the proctor
-    test_template.metadata.proctor = proctor;
+        let version = match latest_version {
+            Some(record) if record.definition == definition => record.version,
+            record => {
+                let version = record.map_or(1, |record| record.version + 1);
+                sqlx::query!(
+                    "INSERT INTO test_definition_versions (test_definition_id, version, definition)
+                    VALUES ($1, $2, $3)",
+                    test_definition_id,
+                    version,
+                    definition
+                )
+                .execute(&mut *connection)
+                .await?;
+                version
+            }
+        };
 
-    Ok(test_template)
+        test.metadata.test_definition_id = Some(test_definition_id);
+        test.metadata.test_definition_version = Some(version);
+    }
 
+    Ok(test_definitions)
 }
 
 
@@ -187,17 +274,32 @@ pub fn parse_test_form_data(test: HashMap<String, String>, mut test_template: Te
 // Save Test to Database
 // -------------------------------------------------------------------------------------------------------------------------------------------------------
 
-/// Assumes that the graded_test has metadata with a testee object or the code panics. Returns the testee id
+/// Assumes that the graded_test has metadata with a testee object or the code panics. Returns the testee id and the test id
 pub async fn save_test_to_database(
     pool: &PgPool,
     graded_test: Test,
-) -> Result<Uuid, TestError> {
+) -> Result<(Uuid, Uuid), TestError> {
+    let mut transaction = pool.begin().await?;
+    let (testee_id, test_id, test_saved_event) = insert_graded_test(&mut transaction, graded_test, Local::now().naive_utc()).await?;
+    publish_webhook_event(&mut *transaction, WebhookEvent::TestSaved, test_saved_event).await?;
+    transaction.commit().await?;
+
+    Ok((testee_id, test_id))
+}
+
+/// Writes a graded test and its testee with the given test date, without committing or publishing anything so that
+/// it can be part of a larger transaction. Returns the testee id, the test id and the body of the test.saved webhook.
+pub async fn insert_graded_test(
+    connection: &mut PgConnection,
+    graded_test: Test,
+    test_date: NaiveDateTime,
+) -> Result<(Uuid, Uuid, serde_json::Value), TestError> {
 
     // Insert the testee in the database or get the testee ID if the testee already exists
     // Since the graded_test has a testee that currently has None for its ID
 
     let testee = create_testee(
-        pool,  // The following garbage could be refactored TODO
+        &mut *connection,  // The following garbage could be refactored TODO
         &graded_test.metadata.testee.clone().ok_or_else(|| TestError::InternalServerError("If this error was thrown, the invariant in the docstring of save_test_to_database was violated.".to_string()))?.first_name, 
         &graded_test.metadata.testee.clone().ok_or_else(|| TestError::InternalServerError("If this error was thrown, the invariant in the docstring of save_test_to_database was violated.".to_string()))?.last_name,
         &graded_test.metadata.testee.clone().ok_or_else(|| TestError::InternalServerError("If this error was thrown, the invariant in the docstring of save_test_to_database was violated.".to_string()))?.email,
@@ -207,46 +309,64 @@ pub async fn save_test_to_database(
     let test_id = sqlx::query!(
         "INSERT INTO tests DEFAULT VALUES RETURNING id"
     )
-    .fetch_one(pool)
+    .fetch_one(&mut *connection)
     .await?
     .id;
 
+    // Built now because the graded test is taken apart while it is saved
+    let test_saved_event = json!({
+        "test_id": test_id,
+        "test_definition_id": graded_test.metadata.test_definition_id,
+        "test_definition_version": graded_test.metadata.test_definition_version,
+        "test_name": graded_test.metadata.test_name,
+        "testee": testee,
+        "proctor": graded_test.metadata.proctor,
+        "grade_summary": graded_test.grade_summary().ok(),
+    });
+
     // Insert test metadata
     sqlx::query!(
-        "INSERT INTO test_metadata (test_id, test_name, minimum_percent, max_score, achieved_score, testee_id, test_date, is_passing, proctor_id, failure_explanation)
-         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+        "INSERT INTO test_metadata (test_id, test_definition_id, test_definition_version, test_name, minimum_percent, max_score, achieved_score, testee_id, test_date, is_passing, proctor_id, failure_explanation)
+         VALUES ($1, $2, $3, $4, $5, $6::TEXT::NUMERIC, $7::TEXT::NUMERIC, $8, $9, $10, $11, $12)",
         test_id,
+        graded_test.metadata.test_definition_id,
+        graded_test.metadata.test_definition_version,
         graded_test.metadata.test_name,
         graded_test.metadata.minimum_percent,
-        graded_test.metadata.max_score,
-        graded_test.metadata.achieved_score,
+        graded_test.metadata.max_score.to_string(),
+        graded_test.metadata.achieved_score.map(|achieved_score| achieved_score.to_string()),
         testee.id,
-        Local::now().naive_utc(),
+        test_date,
         graded_test.metadata.is_passing,
         graded_test.metadata.proctor.ok_or_else(|| TestError::InternalServerError("If this error was thrown, the invariant in the docstring of save_test_to_database was violated.".to_string()))?.id,
         graded_test.metadata.failure_explanation.as_deref()
-    ).execute(pool)
+    ).execute(&mut *connection)
     .await?;
 
     // Insert test tables, sections, scoring categories, and competencies
     for table in graded_test.tables {
         let table_id = sqlx::query!(
-            "INSERT INTO test_tables (test_id)
-            VALUES ($1)
+            "INSERT INTO test_tables (test_id, minimum_percent, max_score)
+            VALUES ($1, $2, $3::TEXT::NUMERIC)
             RETURNING (id)",
-            test_id
-        ).fetch_one(pool)
+            test_id,
+            table.minimum_percent,
+            table.max_score.map(|max_score| max_score.to_string())
+        ).fetch_one(&mut *connection)
         .await?
         .id;
 
         for section in table.sections {
             let section_id = sqlx::query!(
-                "INSERT INTO test_sections (table_id, name)
-                VALUES ($1, $2)
+                "INSERT INTO test_sections (table_id, name, minimum_percent, max_score, weight)
+                VALUES ($1, $2, $3, $4::TEXT::NUMERIC, $5::TEXT::NUMERIC)
                 RETURNING (id)",
                 table_id,
-                section.name
-            ).fetch_one(pool)
+                section.name,
+                section.minimum_percent,
+                section.max_score.map(|max_score| max_score.to_string()),
+                section.weight.map(|weight| weight.to_string())
+            ).fetch_one(&mut *connection)
             .await?
             .id;
 
@@ -257,23 +377,24 @@ pub async fn save_test_to_database(
                     section_id,
                     &scoring_category.name,
                     &scoring_category.values,
-                ).execute(pool)
+                ).execute(&mut *connection)
                 .await?;
             };
 
             for competency in section.competencies {
                 sqlx::query!(
-                    "INSERT INTO competencies (section_id, name, scores, subtext, antithesis, achieved_scores, achieved_score_labels, failing_score_labels)
-                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+                    "INSERT INTO competencies (section_id, name, scores, subtext, antithesis, achieved_scores, achieved_score_labels, failing_score_labels, weight)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9::TEXT::NUMERIC)",
                 section_id,
                 &competency.name,
-                &serde_json::to_value(&competency.scores)?, // Convert Vec<Vec<i64>> to JSON
+                &serde_json::to_value(&competency.scores)?, // Convert Vec<Vec<Points>> to JSON
                 competency.subtext.as_deref(),
                 competency.antithesis.as_deref(),
-                &serde_json::to_value(competency.achieved_scores)?, // Convert Option<Vec<i64>> to JSON
+                &serde_json::to_value(competency.achieved_scores)?, // Convert Option<Vec<Points>> to JSON
                 &serde_json::to_value(competency.achieved_score_labels)?,// Convert Option<Vec<String>> to JSON
                 &serde_json::to_value(&competency.failing_score_labels)?, // Convert Option<Vec<FailingScoreLabels>> to JSON
-                ).execute(pool)
+                competency.weight.map(|weight| weight.to_string()),
+                ).execute(&mut *connection)
                 .await?;
             };
         };
@@ -283,19 +404,20 @@ pub async fn save_test_to_database(
         for bonus in bonus_items {
             sqlx::query!(
                 "INSERT INTO bonus_items (test_id, name, score, achieved)
-                VALUES ($1, $2, $3, $4)",
+                VALUES ($1, $2, $3::TEXT::NUMERIC, $4)",
                 test_id,
                 bonus.name.to_string(),
-                bonus.score,
+                bonus.score.to_string(),
                 bonus.achieved.unwrap_or(false)
             )
-            .execute(pool)
+            .execute(&mut *connection)
             .await?;
         }
     };
 
+    let testee_id = testee.id.ok_or_else(|| TestError::InternalServerError("If this error was thrown, the invariant in the docstring of save_test_to_database was violated.".to_string()))?;
 
-    Ok(testee.id.ok_or_else(|| TestError::InternalServerError("If this error was thrown, the invariant in the docstring of save_test_to_database was violated.".to_string()))?)
+    Ok((testee_id, test_id, test_saved_event))
 }
 
 
@@ -404,7 +526,9 @@ pub async fn fetch_test_results_by_id(pool: &PgPool, test_id: Uuid) -> Result<Op
     // Fetch test metadata
     let raw_metadata = match sqlx::query!(
         r#"
-        SELECT test_id, test_name, minimum_percent, max_score, achieved_score, testee_id, test_date, is_passing, proctor_id, failure_explanation
+        SELECT test_id, test_definition_id, test_definition_version, test_name, minimum_percent,
+               max_score::TEXT AS "max_score!: Points", achieved_score::TEXT AS "achieved_score!: Points",
+               testee_id, test_date, is_passing, proctor_id, failure_explanation
         FROM test_metadata
         WHERE test_id = $1
         "#,
@@ -439,6 +563,8 @@ pub async fn fetch_test_results_by_id(pool: &PgPool, test_id: Uuid) -> Result<Op
     // Create the metadata object
     let metadata: Metadata = Metadata {
         test_id: Some(test_id),
+        test_definition_id: raw_metadata.test_definition_id,
+        test_definition_version: raw_metadata.test_definition_version,
         test_name: raw_metadata.test_name,
         minimum_percent: raw_metadata.minimum_percent,
         max_score: raw_metadata.max_score,
@@ -453,24 +579,24 @@ pub async fn fetch_test_results_by_id(pool: &PgPool, test_id: Uuid) -> Result<Op
     };
 
     // Fetch test tables
-    let table_ids: Vec<Uuid> = sqlx::query!(
-        "SELECT id FROM test_tables WHERE test_id = $1
-        ORDER BY insert_counter ASC",
+    let tables = sqlx::query!(
+        r#"SELECT id, minimum_percent, max_score::TEXT AS "max_score: Points" FROM test_tables WHERE test_id = $1
+        ORDER BY insert_counter ASC"#,
         test_id
     )
     .fetch_all(pool)
-    .await?
-    .into_iter()
-    .map(|record| record.id)
-    .collect();
+    .await?;
 
     let mut test_tables: Vec<TestTable> = Vec::new();
 
-    for table_id in table_ids {
+    for table in tables {
+        let table_id = table.id;
+
         // Fetch sections for each table
         let sections = sqlx::query!(
-            "SELECT id, name FROM test_sections WHERE table_id = $1
-            ORDER BY insert_counter ASC",
+            r#"SELECT id, name, minimum_percent, max_score::TEXT AS "max_score: Points", weight::TEXT AS "weight: Points"
+            FROM test_sections WHERE table_id = $1
+            ORDER BY insert_counter ASC"#,
             table_id
         )
         .fetch_all(pool)
@@ -502,7 +628,8 @@ pub async fn fetch_test_results_by_id(pool: &PgPool, test_id: Uuid) -> Result<Op
             // Fetch competencies for each section
             let raw_competencies = sqlx::query!(
                 r#"
-                SELECT id, section_id, name, scores, subtext, antithesis, achieved_scores, achieved_score_labels, failing_score_labels
+                SELECT id, section_id, name, scores, subtext, antithesis, achieved_scores, achieved_score_labels, failing_score_labels,
+                       weight::TEXT AS "weight: Points"
                 FROM competencies
                 WHERE section_id = $1
                 ORDER BY insert_counter ASC
@@ -514,8 +641,8 @@ pub async fn fetch_test_results_by_id(pool: &PgPool, test_id: Uuid) -> Result<Op
 
             let mut competency_vec: Vec<Competency> = Vec::new();
             for raw_competency in raw_competencies {
-                let scores: Vec<Vec<i32>> = serde_json::from_value(raw_competency.scores)?;
-                let achieved_scores: Option<Vec<i32>> = serde_json::from_value(raw_competency.achieved_scores)?;
+                let scores: Vec<Vec<Points>> = serde_json::from_value(raw_competency.scores)?;
+                let achieved_scores: Option<Vec<Points>> = serde_json::from_value(raw_competency.achieved_scores)?;
                 let achieved_score_labels: Option<Vec<AchievedScoreLabel>> = serde_json::from_value(raw_competency.achieved_score_labels)?;
                 let failing_score_labels: Option<Vec<FailingScoreLabels>> = serde_json::from_value(raw_competency.failing_score_labels)?;
 
@@ -523,6 +650,7 @@ pub async fn fetch_test_results_by_id(pool: &PgPool, test_id: Uuid) -> Result<Op
                     section_id: Some(raw_competency.section_id),
                     name: raw_competency.name,
                     scores,
+                    weight: raw_competency.weight,
                     subtext: raw_competency.subtext,
                     antithesis: raw_competency.antithesis,
                     achieved_scores,
@@ -534,6 +662,9 @@ pub async fn fetch_test_results_by_id(pool: &PgPool, test_id: Uuid) -> Result<Op
             test_sections.push(TestSection {
                 table_id: Some(table_id),
                 name: section.name,
+                minimum_percent: section.minimum_percent,
+                max_score: section.max_score,
+                weight: section.weight,
                 scoring_categories,
                 competencies: competency_vec,
             });
@@ -542,14 +673,16 @@ pub async fn fetch_test_results_by_id(pool: &PgPool, test_id: Uuid) -> Result<Op
         test_tables.push(TestTable {
             test_id: Some(test_id),
             table_id: Some(table_id),
+            minimum_percent: table.minimum_percent,
+            max_score: table.max_score,
             sections: test_sections,
         });
     }
 
     // Fetch bonus items
     let bonus_items = sqlx::query!(
-        "SELECT id, test_id, name, score, achieved FROM bonus_items WHERE test_id = $1
-        ORDER BY insert_counter ASC",
+        r#"SELECT id, test_id, name, score::TEXT AS "score!: Points", achieved FROM bonus_items WHERE test_id = $1
+        ORDER BY insert_counter ASC"#,
         test_id
     )
     .fetch_all(pool)
@@ -570,6 +703,7 @@ pub async fn fetch_test_results_by_id(pool: &PgPool, test_id: Uuid) -> Result<Op
         metadata,
         tables: test_tables,
         bonus_items: (!bonus_items.is_empty()).then_some(bonus_items),
+        rules: None, // Only the failure explanations the rules gave are kept with a graded test
     }))
 }
 
@@ -590,14 +724,14 @@ pub async fn fetch_testee_tests_by_id(
         .ok_or_else(|| TestError::InternalServerError(format!("No testee available with that ID.")))?;
 
     let testee_tests: Vec<FullTestSummary> = sqlx::query!(
-        "
+        r#"
         SELECT 
             tm.test_id, 
             tm.test_name, 
             tm.test_date, 
-            tm.achieved_score, 
+            tm.achieved_score::TEXT AS "achieved_score!: Points", 
             tm.minimum_percent, 
-            tm.max_score, 
+            tm.max_score::TEXT AS "max_score!: Points", 
             tm.is_passing, 
             tm.failure_explanation,
             u.id,
@@ -607,7 +741,7 @@ pub async fn fetch_testee_tests_by_id(
         JOIN users u ON tm.proctor_id = u.id
         WHERE tm.testee_id = $1
         ORDER BY tm.test_date DESC
-        ",
+        "#,
         testee.id
     )
     .fetch_all(pool)
@@ -620,7 +754,7 @@ pub async fn fetch_testee_tests_by_id(
         proctor: Proctor { id: record.id, first_name: record.first_name, last_name: record.last_name },
         grade_summary: TestGradeSummary {
             achieved_score: record.achieved_score,
-            achieved_percent: record.achieved_score as f32 / record.max_score as f32,
+            achieved_percent: record.achieved_score.fraction_of(record.max_score),
             max_score: record.max_score,
             minimum_percent: record.minimum_percent,
             is_passing: record.is_passing,
@@ -656,7 +790,7 @@ pub async fn fetch_testee_by_id(pool: &PgPool, testee_id: Uuid) -> Result<Option
 // -------------------------------------------------------------------------------------------------------------------------------------------------------
 
 /// Returns a testee that 100% has an ID.
-pub async fn create_testee(pool: &PgPool, first_name: &str, last_name: &str, email: &str) -> Result<Testee, TestError> {
+pub async fn create_testee<'e>(executor: impl PgExecutor<'e>, first_name: &str, last_name: &str, email: &str) -> Result<Testee, TestError> {
     sqlx::query_as!(
         Testee,
         "INSERT INTO testees (first_name, last_name, email)
@@ -667,7 +801,7 @@ pub async fn create_testee(pool: &PgPool, first_name: &str, last_name: &str, ema
         last_name,
         email
     )
-    .fetch_one(pool)
+    .fetch_one(executor)
     .await
     .map_err(TestError::from)
 }
@@ -676,44 +810,77 @@ pub async fn create_testee(pool: &PgPool, first_name: &str, last_name: &str, ema
 // Enqueue Testee
 // -------------------------------------------------------------------------------------------------------------------------------------------------------
 
-pub async fn enqueue_testee(pool: &PgPool, testee_id: Uuid, test_definition_index: i32) -> Result<(), TestError> {
-    sqlx::query!(
-        "INSERT INTO queue (testee_id, test_definition_index)
+pub async fn enqueue_testee(pool: &PgPool, testee_id: Uuid, test_definition_id: Uuid) -> Result<(), TestError> {
+    let enqueued = sqlx::query!(
+        "INSERT INTO queue (testee_id, test_definition_id)
         VALUES ($1, $2)
-        ON CONFLICT (testee_id, test_definition_index) DO NOTHING",
+        ON CONFLICT (testee_id, test_definition_id) DO NOTHING
+        RETURNING testee_id",
         testee_id,
-        test_definition_index,
+        test_definition_id,
     )
-    .execute(pool)
+    .fetch_optional(pool)
     .await
     .map_err(TestError::from)?;
 
+    // Testees who were already waiting for this test didn't join the queue again
+    if enqueued.is_some() {
+        publish_queue_event(pool, WebhookEvent::TesteeEnqueued, testee_id, test_definition_id).await;
+    }
+
     Ok(())
 }
 
+/// Publishes a webhook describing the testee's place in the queue, which may already have been removed
+async fn publish_queue_event(pool: &PgPool, event: WebhookEvent, testee_id: Uuid, test_definition_id: Uuid) {
+    let queue_item = sqlx::query!(
+        "SELECT t.first_name, t.last_name, t.email, td.test_name
+        FROM testees t, test_definitions td
+        WHERE t.id = $1 AND td.id = $2",
+        testee_id,
+        test_definition_id
+    )
+    .fetch_one(pool)
+    .await;
+
+    match queue_item {
+        Ok(row) => {
+            let queue_item = QueueItem {
+                testee: Testee { id: Some(testee_id), first_name: row.first_name, last_name: row.last_name, email: row.email },
+                test_definition_id,
+                test_name: row.test_name,
+            };
+            if let Err(e) = publish_webhook_event(pool, event, queue_item).await {
+                eprintln!("Failed to queue the {} webhook: {}", event, e);
+            }
+        },
+        Err(e) => eprintln!("Failed to queue the {} webhook: {}", event, e),
+    }
+}
+
 // -------------------------------------------------------------------------------------------------------------------------------------------------------
 // Dequeue Testee 
 // -------------------------------------------------------------------------------------------------------------------------------------------------------
 
-/// Remove and return the next person on the queue plus the test_definition_index of their desired test.
+/// Remove and return the next person on the queue plus the test_definition_id of their desired test.
 /// If a testee_id is given, remove that person, (or throw an error if not found)
 pub async fn dequeue_testee(
     pool: &PgPool,
     testee_id: Option<Uuid>,
-    test_definition_index: Option<i32>,
-) -> Result<Option<(Testee, i32)>, TestError> {
+    test_definition_id: Option<Uuid>,
+) -> Result<Option<(Testee, Uuid)>, TestError> {
 
-    // Handle different cases based on the presence of testee_id and test_definition_index
-    let (testee_id, test_definition_index) = match (testee_id, test_definition_index) {
+    // Handle different cases based on the presence of testee_id and test_definition_id
+    let (testee_id, test_definition_id) = match (testee_id, test_definition_id) {
         (Some(id), Some(r)) => {
-            // Both testee_id and test_definition_index are provided; delete the specific entry
+            // Both testee_id and test_definition_id are provided; delete the specific entry
             match sqlx::query!(
-                "DELETE FROM queue WHERE testee_id = $1 AND test_definition_index = $2 RETURNING test_definition_index",
+                "DELETE FROM queue WHERE testee_id = $1 AND test_definition_id = $2 RETURNING test_definition_id",
                 id, r
             )
             .fetch_optional(pool)
             .await? {
-                Some(result) => (id, result.test_definition_index),
+                Some(result) => (id, result.test_definition_id),
                 None => return Ok(None),
             }
         }
@@ -722,31 +889,31 @@ pub async fn dequeue_testee(
             match sqlx::query!(
                 "DELETE FROM queue WHERE ctid = (
                     SELECT ctid FROM queue WHERE testee_id = $1 ORDER BY added_at LIMIT 1
-                ) RETURNING test_definition_index",
+                ) RETURNING test_definition_id",
                 id
             )
             .fetch_optional(pool)
             .await? {
-                Some(result) => (id, result.test_definition_index),
+                Some(result) => (id, result.test_definition_id),
                 None => return Ok(None),
             }
         }
         (None, None) => {
-            // Neither testee_id nor test_definition_index is provided; delete the oldest queue item
+            // Neither testee_id nor test_definition_id is provided; delete the oldest queue item
             match sqlx::query!(
                 "DELETE FROM queue WHERE ctid = (
                     SELECT ctid FROM queue ORDER BY added_at LIMIT 1
-                ) RETURNING testee_id, test_definition_index"
+                ) RETURNING testee_id, test_definition_id"
             )
             .fetch_optional(pool)
             .await? {
-                Some(result) => (result.testee_id, result.test_definition_index),
+                Some(result) => (result.testee_id, result.test_definition_id),
                 None => return Ok(None),
             }
         }
         (None, Some(_)) => {
-            // Only test_definition_index is provided, which is an invalid case
-            return Err(TestError::InternalServerError("Test index specified without testee_id when trying to dequeue.".into()));
+            // Only test_definition_id is provided, which is an invalid case
+            return Err(TestError::InternalServerError("Test definition specified without testee_id when trying to dequeue.".into()));
         }
     };
 
@@ -759,24 +926,30 @@ pub async fn dequeue_testee(
     .fetch_one(pool)
     .await?;
 
-    Ok(Some((testee, test_definition_index)))
+    publish_queue_event(pool, WebhookEvent::TesteeDequeued, testee_id, test_definition_id).await;
+
+    Ok(Some((testee, test_definition_id)))
 }
 
 // -------------------------------------------------------------------------------------------------------------------------------------------------------
 // Get Queue
 // -------------------------------------------------------------------------------------------------------------------------------------------------------
 
-pub async fn retrieve_queue(pool: &PgPool) -> Result<Vec<(Testee, i32)>, TestError> {
+pub async fn retrieve_queue(pool: &PgPool) -> Result<Vec<QueueItem>, TestError> {
     let rows = sqlx::query!(
         "
         SELECT 
-            t.id, t.first_name, t.last_name, t.email, q.test_definition_index
+            t.id, t.first_name, t.last_name, t.email, q.test_definition_id, td.test_name
         FROM 
             queue q
         JOIN 
             testees t 
         ON 
             q.testee_id = t.id
+        JOIN
+            test_definitions td
+        ON
+            q.test_definition_id = td.id
         ORDER BY 
             q.added_at
         "
@@ -784,17 +957,17 @@ pub async fn retrieve_queue(pool: &PgPool) -> Result<Vec<(Testee, i32)>, TestErr
     .fetch_all(pool)
     .await?;
 
-    // Map the result into a Vec of (Testee, test_name) tuples
     let queue = rows.into_iter().map(|row| {
-        (
-            Testee {
+        QueueItem {
+            testee: Testee {
                 id: Some(row.id),
                 first_name: row.first_name,
                 last_name: row.last_name,
                 email: row.email,
             },
-            row.test_definition_index,
-        )
+            test_definition_id: row.test_definition_id,
+            test_name: row.test_name,
+        }
     }).collect();
 
     Ok(queue)
@@ -812,12 +985,14 @@ struct EmailTemplate<'a> {
 }
 
 /// Given a testee_id and smtp_config, will generate an email containing all of the 
+/// When attached_test_id is given, that test's score report is attached as a PDF, along with its certificate if it passed.
 pub async fn send_email(
     pool: &PgPool,
     smtp_mailer: &AsyncSmtpTransport<Tokio1Executor>, 
     smtp_config: SMTPConfig,
     testee_id: Uuid,
     server_root_url: String,
+    attached_test_id: Option<Uuid>,
 ) -> Result<lettre::transport::smtp::response::Response, TestError> {
 
     let testee = fetch_testee_by_id(pool, testee_id)
@@ -835,14 +1010,33 @@ pub async fn send_email(
     .render()
     .map_err(|e| TestError::InternalServerError(format!("Error rendering email template: {}", e)))?;
 
-    let email = Message::builder()
+    let attachments = match attached_test_id {
+        Some(test_id) => {
+            let test = fetch_test_results_by_id(pool, test_id)
+                .await?
+                .ok_or_else(|| TestError::InternalServerError("No test with that ID found to attach.".to_string()))?;
+            render_pdf_attachments(&test)?
+        },
+        None => Vec::new(),
+    };
+
+    let email_builder = Message::builder()
         .from(smtp_config.user_email.parse().map_err(|e| TestError::InternalServerError(format!("Error: Unable to parse SMTP config user_email \"{}\": {}", smtp_config.user_email, e)))?)
         .to(testee.email.parse().map_err(|e| TestError::InternalServerError(format!("Error: Unable to parse testee email \"{}\": {}", testee.email, e)))?)
-        .subject("Your Dancexam Results")
-        .header(ContentType::TEXT_HTML)
-        .body(email_body)
-
-        .map_err(|e| TestError::InternalServerError(format!("Error: Unable to create email: {}", e)))?;
+        .subject("Your Dancexam Results");
+
+    let email = match attachments.is_empty() {
+        true => email_builder
+            .header(ContentType::TEXT_HTML)
+            .body(email_body),
+        false => email_builder.multipart(
+            attachments.into_iter().fold(
+                MultiPart::mixed().singlepart(SinglePart::html(email_body)),
+                |multipart, (file_name, pdf)| multipart.singlepart(Attachment::new(file_name).body(pdf, ContentType::parse("application/pdf").expect("application/pdf is a valid content type")))
+            )
+        ),
+    }
+    .map_err(|e| TestError::InternalServerError(format!("Error: Unable to create email: {}", e)))?;
 
 
     // Send the email
@@ -851,6 +1045,18 @@ pub async fn send_email(
         .map_err(|e| TestError::InternalServerError(format!("Error: Unable to send email: {}", e)))
 }
 
+/// The score report of a graded test, and its certificate when it passed, as file names and PDFs
+fn render_pdf_attachments(test: &Test) -> Result<Vec<(String, Vec<u8>)>, TestError> {
+    let mut kinds = vec![PdfDocumentKind::Report];
+    if test.metadata.is_passing == Some(true) {
+        kinds.push(PdfDocumentKind::Certificate);
+    }
+
+    kinds.into_iter()
+        .map(|kind| Ok((pdf_file_name(kind, test), kind.render(test)?)))
+        .collect()
+}
+
 // -------------------------------------------------------------------------------------------------------------------------------------------------------
 // Unit Tests
 // -------------------------------------------------------------------------------------------------------------------------------------------------------
@@ -910,4 +1116,54 @@ pub mod tests {
         }
     }
 
-}
\ No newline at end of file
+    /// The valid test definition, ready to be graded
+    pub fn valid_test() -> Test {
+        parse_test_definition_from_str(&setup_valid_test_str()).unwrap().tests.remove(0)
+    }
+
+    #[sqlx::test]
+    async fn test_sync_test_definitions_versions(pool: PgPool) {
+        let leader_test = valid_test();
+        let mut follower_test = valid_test();
+        follower_test.metadata.test_name = "Standard Follower Test".to_string();
+
+        let first_sync = sync_test_definitions(&pool, TestDefinitionYaml { tests: vec![leader_test.clone(), follower_test.clone()] }).await.unwrap();
+        let leader_id = first_sync.tests[0].metadata.test_definition_id.unwrap();
+        let follower_id = first_sync.tests[1].metadata.test_definition_id.unwrap();
+        assert_ne!(leader_id, follower_id);
+        assert!(first_sync.tests.iter().all(|test| test.metadata.test_definition_version == Some(1)));
+
+        // Reordered, with only the follower test changed
+        follower_test.metadata.minimum_percent = 0.70;
+        let second_sync = sync_test_definitions(&pool, TestDefinitionYaml { tests: vec![follower_test, leader_test] }).await.unwrap();
+        let follower = &second_sync.tests[0].metadata;
+        let leader = &second_sync.tests[1].metadata;
+        assert_eq!((follower.test_definition_id, follower.test_definition_version), (Some(follower_id), Some(2)));
+        assert_eq!((leader.test_definition_id, leader.test_definition_version), (Some(leader_id), Some(1)));
+
+        // Tests started before the change are still graded against the version they were started with
+        let started_before = fetch_test_definition_version(&pool, follower_id, 1).await.unwrap().unwrap();
+        assert_eq!(started_before.metadata.minimum_percent, 0.60);
+        assert!(fetch_test_definition_version(&pool, leader_id, 2).await.unwrap().is_none());
+    }
+
+    #[sqlx::test]
+    async fn test_reload_keeps_running_definitions_when_file_is_invalid(pool: PgPool) {
+        let data = crate::testing::test_app_state(pool);
+        let file_path = std::env::temp_dir().join(format!("test_definitions_{}.yaml", Uuid::new_v4()));
+        let file_path = file_path.to_str().unwrap();
+
+        std::fs::write(file_path, setup_valid_test_str()).unwrap();
+        let reloaded = reload_test_definitions_from_file(&data, file_path).await.unwrap();
+        assert!(Arc::ptr_eq(&reloaded, &data.test_configurations()));
+
+        // The max score no longer adds up
+        std::fs::write(file_path, setup_valid_test_str().replace("max_score: 4", "max_score: 5")).unwrap();
+        let rejected = reload_test_definitions_from_file(&data, file_path).await;
+        std::fs::remove_file(file_path).unwrap();
+
+        assert!(matches!(rejected, Err(TestError::InvalidTestDefinition(_))));
+        assert!(Arc::ptr_eq(&reloaded, &data.test_configurations()));
+        assert_eq!(data.test_configurations().tests[0].metadata.max_score, Points::whole(4));
+    }
+}
@@ -0,0 +1,621 @@
+use chrono::{Local, NaiveDate, NaiveDateTime};
+use serde::Deserialize;
+use sqlx::{PgExecutor, PgPool};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::exam::handlers::{create_testee, fetch_test_definition_version, insert_graded_test, store_test_definitions, TestError};
+use crate::exam::models::{AchievedScoreLabel, Proctor, Test, TestDefinitionYaml, Testee};
+use crate::exam::points::Points;
+
+
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+// Import Records
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+
+/// Testees and graded tests from before the app was used, read from a YAML file or flattened into CSV rows.
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ImportRecords {
+    #[serde(default)]
+    pub testees: Vec<ImportTestee>,
+    #[serde(default)]
+    pub tests: Vec<ImportTest>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ImportTestee {
+    /// Where the record came from, like `testees[0]` or `line 2`, for the import report
+    #[serde(skip)]
+    pub source: String,
+    pub first_name: String,
+    pub last_name: String,
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ImportTest {
+    #[serde(skip)]
+    pub source: String,
+    /// Matched to a test definition by name
+    pub test_name: String,
+    /// Grades against an older version of the definition instead of the current one
+    pub test_definition_version: Option<i32>,
+    /// Either a date like 2019-05-04 or a date and time like 2019-05-04 18:30
+    pub test_date: String,
+    pub testee: ImportTestee,
+    /// Falls back to the default proctor of the import when left out
+    pub proctor_email: Option<String>,
+    #[serde(default)]
+    pub scores: Vec<ImportScore>,
+    /// The names of the achieved bonus items
+    #[serde(default)]
+    pub bonus_items: Vec<String>,
+}
+
+/// The label a testee achieved for one scoring category of a competency. The scoring category can be left out when the
+/// section only has one.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ImportScore {
+    pub section: String,
+    pub competency: String,
+    pub scoring_category: Option<String>,
+    pub label: String,
+}
+
+/// A record that can't be imported, or a file that can't be read
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportProblem {
+    pub source: String,
+    pub message: String,
+}
+
+impl ImportProblem {
+    fn new(source: &str, message: impl Into<String>) -> ImportProblem {
+        ImportProblem { source: source.to_string(), message: message.into() }
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+// Parse Import Files
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImportFormat {
+    Csv,
+    Yaml,
+}
+
+impl ImportFormat {
+    /// Picks the format from the file extension
+    pub fn from_file_name(file_name: &str) -> Option<ImportFormat> {
+        let extension = file_name.rsplit_once('.')?.1.to_ascii_lowercase();
+        match extension.as_str() {
+            "csv" => Some(ImportFormat::Csv),
+            "yaml" | "yml" => Some(ImportFormat::Yaml),
+            _ => None,
+        }
+    }
+}
+
+pub fn parse_import_records(contents: &str, format: ImportFormat) -> Result<ImportRecords, Vec<ImportProblem>> {
+    match format {
+        ImportFormat::Csv => parse_import_csv(contents),
+        ImportFormat::Yaml => parse_import_yaml(contents),
+    }
+}
+
+fn parse_import_yaml(contents: &str) -> Result<ImportRecords, Vec<ImportProblem>> {
+    let mut records: ImportRecords = serde_yaml::from_str(contents)
+        .map_err(|e| vec![ImportProblem::new("file", format!("Couldn't parse the YAML: {}", e))])?;
+
+    for (index, testee) in records.testees.iter_mut().enumerate() {
+        testee.source = format!("testees[{}]", index);
+    }
+    for (index, test) in records.tests.iter_mut().enumerate() {
+        test.source = format!("tests[{}]", index);
+        test.testee.source = test.source.clone();
+    }
+
+    Ok(records)
+}
+
+/// A CSV row is either a testee, when it has no test name, or one score or bonus item of a test. Rows with the same test
+/// name, testee email, test date and proctor are put together into one test.
+#[derive(Debug, Deserialize)]
+struct ImportCsvRow {
+    first_name: String,
+    last_name: String,
+    email: String,
+    #[serde(default)]
+    test_name: Option<String>,
+    #[serde(default)]
+    test_definition_version: Option<i32>,
+    #[serde(default)]
+    test_date: Option<String>,
+    #[serde(default)]
+    proctor_email: Option<String>,
+    #[serde(default)]
+    section: Option<String>,
+    #[serde(default)]
+    competency: Option<String>,
+    #[serde(default)]
+    scoring_category: Option<String>,
+    #[serde(default)]
+    label: Option<String>,
+    #[serde(default)]
+    bonus_item: Option<String>,
+}
+
+/// The test name, lowercased testee email, test date, proctor email and test definition version of a CSV row
+type CsvTestKey = (String, String, String, Option<String>, Option<i32>);
+
+fn parse_import_csv(contents: &str) -> Result<ImportRecords, Vec<ImportProblem>> {
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(contents.as_bytes());
+
+    let mut records = ImportRecords::default();
+    let mut problems = Vec::new();
+    // Maps the columns that identify a test to its index in records.tests
+    let mut test_indices: HashMap<CsvTestKey, usize> = HashMap::new();
+
+    let headers = reader.headers()
+        .map_err(|e| vec![ImportProblem::new("line 1", format!("Couldn't read the header row: {}", e))])?
+        .clone();
+
+    for result in reader.records() {
+        let record = match result {
+            Ok(record) => record,
+            Err(e) => {
+                let source = e.position().map_or("file".to_string(), |position| format!("line {}", position.line()));
+                problems.push(ImportProblem::new(&source, format!("Couldn't read the row: {}", e)));
+                continue;
+            }
+        };
+        let source = record.position().map_or("file".to_string(), |position| format!("line {}", position.line()));
+
+        let row: ImportCsvRow = match record.deserialize(Some(&headers)) {
+            Ok(row) => row,
+            Err(e) => {
+                problems.push(ImportProblem::new(&source, format!("Couldn't read the row: {}", e)));
+                continue;
+            }
+        };
+
+        let testee = ImportTestee {
+            source: source.clone(),
+            first_name: row.first_name,
+            last_name: row.last_name,
+            email: row.email,
+        };
+
+        let test_name = match row.test_name {
+            Some(test_name) => test_name,
+            None => {
+                records.testees.push(testee);
+                continue;
+            }
+        };
+
+        let test_date = match row.test_date {
+            Some(test_date) => test_date,
+            None => {
+                problems.push(ImportProblem::new(&source, "Rows with a test name need a test date."));
+                continue;
+            }
+        };
+
+        let key = (test_name.clone(), testee.email.to_ascii_lowercase(), test_date.clone(), row.proctor_email.clone(), row.test_definition_version);
+        let test_index = *test_indices.entry(key).or_insert_with(|| {
+            records.tests.push(ImportTest {
+                source: source.clone(),
+                test_name,
+                test_definition_version: row.test_definition_version,
+                test_date,
+                testee,
+                proctor_email: row.proctor_email,
+                scores: Vec::new(),
+                bonus_items: Vec::new(),
+            });
+            records.tests.len() - 1
+        });
+        let test = &mut records.tests[test_index];
+
+        match (row.section, row.competency, row.label, row.bonus_item) {
+            (Some(section), Some(competency), Some(label), None) => test.scores.push(ImportScore {
+                section,
+                competency,
+                scoring_category: row.scoring_category,
+                label,
+            }),
+            (None, None, None, Some(bonus_item)) => test.bonus_items.push(bonus_item),
+            _ => problems.push(ImportProblem::new(&source, "Test rows need either a section, competency and label, or a bonus item.")),
+        }
+    }
+
+    match problems.is_empty() {
+        true => Ok(records),
+        false => Err(problems),
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+// Grade Imported Tests
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+
+pub fn parse_test_date(test_date: &str) -> Result<NaiveDateTime, String> {
+    let test_date = test_date.trim();
+
+    let parsed = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(test_date, format).ok())
+        .or_else(|| NaiveDate::parse_from_str(test_date, "%Y-%m-%d").ok().and_then(|date| date.and_hms_opt(0, 0, 0)))
+        .ok_or_else(|| format!("The test date '{}' should look like 2019-05-04 or 2019-05-04 18:30.", test_date))?;
+
+    if parsed > Local::now().naive_local() {
+        return Err(format!("The test date '{}' is in the future.", test_date));
+    }
+
+    Ok(parsed)
+}
+
+fn validate_testee(testee: &ImportTestee) -> Vec<String> {
+    let mut messages = Vec::new();
+    if testee.first_name.trim().is_empty() || testee.last_name.trim().is_empty() {
+        messages.push("Testees need a first and last name.".to_string());
+    }
+    if !testee.email.contains('@') {
+        messages.push(format!("'{}' isn't an email address.", testee.email));
+    }
+    messages
+}
+
+/// Fills the test definition in with the imported labels and grades it the same way a submitted test form is graded.
+/// Every competency needs exactly one label for each of its scoring categories.
+pub fn grade_imported_test(mut test: Test, import: &ImportTest) -> Result<Test, Vec<String>> {
+    let mut messages = Vec::new();
+    let mut used_scores = vec![false; import.scores.len()];
+
+    for section in test.tables.iter_mut().flat_map(|table| table.sections.iter_mut()) {
+        let has_one_scoring_category = section.scoring_categories.len() == 1;
+
+        for competency in &mut section.competencies {
+            let mut achieved_scores = Vec::new();
+            let mut achieved_score_labels = Vec::new();
+
+            for (scoring_category_index, scoring_category) in section.scoring_categories.iter().enumerate() {
+                let matches: Vec<usize> = import.scores.iter().enumerate()
+                    .filter(|(_, score)| score.section == section.name && score.competency == competency.name)
+                    .filter(|(_, score)| match &score.scoring_category {
+                        Some(name) => *name == scoring_category.name,
+                        None => has_one_scoring_category,
+                    })
+                    .map(|(index, _)| index)
+                    .collect();
+
+                let score = match matches.as_slice() {
+                    [index] => {
+                        used_scores[*index] = true;
+                        &import.scores[*index]
+                    },
+                    [] => {
+                        messages.push(format!("Missing a '{}' label for competency '{}' in section '{}'.", scoring_category.name, competency.name, section.name));
+                        continue;
+                    },
+                    indices => {
+                        indices.iter().for_each(|index| used_scores[*index] = true);
+                        messages.push(format!("More than one '{}' label was given for competency '{}' in section '{}'.", scoring_category.name, competency.name, section.name));
+                        continue;
+                    }
+                };
+
+                let points = scoring_category.values.iter()
+                    .position(|value| *value == score.label)
+                    .and_then(|label_index| competency.scores.get(scoring_category_index)?.get(label_index));
+
+                match points {
+                    Some(points) => {
+                        achieved_scores.push(*points);
+                        achieved_score_labels.push(AchievedScoreLabel {
+                            scoring_category_name: scoring_category.name.clone(),
+                            value: score.label.clone(),
+                        });
+                    },
+                    None => messages.push(format!(
+                        "'{}' isn't a '{}' label for competency '{}' in section '{}'. It should be one of: {}.",
+                        score.label, scoring_category.name, competency.name, section.name, scoring_category.values.join(", ")
+                    )),
+                }
+            }
+
+            competency.achieved_scores = Some(achieved_scores);
+            competency.achieved_score_labels = Some(achieved_score_labels);
+        }
+    }
+
+    for (score, _) in import.scores.iter().zip(used_scores).filter(|(_, used)| !used) {
+        messages.push(format!(
+            "There is no competency '{}' with a '{}' scoring category in section '{}' of '{}'.",
+            score.competency, score.scoring_category.as_deref().unwrap_or_default(), score.section, test.metadata.test_name
+        ));
+    }
+
+    for bonus_name in &import.bonus_items {
+        match test.bonus_items.iter_mut().flatten().find(|bonus_item| bonus_item.name == *bonus_name) {
+            Some(bonus_item) => bonus_item.achieved = Some(true),
+            None => messages.push(format!("There is no bonus item '{}' in '{}'.", bonus_name, test.metadata.test_name)),
+        }
+    }
+
+    if !messages.is_empty() {
+        return Err(messages);
+    }
+
+    test.metadata.testee = Some(Testee {
+        id: None,
+        first_name: import.testee.first_name.clone(),
+        last_name: import.testee.last_name.clone(),
+        email: import.testee.email.clone(),
+    });
+
+    test.grade().map_err(|e| vec![e.to_string()])?;
+
+    Ok(test)
+}
+
+
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+// Import Records
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug)]
+pub struct ImportedTest {
+    pub source: String,
+    pub test_name: String,
+    pub test_date: NaiveDateTime,
+    pub testee_name: String,
+    pub testee_email: String,
+    pub achieved_score: Points,
+    pub max_score: Points,
+    pub is_passing: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    /// The number of different testees created or updated, including those who only appear on a test
+    pub testee_count: usize,
+    pub tests: Vec<ImportedTest>,
+    /// Nothing is imported when there are any problems
+    pub problems: Vec<ImportProblem>,
+}
+
+impl ImportReport {
+    /// Whether the records were written to the database
+    pub fn is_committed(&self) -> bool {
+        !self.dry_run && self.problems.is_empty()
+    }
+}
+
+async fn fetch_proctors_by_email<'e>(executor: impl PgExecutor<'e>, emails: &[String]) -> Result<HashMap<String, Proctor>, TestError> {
+    let proctors = sqlx::query!(
+        "SELECT id, first_name, last_name, email FROM users WHERE email = ANY($1)",
+        emails
+    )
+    .fetch_all(executor)
+    .await?;
+
+    Ok(proctors.into_iter()
+        .map(|row| (row.email, Proctor { id: row.id, first_name: row.first_name, last_name: row.last_name }))
+        .collect())
+}
+
+/// Validates and grades every record, then loads the testees and tests in a single transaction. The test definitions are
+/// synced in the same transaction, so nothing at all is written if any record has a problem, and a dry run rolls the
+/// transaction back so that the report can be checked first. Tests without a proctor email are credited to the default proctor. Imported tests don't send test.saved webhooks, since
+/// they are history rather than something that just happened.
+pub async fn import_records(
+    pool: &PgPool,
+    test_definitions: TestDefinitionYaml,
+    records: ImportRecords,
+    default_proctor: Option<Proctor>,
+    dry_run: bool,
+) -> Result<ImportReport, TestError> {
+    let mut report = ImportReport { dry_run, ..Default::default() };
+
+    let mut transaction = pool.begin().await?;
+    // Imported tests are matched to the same definitions the server would grade them with
+    let test_definitions = store_test_definitions(&mut transaction, test_definitions).await?;
+
+    let proctor_emails: Vec<String> = records.tests.iter()
+        .filter_map(|test| test.proctor_email.as_ref().map(|email| email.to_ascii_lowercase()))
+        .collect();
+    let proctors = fetch_proctors_by_email(&mut *transaction, &proctor_emails).await?;
+
+    for testee in &records.testees {
+        report.problems.extend(validate_testee(testee).into_iter().map(|message| ImportProblem::new(&testee.source, message)));
+    }
+
+    let mut older_definitions: HashMap<(Uuid, i32), Option<Test>> = HashMap::new();
+    let mut graded_tests = Vec::new();
+
+    for import in &records.tests {
+        let mut messages = validate_testee(&import.testee);
+
+        let test_date = parse_test_date(&import.test_date).map_err(|message| messages.push(message)).ok();
+
+        let proctor = match &import.proctor_email {
+            Some(email) => proctors.get(&email.to_ascii_lowercase()).cloned()
+                .ok_or_else(|| format!("There is no user with the proctor email '{}'.", email)),
+            None => default_proctor.clone().ok_or_else(|| "The test has no proctor email and there is no default proctor.".to_string()),
+        }.map_err(|message| messages.push(message)).ok();
+
+        let definition = match (test_definitions.get_by_name(&import.test_name), import.test_definition_version) {
+            (None, _) => None,
+            (Some(test), None) => Some(test.clone()),
+            (Some(test), Some(version)) if test.metadata.test_definition_version == Some(version) => Some(test.clone()),
+            (Some(test), Some(version)) => {
+                let test_definition_id = test.metadata.test_definition_id
+                    .ok_or_else(|| TestError::InternalServerError("Test definitions must be synced to the database before importing.".to_string()))?;
+                if let std::collections::hash_map::Entry::Vacant(entry) = older_definitions.entry((test_definition_id, version)) {
+                    entry.insert(fetch_test_definition_version(&mut *transaction, test_definition_id, version).await?);
+                }
+                older_definitions[&(test_definition_id, version)].clone()
+            }
+        };
+
+        let graded_test = match definition {
+            Some(definition) => grade_imported_test(definition, import).map_err(|errors| messages.extend(errors)).ok(),
+            None => {
+                messages.push(match import.test_definition_version {
+                    Some(version) => format!("There is no version {} of a test named '{}'.", version, import.test_name),
+                    None => format!("There is no test named '{}'.", import.test_name),
+                });
+                None
+            }
+        };
+
+        match (graded_test, test_date, proctor) {
+            (Some(mut graded_test), Some(test_date), Some(proctor)) if messages.is_empty() => {
+                graded_test.metadata.proctor = Some(proctor);
+                graded_tests.push((import, graded_test, test_date));
+            },
+            _ => report.problems.extend(messages.into_iter().map(|message| ImportProblem::new(&import.source, message))),
+        }
+    }
+
+    // Dropping the transaction rolls back the synced test definitions
+    if !report.problems.is_empty() {
+        return Ok(report);
+    }
+
+    let mut testee_ids = Vec::new();
+
+    for testee in &records.testees {
+        let testee = create_testee(&mut *transaction, &testee.first_name, &testee.last_name, &testee.email).await?;
+        testee_ids.extend(testee.id);
+    }
+
+    for (import, graded_test, test_date) in graded_tests {
+        report.tests.push(ImportedTest {
+            source: import.source.clone(),
+            test_name: graded_test.metadata.test_name.clone(),
+            test_date,
+            testee_name: format!("{} {}", import.testee.first_name, import.testee.last_name),
+            testee_email: import.testee.email.clone(),
+            achieved_score: graded_test.metadata.achieved_score.unwrap_or_default(),
+            max_score: graded_test.metadata.max_score,
+            is_passing: graded_test.metadata.is_passing.unwrap_or_default(),
+        });
+
+        let (testee_id, _, _) = insert_graded_test(&mut transaction, graded_test, test_date).await?;
+        testee_ids.push(testee_id);
+    }
+
+    testee_ids.sort();
+    testee_ids.dedup();
+    report.testee_count = testee_ids.len();
+
+    match dry_run {
+        true => transaction.rollback().await?,
+        false => transaction.commit().await?,
+    }
+
+    Ok(report)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exam::handlers::{parse_test_definition_from_str, tests::setup_valid_test_str};
+
+    fn setup_import_test(scores: Vec<(&str, &str, Option<&str>, &str)>) -> ImportTest {
+        ImportTest {
+            source: "tests[0]".to_string(),
+            test_name: "Standard Leader Test".to_string(),
+            test_definition_version: None,
+            test_date: "2019-05-04".to_string(),
+            testee: ImportTestee {
+                source: "tests[0]".to_string(),
+                first_name: "Jane".to_string(),
+                last_name: "Doe".to_string(),
+                email: "jane@example.com".to_string(),
+            },
+            proctor_email: None,
+            scores: scores.into_iter().map(|(section, competency, scoring_category, label)| ImportScore {
+                section: section.to_string(),
+                competency: competency.to_string(),
+                scoring_category: scoring_category.map(str::to_string),
+                label: label.to_string(),
+            }).collect(),
+            bonus_items: vec!["No Thumbs".to_string()],
+        }
+    }
+
+    fn setup_definition() -> Test {
+        parse_test_definition_from_str(&setup_valid_test_str()).unwrap().tests.remove(0)
+    }
+
+    #[test]
+    fn imported_labels_are_graded_like_a_test_form() {
+        let import = setup_import_test(vec![
+            ("Pattern Scoring", "Starter Step", Some("Footwork"), "Perfect"),
+            ("Pattern Scoring", "Starter Step", Some("Timing"), "Off"),
+        ]);
+
+        let graded_test = grade_imported_test(setup_definition(), &import).unwrap();
+
+        assert_eq!(graded_test.metadata.achieved_score, Some(Points::whole(4)));
+        assert_eq!(graded_test.metadata.is_passing, Some(true));
+        assert_eq!(graded_test.metadata.testee.unwrap().email, "jane@example.com");
+    }
+
+    #[test]
+    fn missing_duplicate_and_unknown_labels_are_all_reported() {
+        let import = setup_import_test(vec![
+            ("Pattern Scoring", "Starter Step", Some("Footwork"), "Perfect"),
+            ("Pattern Scoring", "Starter Step", Some("Footwork"), "Nope"),
+            ("Pattern Scoring", "Sugar Push", Some("Timing"), "On"),
+        ]);
+
+        let messages = grade_imported_test(setup_definition(), &import).unwrap_err();
+
+        assert_eq!(messages.len(), 3, "{:?}", messages);
+        assert!(messages[0].starts_with("More than one 'Footwork' label"));
+        assert!(messages[1].starts_with("Missing a 'Timing' label"));
+        assert!(messages[2].starts_with("There is no competency 'Sugar Push'"));
+    }
+
+    #[test]
+    fn csv_rows_are_grouped_into_tests() {
+        let csv = "\
+first_name,last_name,email,test_name,test_date,proctor_email,section,competency,scoring_category,label,bonus_item
+John,Smith,john@example.com,,,,,,,,
+Jane,Doe,jane@example.com,Standard Leader Test,2019-05-04,,Pattern Scoring,Starter Step,Footwork,Perfect,
+Jane,Doe,jane@example.com,Standard Leader Test,2019-05-04,,Pattern Scoring,Starter Step,Timing,Off,
+Jane,Doe,jane@example.com,Standard Leader Test,2019-05-04,,,,,,No Thumbs
+";
+
+        let records = parse_import_records(csv, ImportFormat::Csv).unwrap();
+
+        assert_eq!(records.testees.len(), 1);
+        assert_eq!(records.testees[0].source, "line 2");
+        assert_eq!(records.tests.len(), 1);
+        assert_eq!(records.tests[0].source, "line 3");
+        assert_eq!(records.tests[0].scores.len(), 2);
+        assert_eq!(records.tests[0].bonus_items, vec!["No Thumbs".to_string()]);
+    }
+
+    #[test]
+    fn test_dates_can_leave_out_the_time() {
+        assert_eq!(parse_test_date("2019-05-04").unwrap().to_string(), "2019-05-04 00:00:00");
+        assert_eq!(parse_test_date("2019-05-04 18:30").unwrap().to_string(), "2019-05-04 18:30:00");
+        assert!(parse_test_date("05/04/2019").is_err());
+        assert!(parse_test_date("2999-01-01").is_err());
+    }
+}
@@ -1,2 +1,7 @@
 pub mod models;
-pub mod handlers;
\ No newline at end of file
+pub mod handlers;
+pub mod export;
+pub mod import;
+pub mod pdf;
+pub mod submission;
+pub mod points;
\ No newline at end of file
@@ -1,9 +1,12 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 use chrono::NaiveDateTime;
+use lettre::{transport::smtp::{authentication::Credentials, PoolConfig}, AsyncSmtpTransport, Tokio1Executor};
 use serde::{Deserialize, Serialize};
 use sqlx::prelude::FromRow;
+use utoipa::ToSchema;
 use uuid::Uuid;
 use crate::config::get_env_var;
+use crate::exam::points::Points;
 
 
 
@@ -19,97 +22,203 @@ pub struct TestDefinitionYaml {
     pub tests: Vec<Test>
 }
 
+impl TestDefinitionYaml {
+    /// Finds a test definition by the stable id assigned to it when it was synced to the database.
+    pub fn get_by_id(&self, test_definition_id: Uuid) -> Option<&Test> {
+        self.tests.iter().find(|test| test.metadata.test_definition_id == Some(test_definition_id))
+    }
+
+    /// Finds a test definition by its test name, which is unique within the file.
+    pub fn get_by_name(&self, test_name: &str) -> Option<&Test> {
+        self.tests.iter().find(|test| test.metadata.test_name == test_name)
+    }
+
+    /// Validates every test in the file and ensures that test names are unique, since tests are matched to their stored
+    /// definitions by name. Every problem in every test is collected.
+    pub fn validate(&self) -> Result<(), Vec<DefinitionError>> {
+        let mut errors = Vec::new();
+        let mut seen_test_names: Vec<&str> = Vec::new();
+
+        for test in &self.tests {
+            if seen_test_names.contains(&test.metadata.test_name.as_str()) {
+                errors.push(DefinitionError::DuplicateTestName { test_name: test.metadata.test_name.clone() });
+            }
+            seen_test_names.push(&test.metadata.test_name);
+
+            if let Err(test_errors) = test.validate() {
+                errors.extend(test_errors);
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
 /// A test object -- can be graded or ungraded, and is used to store the 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 #[serde(deny_unknown_fields)]
 pub struct Test {
     pub metadata: Metadata,
     pub tables: Vec<TestTable>,
     pub bonus_items: Option<Vec<BonusItem>>,
+    pub rules: Option<Vec<GradingRule>>,
 }
 
 impl Test {
-    /// Iterates over each competency scores lists and calculates the max possible score, not including bonus points. 
-    fn calculate_max_score(&self) -> i32 {
+    /// Iterates over each competency scores lists and calculates the max possible score, weighted and not including bonus points. 
+    pub fn calculate_max_score(&self) -> Points {
         self.tables.iter()
-            .flat_map(|table| table.sections.iter()) // Flatten the list of lists
-            .flat_map(|section| section.competencies.iter()) // Flatten to items to be graded
-            .map(|item| {
-                item.scores.iter()
-                    .map(|score_list| {
-                        score_list.iter()
-                            .max()
-                            .cloned()
-                            .unwrap_or(0)
-                })
-                .sum::<i32>()
-            })
-            .sum() // Sum the max scores of all items
+            .map(|table| table.calculate_max_score())
+            .sum()
     }
     
 
     /// Ensures the score labels are correct, ensures that failing score labels are correct, ensures that antitheses are only present
-    /// for single scoring category questions, and ensures that the max score is properly documented in the metadata. 
+    /// for single scoring category questions, ensures that the max scores are properly documented in the metadata, tables and
+    /// sections, ensures that every minimum percent is a fraction between 0 and 1, ensures that scores are whole or half
+    /// points and weights are positive with at most two decimal places, which keeps the weighted scores exact, and ensures
+    /// that the grading rules only refer to sections, competencies, labels and bonus items that exist. 
     /// This violates parse, don't validate, and if this method is not called it is technically possible to have an invalid test
     /// definition, but I'm going to be real, the serde documentation was a huge PITA to figure out the parse don't validate and I'm the
     /// only one using this so just remember to call validate the 2 times you ever deserialize a test from yaml. 
-    pub fn validate(&self) -> Result<(), String> {
-        for table in &self.tables {
-            for section in &table.sections {
-                validate_score_labels(&section.competencies, &section.scoring_categories, &self.metadata.test_name)?;
+    /// Every problem in the definition is collected instead of stopping at the first one.
+    pub fn validate(&self) -> Result<(), Vec<DefinitionError>> {
+        let test_name = &self.metadata.test_name;
+        let mut errors = Vec::new();
 
-                validate_failing_score_labels(&section.competencies, &section.scoring_categories, &self.metadata.test_name)?;
+        errors.extend(validate_minimum_percent(self.metadata.minimum_percent, test_name, None, None));
 
-                validate_antitheses(&section.competencies, &self.metadata.test_name)?;
+        for (table_index, table) in self.tables.iter().enumerate() {
+            for (section_index, section) in table.sections.iter().enumerate() {
+                errors.extend(validate_score_labels(&section.competencies, &section.scoring_categories, test_name, table_index, section_index));
+
+                errors.extend(validate_failing_score_labels(&section.competencies, &section.scoring_categories, test_name, table_index, section_index));
+
+                errors.extend(validate_antitheses(&section.competencies, test_name, table_index, section_index));
+
+                errors.extend(validate_points(section, test_name, table_index, section_index));
+
+                if let Some(minimum_percent) = section.minimum_percent {
+                    errors.extend(validate_minimum_percent(minimum_percent, test_name, Some(table_index), Some(section_index)));
+                }
+
+                let calculated_max_score = section.calculate_max_score();
+                if let Some(max_score) = section.max_score.filter(|max_score| *max_score != calculated_max_score) {
+                    errors.push(DefinitionError::IncorrectSectionMaxScore {
+                        test_name: test_name.clone(),
+                        table_index,
+                        section_index,
+                        section_name: section.name.clone(),
+                        max_score,
+                        calculated_max_score,
+                    });
+                }
+            }
+
+            if let Some(minimum_percent) = table.minimum_percent {
+                errors.extend(validate_minimum_percent(minimum_percent, test_name, Some(table_index), None));
+            }
+
+            let calculated_max_score = table.calculate_max_score();
+            if let Some(max_score) = table.max_score.filter(|max_score| *max_score != calculated_max_score) {
+                errors.push(DefinitionError::IncorrectTableMaxScore {
+                    test_name: test_name.clone(),
+                    table_index,
+                    max_score,
+                    calculated_max_score,
+                });
+            }
+        }
+
+        for bonus_item in self.bonus_items.iter().flatten() {
+            if !bonus_item.score.is_multiple_of(Points::HALF) {
+                errors.push(DefinitionError::UnsupportedBonusScore {
+                    test_name: test_name.clone(),
+                    bonus_item_name: bonus_item.name.clone(),
+                    score: bonus_item.score,
+                });
             }
         }
 
-        if self.calculate_max_score() != self.metadata.max_score {
-            return Err(format!(
-                "The test metadata for the test named {} indicates a max score of {} when the actual max score (without bonus points) is {}.",
-                self.metadata.test_name, self.metadata.max_score, self.calculate_max_score()
-            ))
+        for (rule_index, rule) in self.rules.iter().flatten().enumerate() {
+            errors.extend(validate_rule(self, rule, rule_index));
+        }
+
+        let calculated_max_score = self.calculate_max_score();
+        if calculated_max_score != self.metadata.max_score {
+            errors.push(DefinitionError::IncorrectMaxScore {
+                test_name: test_name.clone(),
+                max_score: self.metadata.max_score,
+                calculated_max_score,
+            });
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
         }
-            
-        Ok(())
     }
 
-    pub fn grade(& mut self) -> Result<(i32, bool, Option<Vec<String>>), String> {
-        let mut total_score: i32= 0;
+    pub fn grade(& mut self) -> Result<(Points, bool, Option<Vec<String>>), DefinitionError> {
+        let mut total_score = Points::ZERO;
         let mut is_passing: bool = true;
         let mut failure_explanation: Vec<String> = Vec::new();
 
         let delimiter = "-.-."; // If you change this, also change it on the test_grade.html template.
 
 
-        for table in &self.tables {
-            for section in &table.sections {
+        for (table_index, table) in self.tables.iter().enumerate() {
+            let mut table_score = Points::ZERO;
 
-                for competency in &section.competencies {
-                    let competency_score: i32 = match &competency.achieved_scores {
-                        Some(scores) => scores.iter().sum(),
-                        None => return Err(format!("Missing scores for competency '{}' when grading the test.", competency.name)),
+            for (section_index, section) in table.sections.iter().enumerate() {
+                let mut section_score = Points::ZERO;
+
+                for (competency_index, competency) in section.competencies.iter().enumerate() {
+                    let competency_score = match &competency.achieved_scores {
+                        Some(scores) => scores.iter().sum::<Points>().weighted(competency.weight()),
+                        None => return Err(DefinitionError::MissingAchievedScores {
+                            test_name: self.metadata.test_name.clone(),
+                            table_index,
+                            section_index,
+                            competency_index,
+                            competency_name: competency.name.clone(),
+                        }),
                     };
 
-                    total_score += competency_score;
+                    section_score += competency_score;
 
                     // Check to see if a competency is failing and if it is, set the test to failing
                     if let Some(failing_score_labels_items) = &competency.failing_score_labels {
 
                         // Create a hashmap of the header labels so that we can correspond failing score labels on the graded item to the true header labels
                         let mut achieved_scoring_category_hm: HashMap<String, String> = HashMap::new();
-                        for achieved_score_label in competency.achieved_score_labels.clone().ok_or(
-                            format!("Missing score labels for competency '{}' when grading the test.", competency.name),
-                        )? {
+                        for achieved_score_label in competency.achieved_score_labels.clone().ok_or_else(|| DefinitionError::MissingAchievedScoreLabels {
+                            test_name: self.metadata.test_name.clone(),
+                            table_index,
+                            section_index,
+                            competency_index,
+                            competency_name: competency.name.clone(),
+                        })? {
                             achieved_scoring_category_hm.insert(achieved_score_label.scoring_category_name.clone(), achieved_score_label.value.clone());
                         };
 
                         for failing_score_label in failing_score_labels_items {
                             let achieved_score_label_value = achieved_scoring_category_hm
                                 .get(&failing_score_label.scoring_category_name)
-                                .ok_or(format!("Failing score label '{}' for competency '{}' does not match the achieved scoring category names for that section: {:?} (meaning your test definition was invalid).",
-                                    failing_score_label.scoring_category_name, competency.name, achieved_scoring_category_hm.keys())
-                                )?;
+                                .ok_or_else(|| DefinitionError::UnmatchedFailingScoreLabel {
+                                    test_name: self.metadata.test_name.clone(),
+                                    table_index,
+                                    section_index,
+                                    competency_index,
+                                    competency_name: competency.name.clone(),
+                                    scoring_category_name: failing_score_label.scoring_category_name.clone(),
+                                    achieved_scoring_categories: achieved_scoring_category_hm.keys().cloned().collect(),
+                                })?;
 
                             if failing_score_label.values.contains(&achieved_score_label_value) {
                                 
@@ -134,7 +243,22 @@ impl Test {
                         };
                     };
                 };
+
+                let section_score = section_score.weighted(section.weight());
+                if let Some(explanation) = threshold_failure("section", &section.name, section_score, section.calculate_max_score(), section.minimum_percent) {
+                    is_passing = false;
+                    failure_explanation.push(explanation.join(delimiter));
+                }
+
+                table_score += section_score;
             };
+
+            if let Some(explanation) = threshold_failure("table", &table.name(), table_score, table.calculate_max_score(), table.minimum_percent) {
+                is_passing = false;
+                failure_explanation.push(explanation.join(delimiter));
+            }
+
+            total_score += table_score;
         }
 
         // Score the bonus items
@@ -143,14 +267,22 @@ impl Test {
                 .iter()
                 .filter(|bonus_item| bonus_item.achieved.is_some_and(|x| x))
                 .map(|bonus_item| bonus_item.score)
-                .sum::<i32>();
+                .sum::<Points>();
+        }
+
+        // Check the grading rules, which explain themselves with their own message
+        for rule in self.rules.iter().flatten() {
+            if rule.fail_if.is_met(self.count_for_rule(&rule.fail_if.count)?) {
+                is_passing = false;
+                failure_explanation.push(rule.message.clone());
+            }
         }
 
         // Check if the achieved percent is above the minimum percent
-        if ((total_score as f32) / (self.metadata.max_score as f32)) < self.metadata.minimum_percent {
+        if !total_score.meets_minimum(self.metadata.max_score, self.metadata.minimum_percent) {
             is_passing = false;
             failure_explanation.push(vec![
-                format!("{:.1}", ((total_score as f32) / (self.metadata.max_score as f32)) * 100.0 ) , 
+                format!("{:.1}", total_score.fraction_of(self.metadata.max_score) * 100.0 ) , 
                 format!("{:.1}", self.metadata.minimum_percent * 100.0)
                 ].join(delimiter));
         }
@@ -165,6 +297,47 @@ impl Test {
     }
 
 
+    /// Counts what a grading rule looks at on this graded test.
+    fn count_for_rule(&self, count: &RuleCount) -> Result<usize, DefinitionError> {
+        match count {
+            RuleCount::Labels { section_name, competency_names, scoring_category_name, values } => {
+                let mut matching_competencies = 0;
+
+                for (table_index, table) in self.tables.iter().enumerate() {
+                    for (section_index, section) in table.sections.iter().enumerate() {
+                        if section_name.as_ref().is_some_and(|section_name| *section_name != section.name) {
+                            continue;
+                        }
+
+                        for (competency_index, competency) in section.competencies.iter().enumerate() {
+                            if competency_names.as_ref().is_some_and(|competency_names| !competency_names.contains(&competency.name)) {
+                                continue;
+                            }
+
+                            let achieved_score_labels = competency.achieved_score_labels.as_ref().ok_or_else(|| DefinitionError::MissingAchievedScoreLabels {
+                                test_name: self.metadata.test_name.clone(),
+                                table_index,
+                                section_index,
+                                competency_index,
+                                competency_name: competency.name.clone(),
+                            })?;
+
+                            if achieved_score_labels.iter().any(|label| label.scoring_category_name == *scoring_category_name && values.contains(&label.value)) {
+                                matching_competencies += 1;
+                            }
+                        }
+                    }
+                }
+                Ok(matching_competencies)
+            }
+            RuleCount::BonusItems { names } => Ok(self.bonus_items.iter().flatten()
+                .filter(|bonus_item| bonus_item.achieved.is_some_and(|x| x))
+                .filter(|bonus_item| names.as_ref().is_none_or(|names| names.contains(&bonus_item.name)))
+                .count()),
+        }
+    }
+
+
     pub fn full_summary(&self) -> Result<FullTestSummary, String> {
         
         let grade_summary = self.grade_summary()?;
@@ -183,9 +356,11 @@ impl Test {
         // Check that the test is graded
         self.metadata.is_graded.ok_or("Cannot give a grade summary on an ungraded test.".to_string())?;
 
+        let achieved_score = self.metadata.achieved_score.ok_or("Invariant that graded tests all have an achieved score violated in get_test_results fn")?;
+
         Ok(TestGradeSummary {
-            achieved_score: self.metadata.achieved_score.ok_or("Invariant that graded tests all have an achieved score violated in get_test_results fn")?,
-            achieved_percent: self.metadata.achieved_score.ok_or("Invariant that graded tests all have an achieved score violated in get_test_results fn")? as f32 / self.metadata.max_score as f32,
+            achieved_score,
+            achieved_percent: achieved_score.fraction_of(self.metadata.max_score),
             max_score: self.metadata.max_score,
             minimum_percent: self.metadata.minimum_percent,
             is_passing: self.metadata.is_passing.ok_or("Invariant that graded tests all have is_passing violated in get_test_results fn")?,
@@ -194,42 +369,145 @@ impl Test {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 #[serde(deny_unknown_fields)]
 pub struct TestTable {
     pub test_id: Option<Uuid>,
     pub table_id: Option<Uuid>,
+    /// The fraction of this table's points needed to pass, on top of the test's own minimum. Left out for no minimum.
+    pub minimum_percent: Option<f32>,
+    /// Only checked against the table's calculated max score, the same way the test's max score is
+    pub max_score: Option<Points>,
     pub sections: Vec<TestSection>
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+impl TestTable {
+    /// The weighted max possible score of every section in the table, not including bonus points.
+    pub fn calculate_max_score(&self) -> Points {
+        self.sections.iter()
+            .map(|section| section.calculate_max_score())
+            .sum()
+    }
+
+    /// Tables don't have names of their own, so they're described by the sections in them.
+    pub fn name(&self) -> String {
+        self.sections.iter()
+            .map(|section| section.name.as_str())
+            .collect::<Vec<&str>>()
+            .join(", ")
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 #[serde(deny_unknown_fields)]
 pub struct TestSection {
     pub table_id: Option<Uuid>,
     pub name: String,
+    /// The fraction of this section's points needed to pass, on top of the test's own minimum. Left out for no minimum.
+    pub minimum_percent: Option<f32>,
+    /// Only checked against the section's calculated max score, the same way the test's max score is
+    pub max_score: Option<Points>,
+    /// Multiplies the scores of every competency in the section. Left out for a weight of 1.
+    pub weight: Option<Points>,
     pub scoring_categories: Vec<ScoringCategory>,
     pub competencies: Vec<Competency>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+impl TestSection {
+    /// Sums the weighted max score of every competency in the section, then applies the section's weight.
+    pub fn calculate_max_score(&self) -> Points {
+        self.competencies.iter()
+            .map(|competency| competency.calculate_max_score())
+            .sum::<Points>()
+            .weighted(self.weight())
+    }
+
+    pub fn weight(&self) -> Points {
+        self.weight.unwrap_or(Points::ONE)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 #[serde(deny_unknown_fields)]
 
 pub struct BonusItem {
     pub test_id: Option<Uuid>,
     pub name: String,
-    pub score: i32,
+    pub score: Points,
     pub achieved: Option<bool>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// A pass/fail rule on top of the failing score labels and minimum percents. The test fails when the condition is met, and
+/// the message is given to the testee as the reason. IE, the following fails anyone with two or more patterns that had
+/// 'Right Concept' footwork, and anyone who earned fewer than 2 bonus items.
+/// ```yaml
+/// rules:
+///   - message: "Two or more patterns had the right concept but not the right footwork."
+///     fail_if:
+///       count:
+///         of: labels
+///         section_name: "Pattern Scoring"
+///         scoring_category_name: "Footwork"
+///         values: ["Right Concept"]
+///       at_least: 2
+///   - message: "At least 2 bonus items must be earned."
+///     fail_if:
+///       count:
+///         of: bonus_items
+///       fewer_than: 2
+/// ```
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct GradingRule {
+    pub message: String,
+    pub fail_if: RuleCondition,
+}
+
+/// Compares a count against exactly one of at_least or fewer_than.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RuleCondition {
+    pub count: RuleCount,
+    pub at_least: Option<usize>,
+    pub fewer_than: Option<usize>,
+}
+
+impl RuleCondition {
+    pub fn is_met(&self, count: usize) -> bool {
+        self.at_least.is_some_and(|at_least| count >= at_least)
+            || self.fewer_than.is_some_and(|fewer_than| count < fewer_than)
+    }
+}
+
+/// What a grading rule counts on the graded test, picked with the `of` key.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+#[serde(tag = "of", rename_all = "snake_case", deny_unknown_fields)]
+pub enum RuleCount {
+    /// Competencies that were given one of the values in the scoring category. Leaving out the section name or the
+    /// competency names counts every section or competency.
+    Labels {
+        section_name: Option<String>,
+        competency_names: Option<Vec<String>>,
+        scoring_category_name: String,
+        values: Vec<String>,
+    },
+    /// Achieved bonus items, or only the named ones.
+    BonusItems {
+        names: Option<Vec<String>>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 #[serde(deny_unknown_fields)]
 
 pub struct Metadata {
     pub test_id: Option<Uuid>,
+    pub test_definition_id: Option<Uuid>, // Assigned when the definition is synced to the database, not set in the yaml
+    pub test_definition_version: Option<i32>,
     pub test_name: String,
     pub minimum_percent: f32,
-    pub max_score: i32,
-    pub achieved_score: Option<i32>,
+    pub max_score: Points,
+    pub achieved_score: Option<Points>,
     pub testee: Option<Testee>,
     pub test_date: Option<NaiveDateTime>,
     pub is_graded: Option<()>, // An option being used as a bool. So that serde_yaml parses the data and I don't have to do hella if statements in the askama templates
@@ -239,14 +517,14 @@ pub struct Metadata {
     pub config_settings: TestConfig,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 #[serde(deny_unknown_fields)]
 pub struct TestConfig {
     pub live_grading: bool,
     pub show_point_values: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 #[serde(deny_unknown_fields)]
 pub struct ScoringCategory {
     pub section_id: Option<Uuid>,
@@ -255,7 +533,7 @@ pub struct ScoringCategory {
 }
 
 /// This is used to hold the score labels that cause a failure
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 #[serde(deny_unknown_fields)]
 pub struct FailingScoreLabels {
     pub scoring_category_name: String,
@@ -263,25 +541,556 @@ pub struct FailingScoreLabels {
 }
 
 /// This is used to hold the score label that the proctor gave for a competency during a test.
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 pub struct AchievedScoreLabel {
     pub scoring_category_name: String,
     pub value: String, 
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 #[serde(deny_unknown_fields)]
 pub struct Competency {
     pub section_id: Option<Uuid>,
     pub name: String,
-    pub scores: Vec<Vec<i32>>,
+    pub scores: Vec<Vec<Points>>,
+    /// Multiplies the competency's scores, on top of its section's weight. Left out for a weight of 1.
+    pub weight: Option<Points>,
     pub subtext: Option<String>,
     pub failing_score_labels: Option<Vec<FailingScoreLabels>>,
     pub antithesis: Option<String>,
-    pub achieved_scores: Option<Vec<i32>>,
+    pub achieved_scores: Option<Vec<Points>>,
     pub achieved_score_labels: Option<Vec<AchievedScoreLabel>>
 }
 
+impl Competency {
+    /// Sums the highest score of each scoring category and applies the competency's weight, but not its section's.
+    pub fn calculate_max_score(&self) -> Points {
+        self.scores.iter()
+            .map(|score_list| {
+                score_list.iter()
+                    .max()
+                    .cloned()
+                    .unwrap_or(Points::ZERO)
+            })
+            .sum::<Points>()
+            .weighted(self.weight())
+    }
+
+    pub fn weight(&self) -> Points {
+        self.weight.unwrap_or(Points::ONE)
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UnknownFailingScoringCategory {
+    pub test_name: String,
+    pub table_index: usize,
+    pub section_index: usize,
+    pub competency_index: usize,
+    pub competency_name: String,
+    pub scoring_category_name: String,
+    pub failing_values: Vec<String>,
+    pub valid_scoring_categories: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UnknownFailingScoreLabel {
+    pub test_name: String,
+    pub table_index: usize,
+    pub section_index: usize,
+    pub competency_index: usize,
+    pub competency_name: String,
+    pub scoring_category_name: String,
+    pub failing_value: String,
+    pub valid_values: Vec<String>,
+}
+
+
+/// Where in a test definition a validation problem was found. The indices are zero based and are None when the problem
+/// isn't specific to a single table, section, or competency. The field is the yaml key that the problem is about.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DefinitionLocation {
+    pub table_index: Option<usize>,
+    pub section_index: Option<usize>,
+    pub competency_index: Option<usize>,
+    /// Set instead of the table, section and competency indices for problems with a grading rule.
+    pub rule_index: Option<usize>,
+    pub field: Option<&'static str>,
+}
+
+/// Everything that can be wrong with a test definition, or with a test being graded against one. Each variant carries the
+/// test name, the zero based indices of where the problem is, and the offending values, so that callers can report on or
+/// match against the problem without parsing a message. The Display implementation gives the human readable message.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DefinitionError {
+    /// The yaml couldn't be deserialized into test definitions at all.
+    InvalidYaml {
+        message: String,
+    },
+    DuplicateTestName {
+        test_name: String,
+    },
+    IncorrectMaxScore {
+        test_name: String,
+        max_score: Points,
+        calculated_max_score: Points,
+    },
+    IncorrectTableMaxScore {
+        test_name: String,
+        table_index: usize,
+        max_score: Points,
+        calculated_max_score: Points,
+    },
+    IncorrectSectionMaxScore {
+        test_name: String,
+        table_index: usize,
+        section_index: usize,
+        section_name: String,
+        max_score: Points,
+        calculated_max_score: Points,
+    },
+    /// Minimum percents are fractions, so 0.6 is 60%. The indices are None for the test's own minimum percent and the
+    /// section index is None for a table's.
+    InvalidMinimumPercent {
+        test_name: String,
+        table_index: Option<usize>,
+        section_index: Option<usize>,
+        minimum_percent: f32,
+    },
+    /// Scores are whole or half points so that weighting them stays exact.
+    UnsupportedScore {
+        test_name: String,
+        table_index: usize,
+        section_index: usize,
+        competency_index: usize,
+        competency_name: String,
+        score: Points,
+    },
+    UnsupportedBonusScore {
+        test_name: String,
+        bonus_item_name: String,
+        score: Points,
+    },
+    /// Weights are positive with at most two decimal places. The competency index is None for a section's weight.
+    InvalidWeight {
+        test_name: String,
+        table_index: usize,
+        section_index: usize,
+        competency_index: Option<usize>,
+        weight: Points,
+    },
+    /// A competency needs one list of scores per scoring category in its section.
+    ScoresListCountMismatch {
+        test_name: String,
+        table_index: usize,
+        section_index: usize,
+        competency_index: usize,
+        competency_name: String,
+        scores_list_count: usize,
+        scoring_category_count: usize,
+    },
+    /// Each list of scores needs one score per value in the corresponding scoring category.
+    ScoresLengthMismatch {
+        test_name: String,
+        table_index: usize,
+        section_index: usize,
+        competency_index: usize,
+        competency_name: String,
+        scoring_category_index: usize,
+        scores_length: usize,
+        scoring_category_value_count: usize,
+    },
+    DuplicateScoringCategory {
+        test_name: String,
+        table_index: usize,
+        section_index: usize,
+        scoring_category_name: String,
+    },
+    /// Boxed, like UnknownFailingScoreLabel, since the valid values would otherwise make every DefinitionError twice as
+    /// large for the sake of one variant.
+    UnknownFailingScoringCategory(Box<UnknownFailingScoringCategory>),
+    UnknownFailingScoreLabel(Box<UnknownFailingScoreLabel>),
+    /// Antitheses are only shown for competencies with a single scoring category.
+    UnsupportedAntithesis {
+        test_name: String,
+        table_index: usize,
+        section_index: usize,
+        competency_index: usize,
+        competency_name: String,
+        antithesis: String,
+        scoring_category_count: usize,
+    },
+    MissingAchievedScores {
+        test_name: String,
+        table_index: usize,
+        section_index: usize,
+        competency_index: usize,
+        competency_name: String,
+    },
+    MissingAchievedScoreLabels {
+        test_name: String,
+        table_index: usize,
+        section_index: usize,
+        competency_index: usize,
+        competency_name: String,
+    },
+    /// Rule messages are shown as failure explanations, so they can't be empty or contain the explanation delimiter.
+    InvalidRuleMessage {
+        test_name: String,
+        rule_index: usize,
+        message: String,
+    },
+    /// A rule needs exactly one of at_least or fewer_than.
+    InvalidRuleComparison {
+        test_name: String,
+        rule_index: usize,
+    },
+    UnknownRuleSection {
+        test_name: String,
+        rule_index: usize,
+        section_name: String,
+    },
+    UnknownRuleCompetency {
+        test_name: String,
+        rule_index: usize,
+        competency_name: String,
+    },
+    UnknownRuleScoringCategory {
+        test_name: String,
+        rule_index: usize,
+        scoring_category_name: String,
+    },
+    UnknownRuleScoreLabel {
+        test_name: String,
+        rule_index: usize,
+        scoring_category_name: String,
+        value: String,
+    },
+    UnknownRuleBonusItem {
+        test_name: String,
+        rule_index: usize,
+        bonus_item_name: String,
+    },
+    /// A failing score label refers to a scoring category that the graded competency has no achieved label for.
+    UnmatchedFailingScoreLabel {
+        test_name: String,
+        table_index: usize,
+        section_index: usize,
+        competency_index: usize,
+        competency_name: String,
+        scoring_category_name: String,
+        achieved_scoring_categories: Vec<String>,
+    },
+}
+
+impl DefinitionError {
+    /// Where in the test definition the problem is. Used by the dancexam-lint binary to find the offending line.
+    pub fn location(&self) -> DefinitionLocation {
+        let competency_location = |table_index: &usize, section_index: &usize, competency_index: &usize, field: &'static str| DefinitionLocation {
+            table_index: Some(*table_index),
+            section_index: Some(*section_index),
+            competency_index: Some(*competency_index),
+            field: Some(field),
+            ..Default::default()
+        };
+        let rule_location = |rule_index: &usize, field: &'static str| DefinitionLocation {
+            rule_index: Some(*rule_index),
+            field: Some(field),
+            ..Default::default()
+        };
+
+        match self {
+            DefinitionError::InvalidYaml { .. } => DefinitionLocation::default(),
+            DefinitionError::DuplicateTestName { .. } => DefinitionLocation { field: Some("test_name"), ..Default::default() },
+            DefinitionError::IncorrectMaxScore { .. } => DefinitionLocation { field: Some("max_score"), ..Default::default() },
+            DefinitionError::IncorrectTableMaxScore { table_index, .. } => DefinitionLocation {
+                table_index: Some(*table_index),
+                field: Some("max_score"),
+                ..Default::default()
+            },
+            DefinitionError::IncorrectSectionMaxScore { table_index, section_index, .. } => DefinitionLocation {
+                table_index: Some(*table_index),
+                section_index: Some(*section_index),
+                competency_index: None,
+                field: Some("max_score"),
+                ..Default::default()
+            },
+            DefinitionError::InvalidMinimumPercent { table_index, section_index, .. } => DefinitionLocation {
+                table_index: *table_index,
+                section_index: *section_index,
+                competency_index: None,
+                field: Some("minimum_percent"),
+                ..Default::default()
+            },
+            DefinitionError::UnsupportedBonusScore { .. } => DefinitionLocation::default(),
+            DefinitionError::InvalidWeight { table_index, section_index, competency_index, .. } => DefinitionLocation {
+                table_index: Some(*table_index),
+                section_index: Some(*section_index),
+                competency_index: *competency_index,
+                field: Some("weight"),
+                ..Default::default()
+            },
+            DefinitionError::UnsupportedScore { table_index, section_index, competency_index, .. }
+            | DefinitionError::ScoresListCountMismatch { table_index, section_index, competency_index, .. }
+            | DefinitionError::ScoresLengthMismatch { table_index, section_index, competency_index, .. }
+            | DefinitionError::MissingAchievedScores { table_index, section_index, competency_index, .. } => {
+                competency_location(table_index, section_index, competency_index, "scores")
+            }
+            DefinitionError::DuplicateScoringCategory { table_index, section_index, .. } => DefinitionLocation {
+                table_index: Some(*table_index),
+                section_index: Some(*section_index),
+                competency_index: None,
+                field: Some("scoring_categories"),
+                ..Default::default()
+            },
+            DefinitionError::UnknownFailingScoringCategory(error) => {
+                competency_location(&error.table_index, &error.section_index, &error.competency_index, "failing_score_labels")
+            }
+            DefinitionError::UnknownFailingScoreLabel(error) => {
+                competency_location(&error.table_index, &error.section_index, &error.competency_index, "failing_score_labels")
+            }
+            DefinitionError::MissingAchievedScoreLabels { table_index, section_index, competency_index, .. }
+            | DefinitionError::UnmatchedFailingScoreLabel { table_index, section_index, competency_index, .. } => {
+                competency_location(table_index, section_index, competency_index, "failing_score_labels")
+            }
+            DefinitionError::UnsupportedAntithesis { table_index, section_index, competency_index, .. } => {
+                competency_location(table_index, section_index, competency_index, "antithesis")
+            }
+            DefinitionError::InvalidRuleMessage { rule_index, .. } => rule_location(rule_index, "message"),
+            DefinitionError::InvalidRuleComparison { rule_index, .. } => rule_location(rule_index, "fail_if"),
+            DefinitionError::UnknownRuleSection { rule_index, .. } => rule_location(rule_index, "fail_if.count.section_name"),
+            DefinitionError::UnknownRuleCompetency { rule_index, .. } => rule_location(rule_index, "fail_if.count.competency_names"),
+            DefinitionError::UnknownRuleScoringCategory { rule_index, .. } => rule_location(rule_index, "fail_if.count.scoring_category_name"),
+            DefinitionError::UnknownRuleScoreLabel { rule_index, .. } => rule_location(rule_index, "fail_if.count.values"),
+            DefinitionError::UnknownRuleBonusItem { rule_index, .. } => rule_location(rule_index, "fail_if.count.names"),
+        }
+    }
+}
+
+impl std::fmt::Display for DefinitionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DefinitionError::InvalidYaml { message } => write!(f, "{}", message),
+            DefinitionError::DuplicateTestName { test_name } => write!(
+                f, "The test name '{}' is used by more than one test definition. Test names must be unique.", test_name
+            ),
+            DefinitionError::IncorrectMaxScore { test_name, max_score, calculated_max_score } => write!(
+                f, "The test metadata for the test named {} indicates a max score of {} when the actual max score (without bonus points) is {}.",
+                test_name, max_score, calculated_max_score
+            ),
+            DefinitionError::IncorrectTableMaxScore { test_name, table_index, max_score, calculated_max_score } => write!(
+                f, "On the test named '{},' the table at index {} has a max score of {} when the actual max score of its sections is {}.",
+                test_name, table_index, max_score, calculated_max_score
+            ),
+            DefinitionError::IncorrectSectionMaxScore { test_name, section_name, max_score, calculated_max_score, .. } => write!(
+                f, "On the test named '{},' the section named '{}' has a max score of {} when the actual max score of its competencies is {}.",
+                test_name, section_name, max_score, calculated_max_score
+            ),
+            DefinitionError::InvalidMinimumPercent { test_name, table_index, section_index, minimum_percent } => {
+                let scope = match (table_index, section_index) {
+                    (Some(table_index), Some(section_index)) => format!("section at index {} of the table at index {}", section_index, table_index),
+                    (Some(table_index), None) => format!("table at index {}", table_index),
+                    _ => "test".to_string(),
+                };
+                write!(
+                    f, "On the test named '{},' the {} has a minimum percent of {} when it should be a fraction between 0 and 1 (ie, 0.6 for 60%).",
+                    test_name, scope, minimum_percent
+                )
+            },
+            DefinitionError::UnsupportedScore { test_name, competency_name, score, .. } => write!(
+                f, "On the test named '{},' the graded item named '{}' has a score of {} when scores must be whole or half points.",
+                test_name, competency_name, score
+            ),
+            DefinitionError::UnsupportedBonusScore { test_name, bonus_item_name, score } => write!(
+                f, "On the test named '{},' the bonus item named '{}' has a score of {} when scores must be whole or half points.",
+                test_name, bonus_item_name, score
+            ),
+            DefinitionError::InvalidWeight { test_name, table_index, section_index, competency_index, weight } => {
+                let scope = match competency_index {
+                    Some(competency_index) => format!("competency at index {} of the section at index {} of the table at index {}", competency_index, section_index, table_index),
+                    None => format!("section at index {} of the table at index {}", section_index, table_index),
+                };
+                write!(
+                    f, "On the test named '{},' the {} has a weight of {} when weights must be more than 0 with at most two decimal places.",
+                    test_name, scope, weight
+                )
+            },
+            DefinitionError::ScoresListCountMismatch { test_name, competency_name, scores_list_count, scoring_category_count, .. } => write!(
+                f, "On the test named '{},' graded item '{}' has a number of lists of scores ({}) that does not correspond to the number of scoring categories. ({})",
+                test_name, competency_name, scores_list_count, scoring_category_count
+            ),
+            DefinitionError::ScoresLengthMismatch { test_name, competency_name, scoring_category_index, scores_length, scoring_category_value_count, .. } => write!(
+                f, "On the test named '{},' the graded item named '{}' has a score list at index {} of length {} that does not correspond to the number of score labels ({}) for the scoring category at index {}.",
+                test_name, competency_name, scoring_category_index, scores_length, scoring_category_value_count, scoring_category_index
+            ),
+            DefinitionError::DuplicateScoringCategory { test_name, scoring_category_name, .. } => write!(
+                f, "On the test named '{},' the scoring category name '{}' is not unique within its section.",
+                test_name, scoring_category_name
+            ),
+            DefinitionError::UnknownFailingScoringCategory(error) => write!(
+                f, "On the test named '{},' the graded item named '{}' has failing score labels {:?} under the scoring category '{}' that does not correspond to any of the valid scoring category labels ({:?}).",
+                error.test_name, error.competency_name, error.failing_values, error.scoring_category_name, error.valid_scoring_categories
+            ),
+            DefinitionError::UnknownFailingScoreLabel(error) => write!(
+                f, "On the test named '{},' the graded item named '{}' has a failing score label '{}' that does not correspond to any of the score labels ({:?}) in the scoring category named '{}'.",
+                error.test_name, error.competency_name, error.failing_value, error.valid_values, error.scoring_category_name
+            ),
+            DefinitionError::UnsupportedAntithesis { test_name, competency_name, antithesis, .. } => write!(
+                f, "On the test named '{},' the competency named '{}' has an antithesis {} which is not supported when there is more than one scoring category for that item.",
+                test_name, competency_name, antithesis
+            ),
+            DefinitionError::MissingAchievedScores { competency_name, .. } => write!(
+                f, "Missing scores for competency '{}' when grading the test.", competency_name
+            ),
+            DefinitionError::MissingAchievedScoreLabels { competency_name, .. } => write!(
+                f, "Missing score labels for competency '{}' when grading the test.", competency_name
+            ),
+            DefinitionError::InvalidRuleMessage { test_name, rule_index, message } => write!(
+                f, "On the test named '{},' rule {} has the message '{}' when rule messages can't be empty or contain '-.-.'.",
+                test_name, rule_index + 1, message
+            ),
+            DefinitionError::InvalidRuleComparison { test_name, rule_index } => write!(
+                f, "On the test named '{},' rule {} needs exactly one of at_least or fewer_than.",
+                test_name, rule_index + 1
+            ),
+            DefinitionError::UnknownRuleSection { test_name, rule_index, section_name } => write!(
+                f, "On the test named '{},' rule {} refers to a section named '{}' that isn't in the test.",
+                test_name, rule_index + 1, section_name
+            ),
+            DefinitionError::UnknownRuleCompetency { test_name, rule_index, competency_name } => write!(
+                f, "On the test named '{},' rule {} refers to a competency named '{}' that isn't in the sections it counts.",
+                test_name, rule_index + 1, competency_name
+            ),
+            DefinitionError::UnknownRuleScoringCategory { test_name, rule_index, scoring_category_name } => write!(
+                f, "On the test named '{},' rule {} refers to a scoring category named '{}' that isn't in the sections it counts.",
+                test_name, rule_index + 1, scoring_category_name
+            ),
+            DefinitionError::UnknownRuleScoreLabel { test_name, rule_index, scoring_category_name, value } => write!(
+                f, "On the test named '{},' rule {} refers to a score label '{}' that isn't in the scoring category named '{}'.",
+                test_name, rule_index + 1, value, scoring_category_name
+            ),
+            DefinitionError::UnknownRuleBonusItem { test_name, rule_index, bonus_item_name } => write!(
+                f, "On the test named '{},' rule {} refers to a bonus item named '{}' that isn't in the test.",
+                test_name, rule_index + 1, bonus_item_name
+            ),
+            DefinitionError::UnmatchedFailingScoreLabel { competency_name, scoring_category_name, achieved_scoring_categories, .. } => write!(
+                f, "Failing score label '{}' for competency '{}' does not match the achieved scoring category names for that section: {:?} (meaning your test definition was invalid).",
+                scoring_category_name, competency_name, achieved_scoring_categories
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DefinitionError {}
+
+/// Everything that can be wrong with a submitted test, as opposed to the test definition it was graded against. Scores
+/// are only ever taken from the test definition, so a submission can pick score labels but never points. The indices are
+/// zero based.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SubmissionError {
+    /// The body couldn't be read as JSON or as form fields at all.
+    MalformedBody {
+        message: String,
+    },
+    /// A form field has a name or a value that doesn't fit the submission schema.
+    MalformedField {
+        key: String,
+        value: String,
+    },
+    DuplicateField {
+        key: String,
+    },
+    MissingField {
+        field: &'static str,
+    },
+    UnsupportedVersion {
+        version: u32,
+    },
+    UnknownTable {
+        table_index: usize,
+    },
+    UnknownSection {
+        table_index: usize,
+        section_index: usize,
+    },
+    UnknownCompetency {
+        table_index: usize,
+        section_index: usize,
+        competency_index: usize,
+    },
+    /// Score labels were picked for more scoring categories than the competency's section has.
+    UnknownScoringCategory {
+        competency_name: String,
+        scoring_category_index: usize,
+    },
+    UnknownScoreLabel {
+        competency_name: String,
+        scoring_category_name: String,
+        score_label_index: usize,
+    },
+    /// The same competency was submitted more than once.
+    DuplicateCompetency {
+        competency_name: String,
+    },
+    MissingScore {
+        competency_name: String,
+        scoring_category_name: String,
+    },
+    UnknownBonusItem {
+        bonus_index: usize,
+    },
+}
+
+impl std::fmt::Display for SubmissionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SubmissionError::MalformedBody { message } => write!(
+                f, "The submission couldn't be read: {}", message
+            ),
+            SubmissionError::MalformedField { key, value } => write!(
+                f, "The field '{}' with the value '{}' is not valid.", key, value
+            ),
+            SubmissionError::DuplicateField { key } => write!(
+                f, "The field '{}' was given more than once.", key
+            ),
+            SubmissionError::MissingField { field } => write!(
+                f, "The field '{}' is required.", field
+            ),
+            SubmissionError::UnsupportedVersion { version } => write!(
+                f, "Version {} of the submission schema is not supported.", version
+            ),
+            SubmissionError::UnknownTable { table_index } => write!(
+                f, "The test has no table at index {}.", table_index
+            ),
+            SubmissionError::UnknownSection { table_index, section_index } => write!(
+                f, "The table at index {} has no section at index {}.", table_index, section_index
+            ),
+            SubmissionError::UnknownCompetency { table_index, section_index, competency_index } => write!(
+                f, "The section at index {} of the table at index {} has no competency at index {}.", section_index, table_index, competency_index
+            ),
+            SubmissionError::UnknownScoringCategory { competency_name, scoring_category_index } => write!(
+                f, "The competency '{}' has no scoring category at index {}.", competency_name, scoring_category_index
+            ),
+            SubmissionError::UnknownScoreLabel { competency_name, scoring_category_name, score_label_index } => write!(
+                f, "The scoring category '{}' of competency '{}' has no score label at index {}.", scoring_category_name, competency_name, score_label_index
+            ),
+            SubmissionError::DuplicateCompetency { competency_name } => write!(
+                f, "The competency '{}' was scored more than once.", competency_name
+            ),
+            SubmissionError::MissingScore { competency_name, scoring_category_name } => write!(
+                f, "No '{}' score was given for competency '{}'.", scoring_category_name, competency_name
+            ),
+            SubmissionError::UnknownBonusItem { bonus_index } => write!(
+                f, "The test has no bonus item at index {}.", bonus_index
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SubmissionError {}
 
 /// When given the list of GradedItems and the list of HeaderLabels corresponding to a TestSection, will
 /// validate that the GradedItems have scores that line up with the number of HeaderLabels in the TestSection. 
@@ -297,32 +1106,43 @@ pub struct Competency {
 ///          subtext: "(Week 1)"
 ///          scores: 
 ///            - [8, 6, 0, 0, 0]
-fn validate_score_labels(graded_items: &[Competency], score_labels: &[ScoringCategory], test_name: &String) -> Result<(), String> {
+fn validate_score_labels(graded_items: &[Competency], score_labels: &[ScoringCategory], test_name: &str, table_index: usize, section_index: usize) -> Vec<DefinitionError> {
+    let mut errors = Vec::new();
     
     // Check to ensure that each item has one list of scores per header label.
     let expected_number_of_scores_lists = score_labels.len();
-    for item in graded_items {
+    for (item_index, item) in graded_items.iter().enumerate() {
         if item.scores.len() != expected_number_of_scores_lists {
-            return Err(format!(
-                "On the test named '{},' graded item '{}' has a number of lists of scores ({}) that does not correspond to the number of scoring categories. ({})",
-                test_name, item.name, item.scores.len(), score_labels.len()
-            ))
+            errors.push(DefinitionError::ScoresListCountMismatch {
+                test_name: test_name.to_string(),
+                table_index,
+                section_index,
+                competency_index: item_index,
+                competency_name: item.name.clone(),
+                scores_list_count: item.scores.len(),
+                scoring_category_count: expected_number_of_scores_lists,
+            });
+            continue;
         }
-    }
 
-    // Check to ensure that each item's list of scores is the same length as the corresponding list of header labels.
-    for (i, score_label) in score_labels.into_iter().enumerate() {
-        let expected_number_of_scores = score_label.values.len();
-        for item in graded_items {
+        // Check to ensure that each item's list of scores is the same length as the corresponding list of header labels.
+        for (i, score_label) in score_labels.iter().enumerate() {
+            let expected_number_of_scores = score_label.values.len();
             if item.scores[i].len() != expected_number_of_scores {
-                return Err(format!(
-                    "On the test named '{},' the graded item named '{}' has a score list at index {} of length {} that does not correspond to the number of score labels ({}) for the scoring category at index {}.",
-                    test_name, item.name, i, item.scores[i].len(), expected_number_of_scores, i
-                ))
+                errors.push(DefinitionError::ScoresLengthMismatch {
+                    test_name: test_name.to_string(),
+                    table_index,
+                    section_index,
+                    competency_index: item_index,
+                    competency_name: item.name.clone(),
+                    scoring_category_index: i,
+                    scores_length: item.scores[i].len(),
+                    scoring_category_value_count: expected_number_of_scores,
+                });
             }
         }
     }
-    Ok(())
+    errors
 }
 
 /// Checks to ensure that all of the failing score labels for the graded items correspond to actual header values.
@@ -342,20 +1162,23 @@ fn validate_score_labels(graded_items: &[Competency], score_labels: &[ScoringCat
 ///   failing_score_labels: 
 ///     - name: "Footwork"
 ///       values: ["Nope"]
-fn validate_failing_score_labels(graded_items: &[Competency], score_labels: &[ScoringCategory], test_name: &String) -> Result<(), String> {
+fn validate_failing_score_labels(graded_items: &[Competency], score_labels: &[ScoringCategory], test_name: &str, table_index: usize, section_index: usize) -> Vec<DefinitionError> {
+    let mut errors = Vec::new();
 
     // Create a hashmap of the header labels so that we can correspond failing score labels on the graded item to the true header labels
     let mut score_label_hm: HashMap<String, Vec<String>> = HashMap::new();
     for score_label in score_labels {
-        if let Some(duplicate_name) = score_label_hm.insert(score_label.name.clone(), score_label.values.clone()) {
-            return Err(format!(
-                "On the test named '{},' the scoring category name '{:#?}' is not unique within its section.",
-                test_name, duplicate_name
-            ))
+        if score_label_hm.insert(score_label.name.clone(), score_label.values.clone()).is_some() {
+            errors.push(DefinitionError::DuplicateScoringCategory {
+                test_name: test_name.to_string(),
+                table_index,
+                section_index,
+                scoring_category_name: score_label.name.clone(),
+            });
         };
     }
 
-    for item in graded_items {
+    for (item_index, item) in graded_items.iter().enumerate() {
         match &item.failing_score_labels {
             // Has failing score labels
             Some(labels) => for label in labels {
@@ -363,45 +1186,187 @@ fn validate_failing_score_labels(graded_items: &[Competency], score_labels: &[Sc
                 match score_label_hm.get(&label.scoring_category_name) {
                     // The failing score label corresponds to a section (ie, the footwork section)
                     Some(valid_failing_score_labels) => for failing_score_label in &label.values {
-                        if !valid_failing_score_labels.contains(&failing_score_label) {
-                            return Err(format!(
-                                "On the test named '{},' the graded item named '{}' has a failing score label '{}' that does not correspond to any of the score labels ({:?}) in the scoring category named '{}'.",
-                                test_name, item.name, failing_score_label, valid_failing_score_labels, label.scoring_category_name
-                            ))
+                        if !valid_failing_score_labels.contains(failing_score_label) {
+                            errors.push(DefinitionError::UnknownFailingScoreLabel(Box::new(UnknownFailingScoreLabel {
+                                test_name: test_name.to_string(),
+                                table_index,
+                                section_index,
+                                competency_index: item_index,
+                                competency_name: item.name.clone(),
+                                scoring_category_name: label.scoring_category_name.clone(),
+                                failing_value: failing_score_label.clone(),
+                                valid_values: valid_failing_score_labels.clone(),
+                            })));
                         }
                     },
                     // The failing score label does not correspond to a valid section
-                    None => return Err(format!(
-                        "On the test named '{},' the graded item named '{}' has failing score labels '{:#?}' under the scoring category '{}' that does not correspond to any of the valid scoring category labels ({:?}).",
-                        test_name, item.name, label.values, label.scoring_category_name, score_label_hm.keys()
-                    ))
+                    None => errors.push(DefinitionError::UnknownFailingScoringCategory(Box::new(UnknownFailingScoringCategory {
+                        test_name: test_name.to_string(),
+                        table_index,
+                        section_index,
+                        competency_index: item_index,
+                        competency_name: item.name.clone(),
+                        scoring_category_name: label.scoring_category_name.clone(),
+                        failing_values: label.values.clone(),
+                        valid_scoring_categories: score_labels.iter().map(|score_label| score_label.name.clone()).collect(),
+                    }))),
                 }
             }
             // Does not have failing score labels
             None => continue
         }
     }
-    Ok(())
+    errors
+}
+
+/// Ensures a grading rule can be checked and explained: its message can be shown as a failure explanation, it compares its
+/// count one way, and everything it counts exists in the test. Scoring categories and labels only need to exist in one of
+/// the sections the rule counts, since sections don't have to share scoring categories.
+fn validate_rule(test: &Test, rule: &GradingRule, rule_index: usize) -> Vec<DefinitionError> {
+    let test_name = test.metadata.test_name.clone();
+    let mut errors = Vec::new();
+
+    if rule.message.trim().is_empty() || rule.message.contains("-.-.") {
+        errors.push(DefinitionError::InvalidRuleMessage { test_name: test_name.clone(), rule_index, message: rule.message.clone() });
+    }
+
+    if rule.fail_if.at_least.is_some() == rule.fail_if.fewer_than.is_some() {
+        errors.push(DefinitionError::InvalidRuleComparison { test_name: test_name.clone(), rule_index });
+    }
+
+    match &rule.fail_if.count {
+        RuleCount::Labels { section_name, competency_names, scoring_category_name, values } => {
+            let sections: Vec<&TestSection> = test.tables.iter()
+                .flat_map(|table| table.sections.iter())
+                .filter(|section| section_name.as_ref().is_none_or(|section_name| *section_name == section.name))
+                .collect();
+
+            if let Some(section_name) = section_name.as_ref().filter(|_| sections.is_empty()) {
+                errors.push(DefinitionError::UnknownRuleSection { test_name: test_name.clone(), rule_index, section_name: section_name.clone() });
+                return errors;
+            }
+
+            for competency_name in competency_names.iter().flatten() {
+                if !sections.iter().any(|section| section.competencies.iter().any(|competency| competency.name == *competency_name)) {
+                    errors.push(DefinitionError::UnknownRuleCompetency { test_name: test_name.clone(), rule_index, competency_name: competency_name.clone() });
+                }
+            }
+
+            let scoring_categories: Vec<&ScoringCategory> = sections.iter()
+                .flat_map(|section| section.scoring_categories.iter())
+                .filter(|scoring_category| scoring_category.name == *scoring_category_name)
+                .collect();
+
+            if scoring_categories.is_empty() {
+                errors.push(DefinitionError::UnknownRuleScoringCategory { test_name: test_name.clone(), rule_index, scoring_category_name: scoring_category_name.clone() });
+                return errors;
+            }
+
+            for value in values {
+                if !scoring_categories.iter().any(|scoring_category| scoring_category.values.contains(value)) {
+                    errors.push(DefinitionError::UnknownRuleScoreLabel {
+                        test_name: test_name.clone(),
+                        rule_index,
+                        scoring_category_name: scoring_category_name.clone(),
+                        value: value.clone(),
+                    });
+                }
+            }
+        }
+        RuleCount::BonusItems { names } => {
+            for bonus_item_name in names.iter().flatten() {
+                if !test.bonus_items.iter().flatten().any(|bonus_item| bonus_item.name == *bonus_item_name) {
+                    errors.push(DefinitionError::UnknownRuleBonusItem { test_name: test_name.clone(), rule_index, bonus_item_name: bonus_item_name.clone() });
+                }
+            }
+        }
+    }
+    errors
+}
+
+/// Ensures a minimum percent is written as a fraction, since a minimum of 60 instead of 0.6 would fail every testee.
+fn validate_minimum_percent(minimum_percent: f32, test_name: &str, table_index: Option<usize>, section_index: Option<usize>) -> Option<DefinitionError> {
+    (!(0.0..=1.0).contains(&minimum_percent)).then(|| DefinitionError::InvalidMinimumPercent {
+        test_name: test_name.to_string(),
+        table_index,
+        section_index,
+        minimum_percent,
+    })
+}
+
+/// Checks a section's or table's score against its minimum percent. A failure is explained as the scope ("section" or
+/// "table"), its name, the score out of its max score, and the achieved and minimum percents. Parts without any points to
+/// score can't fail.
+fn threshold_failure(scope: &str, name: &str, achieved_score: Points, max_score: Points, minimum_percent: Option<f32>) -> Option<Vec<String>> {
+    let minimum_percent = minimum_percent?;
+    if max_score == Points::ZERO {
+        return None;
+    }
+
+    (!achieved_score.meets_minimum(max_score, minimum_percent)).then(|| vec![
+        scope.to_string(),
+        name.to_string(),
+        format!("{} / {}", achieved_score, max_score),
+        format!("{:.1}", achieved_score.fraction_of(max_score) * 100.0),
+        format!("{:.1}", minimum_percent * 100.0),
+    ])
+}
+
+/// Ensures that every score in the section is a whole or half point and that the section's and competencies' weights are
+/// positive hundredths, so that weighting the scores never has to round.
+fn validate_points(section: &TestSection, test_name: &str, table_index: usize, section_index: usize) -> Vec<DefinitionError> {
+    let mut errors = Vec::new();
+    let is_valid_weight = |weight: Points| weight > Points::ZERO && weight.is_multiple_of(Points::HUNDREDTH);
+
+    if let Some(weight) = section.weight.filter(|weight| !is_valid_weight(*weight)) {
+        errors.push(DefinitionError::InvalidWeight { test_name: test_name.to_string(), table_index, section_index, competency_index: None, weight });
+    }
+
+    for (item_index, item) in section.competencies.iter().enumerate() {
+        if let Some(weight) = item.weight.filter(|weight| !is_valid_weight(*weight)) {
+            errors.push(DefinitionError::InvalidWeight { test_name: test_name.to_string(), table_index, section_index, competency_index: Some(item_index), weight });
+        }
+
+        if let Some(score) = item.scores.iter().flatten().find(|score| !score.is_multiple_of(Points::HALF)) {
+            errors.push(DefinitionError::UnsupportedScore {
+                test_name: test_name.to_string(),
+                table_index,
+                section_index,
+                competency_index: item_index,
+                competency_name: item.name.clone(),
+                score: *score,
+            });
+        }
+    }
+    errors
 }
 
 /// Ensures that if there is more than one scoring category for an competency (which can be checked by checking the length of the
 /// vec of scores) that the item does not have an antithesis. 
-fn validate_antitheses(graded_items: &[Competency], test_name: &String) -> Result<(), String> {
-    for item in graded_items {
+fn validate_antitheses(graded_items: &[Competency], test_name: &str, table_index: usize, section_index: usize) -> Vec<DefinitionError> {
+    let mut errors = Vec::new();
+    for (item_index, item) in graded_items.iter().enumerate() {
         match &item.antithesis {
-            Some(antithesis) => if item.scores.len() > 1 {return Err(format!(
-                "On the test named '{},' the competency named '{}' has an antithesis {} which is not supported when there is more than one scoring category for that item.",
-                test_name, item.name, antithesis
-            ))}
+            Some(antithesis) => if item.scores.len() > 1 {
+                errors.push(DefinitionError::UnsupportedAntithesis {
+                    test_name: test_name.to_string(),
+                    table_index,
+                    section_index,
+                    competency_index: item_index,
+                    competency_name: item.name.clone(),
+                    antithesis: antithesis.clone(),
+                    scoring_category_count: item.scores.len(),
+                });
+            }
             None => continue
         }
     }
-    Ok(())
+    errors
 }
 
 
 
-#[derive(Debug, Clone, sqlx::FromRow, Serialize, Deserialize)]
+#[derive(Debug, Clone, sqlx::FromRow, Serialize, Deserialize, ToSchema)]
 pub struct Testee {
     pub id: Option<Uuid>,  
     pub first_name: String,
@@ -409,7 +1374,7 @@ pub struct Testee {
     pub email: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 /// Passing may be failed even if the achieved percent is above the minimum percent if a competency with a failing score label was graded as failing. 
 pub struct FullTestSummary {
     pub test_id: Uuid,
@@ -419,18 +1384,18 @@ pub struct FullTestSummary {
     pub grade_summary: TestGradeSummary,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 /// Passing may be failed even if the achieved percent is above the minimum percent if a competency with a failing score label was graded as failing. 
 pub struct TestGradeSummary {
-    pub achieved_score: i32,
+    pub achieved_score: Points,
     pub achieved_percent: f32,
-    pub max_score: i32,
+    pub max_score: Points,
     pub minimum_percent: f32,
     pub is_passing: bool,
     pub failure_explanation: Option<Vec<String>>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, FromRow)]
+#[derive(Debug, Serialize, Deserialize, Clone, FromRow, ToSchema)]
 /// Used to search who passed/failed in the get_tests_by_status function
 pub struct TestListItem {
     pub test_id: Uuid,
@@ -443,7 +1408,15 @@ pub struct TestListItem {
     pub testee_email: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+/// A testee waiting in the queue along with the test definition they signed up for
+pub struct QueueItem {
+    pub testee: Testee,
+    pub test_definition_id: Uuid,
+    pub test_name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 pub struct Proctor {
     pub id: Uuid,
     pub first_name: String,
@@ -453,6 +1426,10 @@ pub struct Proctor {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SMTPConfig {
     pub server_host: String,
+    /// Left blank to use the standard port for the connection type
+    pub server_port: Option<u16>,
+    /// Only turned off for a local SMTP sink, like Mailpit, during development. The login isn't sent without TLS.
+    pub use_tls: bool,
     pub user_login: String,
     pub user_password: String,
     pub user_email: String,
@@ -492,13 +1469,26 @@ impl SMTPConfig {
                 None
             }
             (host, user, password, email) => {
+                // These were added after the other SMTP settings, so they can be left out as well as left blank
+                let server_port = std::env::var("SMTP_SERVER_PORT").unwrap_or_default();
+                let server_port = (!server_port.trim().is_empty())
+                    .then(|| server_port.trim().parse::<u16>().expect("SMTP_SERVER_PORT should be a port number or left blank."));
+                let use_tls = std::env::var("SMTP_TLS").unwrap_or_default();
+                let use_tls = use_tls.trim().is_empty() || use_tls.trim().to_lowercase().parse::<bool>().expect("SMTP_TLS should be TRUE, FALSE or left blank.");
+
                 println!(
                     "\nEmail functionality is enabled with the following settings:\n\tServer: {}\n\tUsername: {}\n\tEmail: {}\n",
                     host, user, email
                 );
 
+                if !use_tls {
+                    println!("\tTLS is off, so email is sent unencrypted and without logging in. Only use this with a local SMTP sink.\n");
+                }
+
                 Some(SMTPConfig {
                     server_host: host.to_string(),
+                    server_port,
+                    use_tls,
                     user_login: user.to_string(),
                     user_password: password.to_string(),
                     user_email: email.to_string(),
@@ -506,10 +1496,32 @@ impl SMTPConfig {
             }
         }
     }
+
+    /// Connects over TLS with the login, or in plain text without it when TLS is turned off
+    pub fn build_transport(&self) -> Result<AsyncSmtpTransport<Tokio1Executor>, lettre::transport::smtp::Error> {
+        let builder = match self.use_tls {
+            true => AsyncSmtpTransport::<Tokio1Executor>::relay(&self.server_host)?
+                .credentials(Credentials::new(self.user_login.clone(), self.user_password.clone())),
+            false => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&self.server_host),
+        };
+        let builder = match self.server_port {
+            Some(port) => builder.port(port),
+            None => builder,
+        };
+
+        Ok(builder
+            .pool_config(
+                PoolConfig::new()
+                    .max_size(10)
+                    .idle_timeout(Duration::from_secs(60))
+            )
+            .build())
+    }
 }
 
 
 
+#[cfg(test)]
 mod tests {
     use super::*;
     use crate::exam::{handlers::tests::setup_valid_test_str, handlers::parse_test_definition_from_str};
@@ -572,31 +1584,30 @@ mod tests {
         // Use your eyeballs on the test definition and make sure the max score is properly calculated
 
         // Remember bonus points don't count towards the max score.
-        assert_eq!(tests.tests[0].calculate_max_score(), 4);
+        assert_eq!(tests.tests[0].calculate_max_score(), Points::whole(4));
     }
 
     #[test]
-    #[should_panic]
     fn test_test_validation_incorrect_max_score() {
         let mut tests = parse_test_definition_from_str(
             &setup_valid_test_str()
         ).expect("If this fails then the prior test also failed");
         
         // Edit the max score of the first test to be incorrect
-        tests.tests[0].metadata.max_score = -1;
+        tests.tests[0].metadata.max_score = Points::whole(-1);
         
         // Validate the test and hope it fails
-        let result = tests.tests[0].validate();
-        
-        if result.is_err() {
-            dbg!(&result);
-            panic!();
-        }
+        let errors = tests.tests[0].validate().expect_err("An incorrect max score should fail validation");
+
+        assert_eq!(errors, vec![DefinitionError::IncorrectMaxScore {
+            test_name: tests.tests[0].metadata.test_name.clone(),
+            max_score: Points::whole(-1),
+            calculated_max_score: Points::whole(4),
+        }]);
     }
 
-    /// If the names of the failing score labels do not match the scoring categories for that section. Panic
+    /// If the names of the failing score labels do not match the scoring categories for that section, validation should fail
     #[test]
-    #[should_panic(expected = "Test successful")]
     fn test_test_validation_invalid_score_labels() {
         let mut tests = parse_test_definition_from_str(
             &setup_valid_test_str()
@@ -606,13 +1617,67 @@ mod tests {
         tests.tests[0].tables[0].sections[0].competencies[0].failing_score_labels.as_mut().unwrap()[0].scoring_category_name = "a;slfkal;".to_string();
 
         // Validate the test and hope it fails
-        let result = tests.tests[0].validate();
-        
-        if result.is_err() {
-            dbg!(&result);
-            panic!("Test successful");
+        let errors = tests.tests[0].validate().expect_err("Unknown failing score labels should fail validation");
+
+        assert_eq!(errors.len(), 1);
+        match &errors[0] {
+            DefinitionError::UnknownFailingScoringCategory(error) => {
+                assert_eq!((error.table_index, error.section_index, error.competency_index), (0, 0, 0));
+                assert_eq!(error.scoring_category_name, "a;slfkal;");
+            },
+            other => panic!("Expected an UnknownFailingScoringCategory error, got {:?}", other),
         }
+    }
+
+    /// Every problem should be reported, not just the first one found
+    #[test]
+    fn test_test_validation_collects_all_errors() {
+        let mut tests = parse_test_definition_from_str(
+            &setup_valid_test_str()
+        ).expect("If this fails then the prior test also failed");
+
+        tests.tests[0].metadata.max_score = Points::whole(-1);
+        tests.tests[0].tables[0].sections[0].competencies[0].failing_score_labels.as_mut().unwrap()[0].scoring_category_name = "a;slfkal;".to_string();
+        tests.tests.push(tests.tests[0].clone());
+
+        let errors = tests.validate().expect_err("The test definitions should fail validation");
+
+        assert_eq!(errors.len(), 5);
+        assert!(errors.contains(&DefinitionError::DuplicateTestName { test_name: tests.tests[0].metadata.test_name.clone() }));
+        assert_eq!(errors.iter().filter(|e| matches!(e, DefinitionError::IncorrectMaxScore { .. })).count(), 2);
+        assert_eq!(errors.iter().filter(|e| matches!(e, DefinitionError::UnknownFailingScoringCategory(_))).count(), 2);
+    }
 
+    /// Section and table thresholds are checked like the test's own max score and minimum percent
+    #[test]
+    fn test_test_validation_section_and_table_thresholds() {
+        let mut tests = parse_test_definition_from_str(
+            &setup_valid_test_str()
+        ).expect("If this fails then the prior test also failed");
+
+        tests.tests[0].tables[0].max_score = Some(Points::whole(4));
+        tests.tests[0].tables[0].minimum_percent = Some(0.5);
+        tests.tests[0].tables[0].sections[0].max_score = Some(Points::whole(5));
+        tests.tests[0].tables[0].sections[0].minimum_percent = Some(60.0);
+
+        let errors = tests.tests[0].validate().expect_err("Incorrect section thresholds should fail validation");
+
+        assert_eq!(errors, vec![
+            DefinitionError::InvalidMinimumPercent {
+                test_name: tests.tests[0].metadata.test_name.clone(),
+                table_index: Some(0),
+                section_index: Some(0),
+                minimum_percent: 60.0,
+            },
+            DefinitionError::IncorrectSectionMaxScore {
+                test_name: tests.tests[0].metadata.test_name.clone(),
+                table_index: 0,
+                section_index: 0,
+                section_name: tests.tests[0].tables[0].sections[0].name.clone(),
+                max_score: Points::whole(5),
+                calculated_max_score: Points::whole(4),
+            },
+        ]);
     }
 
     #[test]
@@ -648,9 +1713,142 @@ mod tests {
             panic!();
         },
           Ok((grade, is_passing, _failure_explanation)) => {
-            assert_eq!(grade, 7);
+            assert_eq!(grade, Points::whole(7));
             assert!(is_passing);
           },
         }
     }
-}
\ No newline at end of file
+
+    /// A section below its own minimum fails the test even when the test's overall percent is passing
+    #[test]
+    fn test_test_grading_section_minimum_percent() {
+        let mut tests = parse_test_definition_from_str(
+            &setup_valid_graded_test_str()
+        ).expect("If this fails then the graded test definition is incorrect.");
+
+        tests.tests[0].tables[0].minimum_percent = Some(0.5);
+        tests.tests[0].tables[0].sections[0].minimum_percent = Some(0.8);
+        tests.tests[0].validate().expect("Thresholds between 0 and 1 are valid");
+
+        let (grade, is_passing, failure_explanation) = tests.tests[0].grade().expect("The graded test definition is correct");
+
+        assert_eq!(grade, Points::whole(7));
+        assert!(!is_passing);
+        assert_eq!(failure_explanation, Some(vec!["section-.-.Pattern Scoring-.-.3 / 4-.-.75.0-.-.80.0".to_string()]));
+    }
+
+    /// Weights multiply the scores exactly, and the max score in the metadata has to account for them
+    #[test]
+    fn test_test_grading_weights() {
+        let mut tests = parse_test_definition_from_str(
+            &setup_valid_graded_test_str()
+        ).expect("If this fails then the graded test definition is incorrect.");
+
+        let section = &mut tests.tests[0].tables[0].sections[0];
+        section.weight = Some("2".parse().unwrap());
+        section.competencies[0].weight = Some("1.5".parse().unwrap());
+        section.competencies[0].scores[1] = vec!["1.5".parse().unwrap(), Points::ZERO];
+        section.competencies[0].achieved_scores = Some(vec![Points::whole(2), "1.5".parse().unwrap()]);
+        tests.tests[0].metadata.max_score = Points::whole(4);
+
+        let errors = tests.tests[0].validate().expect_err("The max score doesn't account for the weights");
+        assert_eq!(errors, vec![DefinitionError::IncorrectMaxScore {
+            test_name: tests.tests[0].metadata.test_name.clone(),
+            max_score: Points::whole(4),
+            calculated_max_score: "13.5".parse().unwrap(),
+        }]);
+
+        tests.tests[0].metadata.max_score = "13.5".parse().unwrap();
+        tests.tests[0].validate().expect("Half points and weights with two decimal places are valid");
+
+        // (2 + 1.5) * 1.5 * 2 points from the competency and 4 from the bonus item
+        let (grade, is_passing, _failure_explanation) = tests.tests[0].grade().expect("The graded test definition is correct");
+        assert_eq!(grade, "14.5".parse().unwrap());
+        assert!(is_passing);
+    }
+
+    #[test]
+    fn test_test_validation_unsupported_points() {
+        let mut tests = parse_test_definition_from_str(
+            &setup_valid_test_str()
+        ).expect("If this fails then the prior test also failed");
+
+        let section = &mut tests.tests[0].tables[0].sections[0];
+        section.weight = Some(Points::ZERO);
+        section.competencies[0].weight = Some("0.125".parse().unwrap());
+        section.competencies[0].scores[0][0] = "3.25".parse().unwrap();
+
+        let errors = tests.tests[0].validate().expect_err("Quarter points and weights of 0 should fail validation");
+
+        assert_eq!(errors.iter().filter(|e| matches!(e, DefinitionError::InvalidWeight { .. })).count(), 2);
+        assert!(errors.iter().any(|e| matches!(e, DefinitionError::UnsupportedScore { competency_index: 0, .. })));
+    }
+
+    fn rule(message: &str, count: RuleCount, at_least: Option<usize>, fewer_than: Option<usize>) -> GradingRule {
+        GradingRule { message: message.to_string(), fail_if: RuleCondition { count, at_least, fewer_than } }
+    }
+
+    /// Rules fail the test with their own message when their condition is met
+    #[test]
+    fn test_test_grading_rules() {
+        let mut tests = parse_test_definition_from_str(
+            &setup_valid_graded_test_str()
+        ).expect("If this fails then the graded test definition is incorrect.");
+
+        let variation_footwork = RuleCount::Labels {
+            section_name: Some("Pattern Scoring".to_string()),
+            competency_names: None,
+            scoring_category_name: "Footwork".to_string(),
+            values: vec!["Variation?".to_string(), "Right Concept".to_string()],
+        };
+        tests.tests[0].rules = Some(vec![
+            rule("Too many patterns had the wrong footwork.", variation_footwork.clone(), Some(2), None),
+            rule("At least 2 bonus items must be earned.", RuleCount::BonusItems { names: None }, None, Some(2)),
+            rule("The swung triple must be earned.", RuleCount::BonusItems { names: Some(vec!["Swung Triple".to_string()]) }, None, Some(1)),
+        ]);
+        tests.tests[0].validate().expect("The rules only refer to parts of the test that exist");
+
+        let (_grade, is_passing, failure_explanation) = tests.tests[0].grade().expect("The graded test definition is correct");
+        assert!(!is_passing);
+        assert_eq!(failure_explanation, Some(vec!["At least 2 bonus items must be earned.".to_string()]));
+
+        tests.tests[0].rules = Some(vec![rule("One wrong footwork fails.", variation_footwork, Some(1), None)]);
+        let (_grade, is_passing, failure_explanation) = tests.tests[0].grade().expect("The graded test definition is correct");
+        assert!(!is_passing);
+        assert_eq!(failure_explanation, Some(vec!["One wrong footwork fails.".to_string()]));
+    }
+
+    #[test]
+    fn test_test_validation_rules() {
+        let mut tests = parse_test_definition_from_str(
+            &setup_valid_graded_test_str()
+        ).expect("If this fails then the graded test definition is incorrect.");
+
+        tests.tests[0].rules = Some(vec![
+            rule("", RuleCount::BonusItems { names: Some(vec!["Dips".to_string()]) }, Some(1), Some(2)),
+            rule("Unknown label", RuleCount::Labels {
+                section_name: None,
+                competency_names: Some(vec!["Starter Step".to_string()]),
+                scoring_category_name: "Timing".to_string(),
+                values: vec!["Late".to_string()],
+            }, Some(1), None),
+            rule("Unknown section", RuleCount::Labels {
+                section_name: Some("Technique".to_string()),
+                competency_names: None,
+                scoring_category_name: "Timing".to_string(),
+                values: vec![],
+            }, Some(1), None),
+        ]);
+
+        let test_name = tests.tests[0].metadata.test_name.clone();
+        let errors = tests.tests[0].validate().expect_err("The rules refer to parts of the test that don't exist");
+        assert_eq!(errors, vec![
+            DefinitionError::InvalidRuleMessage { test_name: test_name.clone(), rule_index: 0, message: String::new() },
+            DefinitionError::InvalidRuleComparison { test_name: test_name.clone(), rule_index: 0 },
+            DefinitionError::UnknownRuleBonusItem { test_name: test_name.clone(), rule_index: 0, bonus_item_name: "Dips".to_string() },
+            DefinitionError::UnknownRuleScoreLabel { test_name: test_name.clone(), rule_index: 1, scoring_category_name: "Timing".to_string(), value: "Late".to_string() },
+            DefinitionError::UnknownRuleSection { test_name: test_name.clone(), rule_index: 2, section_name: "Technique".to_string() },
+        ]);
+        assert_eq!(errors[3].location().rule_index, Some(1));
+    }
+}
@@ -0,0 +1,484 @@
+use printpdf::{path::PaintMode, BuiltinFont, Color, IndirectFontRef, Line, Mm, PdfDocument, PdfDocumentReference, PdfLayerReference, Point, Rect, Rgb};
+use serde::Deserialize;
+
+use crate::exam::export::describe_failure_explanation;
+use crate::exam::handlers::TestError;
+use crate::exam::models::{Test, TestSection};
+use crate::exam::points::Points;
+
+
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+// PDF Options
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+
+/// A certificate is a single landscape page to print and frame, and is only made for passing tests. The score report
+/// lists every graded competency and why a test failed.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PdfDocumentKind {
+    Certificate,
+    #[default]
+    Report,
+}
+
+impl PdfDocumentKind {
+    pub fn file_stem(&self) -> &'static str {
+        match self {
+            PdfDocumentKind::Certificate => "certificate",
+            PdfDocumentKind::Report => "score-report",
+        }
+    }
+
+    pub fn render(&self, test: &Test) -> Result<Vec<u8>, TestError> {
+        match self {
+            PdfDocumentKind::Certificate => render_certificate(test),
+            PdfDocumentKind::Report => render_score_report(test),
+        }
+    }
+}
+
+/// The name of a downloaded or attached PDF, like `score-report-standard-leader-test-jane-doe.pdf`
+pub fn pdf_file_name(kind: PdfDocumentKind, test: &Test) -> String {
+    let testee_name = test.metadata.testee.as_ref()
+        .map(|testee| format!("{} {}", testee.first_name, testee.last_name))
+        .unwrap_or_default();
+
+    let slug = format!("{} {}", test.metadata.test_name, testee_name)
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { ' ' })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join("-");
+
+    format!("{}-{}.pdf", kind.file_stem(), slug)
+}
+
+
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+// Page Writer
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+
+const LETTER_SHORT_SIDE: f32 = 215.9;
+const LETTER_LONG_SIDE: f32 = 279.4;
+const MARGIN: f32 = 20.0;
+const POINTS_PER_MM: f32 = 2.834_646;
+
+/// The built in PDF fonts don't need to be embedded, but their text isn't measured by printpdf. This estimates the
+/// width of Helvetica text closely enough to center and wrap it. Characters outside of Windows-1252 aren't drawn by the
+/// built in fonts.
+fn text_width(text: &str, font_size: f32, bold: bool) -> f32 {
+    let em_widths: f32 = text.chars()
+        .map(|c| match c {
+            'i' | 'j' | 'l' | '.' | ',' | '\'' | '|' | '!' | ':' | ';' => 0.24,
+            ' ' | 'f' | 't' | 'r' | 'I' | '(' | ')' | '-' | '/' => 0.32,
+            'm' | 'w' | 'M' | 'W' => 0.85,
+            c if c.is_ascii_uppercase() => 0.68,
+            c if c.is_ascii_digit() => 0.556,
+            _ => 0.53,
+        })
+        .sum();
+
+    let bold_factor = if bold { 1.07 } else { 1.0 };
+    em_widths * font_size * bold_factor / POINTS_PER_MM
+}
+
+/// Splits text into lines that fit within the width, breaking between words. Words longer than a line get a line of
+/// their own.
+fn wrap_text(text: &str, font_size: f32, bold: bool, width: f32) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    let mut current = String::new();
+
+    for word in text.split_whitespace() {
+        let candidate = match current.is_empty() {
+            true => word.to_string(),
+            false => format!("{} {}", current, word),
+        };
+
+        if current.is_empty() || text_width(&candidate, font_size, bold) <= width {
+            current = candidate;
+        } else {
+            lines.push(std::mem::replace(&mut current, word.to_string()));
+        }
+    }
+
+    if !current.is_empty() || lines.is_empty() {
+        lines.push(current);
+    }
+
+    lines
+}
+
+/// The height of a line of text in mm
+fn line_height(font_size: f32) -> f32 {
+    font_size * 1.35 / POINTS_PER_MM
+}
+
+fn gray(level: f32) -> Color {
+    Color::Rgb(Rgb::new(level, level, level, None))
+}
+
+fn green() -> Color {
+    Color::Rgb(Rgb::new(0.08, 0.5, 0.24, None))
+}
+
+fn red() -> Color {
+    Color::Rgb(Rgb::new(0.75, 0.11, 0.11, None))
+}
+
+/// Writes top to bottom, starting a new page when the next block doesn't fit. Coordinates are in mm from the bottom
+/// left corner of the page, like printpdf's.
+struct PageWriter {
+    document: PdfDocumentReference,
+    layer: PdfLayerReference,
+    regular: IndirectFontRef,
+    bold: IndirectFontRef,
+    page_width: f32,
+    page_height: f32,
+    /// The baseline of the next line
+    cursor: f32,
+}
+
+impl PageWriter {
+    fn new(title: &str, page_width: f32, page_height: f32) -> Result<PageWriter, TestError> {
+        let (document, page, layer) = PdfDocument::new(title, Mm(page_width), Mm(page_height), "Layer 1");
+        let regular = document.add_builtin_font(BuiltinFont::Helvetica).map_err(pdf_error)?;
+        let bold = document.add_builtin_font(BuiltinFont::HelveticaBold).map_err(pdf_error)?;
+        let layer = document.get_page(page).get_layer(layer);
+
+        Ok(PageWriter { document, layer, regular, bold, page_width, page_height, cursor: page_height - MARGIN })
+    }
+
+    fn content_width(&self) -> f32 {
+        self.page_width - 2.0 * MARGIN
+    }
+
+    /// Starts a new page unless the height fits above the bottom margin
+    fn ensure_space(&mut self, height: f32) {
+        if self.cursor - height < MARGIN {
+            let (page, layer) = self.document.add_page(Mm(self.page_width), Mm(self.page_height), "Layer 1");
+            self.layer = self.document.get_page(page).get_layer(layer);
+            self.cursor = self.page_height - MARGIN;
+        }
+    }
+
+    fn skip(&mut self, height: f32) {
+        self.cursor -= height;
+    }
+
+    fn text_at(&self, text: &str, font_size: f32, bold: bool, x: f32, y: f32, color: Color) {
+        let font = if bold { &self.bold } else { &self.regular };
+        self.layer.set_fill_color(color);
+        self.layer.use_text(text, font_size, Mm(x), Mm(y), font);
+    }
+
+    /// Writes wrapped text at the left margin, or centered, and moves the cursor below it
+    fn paragraph(&mut self, text: &str, font_size: f32, bold: bool, centered: bool, color: Color) {
+        for line in wrap_text(text, font_size, bold, self.content_width()) {
+            let height = line_height(font_size);
+            self.ensure_space(height);
+            self.cursor -= height;
+            let x = match centered {
+                true => (self.page_width - text_width(&line, font_size, bold)) / 2.0,
+                false => MARGIN,
+            };
+            self.text_at(&line, font_size, bold, x, self.cursor, color.clone());
+        }
+    }
+
+    fn rule(&mut self, thickness: f32, color: Color) {
+        self.horizontal_line(MARGIN, self.page_width - MARGIN, self.cursor, thickness, color);
+    }
+
+    fn horizontal_line(&self, from_x: f32, to_x: f32, y: f32, thickness: f32, color: Color) {
+        self.layer.set_outline_color(color);
+        self.layer.set_outline_thickness(thickness);
+        self.layer.add_line(Line {
+            points: vec![(Point::new(Mm(from_x), Mm(y)), false), (Point::new(Mm(to_x), Mm(y)), false)],
+            is_closed: false,
+        });
+    }
+
+    fn border(&self, inset: f32, thickness: f32, color: Color) {
+        self.layer.set_outline_color(color);
+        self.layer.set_outline_thickness(thickness);
+        self.layer.add_rect(
+            Rect::new(Mm(inset), Mm(inset), Mm(self.page_width - inset), Mm(self.page_height - inset)).with_mode(PaintMode::Stroke)
+        );
+    }
+
+    fn finish(self) -> Result<Vec<u8>, TestError> {
+        self.document.save_to_bytes().map_err(pdf_error)
+    }
+}
+
+fn pdf_error(error: printpdf::Error) -> TestError {
+    TestError::InternalServerError(format!("Couldn't create the PDF: {}", error))
+}
+
+
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+// Certificate
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+
+/// Renders a landscape certificate for a passing graded test.
+pub fn render_certificate(test: &Test) -> Result<Vec<u8>, TestError> {
+    let summary = test.full_summary().map_err(TestError::InternalServerError)?;
+    if !summary.grade_summary.is_passing {
+        return Err(TestError::InternalServerError("Certificates are only made for passing tests.".to_string()));
+    }
+    let testee = test.metadata.testee.as_ref()
+        .ok_or_else(|| TestError::InternalServerError("Graded tests must have a testee to make a certificate.".to_string()))?;
+
+    let mut writer = PageWriter::new(&format!("{} Certificate", test.metadata.test_name), LETTER_LONG_SIDE, LETTER_SHORT_SIDE)?;
+
+    writer.border(10.0, 3.0, gray(0.2));
+    writer.border(14.0, 1.0, gray(0.5));
+
+    writer.skip(18.0);
+    writer.paragraph("Certificate of Achievement", 34.0, true, true, gray(0.1));
+    writer.skip(12.0);
+    writer.paragraph("This certifies that", 16.0, false, true, gray(0.35));
+    writer.skip(6.0);
+    writer.paragraph(&format!("{} {}", testee.first_name, testee.last_name), 30.0, true, true, gray(0.1));
+    let name_rule_width = 140.0;
+    writer.skip(3.0);
+    writer.horizontal_line((writer.page_width - name_rule_width) / 2.0, (writer.page_width + name_rule_width) / 2.0, writer.cursor, 0.8, gray(0.5));
+    writer.skip(6.0);
+    writer.paragraph("has passed the", 16.0, false, true, gray(0.35));
+    writer.skip(4.0);
+    writer.paragraph(&test.metadata.test_name, 24.0, true, true, gray(0.1));
+    writer.skip(6.0);
+    writer.paragraph(
+        &format!(
+            "with a score of {} / {} ({:.0}%)",
+            summary.grade_summary.achieved_score, summary.grade_summary.max_score, summary.grade_summary.achieved_percent * 100.0
+        ),
+        16.0, false, true, gray(0.35)
+    );
+
+    // The date and proctor sign off at the bottom corners
+    let footer_y = 38.0;
+    let signature_width = 80.0;
+    let left_x = 40.0;
+    let right_x = writer.page_width - 40.0 - signature_width;
+    writer.horizontal_line(left_x, left_x + signature_width, footer_y, 0.8, gray(0.5));
+    writer.horizontal_line(right_x, right_x + signature_width, footer_y, 0.8, gray(0.5));
+    writer.text_at(&summary.test_date.format("%B %-d, %Y").to_string(), 14.0, false, left_x, footer_y + 2.0, gray(0.1));
+    writer.text_at(&format!("{} {}", summary.proctor.first_name, summary.proctor.last_name), 14.0, false, right_x, footer_y + 2.0, gray(0.1));
+    writer.text_at("Date", 11.0, false, left_x, footer_y - 5.0, gray(0.45));
+    writer.text_at("Proctor", 11.0, false, right_x, footer_y - 5.0, gray(0.45));
+
+    writer.finish()
+}
+
+
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+// Score Report
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+
+const REPORT_FONT_SIZE: f32 = 10.0;
+const CELL_PADDING: f32 = 1.5;
+
+/// Renders a portrait report of a graded test with the label achieved in every scoring category of every competency,
+/// the bonus items, and the reasons the test failed. Point values are only shown when the test definition shows them.
+pub fn render_score_report(test: &Test) -> Result<Vec<u8>, TestError> {
+    let summary = test.full_summary().map_err(TestError::InternalServerError)?;
+    let grade_summary = &summary.grade_summary;
+    let testee = test.metadata.testee.as_ref()
+        .ok_or_else(|| TestError::InternalServerError("Graded tests must have a testee to make a score report.".to_string()))?;
+    let show_point_values = test.metadata.config_settings.show_point_values;
+
+    let mut writer = PageWriter::new(&format!("{} Score Report", test.metadata.test_name), LETTER_SHORT_SIDE, LETTER_LONG_SIDE)?;
+
+    writer.paragraph(&test.metadata.test_name, 20.0, true, false, gray(0.1));
+    writer.paragraph("Score Report", 14.0, false, false, gray(0.35));
+    writer.skip(3.0);
+    writer.rule(0.8, gray(0.3));
+    writer.skip(2.0);
+
+    let details = [
+        ("Testee", format!("{} {} ({})", testee.first_name, testee.last_name, testee.email)),
+        ("Test Date", summary.test_date.format("%Y-%m-%d").to_string()),
+        ("Proctor", format!("{} {}", summary.proctor.first_name, summary.proctor.last_name)),
+        ("Score", format!("{} / {} = {:.0}%", grade_summary.achieved_score, grade_summary.max_score, grade_summary.achieved_percent * 100.0)),
+        ("Passing Score", format!("{:.0}%", grade_summary.minimum_percent * 100.0)),
+    ];
+    for (label, value) in details {
+        writer.paragraph(&format!("{}: {}", label, value), 11.0, false, false, gray(0.1));
+    }
+
+    let (status, status_color) = match grade_summary.is_passing {
+        true => ("Status: Passing", green()),
+        false => ("Status: Failing", red()),
+    };
+    writer.paragraph(status, 12.0, true, false, status_color);
+
+    if let Some(failure_explanations) = &grade_summary.failure_explanation {
+        writer.skip(2.0);
+        for failure_explanation in failure_explanations {
+            writer.paragraph(&format!("- {}", describe_failure_explanation(failure_explanation)), REPORT_FONT_SIZE, false, false, red());
+        }
+    }
+
+    for section in test.tables.iter().flat_map(|table| table.sections.iter()) {
+        write_section(&mut writer, section, show_point_values);
+    }
+
+    if let Some(bonus_items) = test.bonus_items.as_ref().filter(|bonus_items| !bonus_items.is_empty()) {
+        writer.skip(6.0);
+        writer.paragraph("Bonus Points", 14.0, true, false, gray(0.1));
+        writer.skip(1.0);
+
+        for bonus_item in bonus_items {
+            let achieved = bonus_item.achieved.unwrap_or(false);
+            let line = match (achieved, show_point_values) {
+                (true, true) => format!("{}: Achieved (+{})", bonus_item.name, bonus_item.score),
+                (true, false) => format!("{}: Achieved", bonus_item.name),
+                (false, _) => format!("{}: Not achieved", bonus_item.name),
+            };
+            writer.paragraph(&line, REPORT_FONT_SIZE, false, false, if achieved { gray(0.1) } else { gray(0.45) });
+        }
+    }
+
+    writer.finish()
+}
+
+/// Writes a section as a table with a row for each competency and a column for each scoring category, plus the points
+/// when they are shown. Rows are kept together when the table breaks across pages.
+fn write_section(writer: &mut PageWriter, section: &TestSection, show_point_values: bool) {
+    let content_width = writer.content_width();
+    let points_width = if show_point_values { 18.0 } else { 0.0 };
+    let competency_width = content_width * 0.3;
+    let category_width = (content_width - competency_width - points_width) / section.scoring_categories.len().max(1) as f32;
+
+    let mut column_widths = vec![competency_width];
+    column_widths.extend(section.scoring_categories.iter().map(|_| category_width));
+    let mut header = vec![String::new()];
+    header.extend(section.scoring_categories.iter().map(|scoring_category| scoring_category.name.clone()));
+    if show_point_values {
+        column_widths.push(points_width);
+        header.push("Points".to_string());
+    }
+
+    writer.skip(6.0);
+    if !section.name.is_empty() {
+        // Keeps the section name with its header row
+        writer.ensure_space(line_height(14.0) + 3.0 * line_height(REPORT_FONT_SIZE));
+        writer.paragraph(&section.name, 14.0, true, false, gray(0.1));
+        writer.skip(1.0);
+    }
+
+    write_row(writer, &column_widths, &header, true);
+
+    for competency in &section.competencies {
+        let mut row = vec![competency.name.clone()];
+        row.extend(section.scoring_categories.iter().map(|scoring_category| {
+            competency.achieved_score_labels.iter().flatten()
+                .find(|label| label.scoring_category_name == scoring_category.name)
+                .map_or_else(|| "-".to_string(), |label| label.value.clone())
+        }));
+        if show_point_values {
+            let achieved = competency.achieved_scores.iter().flatten().sum::<Points>()
+                .weighted(competency.weight())
+                .weighted(section.weight());
+            let possible = competency.calculate_max_score().weighted(section.weight());
+            row.push(format!("{} / {}", achieved, possible));
+        }
+
+        write_row(writer, &column_widths, &row, false);
+    }
+}
+
+fn write_row(writer: &mut PageWriter, column_widths: &[f32], cells: &[String], is_header: bool) {
+    let wrapped: Vec<Vec<String>> = cells.iter().zip(column_widths)
+        .map(|(cell, width)| wrap_text(cell, REPORT_FONT_SIZE, is_header, width - 2.0 * CELL_PADDING))
+        .collect();
+    let line_count = wrapped.iter().map(Vec::len).max().unwrap_or(1);
+    let row_height = line_count as f32 * line_height(REPORT_FONT_SIZE) + 2.0 * CELL_PADDING;
+
+    writer.ensure_space(row_height);
+
+    // The competency names in the first column are bold like on the test page
+    let mut x = MARGIN;
+    for (column_index, (lines, width)) in wrapped.iter().zip(column_widths).enumerate() {
+        let mut y = writer.cursor - CELL_PADDING;
+        for line in lines {
+            y -= line_height(REPORT_FONT_SIZE);
+            writer.text_at(line, REPORT_FONT_SIZE, is_header || column_index == 0, x + CELL_PADDING, y + 1.0, gray(0.1));
+        }
+        x += width;
+    }
+
+    writer.skip(row_height);
+    writer.rule(if is_header { 0.6 } else { 0.2 }, gray(if is_header { 0.3 } else { 0.7 }));
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exam::import::{grade_imported_test, ImportScore, ImportTest, ImportTestee};
+    use crate::exam::handlers::{parse_test_definition_from_str, tests::setup_valid_test_str};
+    use crate::exam::models::Proctor;
+
+    fn setup_graded_test(footwork_label: &str) -> Test {
+        let definition = parse_test_definition_from_str(&setup_valid_test_str()).unwrap().tests.remove(0);
+        let score = |scoring_category: &str, label: &str| ImportScore {
+            section: "Pattern Scoring".to_string(),
+            competency: "Starter Step".to_string(),
+            scoring_category: Some(scoring_category.to_string()),
+            label: label.to_string(),
+        };
+        let import = ImportTest {
+            source: "tests[0]".to_string(),
+            test_name: definition.metadata.test_name.clone(),
+            test_definition_version: None,
+            test_date: "2019-05-04".to_string(),
+            testee: ImportTestee {
+                source: "tests[0]".to_string(),
+                first_name: "Jane".to_string(),
+                last_name: "Doe".to_string(),
+                email: "jane@example.com".to_string(),
+            },
+            proctor_email: None,
+            scores: vec![score("Footwork", footwork_label), score("Timing", "Off")],
+            bonus_items: vec!["No Thumbs".to_string()],
+        };
+
+        let mut test = grade_imported_test(definition, &import).unwrap();
+        test.metadata.test_id = Some(uuid::Uuid::new_v4());
+        test.metadata.test_date = Some(chrono::NaiveDate::from_ymd_opt(2019, 5, 4).unwrap().and_hms_opt(18, 30, 0).unwrap());
+        test.metadata.proctor = Some(Proctor { id: uuid::Uuid::new_v4(), first_name: "Pat".to_string(), last_name: "Proctor".to_string() });
+        test
+    }
+
+    #[test]
+    fn passing_tests_get_a_certificate_and_a_report() {
+        let test = setup_graded_test("Perfect");
+
+        for kind in [PdfDocumentKind::Certificate, PdfDocumentKind::Report] {
+            let pdf = kind.render(&test).unwrap();
+            assert!(pdf.starts_with(b"%PDF-"), "{:?}", kind);
+        }
+        assert_eq!(pdf_file_name(PdfDocumentKind::Report, &test), "score-report-standard-leader-test-jane-doe.pdf");
+    }
+
+    #[test]
+    fn failing_tests_only_get_a_report() {
+        let test = setup_graded_test("Nope");
+        assert_eq!(test.metadata.is_passing, Some(false));
+
+        assert!(render_certificate(&test).is_err());
+        assert!(render_score_report(&test).unwrap().starts_with(b"%PDF-"));
+    }
+
+    #[test]
+    fn text_wraps_between_words() {
+        let lines = wrap_text("Competency 'Starter Step' is failing because a label of 'Fail' was achieved", 10.0, false, 60.0);
+
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|line| text_width(line, 10.0, false) <= 60.0));
+        assert_eq!(lines.join(" "), "Competency 'Starter Step' is failing because a label of 'Fail' was achieved");
+    }
+}
@@ -0,0 +1,284 @@
+use std::{fmt, iter::Sum, ops::{Add, AddAssign}, str::FromStr};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sqlx::{decode::Decode, error::BoxDynError, postgres::{PgTypeInfo, PgValueRef}, Postgres, Type};
+use utoipa::{openapi::{schema::{ObjectBuilder, Schema, SchemaFormat, KnownFormat, Type as SchemaType}, RefOr}, PartialSchema, ToSchema};
+
+
+
+// #######################################################################################################################################################
+// #######################################################################################################################################################
+// Points
+// #######################################################################################################################################################
+// #######################################################################################################################################################
+
+/// How many units make up a single point. Five decimal places is enough to multiply a half point score by a competency
+/// weight and a section weight, each with up to two decimal places, without rounding.
+const SCALE: i64 = 100_000;
+
+/// A number of points, or a weight. Kept as a whole number of hundred-thousandths so that adding up and weighting scores is
+/// exact and pass/fail decisions never depend on float rounding. In yaml and JSON it is written as a plain number, like
+/// 2 or 1.5, and in the database it is stored as a NUMERIC.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Points(i64);
+
+impl Points {
+    pub const ZERO: Points = Points(0);
+    pub const ONE: Points = Points(SCALE);
+    pub const HALF: Points = Points(SCALE / 2);
+    /// The smallest step a weight can take, since weights have at most two decimal places.
+    pub const HUNDREDTH: Points = Points(SCALE / 100);
+
+    pub const fn whole(points: i64) -> Points {
+        Points(points * SCALE)
+    }
+
+    /// True when this is a whole number of steps, IE, a whole number of half points when the step is Points::HALF.
+    pub fn is_multiple_of(self, step: Points) -> bool {
+        step.0 != 0 && self.0 % step.0 == 0
+    }
+
+    /// Multiplies the points by a weight. This is exact for half point scores and weights with up to two decimal places,
+    /// which validation holds test definitions to. Anything finer is rounded to the nearest unit.
+    pub fn weighted(self, weight: Points) -> Points {
+        let product = self.0 as i128 * weight.0 as i128;
+        let rounded = (product + product.signum() * (SCALE as i128 / 2)) / SCALE as i128;
+        Points(rounded as i64)
+    }
+
+    /// The fraction of the max score achieved, only for display. Use meets_minimum to decide whether a score passes.
+    pub fn fraction_of(self, max_score: Points) -> f32 {
+        if max_score.0 == 0 {
+            return 0.0;
+        }
+        (self.0 as f64 / max_score.0 as f64) as f32
+    }
+
+    /// Checks achieved / max >= minimum percent without dividing. The minimum percent is a fraction (0.6 for 60%) and is
+    /// taken to the nearest hundredth of a percent, so that an f32 of 0.6 is treated as exactly 60%.
+    pub fn meets_minimum(self, max_score: Points, minimum_percent: f32) -> bool {
+        let minimum_basis_points = (minimum_percent as f64 * 10_000.0).round() as i128;
+        self.0 as i128 * 10_000 >= minimum_basis_points * max_score.0 as i128
+    }
+
+    /// Only for places that can't take anything but a float, like spreadsheet cells.
+    pub fn to_f64(self) -> f64 {
+        self.0 as f64 / SCALE as f64
+    }
+}
+
+impl Add for Points {
+    type Output = Points;
+
+    fn add(self, other: Points) -> Points {
+        Points(self.0 + other.0)
+    }
+}
+
+impl AddAssign for Points {
+    fn add_assign(&mut self, other: Points) {
+        self.0 += other.0;
+    }
+}
+
+impl Sum for Points {
+    fn sum<I: Iterator<Item = Points>>(iter: I) -> Points {
+        iter.fold(Points::ZERO, Add::add)
+    }
+}
+
+impl<'a> Sum<&'a Points> for Points {
+    fn sum<I: Iterator<Item = &'a Points>>(iter: I) -> Points {
+        iter.copied().sum()
+    }
+}
+
+/// Writes the points without trailing zeros, so 2 points is "2" and a half point is "0.5".
+impl fmt::Display for Points {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let whole = (self.0 / SCALE).abs();
+        let fraction = (self.0 % SCALE).abs();
+
+        if fraction == 0 {
+            return write!(f, "{}{}", sign, whole);
+        }
+        let fraction = format!("{:05}", fraction);
+        write!(f, "{}{}.{}", sign, whole, fraction.trim_end_matches('0'))
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+// Parsing
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PointsError {
+    pub value: String,
+}
+
+impl fmt::Display for PointsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}' isn't a number of points with at most five decimal places.", self.value)
+    }
+}
+
+impl std::error::Error for PointsError {}
+
+/// Parses a decimal like "2", "-1.5" or "0.25" digit by digit, so no precision is lost on the way in.
+impl FromStr for Points {
+    type Err = PointsError;
+
+    fn from_str(value: &str) -> Result<Points, PointsError> {
+        let error = || PointsError { value: value.to_string() };
+
+        let trimmed = value.trim();
+        let (is_negative, unsigned) = match trimmed.strip_prefix('-') {
+            Some(unsigned) => (true, unsigned),
+            None => (false, trimmed),
+        };
+        let (whole, fraction) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+
+        let is_digits = |part: &str| part.chars().all(|c| c.is_ascii_digit());
+        if whole.is_empty() || !is_digits(whole) || !is_digits(fraction) {
+            return Err(error());
+        }
+
+        // Trailing zeros don't add precision, so "2.500000" is still 2.5
+        let fraction = fraction.trim_end_matches('0');
+        if fraction.len() > 5 {
+            return Err(error());
+        }
+
+        let whole: i64 = whole.parse().map_err(|_| error())?;
+        let fraction: i64 = format!("{:0<5}", fraction).parse().map_err(|_| error())?;
+        let units = whole.checked_mul(SCALE).and_then(|units| units.checked_add(fraction)).ok_or_else(error)?;
+
+        Ok(Points(if is_negative { -units } else { units }))
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+// Serde
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+
+impl Serialize for Points {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 % SCALE {
+            0 => serializer.serialize_i64(self.0 / SCALE),
+            _ => serializer.serialize_f64(self.to_f64()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Points {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Points, D::Error> {
+        deserializer.deserialize_any(PointsVisitor)
+    }
+}
+
+struct PointsVisitor;
+
+impl de::Visitor<'_> for PointsVisitor {
+    type Value = Points;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a number with at most five decimal places")
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Points, E> {
+        value.checked_mul(SCALE).map(Points).ok_or_else(|| E::custom(PointsError { value: value.to_string() }))
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Points, E> {
+        i64::try_from(value).map_err(|_| E::custom(PointsError { value: value.to_string() })).and_then(|value| self.visit_i64(value))
+    }
+
+    /// Floats are read back through their shortest decimal form, which is what was written in the yaml or JSON, so 0.1
+    /// becomes exactly a tenth of a point.
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<Points, E> {
+        if !value.is_finite() {
+            return Err(E::custom(PointsError { value: value.to_string() }));
+        }
+        value.to_string().parse().map_err(E::custom)
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Points, E> {
+        value.parse().map_err(E::custom)
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+// Database and OpenAPI
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+
+/// Points are stored as NUMERIC and selected as TEXT (IE, `achieved_score::TEXT AS "achieved_score!: Points"`), since
+/// sqlx has no exact decimal type without pulling in another crate.
+impl Type<Postgres> for Points {
+    fn type_info() -> PgTypeInfo {
+        <String as Type<Postgres>>::type_info()
+    }
+
+    fn compatible(ty: &PgTypeInfo) -> bool {
+        <String as Type<Postgres>>::compatible(ty)
+    }
+}
+
+impl<'r> Decode<'r, Postgres> for Points {
+    fn decode(value: PgValueRef<'r>) -> Result<Points, BoxDynError> {
+        Ok(<&str as Decode<Postgres>>::decode(value)?.parse()?)
+    }
+}
+
+impl PartialSchema for Points {
+    fn schema() -> RefOr<Schema> {
+        ObjectBuilder::new()
+            .schema_type(SchemaType::Number)
+            .format(Some(SchemaFormat::KnownFormat(KnownFormat::Double)))
+            .description(Some("A number of points, with at most five decimal places."))
+            .into()
+    }
+}
+
+impl ToSchema for Points {}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn points_are_parsed_and_written_exactly() {
+        assert_eq!("2".parse::<Points>(), Ok(Points::whole(2)));
+        assert_eq!("1.5".parse::<Points>(), Ok(Points::whole(1) + Points::HALF));
+        assert_eq!("-0.25".parse::<Points>().unwrap().to_string(), "-0.25");
+        assert_eq!("3.500000".parse::<Points>().unwrap().to_string(), "3.5");
+        assert!("0.000001".parse::<Points>().is_err());
+        assert!("1e3".parse::<Points>().is_err());
+
+        let from_yaml: Vec<Points> = serde_yaml::from_str("[3, 0.1, 2.5]").unwrap();
+        assert_eq!(from_yaml.iter().map(ToString::to_string).collect::<Vec<_>>(), vec!["3", "0.1", "2.5"]);
+        assert_eq!(serde_json::to_string(&from_yaml).unwrap(), "[3,0.1,2.5]");
+    }
+
+    /// Ten tenths of a point add up to exactly one point, where f32 would drift
+    #[test]
+    fn sums_and_weights_do_not_drift() {
+        let tenth: Points = "0.1".parse().unwrap();
+        assert_eq!(std::iter::repeat_n(tenth, 10).sum::<Points>(), Points::ONE);
+
+        let weight: Points = "1.33".parse().unwrap();
+        assert_eq!(Points::HALF.weighted(weight).weighted(weight).to_string(), "0.88445");
+    }
+
+    #[test]
+    fn minimums_are_compared_without_dividing() {
+        // 0.6 as an f32 is slightly more than 0.6, which used to fail a testee with exactly 60%
+        assert!(Points::whole(3).meets_minimum(Points::whole(5), 0.6));
+        assert!(!"2.99999".parse::<Points>().unwrap().meets_minimum(Points::whole(5), 0.6));
+        assert!(Points::ZERO.meets_minimum(Points::ZERO, 0.6));
+    }
+}
@@ -0,0 +1,384 @@
+use axum::{
+    async_trait,
+    extract::{FromRequest, Request},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Form, Json,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use utoipa::ToSchema;
+
+use crate::exam::handlers::TestError;
+use crate::exam::models::{AchievedScoreLabel, Proctor, SubmissionError, Test, Testee};
+
+
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+// Test Submission
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+
+// A graded test is submitted as the score label picked for each competency, never as points, so it is graded the same way
+// wherever it comes from. The grading form posts it form encoded and API clients post it as JSON, and both are read into
+// a TestSubmission before anything is graded.
+
+/// The current version of the submission schema. Submissions with any other version are rejected, so bump this when the
+/// schema changes in a way that older clients would get wrong.
+pub const SUBMISSION_VERSION: u32 = 1;
+
+/// A test as submitted for grading
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct TestSubmission {
+    /// The version of the submission schema, which is 1
+    pub version: u32,
+    /// The version of the test definition the test was started with. The current version is used when this is left out.
+    #[serde(default)]
+    pub test_definition_version: Option<i32>,
+    pub first_name: String,
+    pub last_name: String,
+    pub email: String,
+    /// The score labels picked for every competency on the test
+    pub competencies: Vec<CompetencySelection>,
+    /// The indices of the bonus items that were achieved
+    #[serde(default)]
+    pub bonus_items: Vec<usize>,
+    /// Whether to email the testee their results once the test is saved
+    #[serde(default)]
+    pub send_email_results: bool,
+    /// Whether to attach the score report and certificate to the results email
+    #[serde(default)]
+    pub attach_pdf_results: bool,
+}
+
+/// The score labels picked for one competency. The indices are zero based and follow the test definition.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CompetencySelection {
+    pub table_index: usize,
+    pub section_index: usize,
+    pub competency_index: usize,
+    /// The index of the score label picked in each of the section's scoring categories, in the order they are defined
+    pub score_label_indices: Vec<usize>,
+}
+
+impl TestSubmission {
+    /// Reads a form encoded submission. Each score is a field named `score.<table>.<section>.<competency>.<scoring category>`
+    /// holding the index of the picked score label, each achieved bonus item is a `bonus_items` field holding its index,
+    /// and the email checkboxes are true when they are present. The other fields match the JSON names.
+    pub fn from_form_fields(fields: Vec<(String, String)>) -> Result<TestSubmission, SubmissionError> {
+        let mut version = None;
+        let mut test_definition_version = None;
+        let mut first_name = None;
+        let mut last_name = None;
+        let mut email = None;
+        let mut bonus_items = Vec::new();
+        let mut send_email_results = false;
+        let mut attach_pdf_results = false;
+        let mut scores: BTreeMap<(usize, usize, usize), BTreeMap<usize, usize>> = BTreeMap::new();
+
+        for (key, value) in fields {
+            let malformed_field = || SubmissionError::MalformedField { key: key.clone(), value: value.clone() };
+            let index = |part: &str| part.parse::<usize>().map_err(|_| malformed_field());
+            let set_once = |field: &mut Option<String>| match field.replace(value.clone()) {
+                Some(_) => Err(SubmissionError::DuplicateField { key: key.clone() }),
+                None => Ok(()),
+            };
+
+            match key.as_str() {
+                "version" => {
+                    let parsed = value.parse::<u32>().map_err(|_| malformed_field())?;
+                    if version.replace(parsed).is_some() {
+                        return Err(SubmissionError::DuplicateField { key });
+                    }
+                },
+                "test_definition_version" => {
+                    let parsed = value.parse::<i32>().map_err(|_| malformed_field())?;
+                    if test_definition_version.replace(parsed).is_some() {
+                        return Err(SubmissionError::DuplicateField { key });
+                    }
+                },
+                "first_name" => set_once(&mut first_name)?,
+                "last_name" => set_once(&mut last_name)?,
+                "email" => set_once(&mut email)?,
+                "bonus_items" => bonus_items.push(index(&value)?),
+                "send_email_results" => send_email_results = true,
+                "attach_pdf_results" => attach_pdf_results = true,
+                _ => {
+                    let location = key.strip_prefix("score.").ok_or_else(malformed_field)?;
+                    let [table_index, section_index, competency_index, scoring_category_index] = location.split('.').collect::<Vec<&str>>()[..] else {
+                        return Err(malformed_field());
+                    };
+
+                    let competency_scores = scores.entry((index(table_index)?, index(section_index)?, index(competency_index)?)).or_default();
+                    if competency_scores.insert(index(scoring_category_index)?, index(&value)?).is_some() {
+                        return Err(SubmissionError::DuplicateField { key });
+                    }
+                },
+            }
+        }
+
+        let mut competencies = Vec::new();
+        for ((table_index, section_index, competency_index), competency_scores) in scores {
+            // The scoring categories have to be numbered from zero without gaps to line up with the test definition
+            let gap = competency_scores.iter().enumerate().find(|(position, (scoring_category_index, _))| *position != **scoring_category_index);
+            if let Some((_, (scoring_category_index, score_label_index))) = gap {
+                return Err(SubmissionError::MalformedField {
+                    key: format!("score.{}.{}.{}.{}", table_index, section_index, competency_index, scoring_category_index),
+                    value: score_label_index.to_string(),
+                });
+            }
+
+            competencies.push(CompetencySelection {
+                table_index,
+                section_index,
+                competency_index,
+                score_label_indices: competency_scores.into_values().collect(),
+            });
+        }
+
+        Ok(TestSubmission {
+            version: version.ok_or(SubmissionError::MissingField { field: "version" })?,
+            test_definition_version,
+            first_name: first_name.ok_or(SubmissionError::MissingField { field: "first_name" })?,
+            last_name: last_name.ok_or(SubmissionError::MissingField { field: "last_name" })?,
+            email: email.ok_or(SubmissionError::MissingField { field: "email" })?,
+            competencies,
+            bonus_items,
+            send_email_results,
+            attach_pdf_results,
+        })
+    }
+}
+
+/// Reads a submission from a JSON body, or from a form encoded body for any other content type.
+#[async_trait]
+impl<S: Send + Sync> FromRequest<S> for TestSubmission {
+    type Rejection = SubmissionError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let is_json = req.headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .is_some_and(|content_type| content_type.starts_with("application/json"));
+
+        if is_json {
+            let Json(submission) = Json::<TestSubmission>::from_request(req, state)
+                .await
+                .map_err(|e| SubmissionError::MalformedBody { message: e.body_text() })?;
+            Ok(submission)
+        } else {
+            let Form(fields) = Form::<Vec<(String, String)>>::from_request(req, state)
+                .await
+                .map_err(|e| SubmissionError::MalformedBody { message: e.body_text() })?;
+            TestSubmission::from_form_fields(fields)
+        }
+    }
+}
+
+impl IntoResponse for SubmissionError {
+    fn into_response(self) -> Response {
+        (StatusCode::UNPROCESSABLE_ENTITY, self.to_string()).into_response()
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+// Grade Submission
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+
+/// Fills in the achieved scores and labels of every competency from the picked score labels, checking every index against
+/// the test definition. The points come from the test definition. Every scoring category of every competency needs a
+/// score label.
+fn apply_competency_selections(test: &mut Test, selections: &[CompetencySelection]) -> Result<(), SubmissionError> {
+    let mut selected: Vec<Vec<Vec<Option<&[usize]>>>> = test.tables.iter()
+        .map(|table| table.sections.iter().map(|section| vec![None; section.competencies.len()]).collect())
+        .collect();
+
+    for selection in selections {
+        let CompetencySelection { table_index, section_index, competency_index, .. } = *selection;
+        let table = test.tables.get(table_index)
+            .ok_or(SubmissionError::UnknownTable { table_index })?;
+        let section = table.sections.get(section_index)
+            .ok_or(SubmissionError::UnknownSection { table_index, section_index })?;
+        let competency = section.competencies.get(competency_index)
+            .ok_or(SubmissionError::UnknownCompetency { table_index, section_index, competency_index })?;
+
+        if selected[table_index][section_index][competency_index].replace(&selection.score_label_indices).is_some() {
+            return Err(SubmissionError::DuplicateCompetency { competency_name: competency.name.clone() });
+        }
+    }
+
+    for (table, table_selections) in test.tables.iter_mut().zip(selected) {
+        for (section, section_selections) in table.sections.iter_mut().zip(table_selections) {
+            for (competency, score_label_indices) in section.competencies.iter_mut().zip(section_selections) {
+                let score_label_indices = score_label_indices.unwrap_or_default();
+                if score_label_indices.len() > section.scoring_categories.len() {
+                    return Err(SubmissionError::UnknownScoringCategory {
+                        competency_name: competency.name.clone(),
+                        scoring_category_index: section.scoring_categories.len(),
+                    });
+                }
+
+                let mut achieved_scores = Vec::new();
+                let mut achieved_score_labels = Vec::new();
+
+                for (scoring_category_index, scoring_category) in section.scoring_categories.iter().enumerate() {
+                    let score_label_index = *score_label_indices.get(scoring_category_index).ok_or_else(|| SubmissionError::MissingScore {
+                        competency_name: competency.name.clone(),
+                        scoring_category_name: scoring_category.name.clone(),
+                    })?;
+
+                    let points = competency.scores.get(scoring_category_index).and_then(|scores| scores.get(score_label_index));
+                    let label = scoring_category.values.get(score_label_index);
+                    let (Some(points), Some(label)) = (points, label) else {
+                        return Err(SubmissionError::UnknownScoreLabel {
+                            competency_name: competency.name.clone(),
+                            scoring_category_name: scoring_category.name.clone(),
+                            score_label_index,
+                        });
+                    };
+
+                    achieved_scores.push(*points);
+                    achieved_score_labels.push(AchievedScoreLabel {
+                        scoring_category_name: scoring_category.name.clone(),
+                        value: label.clone(),
+                    });
+                }
+
+                competency.achieved_scores = Some(achieved_scores);
+                competency.achieved_score_labels = Some(achieved_score_labels);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Grades a submission against the test definition it was made for, which it then returns graded. Only the score label
+/// indices are read from the submission. The points for each label, and for each bonus item, come from the test
+/// definition so that a tampered submission can't award any score it likes.
+pub fn grade_submission(submission: TestSubmission, mut test_definition: Test, proctor: Option<Proctor>) -> Result<Test, TestError> {
+    if submission.version != SUBMISSION_VERSION {
+        return Err(SubmissionError::UnsupportedVersion { version: submission.version }.into());
+    }
+
+    apply_competency_selections(&mut test_definition, &submission.competencies)?;
+
+    for &bonus_index in &submission.bonus_items {
+        let bonus_item = test_definition.bonus_items
+            .as_mut()
+            .and_then(|bonus_items| bonus_items.get_mut(bonus_index))
+            .ok_or(SubmissionError::UnknownBonusItem { bonus_index })?;
+        bonus_item.achieved = Some(true);
+    }
+
+    test_definition.metadata.testee = Some(Testee {
+        id: None,
+        first_name: submission.first_name,
+        last_name: submission.last_name,
+        email: submission.email,
+    });
+
+    test_definition.grade().map_err(|e| TestError::InternalServerError(e.to_string()))?;
+
+    test_definition.metadata.proctor = proctor;
+
+    Ok(test_definition)
+}
+
+
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+// Unit Tests
+// -------------------------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exam::handlers::tests::valid_test;
+    use crate::exam::points::Points;
+
+    /// The grading form's fields for the valid test, picking the given footwork and timing labels
+    fn form_fields(footwork: &str, timing: &str) -> Vec<(String, String)> {
+        [
+            ("version", "1"),
+            ("first_name", "Jane"),
+            ("last_name", "Doe"),
+            ("email", "jane@example.com"),
+            ("score.0.0.0.0", footwork),
+            ("score.0.0.0.1", timing),
+            ("bonus_items", "2"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+    }
+
+    #[test]
+    fn form_and_json_submissions_are_read_the_same() {
+        let from_form = TestSubmission::from_form_fields(form_fields("2", "0")).unwrap();
+        let from_json: TestSubmission = serde_json::from_str(r#"{
+            "version": 1,
+            "first_name": "Jane",
+            "last_name": "Doe",
+            "email": "jane@example.com",
+            "competencies": [{"table_index": 0, "section_index": 0, "competency_index": 0, "score_label_indices": [2, 0]}],
+            "bonus_items": [2]
+        }"#).unwrap();
+        assert_eq!(from_form, from_json);
+
+        let graded_test = grade_submission(from_form, valid_test(), None).unwrap();
+        assert_eq!(graded_test.tables[0].sections[0].competencies[0].achieved_scores, Some(vec![Points::whole(1), Points::whole(1)]));
+        assert_eq!(graded_test.metadata.achieved_score, Some(Points::whole(6)));
+    }
+
+    #[test]
+    fn indices_are_compared_as_numbers() {
+        let mut fields = form_fields("0", "0");
+        fields.extend([("score.0.0.10.0".to_string(), "3".to_string()), ("score.0.0.2.0".to_string(), "1".to_string())]);
+        let submission = TestSubmission::from_form_fields(fields).unwrap();
+
+        let competency_indices: Vec<usize> = submission.competencies.iter().map(|selection| selection.competency_index).collect();
+        assert_eq!(competency_indices, vec![0, 2, 10]);
+    }
+
+    #[test]
+    fn malformed_form_fields_are_rejected() {
+        let mut fields = form_fields("0", "0");
+        fields.push(("score.0.0.0.01".to_string(), "1".to_string()));
+        assert!(matches!(TestSubmission::from_form_fields(fields), Err(SubmissionError::DuplicateField { .. })));
+
+        let mut fields = form_fields("0", "0");
+        fields.push(("score.0.0.0.3".to_string(), "1".to_string()));
+        assert!(matches!(TestSubmission::from_form_fields(fields), Err(SubmissionError::MalformedField { key, .. }) if key == "score.0.0.0.3"));
+
+        let fields = form_fields("0", "0").into_iter().filter(|(key, _)| key != "version").collect();
+        assert_eq!(TestSubmission::from_form_fields(fields), Err(SubmissionError::MissingField { field: "version" }));
+
+        let mut fields = form_fields("0", "0");
+        fields.push(("table_index---0".to_string(), "0".to_string()));
+        assert!(matches!(TestSubmission::from_form_fields(fields), Err(SubmissionError::MalformedField { .. })));
+    }
+
+    #[test]
+    fn out_of_range_and_missing_scores_are_rejected() {
+        let grade = |footwork: &str, timing: &str| grade_submission(TestSubmission::from_form_fields(form_fields(footwork, timing)).unwrap(), valid_test(), None);
+
+        assert!(matches!(grade("4", "0"), Err(TestError::InvalidSubmission(SubmissionError::UnknownScoreLabel { score_label_index: 4, .. }))));
+
+        let mut submission = TestSubmission::from_form_fields(form_fields("0", "0")).unwrap();
+        submission.competencies[0].score_label_indices.pop();
+        assert!(matches!(grade_submission(submission, valid_test(), None), Err(TestError::InvalidSubmission(SubmissionError::MissingScore { .. }))));
+
+        let mut submission = TestSubmission::from_form_fields(form_fields("0", "0")).unwrap();
+        submission.competencies.push(CompetencySelection { table_index: 3, section_index: 0, competency_index: 0, score_label_indices: vec![0, 0] });
+        assert!(matches!(grade_submission(submission, valid_test(), None), Err(TestError::InvalidSubmission(SubmissionError::UnknownTable { table_index: 3 }))));
+
+        let mut submission = TestSubmission::from_form_fields(form_fields("0", "0")).unwrap();
+        submission.competencies.push(submission.competencies[0].clone());
+        assert!(matches!(grade_submission(submission, valid_test(), None), Err(TestError::InvalidSubmission(SubmissionError::DuplicateCompetency { .. }))));
+
+        let mut submission = TestSubmission::from_form_fields(form_fields("0", "0")).unwrap();
+        submission.version = 2;
+        assert!(matches!(grade_submission(submission, valid_test(), None), Err(TestError::InvalidSubmission(SubmissionError::UnsupportedVersion { version: 2 }))));
+    }
+}
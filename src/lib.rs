@@ -0,0 +1,45 @@
+pub mod api;
+pub mod config;
+pub mod router;
+pub mod auth;
+pub mod views;
+pub mod filters;
+pub mod exam;
+pub mod emails;
+pub mod webhooks;
+pub mod outbox;
+#[cfg(test)]
+mod testing;
+
+use auth::oidc::OidcProvider;
+use config::{GoogleOAuthConfig, SecretsConfig};
+use exam::models::{SMTPConfig, TestDefinitionYaml};
+use lettre::{AsyncSmtpTransport, Tokio1Executor};
+use oauth2::reqwest;
+use redis::Client;
+use sqlx::{Pool, Postgres};
+use std::sync::{Arc, RwLock};
+
+pub struct AppState {
+    pub db: Pool<Postgres>,
+    pub env: SecretsConfig,
+    pub redis_client: Client,
+    pub smtp_config: Option<SMTPConfig>,
+    pub smtp_mailer: Option<AsyncSmtpTransport<Tokio1Executor>>,
+    pub google_oauth_config: Option<GoogleOAuthConfig>,
+    /// OpenID Connect providers that were discovered when the server started
+    pub oidc_providers: Vec<OidcProvider>,
+    pub http_client: reqwest::Client,
+    pub test_configurations: RwLock<Arc<TestDefinitionYaml>>,
+    /// Held while reloading the test definitions, so that the file watcher and the reload button can't interleave their
+    /// syncs and leave older definitions running than the ones in the database
+    pub test_definitions_reload_lock: tokio::sync::Mutex<()>,
+}
+
+impl AppState {
+    /// Returns a snapshot of the currently loaded test definitions. Reloading swaps in a new snapshot, so a request
+    /// keeps seeing the same definitions for as long as it holds onto this one.
+    pub fn test_configurations(&self) -> Arc<TestDefinitionYaml> {
+        self.test_configurations.read().expect("Test definitions lock was poisoned").clone()
+    }
+}
@@ -1,16 +1,20 @@
-mod config;
-mod router;
-mod auth;
-mod views;
-mod filters;
-mod exam;
-
-use config::{GoogleOAuthConfig, SecretsConfig};
-use exam::{handlers::parse_test_definition_from_str, models::{SMTPConfig, TestDefinitionYaml}};
-use lettre::{transport::smtp::authentication::Credentials, AsyncSmtpTransport, Tokio1Executor};
-use lettre::transport::smtp::PoolConfig;
+use dancer_test::{
+    auth::oidc::discover_oidc_providers,
+    auth::handlers::get_user,
+    config::{get_env_var, GoogleOAuthConfig, OidcProviderConfig, SecretsConfig},
+    exam::{
+        handlers::{load_test_definitions_from_file, sync_test_definitions, watch_test_definitions_file, TEST_DEFINITIONS_FILE_PATH},
+        import::{import_records, parse_import_records, ImportFormat},
+        models::{Proctor, SMTPConfig},
+    },
+    emails::deliver_emails,
+    router::create_router,
+    webhooks::deliver_webhooks,
+    AppState,
+};
+use lettre::{AsyncSmtpTransport, Tokio1Executor};
 use oauth2::reqwest;
-use std::{fs::File, io::Read, sync::Arc, time::Duration};
+use std::{net::SocketAddr, process::ExitCode, sync::{Arc, RwLock}};
 
 use axum::http::{
     header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE},
@@ -18,47 +22,26 @@ use axum::http::{
 };
 use dotenv::dotenv;
 use redis::Client;
-use router::create_router;
-use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
+use sqlx::postgres::PgPoolOptions;
 use tower_http::cors::CorsLayer;
 
-pub struct AppState {
-    db: Pool<Postgres>,
-    env: SecretsConfig,
-    redis_client: Client,
-    smtp_config: Option<SMTPConfig>,
-    smtp_mailer: Option<AsyncSmtpTransport<Tokio1Executor>>,
-    google_oauth_config: Option<GoogleOAuthConfig>,
-    http_client: reqwest::Client,
-    test_configurations: TestDefinitionYaml,
-}
-
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
 
     dotenv().ok();
 
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("import") {
+        return import_command(&args[2..]).await;
+    }
+
     let config = SecretsConfig::init();
 
     let smtp_config = SMTPConfig::init();
     let smtp_mailer: Option<AsyncSmtpTransport<Tokio1Executor>> = smtp_config.as_ref().and_then(|config| {
-        let creds = Credentials::new(
-            config.user_login.clone(),
-            config.user_password.clone(),
-        );
-    
-        match AsyncSmtpTransport::<Tokio1Executor>::relay(&config.server_host) {
-            Ok(transport) => Some(
-                transport
-                    .credentials(creds)
-                    .pool_config(
-                        PoolConfig::new()
-                            .max_size(10)
-                            .idle_timeout(Duration::from_secs(60))
-                    )
-                    .build()
-            ),
+        match config.build_transport() {
+            Ok(transport) => Some(transport),
             Err(e) => {
                 eprintln!("Error: Unable to connect to email server: {}", e);
                 None
@@ -68,15 +51,7 @@ async fn main() {
 
     let google_oauth_config = GoogleOAuthConfig::init();
 
-    let file_path = "test_definitions.yaml";
-    let mut file = File::open(file_path).expect(&format!("couldn't open file: {}", file_path));
-    let mut yaml_string = String::new();
-    file.read_to_string(&mut yaml_string).expect(&format!("Couldn't read file '{}' to string. This should work...", file_path));
-    let mut tests = parse_test_definition_from_str(&yaml_string).expect("Error parsing test_definition.yaml");
-
-    for test in &mut tests.tests {
-        test.validate().expect("Invalid test definition");
-    }
+    let tests = load_test_definitions_from_file(TEST_DEFINITIONS_FILE_PATH).expect("Invalid test definitions");
 
     let pool = match PgPoolOptions::new()
         .max_connections(10)
@@ -93,6 +68,15 @@ async fn main() {
         }
     };
 
+    // Give every test definition its stable id and version before anything can reference it
+    let tests = match sync_test_definitions(&pool, tests).await {
+        Ok(tests) => tests,
+        Err(err) => {
+            println!("🔥 Failed to sync the test definitions to the database: {:?}", err);
+            std::process::exit(1);
+        }
+    };
+
     let redis_client = match Client::open(config.redis_url.to_owned()) {
         Ok(client) => {
             println!("✅ Connection to the redis server is successful!");
@@ -111,25 +95,156 @@ async fn main() {
         .build()
         .expect("Client should build");
 
+    let oidc_providers = discover_oidc_providers(OidcProviderConfig::init(), &http_client).await;
+
     let cors = CorsLayer::new()
         .allow_origin("http://localhost:3000".parse::<HeaderValue>().unwrap())
         .allow_methods([Method::GET, Method::POST, Method::PATCH, Method::DELETE])
         .allow_credentials(true)
         .allow_headers([AUTHORIZATION, ACCEPT, CONTENT_TYPE]);
 
-    let app = create_router(Arc::new(AppState {
+    let app_state = Arc::new(AppState {
         db: pool.clone(),
         env: config.clone(),
         smtp_config,
         smtp_mailer,
         google_oauth_config,
+        oidc_providers,
         http_client,
         redis_client: redis_client.clone(),
-        test_configurations: tests,
-    }))
-    .layer(cors);
+        test_configurations: RwLock::new(Arc::new(tests)),
+        test_definitions_reload_lock: tokio::sync::Mutex::new(()),
+    });
+
+    tokio::spawn(watch_test_definitions_file(app_state.clone()));
+    tokio::spawn(deliver_webhooks(app_state.clone()));
+    tokio::spawn(deliver_emails(app_state.clone()));
+
+    let app = create_router(app_state)
+        .layer(cors);
 
     println!("🚀 Server started successfully on port {}", config.server_port);
     let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", config.server_port)).await.unwrap();
-    axum::serve(listener, app).await.unwrap()
-}
\ No newline at end of file
+    // The peer address is recorded on sessions when the request didn't come through a reverse proxy
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+    ExitCode::SUCCESS
+}
+
+
+const IMPORT_USAGE: &str = "Usage: dancer_test import <records.csv|records.yaml> [--dry-run] [--proctor <email>]";
+
+/// Loads testees and graded tests from before the app was used instead of starting the server. Tests without a proctor
+/// email are credited to the --proctor user. Nothing is imported if any record has a problem.
+async fn import_command(args: &[String]) -> ExitCode {
+    let mut file_path = None;
+    let mut dry_run = false;
+    let mut proctor_email = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            "--proctor" => match args.next() {
+                Some(email) => proctor_email = Some(email.clone()),
+                None => {
+                    println!("{}", IMPORT_USAGE);
+                    return ExitCode::FAILURE;
+                }
+            },
+            _ if file_path.is_none() && !arg.starts_with("--") => file_path = Some(arg.clone()),
+            _ => {
+                println!("{}", IMPORT_USAGE);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let Some(file_path) = file_path else {
+        println!("{}", IMPORT_USAGE);
+        return ExitCode::FAILURE;
+    };
+
+    let Some(format) = ImportFormat::from_file_name(&file_path) else {
+        println!("{}: error: Import files must end in .csv, .yaml or .yml.", file_path);
+        return ExitCode::FAILURE;
+    };
+
+    let contents = match std::fs::read_to_string(&file_path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            println!("{}: error: Couldn't read file: {}", file_path, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let records = match parse_import_records(&contents, format) {
+        Ok(records) => records,
+        Err(problems) => {
+            for problem in problems {
+                println!("{}: {}: error: {}", file_path, problem.source, problem.message);
+            }
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let pool = match PgPoolOptions::new().max_connections(2).connect(&get_env_var("DATABASE_URL")).await {
+        Ok(pool) => pool,
+        Err(err) => {
+            println!("🔥 Failed to connect to the database: {:?}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let test_definitions = match load_test_definitions_from_file(TEST_DEFINITIONS_FILE_PATH) {
+        Ok(test_definitions) => test_definitions,
+        Err(err) => {
+            println!("🔥 Failed to load the test definitions: {:?}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let default_proctor = match proctor_email {
+        Some(email) => match get_user(&email, &pool).await {
+            Ok(Some(user)) => Some(Proctor { id: user.id, first_name: user.first_name, last_name: user.last_name }),
+            Ok(None) => {
+                println!("error: There is no user with the email '{}'.", email);
+                return ExitCode::FAILURE;
+            },
+            Err(err) => {
+                println!("🔥 Failed to look up the proctor: {:?}", err);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+
+    let report = match import_records(&pool, test_definitions, records, default_proctor, dry_run).await {
+        Ok(report) => report,
+        Err(err) => {
+            println!("🔥 The import failed and nothing was imported: {:?}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    for problem in &report.problems {
+        println!("{}: {}: error: {}", file_path, problem.source, problem.message);
+    }
+    for test in &report.tests {
+        println!(
+            "{}: {}: {} for {} <{}> on {}: {}/{} {}",
+            file_path, test.source, test.test_name, test.testee_name, test.testee_email, test.test_date.format("%Y-%m-%d"),
+            test.achieved_score, test.max_score, if test.is_passing { "pass" } else { "fail" }
+        );
+    }
+
+    if !report.problems.is_empty() {
+        println!("{} problem(s) found, nothing was imported.", report.problems.len());
+        ExitCode::FAILURE
+    } else if report.dry_run {
+        println!("Dry run: {} testee(s) and {} test(s) would be imported.", report.testee_count, report.tests.len());
+        ExitCode::SUCCESS
+    } else {
+        println!("✅ Imported {} testee(s) and {} test(s).", report.testee_count, report.tests.len());
+        ExitCode::SUCCESS
+    }
+}
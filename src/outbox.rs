@@ -0,0 +1,157 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+// #######################################################################################################################################################
+// Outboxes
+// #######################################################################################################################################################
+
+// An outbox is a table of things to send, like webhook deliveries or results emails, written by the code that wants them
+// sent. A worker claims the rows that are due, sends them one after another and records each attempt, retrying failures
+// with exponential backoff until it gives up. Claiming a row pushes its next attempt past a lease, so that rows aren't sent
+// twice when more than one server is running.
+
+/// How often an outbox is checked for items that are due
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How an outbox paces its work and retries failures
+pub struct OutboxPolicy {
+    pub items_per_poll: i64,
+    /// The longest a single send can take before it fails
+    pub send_timeout: Duration,
+    pub max_attempts: i32,
+    pub first_retry_delay_seconds: i64,
+    pub max_retry_delay_seconds: i64,
+}
+
+impl OutboxPolicy {
+    /// A claimed item is skipped by other workers for this long, in case the worker that claimed it dies mid-attempt. The
+    /// items in a batch are sent one after another, so the lease outlasts a batch where every send times out.
+    pub const fn claim_lease_seconds(&self) -> f64 {
+        (self.items_per_poll as u64 * self.send_timeout.as_secs() + 60) as f64
+    }
+
+    /// How long to wait before the next attempt, after the given number of failed attempts. The delay doubles every
+    /// attempt, up to max_retry_delay_seconds.
+    pub fn retry_delay(&self, failed_attempts: i32) -> chrono::Duration {
+        let doublings = failed_attempts.saturating_sub(1).clamp(0, 20) as u32;
+        chrono::Duration::seconds((self.first_retry_delay_seconds << doublings).min(self.max_retry_delay_seconds))
+    }
+
+    /// Where an item stands once it has had the given number of attempts, the last of which did or didn't succeed
+    pub fn outcome(&self, attempts: i32, succeeded: bool) -> AttemptOutcome {
+        match (succeeded, attempts >= self.max_attempts) {
+            (true, _) => AttemptOutcome::Succeeded,
+            (false, true) => AttemptOutcome::GaveUp,
+            (false, false) => AttemptOutcome::Retrying,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttemptOutcome {
+    Succeeded,
+    /// Failed, and is tried again at its next attempt
+    Retrying,
+    /// Failed for the last time, after max_attempts
+    GaveUp,
+}
+
+/// What is recorded on an item after an attempt, along with what the outbox itself keeps about the attempt
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecordedAttempt {
+    /// Including this one
+    pub attempts: i32,
+    pub outcome: AttemptOutcome,
+    pub next_attempt_at: DateTime<Utc>,
+}
+
+/// A table of items to send. Implementations hold whatever they need to reach the database and send an item.
+pub(crate) trait Outbox {
+    type Item;
+    /// What happened when an item was sent
+    type Attempt;
+
+    const POLICY: OutboxPolicy;
+    /// What is being sent, for log messages, IE "webhooks"
+    const NAME: &'static str;
+
+    /// Claims up to POLICY.items_per_poll items that are due by pushing their next attempt POLICY.claim_lease_seconds() out
+    async fn claim_due(&self) -> Result<Vec<Self::Item>, sqlx::Error>;
+
+    /// How many attempts the item had before this one
+    fn previous_attempts(item: &Self::Item) -> i32;
+
+    async fn send(&self, item: &Self::Item) -> Self::Attempt;
+
+    fn succeeded(attempt: &Self::Attempt) -> bool;
+
+    async fn record_attempt(&self, item: &Self::Item, attempt: Self::Attempt, recorded: RecordedAttempt) -> Result<(), sqlx::Error>;
+}
+
+/// Sends every item that is due, returning how many were attempted
+pub(crate) async fn deliver_due<O: Outbox>(outbox: &O) -> Result<usize, sqlx::Error> {
+    let items = outbox.claim_due().await?;
+
+    for item in &items {
+        let attempt = outbox.send(item).await;
+        let attempts = O::previous_attempts(item) + 1;
+        let recorded = RecordedAttempt {
+            attempts,
+            outcome: O::POLICY.outcome(attempts, O::succeeded(&attempt)),
+            next_attempt_at: Utc::now() + O::POLICY.retry_delay(attempts),
+        };
+        outbox.record_attempt(item, attempt, recorded).await?;
+    }
+
+    Ok(items.len())
+}
+
+/// Runs for the life of the server, working through the outbox
+pub(crate) async fn run_outbox<O: Outbox>(outbox: O) {
+    loop {
+        match deliver_due(&outbox).await {
+            // Keep going straight away if there may be more due
+            Ok(attempted) if attempted as i64 == O::POLICY.items_per_poll => continue,
+            Ok(_) => (),
+            Err(e) => eprintln!("Failed to deliver {}: {}", O::NAME, e),
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: OutboxPolicy = OutboxPolicy {
+        items_per_poll: 20,
+        send_timeout: Duration::from_secs(10),
+        max_attempts: 3,
+        first_retry_delay_seconds: 30,
+        max_retry_delay_seconds: 60 * 60,
+    };
+
+    #[test]
+    fn leases_outlast_a_batch_of_timeouts() {
+        assert!(POLICY.claim_lease_seconds() > (POLICY.items_per_poll as u64 * POLICY.send_timeout.as_secs()) as f64);
+    }
+
+    #[test]
+    fn retries_back_off_exponentially() {
+        assert_eq!(POLICY.retry_delay(1), chrono::Duration::seconds(30));
+        assert_eq!(POLICY.retry_delay(2), chrono::Duration::seconds(60));
+        assert_eq!(POLICY.retry_delay(5), chrono::Duration::seconds(480));
+        assert_eq!(POLICY.retry_delay(20), chrono::Duration::seconds(POLICY.max_retry_delay_seconds));
+        assert_eq!(POLICY.retry_delay(i32::MAX), chrono::Duration::seconds(POLICY.max_retry_delay_seconds));
+    }
+
+    #[test]
+    fn failures_are_retried_until_the_last_attempt() {
+        assert_eq!(POLICY.outcome(1, true), AttemptOutcome::Succeeded);
+        assert_eq!(POLICY.outcome(1, false), AttemptOutcome::Retrying);
+        assert_eq!(POLICY.outcome(POLICY.max_attempts - 1, false), AttemptOutcome::Retrying);
+        assert_eq!(POLICY.outcome(POLICY.max_attempts, false), AttemptOutcome::GaveUp);
+        assert_eq!(POLICY.outcome(POLICY.max_attempts, true), AttemptOutcome::Succeeded);
+    }
+}
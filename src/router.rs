@@ -7,9 +7,12 @@ use axum::{
 };
 
 use crate::{
-    auth::middleware::{check_auth_middleware, require_auth_middleware}, 
+    api::{
+        api_fallback, delete_api_queue, get_api_queue, get_api_test, get_api_tests, get_openapi_document, get_api_test_definition, get_api_test_definitions, get_api_testee, get_api_testee_tests, get_api_testees, post_api_grade_test, post_api_queue, post_api_resend_email, post_api_test, require_administer_tests_scope_middleware, require_api_auth_middleware, require_read_results_scope_middleware, require_write_queue_scope_middleware
+    },
+    auth::middleware::{check_auth_middleware, require_admin_middleware, require_auth_middleware, require_proctor_middleware}, 
     views::{
-        delete_dequeue, get_broad_test_results, get_contact_page, get_dashboard_page, get_google_oauth_callback, get_google_oauth_init_flow, get_home_page, get_login_page, get_logout_page, get_queue, get_search_testee_form, get_signup_page, get_test_page, get_test_results, get_test_summaries, get_user_dropdown, post_grade_test, post_login_form, post_queue, post_signup_form, post_test_form
+        delete_dequeue, delete_linked_account, delete_session, delete_user_session, get_api_keys_page, get_broad_test_results, get_broad_test_results_export, get_contact_page, get_dashboard_page, get_edit_user_page, get_emails_page, get_forgot_password_page, get_import_page, get_google_oauth_callback, get_google_oauth_init_flow, get_google_oauth_link_flow, get_home_page, get_linked_accounts_page, get_login_page, get_logout_page, get_oidc_callback, get_oidc_init_flow, get_oidc_link_flow, get_queue, get_reset_password_page, get_sessions_page, get_search_testee_form, get_signup_page, get_test_page, get_test_results, get_test_results_pdf, get_test_summaries, get_user_dropdown, get_users_page, get_webhooks_page, post_api_key_form, post_delete_webhook_endpoint, post_disable_user, post_edit_user_form, post_enable_user, post_force_logout_user, post_forgot_password_form, post_grade_test, post_import_form, post_login_form, post_ping_webhook_endpoint, post_queue, post_refresh_tokens, post_reload_test_definitions, post_reset_password_form, post_resend_email_job, post_resend_results_email, post_reset_user_password_form, post_retry_webhook_delivery, post_revoke_all_sessions, post_revoke_api_key, post_signup_form, post_test_form, post_webhook_form
     },
     AppState
 };
@@ -19,15 +22,46 @@ use tower_http::services::ServeDir;
 
 pub fn create_router(app_state: Arc<AppState>) -> Router {
     Router::new()
-        .route("/dashboard", get(get_dashboard_page))
-        .route("/logout", get(get_logout_page))
-        .route("/administer-test/:test_index", get(get_test_page).post(post_test_form))
-        .route("/private/grade-test/:test_index", post(post_grade_test))
-        // .route("/api/v1/test-results/:test_id", get(get_json_test_results))
+        .route("/admin/reload-test-definitions", post(post_reload_test_definitions))
+        .route("/admin/api-keys", get(get_api_keys_page).post(post_api_key_form))
+        .route("/admin/api-keys/:api_key_id/revoke", post(post_revoke_api_key))
+        .route("/admin/import", get(get_import_page).post(post_import_form))
+        .route("/admin/emails", get(get_emails_page))
+        .route("/admin/emails/:job_id/resend", post(post_resend_email_job))
+        .route("/admin/users", get(get_users_page))
+        .route("/admin/webhooks", get(get_webhooks_page).post(post_webhook_form))
+        .route("/admin/webhooks/:endpoint_id/delete", post(post_delete_webhook_endpoint))
+        .route("/admin/webhooks/:endpoint_id/ping", post(post_ping_webhook_endpoint))
+        .route("/admin/webhooks/deliveries/:delivery_id/retry", post(post_retry_webhook_delivery))
+        .route("/admin/users/:user_id", get(get_edit_user_page).post(post_edit_user_form))
+        .route("/admin/users/:user_id/password", post(post_reset_user_password_form))
+        .route("/admin/users/:user_id/disable", post(post_disable_user))
+        .route("/admin/users/:user_id/enable", post(post_enable_user))
+        .route("/admin/users/:user_id/logout", post(post_force_logout_user))
+        .route("/admin/users/:user_id/sessions/:session_id", delete(delete_user_session))
+    .route_layer(middleware::from_fn(require_admin_middleware))
+    // Anything above this line is only available to admins
+
+        .route("/administer-test/:test_definition_id", get(get_test_page).post(post_test_form))
+        .route("/private/grade-test/:test_definition_id", post(post_grade_test))
         .route("/search-testee", get(get_search_testee_form))
         .route("/test-summaries/:testee_id", get(get_test_summaries))
+        .route("/test-summaries/:testee_id/email", post(post_resend_results_email))
+    .route_layer(middleware::from_fn(require_proctor_middleware))
+    // Anything above this line is only available to proctors and admins, front desk staff can use everything below it
+
+        .route("/dashboard", get(get_dashboard_page))
+        .route("/logout", get(get_logout_page))
+        .route("/sessions", get(get_sessions_page))
+        .route("/sessions/:session_id", delete(delete_session))
+        .route("/sessions/revoke-all", post(post_revoke_all_sessions))
+        .route("/linked-accounts", get(get_linked_accounts_page))
+        .route("/linked-accounts/:identity_id", delete(delete_linked_account))
+        .route("/auth/google/link", get(get_google_oauth_link_flow))
+        .route("/auth/oidc/:provider_id/link", get(get_oidc_link_flow))
         .route("/queue/dequeue", delete(delete_dequeue))
         .route("/broad-test-results", get(get_broad_test_results))
+        .route("/broad-test-results/export", get(get_broad_test_results_export))
         
     .route_layer(middleware::from_fn_with_state(app_state.clone(), require_auth_middleware))
     // Anything above this line will redirect to the login page if the user is not logged in
@@ -36,17 +70,61 @@ pub fn create_router(app_state: Arc<AppState>) -> Router {
         .route("/contact", get(get_contact_page))
         .route("/sign-up", get(get_signup_page).post(post_signup_form))
         .route("/login", get(get_login_page).post(post_login_form))
+        .route("/forgot-password", get(get_forgot_password_page).post(post_forgot_password_form))
+        .route("/reset-password", get(get_reset_password_page).post(post_reset_password_form))
         .route("/queue", get(get_queue).post(post_queue))
         .route("/private/user-dropdown", get(get_user_dropdown)) 
         .route("/test-results/:test_id", get(get_test_results))
+        .route("/test-results/:test_id/pdf", get(get_test_results_pdf))
     .route_layer(middleware::from_fn_with_state(app_state.clone(), check_auth_middleware))
     // Anything above this line checks if the user is logged in and adds an AuthStatus extension to the request
 
     .route("/auth/google", get(get_google_oauth_init_flow))
     .route("/auth/google/callback", get(get_google_oauth_callback))
+    .route("/auth/oidc/:provider_id", get(get_oidc_init_flow))
+    .route("/auth/oidc/:provider_id/callback", get(get_oidc_callback))
+    .route("/auth/refresh", post(post_refresh_tokens))
+
+    .nest("/api/v1", create_api_router(app_state.clone()))
 
 
     .with_state(app_state)
 
     .nest_service("/static", ServeDir::new("static/"))
-}
\ No newline at end of file
+}
+
+/// The JSON API, authenticated with a bearer token or an API key, and its OpenAPI document, which is public. Errors are
+/// returned as JSON with a matching status code rather than the HTML pages' OK status. Each group of routes needs its own
+/// scope, so they are merged instead of layered.
+fn create_api_router(app_state: Arc<AppState>) -> Router<Arc<AppState>> {
+    let read_results_routes = Router::new()
+        .route("/testees", get(get_api_testees))
+        .route("/tests", get(get_api_tests))
+        .route("/testees/:testee_id", get(get_api_testee))
+        .route("/testees/:testee_id/tests", get(get_api_testee_tests))
+        .route("/tests/:test_id", get(get_api_test))
+    .route_layer(middleware::from_fn(require_read_results_scope_middleware));
+
+    let write_queue_routes = Router::new()
+        .route("/queue", get(get_api_queue).post(post_api_queue).delete(delete_api_queue))
+    .route_layer(middleware::from_fn(require_write_queue_scope_middleware));
+
+    let administer_tests_routes = Router::new()
+        .route("/test-definitions/:test_definition_id/tests", post(post_api_test))
+        .route("/test-definitions/:test_definition_id/grade", post(post_api_grade_test))
+        .route("/testees/:testee_id/email", post(post_api_resend_email))
+    .route_layer(middleware::from_fn(require_administer_tests_scope_middleware));
+
+    Router::new()
+        .merge(read_results_routes)
+        .merge(write_queue_routes)
+        .merge(administer_tests_routes)
+        // Every caller needs the test definitions to know what to enqueue or submit
+        .route("/test-definitions", get(get_api_test_definitions))
+        .route("/test-definitions/:test_definition_id", get(get_api_test_definition))
+    .route_layer(middleware::from_fn_with_state(app_state, require_api_auth_middleware))
+
+        .route("/openapi.json", get(get_openapi_document))
+
+    .fallback(api_fallback)
+}
@@ -0,0 +1,135 @@
+use std::sync::{Arc, RwLock};
+
+use base64::{engine::general_purpose, Engine as _};
+use sqlx::PgPool;
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, sync::mpsc};
+
+use crate::{
+    auth::model::{Role, User},
+    config::SecretsConfig,
+    exam::models::{SMTPConfig, TestDefinitionYaml},
+    AppState,
+};
+
+// #######################################################################################################################################################
+// Test Helpers
+// #######################################################################################################################################################
+
+// Tests that need the database get a fresh one from #[sqlx::test], which reads DATABASE_URL. Tests that need Redis use the
+// server at REDIS_URL. Every Redis key the app writes contains a random id, so tests can share one server.
+
+/// An app with no test definitions and no email or external sign in, signing tokens with the key pairs next to this file
+pub(crate) fn test_app_state(pool: PgPool) -> Arc<AppState> {
+    Arc::new(build_test_app_state(pool, None))
+}
+
+/// Like test_app_state, but sending email through the SMTP server on the given local port
+pub(crate) fn test_app_state_with_smtp(pool: PgPool, smtp_port: u16) -> Arc<AppState> {
+    Arc::new(build_test_app_state(pool, Some(local_smtp_config(smtp_port))))
+}
+
+fn build_test_app_state(pool: PgPool, smtp_config: Option<SMTPConfig>) -> AppState {
+    let redis_url = std::env::var("REDIS_URL").expect("REDIS_URL must point at a Redis server to run the tests");
+    let pem = |key: &str| general_purpose::STANDARD.encode(key);
+
+    AppState {
+        db: pool,
+        env: SecretsConfig {
+            is_demo_mode: false,
+            signup_licensing_key: "licensing-key".to_string(),
+            queue_signup_key: "queue-key".to_string(),
+            server_port: 8000,
+            database_url: String::new(),
+            redis_url: redis_url.clone(),
+            public_base_url: "https://dancexam.example.com".to_string(),
+            access_token_private_key: pem(include_str!("testing/access_token_private.pem")),
+            access_token_public_key: pem(include_str!("testing/access_token_public.pem")),
+            access_token_expires_in: "15m".to_string(),
+            access_token_max_age: 15,
+            refresh_token_private_key: pem(include_str!("testing/refresh_token_private.pem")),
+            refresh_token_public_key: pem(include_str!("testing/refresh_token_public.pem")),
+            refresh_token_expires_in: "60m".to_string(),
+            refresh_token_max_age: 60,
+        },
+        redis_client: redis::Client::open(redis_url).expect("REDIS_URL should be a Redis URL"),
+        smtp_mailer: smtp_config.as_ref().map(|smtp_config| smtp_config.build_transport().unwrap()),
+        smtp_config,
+        google_oauth_config: None,
+        oidc_providers: Vec::new(),
+        http_client: oauth2::reqwest::Client::new(),
+        test_configurations: RwLock::new(Arc::new(TestDefinitionYaml { tests: Vec::new() })),
+        test_definitions_reload_lock: tokio::sync::Mutex::new(()),
+    }
+}
+
+/// Starts an SMTP sink on a local port that records the message data it is sent, or rejects every message with a
+/// temporary failure
+pub(crate) async fn spawn_smtp_sink(accept: bool) -> (u16, mpsc::UnboundedReceiver<String>) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                let (reader, mut writer) = stream.into_split();
+                let mut lines = BufReader::new(reader).lines();
+                writer.write_all(b"220 sink ready\r\n").await.unwrap();
+
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let command = line.to_ascii_uppercase();
+                    let reply: &[u8] = if command.starts_with("EHLO") || command.starts_with("HELO") {
+                        b"250 sink\r\n"
+                    } else if command.starts_with("MAIL") && !accept {
+                        b"451 try again later\r\n"
+                    } else if command.starts_with("DATA") {
+                        writer.write_all(b"354 go ahead\r\n").await.unwrap();
+                        let mut data = String::new();
+                        while let Ok(Some(data_line)) = lines.next_line().await {
+                            if data_line == "." {
+                                break;
+                            }
+                            data.push_str(&data_line);
+                            data.push('\n');
+                        }
+                        sender.send(data).unwrap();
+                        b"250 queued\r\n"
+                    } else if command.starts_with("QUIT") {
+                        writer.write_all(b"221 bye\r\n").await.unwrap();
+                        break;
+                    } else {
+                        b"250 ok\r\n"
+                    };
+                    writer.write_all(reply).await.unwrap();
+                }
+            });
+        }
+    });
+
+    (port, receiver)
+}
+
+pub(crate) fn local_smtp_config(port: u16) -> SMTPConfig {
+    SMTPConfig {
+        server_host: "127.0.0.1".to_string(),
+        server_port: Some(port),
+        use_tls: false,
+        user_login: "unused".to_string(),
+        user_password: "unused".to_string(),
+        user_email: "results@example.com".to_string(),
+    }
+}
+
+/// Adds an account with a password nobody knows
+pub(crate) async fn create_test_user(pool: &PgPool, role: Role, email: &str) -> User {
+    sqlx::query_as(
+        "INSERT INTO users (first_name, last_name, email, password, role) VALUES ('Test', 'User', $1, 'unused', $2)
+        RETURNING id, first_name, last_name, email, password, created_at, updated_at, role, disabled_at",
+    )
+    .bind(email)
+    .bind(role)
+    .fetch_one(pool)
+    .await
+    .unwrap()
+}
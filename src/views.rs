@@ -2,24 +2,32 @@ use std::{collections::HashMap, default, sync::Arc};
 
 use askama_axum::Template; // bring trait in scope
 use axum::{
-    extract::{Host, Path, Query, State}, http::{HeaderMap, StatusCode}, response::{Html, IntoResponse, Redirect}, Extension, Form, Json
+    extract::{Multipart, Path, Query, State}, http::{header, HeaderMap, StatusCode}, response::{Html, IntoResponse, Redirect, Response}, Extension, Form, Json
 };
 use axum_extra::extract::CookieJar;
 use chrono::NaiveDateTime;
-use lettre::{transport::smtp::authentication::Credentials, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
 use serde::Deserialize;
 use serde_json::json;
+use strum::IntoEnumIterator;
 use uuid::Uuid;
 
 use crate::{
     auth::{
-        handlers::{google_oauth_callback_handler, google_oauth_init_flow_handler, login_user_handler, logout_handler, register_user_handler, GoogleOAuthCallbackParams}, 
+        api_key::{create_api_key, fetch_api_keys, revoke_api_key},
+        handlers::{add_token_cookies, fetch_user_by_id, fetch_users, google_oauth_callback_handler, google_oauth_init_flow_handler, google_oauth_link_flow_handler, is_password_reset_token_valid, login_user_handler, logout_handler, register_user_handler, request_password_reset_handler, reset_forgotten_password_handler, refresh_session, remove_token_cookies, reset_user_password_handler, revoke_user_tokens, set_user_disabled_handler, update_user_handler, GoogleOAuthCallbackParams, RefreshedSession, PASSWORD_RESET_TOKEN_MAX_AGE_MINUTES}, 
         middleware::{AuthError, AuthStatus},
-        model::User
+        identity::{fetch_user_identities, unlink_identity},
+        model::{ApiKey, ApiKeyScope, Role, User, UserIdentity},
+        oidc::{oidc_callback_handler, oidc_init_flow_handler, oidc_link_flow_handler, OidcCallbackParams},
+        session::{fetch_user_sessions, revoke_session, Session, SessionContext}
     }, exam::{
-        handlers::{create_testee, dequeue_testee, enqueue_testee, fetch_test_results_by_id, fetch_testee_by_id, fetch_testee_tests_by_id, fetch_tests_by_status, fetch_unique_test_names, parse_test_form_data, retrieve_queue, save_test_to_database, search_for_testee, send_email, TestError}, 
-        models::{FullTestSummary, Proctor, Test, TestGradeSummary, TestListItem, Testee}
-    }, filters, AppState
+        export::{build_competency_export, build_results_export, ExportFormat, ExportReport},
+        import::{import_records, parse_import_records, ImportFormat, ImportReport},
+        pdf::{pdf_file_name, PdfDocumentKind},
+        handlers::{create_testee, dequeue_testee, fetch_test_definition, reload_test_definitions, enqueue_testee, fetch_test_results_by_id, fetch_testee_by_id, fetch_testee_tests_by_id, fetch_tests_by_status, fetch_unique_test_names, retrieve_queue, save_test_to_database, search_for_testee, TestError}, 
+        models::{FullTestSummary, Proctor, QueueItem, SubmissionError, Test, TestGradeSummary, TestListItem, Testee},
+        submission::{grade_submission, TestSubmission}
+    }, config::OidcProviderConfig, emails::{enqueue_results_email, fetch_email_jobs, request_results_email, resend_email_job, EmailJob, EmailJobStatus}, filters, webhooks::{create_webhook_endpoint, delete_webhook_endpoint, fetch_webhook_deliveries, fetch_webhook_endpoints, ping_webhook_endpoint, retry_webhook_delivery, WebhookDelivery, WebhookDeliveryStatus, WebhookEndpoint, WebhookEvent}, AppState
 };
 
 /// A helper function to handle errors consistently
@@ -92,10 +100,16 @@ pub async fn get_user_dropdown(
 #[derive(Template)]
 #[template(path = "./auth_templates/sign-up.html")] 
 pub struct SignUpTemplate {
+    google_auto_provision_enabled: bool,
+    /// Only the providers that can create accounts
+    oidc_providers: Vec<OidcProviderConfig>,
 }
 
-pub async fn get_signup_page() -> impl IntoResponse {
-    let template = SignUpTemplate {};
+pub async fn get_signup_page(State(data): State<Arc<AppState>>) -> impl IntoResponse {
+    let template = SignUpTemplate {
+        google_auto_provision_enabled: data.google_oauth_config.as_ref().is_some_and(|config| config.auto_provision.enabled),
+        oidc_providers: data.oidc_providers.iter().map(|provider| provider.config.clone()).filter(|config| config.auto_provision.enabled).collect(),
+    };
 
     (StatusCode::OK, Html(template.render().unwrap()))
 }
@@ -148,10 +162,17 @@ pub async fn post_signup_form(
 pub struct LoginTemplate {
     is_demo_mode: bool,
     google_oauth_enabled: bool,
+    oidc_providers: Vec<OidcProviderConfig>,
+    password_reset_enabled: bool,
 }
 
 pub async fn get_login_page(State(data): State<Arc<AppState>>) -> impl IntoResponse  {
-    let template: LoginTemplate = LoginTemplate {is_demo_mode: data.env.is_demo_mode, google_oauth_enabled: data.google_oauth_config.is_some()};
+    let template: LoginTemplate = LoginTemplate {
+        is_demo_mode: data.env.is_demo_mode,
+        google_oauth_enabled: data.google_oauth_config.is_some(),
+        oidc_providers: data.oidc_providers.iter().map(|provider| provider.config.clone()).collect(),
+        password_reset_enabled: data.smtp_mailer.is_some(),
+    };
 
     (StatusCode::OK, Html(template.render().unwrap()))
 }
@@ -167,27 +188,37 @@ pub struct LoginForm {
 pub async fn post_login_form(
     cookie_jar: CookieJar,
     State(data): State<Arc<AppState>>,
+    session_context: SessionContext,
     Form(login) : Form<LoginForm>,
 ) -> impl IntoResponse {
 
-    match login_user_handler(data, cookie_jar, login.email, login.password).await {
+    match login_user_handler(data, cookie_jar, login.email, login.password, session_context).await {
         Ok(response) => return response.into_response(),
         Err(e) => match e {
             AuthError::InvalidEmailOrPassword => return (StatusCode::OK, Html("<h1>Invalid Email or Password</h1>")).into_response(),
+            AuthError::AccountDisabled => (StatusCode::OK, Html("<h1>This account has been disabled. Contact an admin if you need access.</h1>")).into_response(),
             AuthError::InternalServerError(ee) => return (StatusCode::OK, Html(format!("Error: {:?}", ee))).into_response(),
             _ => return (StatusCode::OK, Html("<h1>Error: Unexpected error occurred</h1>")).into_response()
         }
     }
 }
 
+/// The licensing key is supplied from the sign-up page, to allow creating an account for an unknown external account
+#[derive(Debug, Deserialize)]
+pub struct OAuthInitParams {
+    licensing_key: Option<String>,
+}
+
 pub async fn get_google_oauth_init_flow(
     State(data): State<Arc<AppState>>,
     cookie_jar: CookieJar,
+    Query(params): Query<OAuthInitParams>,
 ) -> impl IntoResponse {
 
-    match google_oauth_init_flow_handler(data, cookie_jar).await {
+    match google_oauth_init_flow_handler(data, cookie_jar, params.licensing_key).await {
         Ok(response) => return response.into_response(),
         Err(e) => match e {
+            AuthError::InvalidLicensingKey => (StatusCode::OK, Html("<h1>Invalid Licensing Key</h1>")).into_response(),
             AuthError::OAuthError(ee) => return (StatusCode::OK, Html(format!("OAuth Error: {:?}", ee))).into_response(),
             AuthError::InternalServerError(ee) => return (StatusCode::OK, Html(format!("Error: {:?}", ee))).into_response(),
             _ => return (StatusCode::OK, Html("<h1>Error: Unexpected error occurred</h1>")).into_response()
@@ -199,21 +230,154 @@ pub async fn get_google_oauth_init_flow(
 pub async fn get_google_oauth_callback(
     cookie_jar: CookieJar,
     State(data): State<Arc<AppState>>,
+    session_context: SessionContext,
     Query(callback_params): Query<GoogleOAuthCallbackParams>,
 ) -> impl IntoResponse {
 
-    match google_oauth_callback_handler(data, cookie_jar, callback_params).await {
+    match google_oauth_callback_handler(data, cookie_jar, callback_params, session_context).await {
         Ok(response) => return response.into_response(),
         Err(e) => match e {
             AuthError::OAuthError(ee) => return (StatusCode::OK, Html(format!("OAuth Error: {:?}", ee))).into_response(),
             AuthError::InternalServerError(ee) => return (StatusCode::OK, Html(format!("Error: {:?}", ee))).into_response(),
+            AuthError::AccountDisabled => (StatusCode::OK, Html("<h1>This account has been disabled. Contact an admin if you need access.</h1>")).into_response(),
             AuthError::AccountNotFound => return (StatusCode::OK, Html("<h1>You do not yet have an account. Create an account on our sign-up page using your Google account's email address and in the future you will be able to sign in with Google.</h1>".to_string())).into_response(),
+            AuthError::IdentityLinkedToAnotherUser => (StatusCode::OK, Html("<h1>That Google account is already linked to another account.</h1>")).into_response(),
+            AuthError::CSRFTokenMismatch => (StatusCode::OK, Html("<h1>The sign in with Google expired or was already completed. Try again.</h1>")).into_response(),
             _ => return (StatusCode::OK, Html("<h1>Error: Unexpected error occurred</h1>")).into_response()
         }
     }
     
 }
 
+// #######################################################################################################################################################
+// OpenID Connect endpoints
+// #######################################################################################################################################################
+
+/// Errors from signing in with an external provider. These pages aren't loaded with HTMX, so the status codes don't matter.
+fn external_sign_in_error_response(error: AuthError) -> axum::response::Response {
+    match error {
+        AuthError::OAuthError(e) => (StatusCode::OK, Html(format!("OAuth Error: {:?}", e))).into_response(),
+        AuthError::InternalServerError(e) => (StatusCode::OK, Html(format!("Error: {:?}", e))).into_response(),
+        AuthError::InvalidLicensingKey => (StatusCode::OK, Html("<h1>Invalid Licensing Key</h1>")).into_response(),
+        AuthError::AccountDisabled => (StatusCode::OK, Html("<h1>This account has been disabled. Contact an admin if you need access.</h1>")).into_response(),
+        AuthError::AccountNotFound => (StatusCode::OK, Html("<h1>You do not yet have an account. Create an account on our sign-up page, log in, and link this account from the Linked Accounts page.</h1>")).into_response(),
+        AuthError::IdentityLinkedToAnotherUser => (StatusCode::OK, Html("<h1>That account is already linked to another account.</h1>")).into_response(),
+        AuthError::CSRFTokenMismatch => (StatusCode::OK, Html("<h1>The sign in expired or was already completed. Try again.</h1>")).into_response(),
+        _ => (StatusCode::OK, Html("<h1>Error: Unexpected error occurred</h1>")).into_response(),
+    }
+}
+
+pub async fn get_oidc_init_flow(
+    State(data): State<Arc<AppState>>,
+    cookie_jar: CookieJar,
+    Path(provider_id): Path<String>,
+    Query(params): Query<OAuthInitParams>,
+) -> impl IntoResponse {
+    match oidc_init_flow_handler(data, cookie_jar, provider_id, params.licensing_key).await {
+        Ok(response) => response.into_response(),
+        Err(e) => external_sign_in_error_response(e),
+    }
+}
+
+pub async fn get_oidc_link_flow(
+    State(data): State<Arc<AppState>>,
+    cookie_jar: CookieJar,
+    Extension(auth_status): Extension<AuthStatus>,
+    Path(provider_id): Path<String>,
+) -> impl IntoResponse {
+    let authorized_user = match auth_status {
+        AuthStatus::Authorized(user) => user,
+        AuthStatus::Unauthorized(_) => panic!("If this happens, check your auth middleware application.")
+    };
+
+    match oidc_link_flow_handler(data, cookie_jar, provider_id, authorized_user).await {
+        Ok(response) => response.into_response(),
+        Err(e) => external_sign_in_error_response(e),
+    }
+}
+
+pub async fn get_oidc_callback(
+    cookie_jar: CookieJar,
+    State(data): State<Arc<AppState>>,
+    session_context: SessionContext,
+    Path(provider_id): Path<String>,
+    Query(callback_params): Query<OidcCallbackParams>,
+) -> impl IntoResponse {
+    match oidc_callback_handler(data, cookie_jar, provider_id, callback_params, session_context).await {
+        Ok(response) => response,
+        Err(e) => external_sign_in_error_response(e),
+    }
+}
+
+// #######################################################################################################################################################
+// linked_accounts.html
+// #######################################################################################################################################################
+
+#[derive(Template)]
+#[template(path = "./auth_templates/linked_accounts.html")]
+pub struct LinkedAccountsTemplate {
+    identities: Vec<UserIdentity>,
+    google_oauth_enabled: bool,
+    oidc_providers: Vec<OidcProviderConfig>,
+}
+
+pub async fn get_linked_accounts_page(
+    State(data): State<Arc<AppState>>,
+    Extension(auth_status): Extension<AuthStatus>,
+) -> impl IntoResponse {
+    let authorized_user = match auth_status {
+        AuthStatus::Authorized(user) => user,
+        AuthStatus::Unauthorized(_) => panic!("If this happens, check your auth middleware application.")
+    };
+
+    match fetch_user_identities(authorized_user.user.id, &data.db).await {
+        Ok(identities) => {
+            let template = LinkedAccountsTemplate {
+                identities,
+                google_oauth_enabled: data.google_oauth_config.is_some(),
+                oidc_providers: data.oidc_providers.iter().map(|provider| provider.config.clone()).collect(),
+            };
+            (StatusCode::OK, Html(template.render().unwrap())).into_response()
+        },
+        Err(e) => error_response(&format!("Error: {:?}", e)).into_response(),
+    }
+}
+
+/// Sends the logged in user to Google to pick the account to link
+pub async fn get_google_oauth_link_flow(
+    State(data): State<Arc<AppState>>,
+    cookie_jar: CookieJar,
+    Extension(auth_status): Extension<AuthStatus>,
+) -> impl IntoResponse {
+    let authorized_user = match auth_status {
+        AuthStatus::Authorized(user) => user,
+        AuthStatus::Unauthorized(_) => panic!("If this happens, check your auth middleware application.")
+    };
+
+    match google_oauth_link_flow_handler(data, cookie_jar, authorized_user).await {
+        Ok(response) => response.into_response(),
+        Err(AuthError::InternalServerError(e)) => error_response(&format!("Error: {:?}", e)).into_response(),
+        Err(e) => error_response(&format!("Unexpected error: {:?}", e)).into_response(),
+    }
+}
+
+/// Unlinks one of the user's identities. Returns an empty body so that HTMX removes the identity's table row.
+pub async fn delete_linked_account(
+    State(data): State<Arc<AppState>>,
+    Extension(auth_status): Extension<AuthStatus>,
+    Path(identity_id): Path<Uuid>,
+) -> impl IntoResponse {
+    let authorized_user = match auth_status {
+        AuthStatus::Authorized(user) => user,
+        AuthStatus::Unauthorized(_) => panic!("If this happens, check your auth middleware application.")
+    };
+
+    match unlink_identity(authorized_user.user.id, identity_id, &data.db).await {
+        Ok(_) => (StatusCode::OK, Html("")).into_response(),
+        Err(e) => (StatusCode::OK, Html(format!("<tr><td colspan=\"4\">Error: {:?}</td></tr>", e))).into_response(),
+    }
+}
+
 // #######################################################################################################################################################
 // logout endpoint
 // #######################################################################################################################################################
@@ -241,6 +405,198 @@ pub async fn get_logout_page(
     }
 }
 
+// #######################################################################################################################################################
+// sessions.html
+// #######################################################################################################################################################
+
+#[derive(Template)]
+#[template(path = "./auth_templates/sessions.html")]
+pub struct SessionsTemplate {
+    sessions: Vec<Session>,
+    current_session_id: Option<Uuid>,
+    revoke_url_prefix: String,
+}
+
+pub async fn get_sessions_page(
+    State(data): State<Arc<AppState>>,
+    Extension(auth_status): Extension<AuthStatus>,
+) -> impl IntoResponse {
+    let authorized_user = match auth_status {
+        AuthStatus::Authorized(user) => user,
+        AuthStatus::Unauthorized(_) => panic!("If this happens, check your auth middleware application.")
+    };
+
+    match fetch_user_sessions(&data, authorized_user.user.id).await {
+        Ok(sessions) => {
+            let template = SessionsTemplate {
+                sessions,
+                current_session_id: authorized_user.session_id,
+                revoke_url_prefix: "/sessions".to_string(),
+            };
+            (StatusCode::OK, Html(template.render().unwrap())).into_response()
+        },
+        Err(e) => error_response(&format!("Error: {:?}", e)).into_response(),
+    }
+}
+
+/// Revokes one of the user's own sessions. Returns an empty body so that HTMX removes the session's table row.
+pub async fn delete_session(
+    State(data): State<Arc<AppState>>,
+    Extension(auth_status): Extension<AuthStatus>,
+    Path(session_id): Path<Uuid>,
+) -> impl IntoResponse {
+    let authorized_user = match auth_status {
+        AuthStatus::Authorized(user) => user,
+        AuthStatus::Unauthorized(_) => panic!("If this happens, check your auth middleware application.")
+    };
+
+    match revoke_session(&data, authorized_user.user.id, session_id).await {
+        Ok(_) => (StatusCode::OK, Html("")).into_response(),
+        Err(e) => (StatusCode::OK, Html(format!("<tr><td colspan=\"5\">Error: {:?}</td></tr>", e))).into_response(),
+    }
+}
+
+/// Logs the user out of every session, including the one making the request.
+pub async fn post_revoke_all_sessions(
+    cookie_jar: CookieJar,
+    State(data): State<Arc<AppState>>,
+    Extension(auth_status): Extension<AuthStatus>,
+) -> impl IntoResponse {
+    let authorized_user = match auth_status {
+        AuthStatus::Authorized(user) => user,
+        AuthStatus::Unauthorized(_) => panic!("If this happens, check your auth middleware application.")
+    };
+
+    match revoke_user_tokens(&data, authorized_user.user.id).await {
+        Ok(_) => (remove_token_cookies(cookie_jar), Redirect::to("/login")).into_response(),
+        Err(e) => error_response(&format!("Error: {:?}", e)).into_response(),
+    }
+}
+
+// #######################################################################################################################################################
+// refresh endpoint
+// #######################################################################################################################################################
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshTokenRequest {
+    refresh_token: String,
+}
+
+/// Exchanges a refresh token for a new access and refresh token pair. Browsers are refreshed automatically by the auth middleware,
+/// so this is for API clients. The refresh token is read from the JSON body, falling back to the refresh_token cookie.
+pub async fn post_refresh_tokens(
+    cookie_jar: CookieJar,
+    State(data): State<Arc<AppState>>,
+    session_context: SessionContext,
+    body: Option<Json<RefreshTokenRequest>>,
+) -> impl IntoResponse {
+    let refresh_token = match body {
+        Some(Json(request)) => request.refresh_token,
+        None => match cookie_jar.get("refresh_token") {
+            Some(cookie) => cookie.value().to_string(),
+            None => return (StatusCode::UNAUTHORIZED, Json(json!({"error": "No refresh token was provided."}))).into_response(),
+        },
+    };
+
+    match refresh_session(&data, &refresh_token, session_context).await {
+        Ok(RefreshedSession::Rotated { access_token, refresh_token, .. }) => {
+            let body = json!({
+                "access_token": access_token.token,
+                "refresh_token": refresh_token.token,
+                "expires_in": data.env.access_token_max_age * 60,
+            });
+            let cookie_jar = add_token_cookies(cookie_jar, &data, access_token, refresh_token);
+            (StatusCode::OK, cookie_jar, Json(body)).into_response()
+        },
+        Ok(RefreshedSession::ConcurrentlyRotated { .. }) => (StatusCode::CONFLICT, Json(json!({"error": "This refresh token was just used by another request."}))).into_response(),
+        Err(AuthError::InternalServerError(e)) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": e}))).into_response(),
+        Err(AuthError::RefreshTokenReused) => (StatusCode::UNAUTHORIZED, Json(json!({"error": "This refresh token was already used. All sessions for this account have been logged out."}))).into_response(),
+        Err(e) => (StatusCode::UNAUTHORIZED, Json(json!({"error": format!("{:?}", e)}))).into_response(),
+    }
+}
+
+// #######################################################################################################################################################
+// forgot_password.html
+// #######################################################################################################################################################
+
+#[derive(Template)]
+#[template(path = "./auth_templates/forgot_password.html")] 
+pub struct ForgotPasswordTemplate {}
+
+pub async fn get_forgot_password_page() -> impl IntoResponse {
+    let template = ForgotPasswordTemplate {};
+
+    (StatusCode::OK, Html(template.render().unwrap()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForgotPasswordForm {
+    email: String,
+}
+
+/// Always gives the same answer whether or not the account exists. All the errors must return the OK status code for HTMX.
+pub async fn post_forgot_password_form(
+    State(data): State<Arc<AppState>>,
+    Form(form): Form<ForgotPasswordForm>,
+) -> impl IntoResponse {
+    match request_password_reset_handler(data, form.email).await {
+        Ok(_) => (StatusCode::OK, Html(format!(
+            "<h1 id=\"primary-content\">If there is an account with that email, a link to reset its password is on its way. The link expires in {} minutes.</h1>",
+            PASSWORD_RESET_TOKEN_MAX_AGE_MINUTES
+        ))).into_response(),
+        Err(AuthError::InternalServerError(e)) => error_response(&format!("Error: {}", e.unwrap_or_default())).into_response(),
+        Err(e) => error_response(&format!("Unexpected error: {:?}", e)).into_response(),
+    }
+}
+
+// #######################################################################################################################################################
+// reset_password.html
+// #######################################################################################################################################################
+
+#[derive(Template)]
+#[template(path = "./auth_templates/reset_password.html")] 
+pub struct ResetPasswordTemplate {
+    token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetPasswordParams {
+    token: String,
+}
+
+pub async fn get_reset_password_page(
+    State(data): State<Arc<AppState>>,
+    Query(params): Query<ResetPasswordParams>,
+) -> impl IntoResponse {
+    match is_password_reset_token_valid(&data, &params.token).await {
+        Ok(true) => (StatusCode::OK, Html(ResetPasswordTemplate { token: params.token }.render().unwrap())).into_response(),
+        Ok(false) => error_response("This password reset link has expired or was already used. Request a new one from the login page.").into_response(),
+        Err(e) => error_response(&format!("Error: {:?}", e)).into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForgottenPasswordResetForm {
+    token: String,
+    password: String,
+    confirm_password: String,
+}
+
+pub async fn post_reset_password_form(
+    State(data): State<Arc<AppState>>,
+    Form(form): Form<ForgottenPasswordResetForm>,
+) -> impl IntoResponse {
+    if form.password != form.confirm_password {
+        return error_response("Error: Passwords do not match. Go back and try again.").into_response();
+    }
+
+    match reset_forgotten_password_handler(data, form.token, form.password).await {
+        Ok(_) => Redirect::to("/login").into_response(),
+        Err(AuthError::InvalidPasswordResetToken) => error_response("This password reset link has expired or was already used. Request a new one from the login page.").into_response(),
+        Err(e) => error_response(&format!("Error: {:?}", e)).into_response(),
+    }
+}
+
 // #######################################################################################################################################################
 // dashboard.html
 // #######################################################################################################################################################
@@ -248,15 +604,40 @@ pub async fn get_logout_page(
 #[derive(Template)]
 #[template(path = "./primary_templates/dashboard.html")] 
 pub struct DashboardTemplate {
-    test_names: Vec<String>
+    test_definitions: Vec<(Uuid, String)>,
+    role: Role,
 }
 
-pub async fn get_dashboard_page(State(data): State<Arc<AppState>>) -> impl IntoResponse  {
-    let test_names = data.test_configurations.tests.iter().map(|test| test.metadata.test_name.clone()).collect();
-    let template: DashboardTemplate = DashboardTemplate {test_names};
+pub async fn get_dashboard_page(
+    State(data): State<Arc<AppState>>,
+    Extension(auth_status): Extension<AuthStatus>,
+) -> impl IntoResponse  {
+    let role = match auth_status {
+        AuthStatus::Authorized(user) => user.user.role,
+        AuthStatus::Unauthorized(_) => panic!("If this happens, check your auth middleware application.")
+    };
+
+    let test_definitions = data.test_configurations().tests
+        .iter()
+        .filter_map(|test| test.metadata.test_definition_id.map(|id| (id, test.metadata.test_name.clone())))
+        .collect();
+    let template: DashboardTemplate = DashboardTemplate {test_definitions, role};
     (StatusCode::OK, Html(template.render().unwrap()))
 }
 
+/// Reloads test_definitions.yaml without restarting the server. Returns a snippet for the dashboard describing the result.
+/// Errors return the OK status code so that HTMX swaps them in.
+pub async fn post_reload_test_definitions(State(data): State<Arc<AppState>>) -> impl IntoResponse {
+    match reload_test_definitions(&data).await {
+        Ok(tests) => (StatusCode::OK, Html(format!("<p id=\"reload-test-definitions-result\" class=\"text-green-700\">Reloaded {} test definitions. Refresh the page to see them.</p>", tests.tests.len()))).into_response(),
+        Err(TestError::InvalidTestDefinition(errors)) => {
+            let error_list: String = errors.iter().map(|e| format!("<li>{}</li>", e)).collect();
+            (StatusCode::OK, Html(format!("<div id=\"reload-test-definitions-result\" class=\"text-red-700\"><p>The test definitions were not reloaded because they are invalid:</p><ul class=\"list-disc pl-5\">{}</ul></div>", error_list))).into_response()
+        },
+        Err(e) => (StatusCode::OK, Html(format!("<p id=\"reload-test-definitions-result\" class=\"text-red-700\">Error reloading the test definitions: {:?}</p>", e))).into_response(),
+    }
+}
+
 
 // #######################################################################################################################################################
 // dancer_test.html
@@ -268,7 +649,7 @@ pub struct DancerTestPageTemplate {
     test: Test,
     prefilled_user_info: PrefilledTestData,
     test_summary: Option<FullTestSummary>,
-    test_index: i32, // Used for on the fly test grading
+    test_definition_id: Uuid, // Used for on the fly test grading
     is_demo_mode: bool,
     email_functionality_active: bool,
 }
@@ -282,23 +663,23 @@ pub struct PrefilledTestData {
 
 pub async fn get_test_page(
     State(data): State<Arc<AppState>>,
-    Path(test_index): Path<i32>,
+    Path(test_definition_id): Path<Uuid>,
     Query(prefilled_user_info): Query<PrefilledTestData>,
 ) -> impl IntoResponse  {
 
-    if let Some(test) = data.test_configurations.tests.get(test_index as usize) {
+    if let Some(test) = data.test_configurations().get_by_id(test_definition_id) {
         let template = DancerTestPageTemplate {
             test: test.clone(),
             prefilled_user_info,
             test_summary: None,
-            test_index,
+            test_definition_id,
             is_demo_mode: data.env.is_demo_mode,
             email_functionality_active: data.smtp_config.is_some()
         };
         (StatusCode::OK, Html(template.render().unwrap()))
         
     } else {
-        (StatusCode::OK, Html(format!("<h1 id=\"primary-content\">Error: Invalid test index ({}) in url.</h1>", test_index)))
+        (StatusCode::OK, Html(format!("<h1 id=\"primary-content\">Error: Invalid test definition id ({}) in url.</h1>", test_definition_id)))
     }
 }
 
@@ -306,9 +687,8 @@ pub async fn get_test_page(
 pub async fn post_test_form(
     State(data): State<Arc<AppState>>,
     Extension(auth_status): Extension<AuthStatus>,
-    Path(test_index): Path<i32>,
-    Host(server_root_url): Host,
-    Form(test): Form<HashMap<String, String>>,
+    Path(test_definition_id): Path<Uuid>,
+    submission: Result<TestSubmission, SubmissionError>,
 ) -> impl IntoResponse {
 
     let proctor = match auth_status {
@@ -316,37 +696,43 @@ pub async fn post_test_form(
         AuthStatus::Unauthorized(e) => return error_response(&format!("Unauthorized: {:?}", e)).into_response()
     };
 
-    // By virtue of this existing, they want the email sent.
-    let testee_wants_email_sent = test.get("send_email_results").is_some();
+    let submission = match submission {
+        Ok(submission) => submission,
+        Err(e) => return error_response(&format!("Error parsing test form data: {}", e)).into_response()
+    };
+
+    let testee_wants_email_sent = submission.send_email_results;
+    let testee_wants_pdfs_attached = submission.attach_pdf_results;
+
+    // Tests started before the test definitions were reloaded are graded against the version they were started with
+    let test_definition_version = submission.test_definition_version;
+
+    let test_definition = match fetch_test_definition(&data, test_definition_id, test_definition_version).await {
+        Ok(option) => option,
+        Err(e) => return error_response(&format!("Error fetching the test definition: {:?}", e)).into_response()
+    };
 
-    if let Some(test_definition) = data.test_configurations.tests.get(test_index as usize) {
-        match parse_test_form_data(test, test_definition.clone(), Some(proctor)) {
+    if let Some(test_definition) = test_definition {
+        match grade_submission(submission, test_definition, Some(proctor)) {
             Ok(graded_test) => {
                 match save_test_to_database(&data.db, graded_test).await {
-                    Ok(testee_id) => {
-                        if let (
-                            Some(smtp_config), 
-                            Some(smtp_mailer), 
-                            true) = (
-                                data.smtp_config.clone(), 
-                                data.smtp_mailer.clone(),
-                                testee_wants_email_sent
-                            ) {
-                            tokio::spawn(async move {
-                                if let Err(e) = send_email(&data.db, &smtp_mailer, smtp_config, testee_id, server_root_url).await {
-                                    eprintln!("Failed to send email: {:?}", e);
-                                }
-                            });
+                    Ok((testee_id, test_id)) => {
+                        if data.smtp_mailer.is_some() && testee_wants_email_sent {
+                            let attached_test_id = testee_wants_pdfs_attached.then_some(test_id);
+                            // The test is already saved, so a failure to queue the email is logged rather than shown
+                            if let Err(e) = enqueue_results_email(&data.db, testee_id, attached_test_id, &data.env.public_base_url).await {
+                                eprintln!("Failed to queue the results email: {:?}", e);
+                            }
                         };
                         Redirect::to("/dashboard").into_response()
                     },
                     Err(e) => error_response(&format!("Error saving test to database: {:?}", e)).into_response()
                 }
             },
-            Err(e) => error_response(&format!("Error parsing test form data: {:?}", e)).into_response()
+            Err(e) => error_response(&format!("Error parsing test form data: {}", e)).into_response()
         }
     } else {
-        error_response(&format!("Invalid test index ({}) in URL", test_index)).into_response()
+        error_response(&format!("Invalid test definition id ({}) in URL", test_definition_id)).into_response()
     }
 }
 
@@ -366,19 +752,26 @@ pub struct GradeTestTemplate {
 /// Used to grade a test on the fly.
 pub async fn post_grade_test(
     State(data): State<Arc<AppState>>,
-    Path(test_index): Path<i32>,
-    Form(test): Form<HashMap<String, String>>,
+    Path(test_definition_id): Path<Uuid>,
+    submission: Result<TestSubmission, SubmissionError>,
 ) -> impl IntoResponse {
-    if let Some(test_definition) = data.test_configurations.tests.get(test_index as usize) {
-        match parse_test_form_data(test, test_definition.clone(), None) {
-            Ok(mut parsed_test) => {
-                
-                match parsed_test.grade() {
-                    Ok(_) => (),
-                    Err(e) => return error_response(&format!("Error grading test in post_grade_test function: {:?}", e)).into_response()
-                };
-                
-                let grade_summary = match parsed_test.grade_summary() {
+    let submission = match submission {
+        Ok(submission) => submission,
+        Err(e) => return error_response(&format!("Error parsing test form data: {}", e)).into_response()
+    };
+
+    // Tests started before the test definitions were reloaded are graded against the version they were started with
+    let test_definition_version = submission.test_definition_version;
+
+    let test_definition = match fetch_test_definition(&data, test_definition_id, test_definition_version).await {
+        Ok(option) => option,
+        Err(e) => return error_response(&format!("Error fetching the test definition: {:?}", e)).into_response()
+    };
+
+    if let Some(test_definition) = test_definition {
+        match grade_submission(submission, test_definition, None) {
+            Ok(graded_test) => {
+                let grade_summary = match graded_test.grade_summary() {
                     Ok(summary) => summary,
                     Err(e) => return error_response(&format!("Error summarizing test in post_grade_test function: {:?}", e)).into_response()
                 };
@@ -392,36 +785,17 @@ pub async fn post_grade_test(
                     proctor_last_name: None
                 };
 
-                return (StatusCode::OK, Html(template.render().unwrap())).into_response()
+                (StatusCode::OK, Html(template.render().unwrap())).into_response()
             },
-            Err(e) => return error_response(&format!("Error parsing test form data: {:?}", e)).into_response()
+            Err(e) => error_response(&format!("Error parsing test form data: {}", e)).into_response()
         }
     } else {
-        return error_response(&format!("Invalid test index ({}) in URL", test_index)).into_response()
-    };
+        error_response(&format!("Invalid test definition id ({}) in URL", test_definition_id)).into_response()
+    }
 }
 
 
 
-// // #######################################################################################################################################################
-// // Json Test Results API
-// // #######################################################################################################################################################
-
-// pub async fn get_json_test_results(
-//     State(data): State<Arc<AppState>>,
-//     Path(test_id): Path<i32>,
-// ) -> impl IntoResponse {
-//     match fetch_test_results_by_id(&data.db, test_id).await {
-//         Ok(test_result) => match test_result {
-//             Some(graded_test) => (StatusCode::OK, Json(graded_test)).into_response(),
-//             None => (StatusCode::NOT_FOUND, Json("No test with that ID found")).into_response(),
-//         }
-//         Err(TestError::InternalServerError(err)) => {
-//             (StatusCode::OK, Json(format!("Error: {:?}", err))).into_response()
-//         }
-//     }
-// }
-
 // #######################################################################################################################################################
 // dancer_test.html
 // #######################################################################################################################################################
@@ -431,7 +805,7 @@ pub async fn post_grade_test(
 pub struct GradedTestTemplate {
     test: Test,
     test_summary: Option<FullTestSummary>,
-    test_index: i32, // Unused for this template
+    test_definition_id: Uuid, // Unused for this template
     prefilled_user_info: PrefilledTestData,
     is_demo_mode: bool,
     email_functionality_active: bool, // Unused for this template
@@ -455,9 +829,9 @@ pub async fn get_test_results(
             };
 
             let template = GradedTestTemplate {
-                test,
                 prefilled_user_info,
-                test_index: -1,
+                test_definition_id: test.metadata.test_definition_id.unwrap_or_default(),
+                test,
                 test_summary,
                 is_demo_mode: data.env.is_demo_mode,
                 email_functionality_active: false,
@@ -470,12 +844,51 @@ pub async fn get_test_results(
             }
         },
         Ok(None) => error_response(&format!("No test found for test id ({}) in URL", test_id)).into_response(),
-        Err(TestError::InternalServerError(err)) => {
+        Err(err) => {
             (StatusCode::OK, Html(format!("<h1 id=\"primary-content\">Error: {:?}<h1>", err))).into_response()
         }
     }
 }
 
+/// Which PDF to download for a graded test, defaulting to the score report
+#[derive(Deserialize)]
+pub struct TestResultsPdfQuery {
+    #[serde(default)]
+    document: PdfDocumentKind,
+}
+
+/// Downloads a graded test's score report, or its certificate if it passed, as a PDF. Like the results page, anyone with
+/// the link can download them.
+pub async fn get_test_results_pdf(
+    State(data): State<Arc<AppState>>,
+    Path(test_id): Path<Uuid>,
+    Query(query): Query<TestResultsPdfQuery>,
+) -> impl IntoResponse {
+    let test = match fetch_test_results_by_id(&data.db, test_id).await {
+        Ok(Some(test)) => test,
+        Ok(None) => return error_response(&format!("No test found for test id ({}) in URL", test_id)).into_response(),
+        Err(e) => return error_response(&format!("Error: {:?}", e)).into_response(),
+    };
+
+    if query.document == PdfDocumentKind::Certificate && test.metadata.is_passing != Some(true) {
+        return error_response("Certificates are only available for passing tests.").into_response()
+    }
+
+    let pdf = match query.document.render(&test) {
+        Ok(pdf) => pdf,
+        Err(e) => return error_response(&format!("Couldn't create the PDF: {:?}", e)).into_response()
+    };
+
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, "application/pdf".to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", pdf_file_name(query.document, &test))),
+        ],
+        pdf
+    ).into_response()
+}
+
 // #######################################################################################################################################################
 // search_testee.html
 // #######################################################################################################################################################
@@ -519,12 +932,13 @@ pub async fn get_search_testee_form(
 pub struct TestSummariesTemplate {
     option_test_summaries: Option<Vec<FullTestSummary>>,
     option_testee: Option<Testee>,
+    testee_id: Uuid,
+    email_functionality_active: bool,
+    /// The outcome of resending the results email, and whether it worked
+    email_message: Option<(bool, String)>,
 }
 
-pub async fn get_test_summaries(
-    State(data): State<Arc<AppState>>,
-    Path(testee_id): Path<Uuid>,
-) -> impl IntoResponse {
+async fn render_test_summaries(data: &AppState, testee_id: Uuid, email_message: Option<(bool, String)>) -> Response {
 
     let option_test_summaries = match fetch_testee_tests_by_id(&data.db, testee_id).await {
         Ok(option) => option,
@@ -545,10 +959,49 @@ pub async fn get_test_summaries(
     let template = TestSummariesTemplate {
         option_test_summaries,
         option_testee,
+        testee_id,
+        email_functionality_active: data.smtp_mailer.is_some(),
+        email_message,
     };
     (StatusCode::OK, Html(template.render().unwrap())).into_response()
 }
 
+pub async fn get_test_summaries(
+    State(data): State<Arc<AppState>>,
+    Path(testee_id): Path<Uuid>,
+) -> impl IntoResponse {
+    render_test_summaries(&data, testee_id, None).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResendEmailForm {
+    /// The test whose score report and certificate are attached. Left out to send the list of tests without attachments.
+    test_id: Option<Uuid>,
+}
+
+/// Sends a testee their results email again. The email is queued like any other, and recorded as sent by the user.
+pub async fn post_resend_results_email(
+    State(data): State<Arc<AppState>>,
+    Extension(auth_status): Extension<AuthStatus>,
+    Path(testee_id): Path<Uuid>,
+    Form(form): Form<ResendEmailForm>,
+) -> impl IntoResponse {
+    let user_id = match auth_status {
+        AuthStatus::Authorized(user) => user.user.id,
+        AuthStatus::Unauthorized(e) => return error_response(&format!("Unauthorized: {:?}", e)).into_response()
+    };
+
+    let email_message = match data.smtp_mailer.is_some() {
+        false => (false, "Email is not set up on this server.".to_string()),
+        true => match request_results_email(&data.db, testee_id, form.test_id, &data.env.public_base_url, user_id).await {
+            Ok(_) => (true, "The results email has been queued and will be sent shortly.".to_string()),
+            Err(e) => (false, e.to_string()),
+        },
+    };
+
+    render_test_summaries(&data, testee_id, Some(email_message)).await
+}
+
 // #######################################################################################################################################################
 // broad_test_results.html
 // #######################################################################################################################################################
@@ -558,6 +1011,9 @@ pub async fn get_test_summaries(
 pub struct BroadTestResultsTemplate {
     test_names: Vec<String>,
     test_list_items: Option<Vec<TestListItem>>,
+    selected_test_names: Vec<String>,
+    pass_filter: String,
+    can_export_breakdowns: bool,
 }
 
 #[derive(Deserialize)]
@@ -568,8 +1024,22 @@ pub struct TestFilterQuery {
     pass_filter: Option<String>,
 }
 
+/// Convert this to an option or bool because the form returns strings and we need a bool
+fn parse_pass_filter(pass_filter: Option<&str>) -> Result<Option<bool>, ()> {
+    match pass_filter {
+        Some(data) => match data.to_lowercase().as_str() {
+            "passing" => Ok(Some(true)),
+            "failing" => Ok(Some(false)),
+            "both" => Ok(None),
+            _ => Err(())
+        },
+        None => Ok(None)
+    }
+}
+
 pub async fn get_broad_test_results(
     State(data): State<Arc<AppState>>,
+    Extension(auth_status): Extension<AuthStatus>,
     axum_extra::extract::Query(form_data): axum_extra::extract::Query<TestFilterQuery>,
 ) -> impl IntoResponse {
     
@@ -580,15 +1050,9 @@ pub async fn get_broad_test_results(
     test_names.sort();
 
     
-    // Convert this to an option or bool because the form returns strings and we need a bool
-    let is_passing_filter = match form_data.pass_filter {
-        Some(data) => match data.to_lowercase().as_str() {
-            "passing" => Some(true),
-            "failing" => Some(false),
-            "both" => None,
-            _ => return error_response("An unexpected form value was submitted. You're trying to mess with the website.").into_response()
-        },
-        None => None
+    let is_passing_filter = match parse_pass_filter(form_data.pass_filter.as_deref()) {
+        Ok(filter) => filter,
+        Err(_) => return error_response("An unexpected form value was submitted. You're trying to mess with the website.").into_response()
     };
 
     let test_list_items = match form_data.test_names.is_empty() {
@@ -599,14 +1063,79 @@ pub async fn get_broad_test_results(
         true => None,
     };
 
+    let can_export_breakdowns = match auth_status {
+        AuthStatus::Authorized(authorized_user) => authorized_user.user.role.can_administer_tests(),
+        AuthStatus::Unauthorized(_) => panic!("If this happens, check your auth middleware application.")
+    };
+
     let template = BroadTestResultsTemplate {
         test_names: test_names,
-        test_list_items
+        test_list_items,
+        selected_test_names: form_data.test_names,
+        pass_filter: form_data.pass_filter.unwrap_or("both".to_string()),
+        can_export_breakdowns,
     };
 
     (StatusCode::OK, Html(template.render().unwrap())).into_response()
 }
 
+#[derive(Deserialize)]
+pub struct TestExportQuery {
+    #[serde(default)]
+    test_names: Vec<String>,
+
+    pass_filter: Option<String>,
+
+    format: ExportFormat,
+
+    #[serde(default)]
+    report: ExportReport,
+}
+
+/// Downloads the broad test results search, or the per competency breakdown of those tests, as a CSV or XLSX file.
+/// No test names exports every test name. Front desk staff can export the results for attendance, but the breakdowns
+/// are limited to those who can administer tests.
+pub async fn get_broad_test_results_export(
+    State(data): State<Arc<AppState>>,
+    Extension(auth_status): Extension<AuthStatus>,
+    axum_extra::extract::Query(query): axum_extra::extract::Query<TestExportQuery>,
+) -> impl IntoResponse {
+    let authorized_user = match auth_status {
+        AuthStatus::Authorized(authorized_user) => authorized_user,
+        AuthStatus::Unauthorized(_) => panic!("If this happens, check your auth middleware application.")
+    };
+
+    if query.report == ExportReport::Competencies && !authorized_user.user.role.can_administer_tests() {
+        return error_response("You don't have permission to export competency breakdowns.").into_response()
+    }
+
+    let is_passing_filter = match parse_pass_filter(query.pass_filter.as_deref()) {
+        Ok(filter) => filter,
+        Err(_) => return error_response("An unexpected form value was submitted. You're trying to mess with the website.").into_response()
+    };
+
+    let (export_table, sheet_name) = match query.report {
+        ExportReport::Results => (build_results_export(&data.db, &query.test_names, is_passing_filter).await, "Test Results"),
+        ExportReport::Competencies => (build_competency_export(&data.db, &query.test_names, is_passing_filter).await, "Competencies"),
+    };
+
+    let file = match export_table.and_then(|table| table.render(query.format, sheet_name)) {
+        Ok(file) => file,
+        Err(e) => return error_response(&format!("Couldn't export the test results: {:?}", e)).into_response()
+    };
+
+    let file_name = format!("{}-{}.{}", query.report.file_stem(), chrono::Local::now().format("%Y-%m-%d"), query.format.extension());
+
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, query.format.content_type().to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", file_name)),
+        ],
+        file
+    ).into_response()
+}
+
 
 // #######################################################################################################################################################
 // queue.html
@@ -616,9 +1145,10 @@ pub async fn get_broad_test_results(
 #[template(path = "./primary_templates/queue.html")] 
 pub struct QueueTemplate {
     admin_user: bool,
+    can_administer_tests: bool,
     signup_key_required: bool,
-    test_names: Vec<String>,
-    queue: Vec<(Testee, usize)>,
+    test_definitions: Vec<(Uuid, String)>,
+    queue: Vec<QueueItem>,
     is_demo_mode: bool,
 }
 
@@ -627,30 +1157,29 @@ pub async fn get_queue(
     Extension(auth_status): Extension<AuthStatus>,
 ) -> impl IntoResponse {
     
-    let admin_user = match auth_status {
-        AuthStatus::Authorized(_) => true,
-        AuthStatus::Unauthorized(_) => false
+    let (admin_user, can_administer_tests) = match auth_status {
+        AuthStatus::Authorized(user) => (true, user.user.role.can_administer_tests()),
+        AuthStatus::Unauthorized(_) => (false, false)
     };
  
 
     let queue = match retrieve_queue(&data.db).await {
-        Ok(q) => q.into_iter()
-            .map(|(testee, index)| (testee, index as usize))  // Convert i32 to usize
-            .collect(),
+        Ok(q) => q,
         Err(e) => {
             return (StatusCode::OK, Html(format!("<h1 id=\"primary-content\">Error: {:?}</h1>", e))).into_response()
         }
     };
-    let test_names = data.test_configurations.tests
+    let test_definitions = data.test_configurations().tests
         .iter()
-        .map(|test| test.metadata.test_name.clone())
-        .collect::<Vec<String>>();
+        .filter_map(|test| test.metadata.test_definition_id.map(|id| (id, test.metadata.test_name.clone())))
+        .collect::<Vec<(Uuid, String)>>();
 
     let template = QueueTemplate {
         admin_user,
+        can_administer_tests,
         signup_key_required: (data.env.queue_signup_key != ""),
         queue,
-        test_names,
+        test_definitions,
         is_demo_mode: data.env.is_demo_mode
     };
 
@@ -663,7 +1192,7 @@ pub struct EnqueueForm {
     last_name: String,
     email: String,
     signup_key: Option<String>,
-    test_definition_index: i32,
+    test_definition_id: Uuid,
 }
 
 pub async fn post_queue(
@@ -689,7 +1218,7 @@ pub async fn post_queue(
     };
 
     // Create testee 100% returns a testee with a testee id, so I can call unwrap on this
-    if let Err(e) = enqueue_testee(&data.db, testee.id.unwrap(), user_info.test_definition_index).await {
+    if let Err(e) = enqueue_testee(&data.db, testee.id.unwrap(), user_info.test_definition_id).await {
         return (StatusCode::OK, Html(format!("<h1 id=\"primary-content\">Error enqueuing testee: {:?}</h1>", e))).into_response();
     }
     
@@ -703,7 +1232,7 @@ pub async fn post_queue(
 #[derive(Deserialize, Debug)]
 pub struct DequeueParams {
     testee_id: Option<Uuid>,
-    test_definition_index: Option<i32>,
+    test_definition_id: Option<Uuid>,
 }
 
 /// Removes a user from the queue upon receiving a delete request. If called with a request header HX-Trigger equal to 
@@ -715,7 +1244,7 @@ pub async fn delete_dequeue(
     headers: HeaderMap,
 ) -> impl IntoResponse {
 
-    let (testee, test_definition_index) = match dequeue_testee(&data.db, params.testee_id, params.test_definition_index).await {
+    let (testee, test_definition_id) = match dequeue_testee(&data.db, params.testee_id, params.test_definition_id).await {
         Ok(option) => match option {
             Some(result) => (result.0, result.1),
             None => return (StatusCode::OK, Html("<h1 id=\"primary-content\">Error: No testee with that ID found --> Perhaps the queue was empty.</h1>")).into_response(),
@@ -734,7 +1263,7 @@ pub async fn delete_dequeue(
 
             let redirect_url = format!(
                 "/administer-test/{}?first_name={}&last_name={}&email={}",
-                test_definition_index, first_name, last_name, email
+                test_definition_id, first_name, last_name, email
             );
 
             return Redirect::to(&redirect_url).into_response();
@@ -745,46 +1274,473 @@ pub async fn delete_dequeue(
 }
 
 // #######################################################################################################################################################
-// get send email
+// admin users
 // #######################################################################################################################################################
 
+/// Errors from the user management handlers, returned with the OK status code so that HTMX swaps them in.
+fn user_management_error_response(error: AuthError) -> impl IntoResponse {
+    match error {
+        AuthError::DuplicateEmail => error_response("Error: Another account already uses that email."),
+        AuthError::InvalidUser => error_response("Error: That user does not exist."),
+        AuthError::CannotRemoveOwnAdminAccess => error_response("Error: You can't change your own role or disable your own account. Ask another admin."),
+        AuthError::InternalServerError(e) => error_response(&format!("Error: {:?}", e)),
+        e => error_response(&format!("Unexpected error: {:?}", e)),
+    }.into_response()
+}
 
-// /// This is intended to enable you to manually force the server to send someone an email with links to their results.
-// pub async fn post_send_email(State(data): State<Arc<AppState>>) -> impl IntoResponse {
-    
-//     let smtp_config = match &data.smtp_config {
-//         Some(config) => config,
-//         None => return (StatusCode::OK, error_response("SMTP not set up. No email functionality available."))
-//     };
-
-//     // Create the email message
-//     let email = Message::builder()
-//         .from(smtp_config.user_email.parse().unwrap())
-//         .to("paulrcreamer@gmail.com".parse().unwrap()) // Change this to the actual recipient's email
-//         .subject("Test Email")
-//         .body("This is a test email sent using Lettre. Paul if you see this, this means the email functionality is coming together".to_string())
-//         .unwrap();
-
-//     // Set up the SMTP transport
-//     let creds = Credentials::new(
-//         smtp_config.user_login.clone(),
-//         smtp_config.user_password.clone(),
-//     );
-
-//     let mailer: AsyncSmtpTransport<Tokio1Executor> = AsyncSmtpTransport::<Tokio1Executor>::relay(&smtp_config.server_host)
-//         .unwrap()
-//         .credentials(creds)
-//         .build();
-
-//     // Send the email
-//     match mailer.send(email).await {
-//         Ok(_) => {
-//             println!("Email sent successfully!");
-//             return (StatusCode::OK, error_response("Email sent."))
-//         }
-//         Err(e) => {
-//             eprintln!("Failed to send email: {:?}", e);
-//             return (StatusCode::OK, error_response("Email not sent."))
-//         }
-//     }
-// }
\ No newline at end of file
+#[derive(Template)]
+#[template(path = "./admin_templates/users.html")]
+pub struct UsersTemplate {
+    users: Vec<User>,
+}
+
+pub async fn get_users_page(State(data): State<Arc<AppState>>) -> impl IntoResponse {
+    match fetch_users(&data.db).await {
+        Ok(users) => (StatusCode::OK, Html(UsersTemplate { users }.render().unwrap())).into_response(),
+        Err(e) => user_management_error_response(e).into_response(),
+    }
+}
+
+#[derive(Template)]
+#[template(path = "./admin_templates/edit_user.html")]
+pub struct EditUserTemplate {
+    user: User,
+    roles: Vec<Role>,
+    is_current_user: bool,
+    sessions: Vec<Session>,
+    current_session_id: Option<Uuid>,
+    revoke_url_prefix: String,
+}
+
+pub async fn get_edit_user_page(
+    State(data): State<Arc<AppState>>,
+    Extension(auth_status): Extension<AuthStatus>,
+    Path(user_id): Path<Uuid>,
+) -> impl IntoResponse {
+    let acting_user = match auth_status {
+        AuthStatus::Authorized(user) => user,
+        AuthStatus::Unauthorized(_) => panic!("If this happens, check your auth middleware application.")
+    };
+
+    let sessions = match fetch_user_sessions(&data, user_id).await {
+        Ok(sessions) => sessions,
+        Err(e) => return user_management_error_response(e).into_response(),
+    };
+
+    match fetch_user_by_id(user_id, &data.db).await {
+        Ok(Some(user)) => {
+            let is_current_user = user.id == acting_user.user.id;
+            let template = EditUserTemplate {
+                revoke_url_prefix: format!("/admin/users/{}/sessions", user.id),
+                is_current_user,
+                user,
+                roles: Role::iter().collect(),
+                sessions,
+                current_session_id: if is_current_user { acting_user.session_id } else { None },
+            };
+            (StatusCode::OK, Html(template.render().unwrap())).into_response()
+        },
+        Ok(None) => user_management_error_response(AuthError::InvalidUser).into_response(),
+        Err(e) => user_management_error_response(e).into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EditUserForm {
+    first_name: String,
+    last_name: String,
+    email: String,
+    role: Role,
+}
+
+pub async fn post_edit_user_form(
+    State(data): State<Arc<AppState>>,
+    Extension(auth_status): Extension<AuthStatus>,
+    Path(user_id): Path<Uuid>,
+    Form(form): Form<EditUserForm>,
+) -> impl IntoResponse {
+    let acting_user = match auth_status {
+        AuthStatus::Authorized(user) => user,
+        AuthStatus::Unauthorized(_) => panic!("If this happens, check your auth middleware application.")
+    };
+
+    match update_user_handler(data, &acting_user, user_id, form.first_name, form.last_name, form.email, form.role).await {
+        Ok(_) => Redirect::to("/admin/users").into_response(),
+        Err(e) => user_management_error_response(e).into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetPasswordForm {
+    password: String,
+    confirm_password: String,
+}
+
+pub async fn post_reset_user_password_form(
+    State(data): State<Arc<AppState>>,
+    Path(user_id): Path<Uuid>,
+    Form(form): Form<ResetPasswordForm>,
+) -> impl IntoResponse {
+    if form.password != form.confirm_password {
+        return error_response("Error: Passwords do not match").into_response();
+    }
+
+    match reset_user_password_handler(data, user_id, form.password).await {
+        Ok(_) => Redirect::to("/admin/users").into_response(),
+        Err(e) => user_management_error_response(e).into_response(),
+    }
+}
+
+pub async fn post_disable_user(
+    State(data): State<Arc<AppState>>,
+    Extension(auth_status): Extension<AuthStatus>,
+    Path(user_id): Path<Uuid>,
+) -> impl IntoResponse {
+    set_user_disabled(data, auth_status, user_id, true).await
+}
+
+pub async fn post_enable_user(
+    State(data): State<Arc<AppState>>,
+    Extension(auth_status): Extension<AuthStatus>,
+    Path(user_id): Path<Uuid>,
+) -> impl IntoResponse {
+    set_user_disabled(data, auth_status, user_id, false).await
+}
+
+/// Logs the user out of every session they have
+pub async fn post_force_logout_user(
+    State(data): State<Arc<AppState>>,
+    Path(user_id): Path<Uuid>,
+) -> impl IntoResponse {
+    match revoke_user_tokens(&data, user_id).await {
+        Ok(_) => Redirect::to(&format!("/admin/users/{}", user_id)).into_response(),
+        Err(e) => user_management_error_response(e).into_response(),
+    }
+}
+
+/// Revokes a single session belonging to the user. Returns an empty body so that HTMX removes the session's table row.
+pub async fn delete_user_session(
+    State(data): State<Arc<AppState>>,
+    Path((user_id, session_id)): Path<(Uuid, Uuid)>,
+) -> impl IntoResponse {
+    match revoke_session(&data, user_id, session_id).await {
+        Ok(_) => (StatusCode::OK, Html("")).into_response(),
+        Err(e) => (StatusCode::OK, Html(format!("<tr><td colspan=\"5\">Error: {:?}</td></tr>", e))).into_response(),
+    }
+}
+
+async fn set_user_disabled(data: Arc<AppState>, auth_status: AuthStatus, user_id: Uuid, disabled: bool) -> axum::response::Response {
+    let acting_user = match auth_status {
+        AuthStatus::Authorized(user) => user,
+        AuthStatus::Unauthorized(_) => panic!("If this happens, check your auth middleware application.")
+    };
+
+    match set_user_disabled_handler(data, &acting_user, user_id, disabled).await {
+        Ok(_) => Redirect::to("/admin/users").into_response(),
+        Err(e) => user_management_error_response(e).into_response(),
+    }
+}
+
+// #######################################################################################################################################################
+// api_keys.html
+// #######################################################################################################################################################
+
+#[derive(Template)]
+#[template(path = "./admin_templates/api_keys.html")]
+pub struct ApiKeysTemplate {
+    api_keys: Vec<ApiKey>,
+    scopes: Vec<ApiKeyScope>,
+    /// The name and value of a key that was just issued, which is the only time it can be shown
+    new_api_key: Option<(String, String)>,
+}
+
+async fn render_api_keys_page(data: &AppState, new_api_key: Option<(String, String)>) -> axum::response::Response {
+    match fetch_api_keys(&data.db).await {
+        Ok(api_keys) => {
+            let template = ApiKeysTemplate {
+                api_keys,
+                scopes: ApiKeyScope::iter().collect(),
+                new_api_key,
+            };
+            (StatusCode::OK, Html(template.render().unwrap())).into_response()
+        },
+        Err(e) => user_management_error_response(e).into_response(),
+    }
+}
+
+pub async fn get_api_keys_page(State(data): State<Arc<AppState>>) -> impl IntoResponse {
+    render_api_keys_page(&data, None).await
+}
+
+/// Issues a key with the checked scopes. The form has a name field and a checkbox named after each scope.
+pub async fn post_api_key_form(
+    State(data): State<Arc<AppState>>,
+    Extension(auth_status): Extension<AuthStatus>,
+    Form(form): Form<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let acting_user = match auth_status {
+        AuthStatus::Authorized(user) => user,
+        AuthStatus::Unauthorized(_) => panic!("If this happens, check your auth middleware application.")
+    };
+
+    let name = form.get("name").map(|name| name.trim()).unwrap_or_default();
+    let scopes: Vec<ApiKeyScope> = ApiKeyScope::iter().filter(|scope| form.contains_key(&scope.to_string())).collect();
+
+    if name.is_empty() {
+        return error_response("Error: Give the key a name so that you can tell it apart from the others.").into_response();
+    }
+    if scopes.is_empty() {
+        return error_response("Error: A key needs at least one scope.").into_response();
+    }
+
+    match create_api_key(name, &scopes, acting_user.user.id, &data.db).await {
+        Ok((api_key, key)) => render_api_keys_page(&data, Some((api_key.name, key))).await,
+        Err(e) => user_management_error_response(e).into_response(),
+    }
+}
+
+pub async fn post_revoke_api_key(
+    State(data): State<Arc<AppState>>,
+    Path(api_key_id): Path<Uuid>,
+) -> impl IntoResponse {
+    match revoke_api_key(api_key_id, &data.db).await {
+        Ok(_) => Redirect::to("/admin/api-keys").into_response(),
+        Err(e) => user_management_error_response(e).into_response(),
+    }
+}
+
+// #######################################################################################################################################################
+// webhooks.html
+// #######################################################################################################################################################
+
+/// How many deliveries the delivery log shows
+const WEBHOOK_DELIVERY_LOG_LENGTH: i64 = 100;
+
+#[derive(Template)]
+#[template(path = "./admin_templates/webhooks.html")]
+pub struct WebhooksTemplate {
+    endpoints: Vec<WebhookEndpoint>,
+    deliveries: Vec<WebhookDelivery>,
+    events: Vec<WebhookEvent>,
+    /// The url and signing secret of an endpoint that was just added, which is the only time the secret is shown
+    new_endpoint: Option<(String, String)>,
+}
+
+async fn render_webhooks_page(data: &AppState, new_endpoint: Option<(String, String)>) -> axum::response::Response {
+    let endpoints = match fetch_webhook_endpoints(&data.db).await {
+        Ok(endpoints) => endpoints,
+        Err(e) => return error_response(&format!("Error: {:?}", e)).into_response(),
+    };
+    let deliveries = match fetch_webhook_deliveries(&data.db, WEBHOOK_DELIVERY_LOG_LENGTH).await {
+        Ok(deliveries) => deliveries,
+        Err(e) => return error_response(&format!("Error: {:?}", e)).into_response(),
+    };
+
+    let template = WebhooksTemplate {
+        endpoints,
+        deliveries,
+        events: WebhookEvent::subscribable(),
+        new_endpoint,
+    };
+    (StatusCode::OK, Html(template.render().unwrap())).into_response()
+}
+
+pub async fn get_webhooks_page(State(data): State<Arc<AppState>>) -> impl IntoResponse {
+    render_webhooks_page(&data, None).await
+}
+
+/// Adds an endpoint for the checked events. The form has a url field and a checkbox named after each event.
+pub async fn post_webhook_form(
+    State(data): State<Arc<AppState>>,
+    Form(form): Form<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let url = match form.get("url").map(|url| reqwest::Url::parse(url.trim())) {
+        Some(Ok(url)) if matches!(url.scheme(), "http" | "https") => url,
+        _ => return error_response("Error: The url must be a full http or https address.").into_response(),
+    };
+    let events: Vec<WebhookEvent> = WebhookEvent::subscribable().into_iter().filter(|event| form.contains_key(&event.to_string())).collect();
+
+    if events.is_empty() {
+        return error_response("Error: Choose at least one event to send to the endpoint.").into_response();
+    }
+
+    match create_webhook_endpoint(&data.db, &url, &events).await {
+        Ok((endpoint, secret)) => render_webhooks_page(&data, Some((endpoint.url, secret))).await,
+        Err(e) => error_response(&format!("Error: {:?}", e)).into_response(),
+    }
+}
+
+pub async fn post_delete_webhook_endpoint(
+    State(data): State<Arc<AppState>>,
+    Path(endpoint_id): Path<Uuid>,
+) -> impl IntoResponse {
+    match delete_webhook_endpoint(&data.db, endpoint_id).await {
+        Ok(_) => Redirect::to("/admin/webhooks").into_response(),
+        Err(e) => error_response(&format!("Error: {:?}", e)).into_response(),
+    }
+}
+
+/// Sends the endpoint a ping event, which shows up in the delivery log once it has been attempted
+pub async fn post_ping_webhook_endpoint(
+    State(data): State<Arc<AppState>>,
+    Path(endpoint_id): Path<Uuid>,
+) -> impl IntoResponse {
+    match ping_webhook_endpoint(&data.db, endpoint_id).await {
+        Ok(_) => Redirect::to("/admin/webhooks").into_response(),
+        Err(e) => error_response(&format!("Error: {:?}", e)).into_response(),
+    }
+}
+
+pub async fn post_retry_webhook_delivery(
+    State(data): State<Arc<AppState>>,
+    Path(delivery_id): Path<Uuid>,
+) -> impl IntoResponse {
+    match retry_webhook_delivery(&data.db, delivery_id).await {
+        Ok(_) => Redirect::to("/admin/webhooks").into_response(),
+        Err(e) => error_response(&format!("Error: {:?}", e)).into_response(),
+    }
+}
+
+// #######################################################################################################################################################
+// emails.html
+// #######################################################################################################################################################
+
+/// How many emails the email log shows
+const EMAIL_LOG_LENGTH: i64 = 100;
+
+#[derive(Template)]
+#[template(path = "./admin_templates/emails.html")]
+pub struct EmailsTemplate {
+    email_functionality_active: bool,
+    jobs: Vec<EmailJob>,
+}
+
+pub async fn get_emails_page(State(data): State<Arc<AppState>>) -> impl IntoResponse {
+    let jobs = match fetch_email_jobs(&data.db, EMAIL_LOG_LENGTH).await {
+        Ok(jobs) => jobs,
+        Err(e) => return error_response(&format!("Error: {:?}", e)).into_response(),
+    };
+
+    let template = EmailsTemplate {
+        email_functionality_active: data.smtp_mailer.is_some(),
+        jobs,
+    };
+    (StatusCode::OK, Html(template.render().unwrap())).into_response()
+}
+
+pub async fn post_resend_email_job(
+    State(data): State<Arc<AppState>>,
+    Path(job_id): Path<Uuid>,
+) -> impl IntoResponse {
+    match resend_email_job(&data.db, job_id).await {
+        Ok(_) => Redirect::to("/admin/emails").into_response(),
+        Err(e) => error_response(&format!("Error: {:?}", e)).into_response(),
+    }
+}
+
+// #######################################################################################################################################################
+// import.html
+// #######################################################################################################################################################
+
+#[derive(Template)]
+#[template(path = "./admin_templates/import.html")]
+pub struct ImportTemplate {
+    file_name: Option<String>,
+    report: Option<ImportReport>,
+}
+
+pub async fn get_import_page() -> impl IntoResponse {
+    let template = ImportTemplate { file_name: None, report: None };
+    (StatusCode::OK, Html(template.render().unwrap())).into_response()
+}
+
+/// Imports an uploaded CSV or YAML file of testees and graded tests. The form has a file field and a dry_run checkbox.
+/// Tests without a proctor email are credited to the admin who uploaded the file.
+pub async fn post_import_form(
+    State(data): State<Arc<AppState>>,
+    Extension(auth_status): Extension<AuthStatus>,
+    mut multipart: Multipart,
+) -> impl IntoResponse {
+    let user = match auth_status {
+        AuthStatus::Authorized(authorized_user) => authorized_user.user,
+        AuthStatus::Unauthorized(_) => panic!("If this happens, check your auth middleware application.")
+    };
+
+    let mut file = None;
+    let mut dry_run = false;
+
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return error_response(&format!("Error: Couldn't read the upload: {}", e)).into_response(),
+        };
+
+        match field.name() {
+            Some("file") => {
+                let file_name = field.file_name().unwrap_or_default().to_string();
+                match field.text().await {
+                    Ok(contents) => file = Some((file_name, contents)),
+                    Err(e) => return error_response(&format!("Error: Couldn't read the file as text: {}", e)).into_response(),
+                }
+            },
+            Some("dry_run") => dry_run = true,
+            _ => {}
+        }
+    }
+
+    let Some((file_name, contents)) = file else {
+        return error_response("Error: Choose a file to import.").into_response();
+    };
+
+    let Some(format) = ImportFormat::from_file_name(&file_name) else {
+        return error_response("Error: Import files must end in .csv, .yaml or .yml.").into_response();
+    };
+
+    let report = match parse_import_records(&contents, format) {
+        Ok(records) => {
+            let proctor = Proctor { id: user.id, first_name: user.first_name, last_name: user.last_name };
+            match import_records(&data.db, (*data.test_configurations()).clone(), records, Some(proctor), dry_run).await {
+                Ok(report) => report,
+                Err(e) => return error_response(&format!("Error: The import failed and nothing was imported: {:?}", e)).into_response(),
+            }
+        },
+        Err(problems) => ImportReport { dry_run, problems, ..Default::default() },
+    };
+
+    let template = ImportTemplate { file_name: Some(file_name), report: Some(report) };
+    (StatusCode::OK, Html(template.render().unwrap())).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{create_test_user, spawn_smtp_sink, test_app_state_with_smtp};
+    use sqlx::PgPool;
+
+    async fn forgot_password(data: &Arc<AppState>, email: &str) -> (StatusCode, String) {
+        let response = post_forgot_password_form(State(data.clone()), Form(ForgotPasswordForm { email: email.to_string() }))
+            .await
+            .into_response();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[sqlx::test]
+    async fn forgot_password_answers_the_same_for_unknown_emails(pool: PgPool) {
+        let (port, mut received) = spawn_smtp_sink(true).await;
+        let data = test_app_state_with_smtp(pool.clone(), port);
+        let user = create_test_user(&pool, Role::Proctor, "proctor@example.com").await;
+
+        let unknown = forgot_password(&data, "nobody@example.com").await;
+        let known = forgot_password(&data, &user.email).await;
+        assert_eq!(unknown, known);
+        assert_eq!(known.0, StatusCode::OK);
+
+        // Only the account holder is sent a link
+        let email = received.recv().await.unwrap();
+        assert!(email.contains("To: proctor@example.com"));
+        assert!(tokio::time::timeout(std::time::Duration::from_millis(500), received.recv()).await.is_err());
+    }
+}
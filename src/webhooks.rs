@@ -0,0 +1,431 @@
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+use base64::{engine::general_purpose, Engine};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use reqwest::{redirect::Policy, Client, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::Sha256;
+use sqlx::{PgExecutor, PgPool};
+use uuid::Uuid;
+
+use crate::{outbox::{run_outbox, AttemptOutcome, Outbox, OutboxPolicy, RecordedAttempt}, AppState};
+
+// #######################################################################################################################################################
+// Events
+// #######################################################################################################################################################
+
+// Events are written to the webhook_deliveries outbox, one row per subscribed endpoint, by the code that caused them.
+// deliver_webhooks then sends them in the background so that a slow or broken receiver never holds up a request.
+
+/// Something that happened which endpoints can subscribe to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, strum_macros::Display, strum_macros::EnumString, strum_macros::EnumIter)]
+pub enum WebhookEvent {
+    /// A graded test was saved
+    #[serde(rename = "test.saved")]
+    #[strum(serialize = "test.saved")]
+    TestSaved,
+    /// A testee joined the queue
+    #[serde(rename = "testee.enqueued")]
+    #[strum(serialize = "testee.enqueued")]
+    TesteeEnqueued,
+    /// A testee left the queue, usually to take their test
+    #[serde(rename = "testee.dequeued")]
+    #[strum(serialize = "testee.dequeued")]
+    TesteeDequeued,
+    /// Sent to a single endpoint from the webhooks page to check that it is reachable. It can't be subscribed to.
+    #[serde(rename = "ping")]
+    #[strum(serialize = "ping")]
+    Ping,
+}
+
+impl WebhookEvent {
+    /// The events an endpoint can choose to receive
+    pub fn subscribable() -> Vec<WebhookEvent> {
+        vec![WebhookEvent::TestSaved, WebhookEvent::TesteeEnqueued, WebhookEvent::TesteeDequeued]
+    }
+}
+
+/// Wraps the event's data in the body that is sent to endpoints
+fn event_payload(event: WebhookEvent, data: impl Serialize) -> Result<String, serde_json::Error> {
+    serde_json::to_string(&json!({
+        "id": Uuid::new_v4(),
+        "event": event,
+        "created_at": Utc::now(),
+        "data": data,
+    }))
+}
+
+/// Queues the event for every endpoint subscribed to it. Pass the transaction that made the change, so that the event is
+/// queued if and only if the change is committed.
+pub async fn publish_webhook_event<'e>(executor: impl PgExecutor<'e>, event: WebhookEvent, data: impl Serialize) -> Result<(), sqlx::Error> {
+    let payload = event_payload(event, data).map_err(|e| sqlx::Error::Encode(Box::new(e)))?;
+
+    sqlx::query!(
+        "INSERT INTO webhook_deliveries (endpoint_id, event, payload)
+        SELECT id, $1::text, $2 FROM webhook_endpoints WHERE $1::text = ANY(events)",
+        event.to_string(),
+        payload
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+/// Queues a ping for one endpoint so that an admin can check it is set up correctly
+pub async fn ping_webhook_endpoint(pool: &PgPool, endpoint_id: Uuid) -> Result<(), sqlx::Error> {
+    let payload = event_payload(WebhookEvent::Ping, json!({ "endpoint_id": endpoint_id }))
+        .map_err(|e| sqlx::Error::Encode(Box::new(e)))?;
+
+    sqlx::query!(
+        "INSERT INTO webhook_deliveries (endpoint_id, event, payload) VALUES ($1, $2, $3)",
+        endpoint_id,
+        WebhookEvent::Ping.to_string(),
+        payload
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+// #######################################################################################################################################################
+// Endpoints
+// #######################################################################################################################################################
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookEndpoint {
+    pub id: Uuid,
+    pub url: String,
+    pub events: Vec<WebhookEvent>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Registers an endpoint. Returns it along with its signing secret, which is only shown when the endpoint is created.
+pub async fn create_webhook_endpoint(
+    pool: &PgPool,
+    url: &Url,
+    events: &[WebhookEvent],
+) -> Result<(WebhookEndpoint, String), sqlx::Error> {
+    let mut secret_bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut secret_bytes);
+    let secret = format!("whsec_{}", general_purpose::URL_SAFE_NO_PAD.encode(secret_bytes));
+    let event_names: Vec<String> = events.iter().map(|event| event.to_string()).collect();
+
+    let row = sqlx::query!(
+        "INSERT INTO webhook_endpoints (url, secret, events) VALUES ($1, $2, $3) RETURNING id, created_at",
+        url.as_str(),
+        secret,
+        &event_names
+    )
+    .fetch_one(pool)
+    .await?;
+
+    let endpoint = WebhookEndpoint {
+        id: row.id,
+        url: url.to_string(),
+        events: events.to_vec(),
+        created_at: row.created_at,
+    };
+    Ok((endpoint, secret))
+}
+
+pub async fn fetch_webhook_endpoints(pool: &PgPool) -> Result<Vec<WebhookEndpoint>, sqlx::Error> {
+    let rows = sqlx::query!("SELECT id, url, events, created_at FROM webhook_endpoints ORDER BY created_at")
+        .fetch_all(pool)
+        .await?;
+
+    Ok(rows.into_iter().map(|row| WebhookEndpoint {
+        id: row.id,
+        url: row.url,
+        // Events that no longer exist are ignored rather than breaking the page
+        events: row.events.iter().filter_map(|event| WebhookEvent::from_str(event).ok()).collect(),
+        created_at: row.created_at,
+    }).collect())
+}
+
+/// Deletes the endpoint along with its delivery log and any deliveries that haven't been sent yet
+pub async fn delete_webhook_endpoint(pool: &PgPool, endpoint_id: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM webhook_endpoints WHERE id = $1", endpoint_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+// #######################################################################################################################################################
+// Delivery Log
+// #######################################################################################################################################################
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, sqlx::Type, strum_macros::Display)]
+#[sqlx(type_name = "varchar", rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum WebhookDeliveryStatus {
+    /// Waiting for its first attempt or a retry
+    Pending,
+    Delivered,
+    /// Gave up after DELIVERY_POLICY.max_attempts
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookDelivery {
+    pub id: Uuid,
+    pub endpoint_url: String,
+    pub event: String,
+    pub status: WebhookDeliveryStatus,
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_attempt_at: Option<DateTime<Utc>>,
+    pub last_response_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The most recent deliveries to every endpoint, newest first
+pub async fn fetch_webhook_deliveries(pool: &PgPool, limit: i64) -> Result<Vec<WebhookDelivery>, sqlx::Error> {
+    sqlx::query_as!(
+        WebhookDelivery,
+        r#"SELECT d.id, e.url AS endpoint_url, d.event, d.status AS "status: WebhookDeliveryStatus", d.attempts, d.next_attempt_at,
+            d.last_attempt_at, d.last_response_status, d.last_error, d.created_at
+        FROM webhook_deliveries d
+        JOIN webhook_endpoints e ON e.id = d.endpoint_id
+        ORDER BY d.created_at DESC
+        LIMIT $1"#,
+        limit
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Sends a delivery that gave up again, with a fresh set of attempts
+pub async fn retry_webhook_delivery(pool: &PgPool, delivery_id: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE webhook_deliveries SET status = 'pending', attempts = 0, next_attempt_at = NOW() WHERE id = $1 AND status = 'failed'",
+        delivery_id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+// #######################################################################################################################################################
+// Delivery
+// #######################################################################################################################################################
+
+/// With the backoff, the last attempt is made a little over four hours after the first
+const DELIVERY_POLICY: OutboxPolicy = OutboxPolicy {
+    items_per_poll: 20,
+    send_timeout: Duration::from_secs(10),
+    max_attempts: 10,
+    first_retry_delay_seconds: 30,
+    max_retry_delay_seconds: 6 * 60 * 60,
+};
+
+pub const SIGNATURE_HEADER: &str = "X-Dancexam-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-Dancexam-Timestamp";
+pub const EVENT_HEADER: &str = "X-Dancexam-Event";
+pub const DELIVERY_HEADER: &str = "X-Dancexam-Delivery";
+
+/// Signs `<timestamp>.<payload>` with the endpoint's secret. Receivers recompute this to check that a delivery is
+/// genuine, and check the timestamp is recent to reject replays.
+pub fn sign_payload(secret: &str, timestamp: i64, payload: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}.{}", timestamp, payload).as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// A delivery that is due, along with where to send it
+struct DueDelivery {
+    id: Uuid,
+    event: String,
+    payload: String,
+    attempts: i32,
+    url: String,
+    secret: String,
+}
+
+/// What happened when a delivery was attempted
+#[derive(Debug, PartialEq)]
+struct DeliveryAttempt {
+    response_status: Option<u16>,
+    error: Option<String>,
+}
+
+/// Posts the payload to the endpoint. Any 2xx response counts as delivered.
+async fn send_webhook(client: &Client, delivery: &DueDelivery) -> DeliveryAttempt {
+    let timestamp = Utc::now().timestamp();
+
+    let response = client
+        .post(&delivery.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(EVENT_HEADER, &delivery.event)
+        .header(DELIVERY_HEADER, delivery.id.to_string())
+        .header(TIMESTAMP_HEADER, timestamp.to_string())
+        .header(SIGNATURE_HEADER, sign_payload(&delivery.secret, timestamp, &delivery.payload))
+        .body(delivery.payload.clone())
+        .send()
+        .await;
+
+    match response {
+        Ok(response) if response.status().is_success() => DeliveryAttempt { response_status: Some(response.status().as_u16()), error: None },
+        Ok(response) => DeliveryAttempt {
+            response_status: Some(response.status().as_u16()),
+            error: Some(format!("The endpoint responded with {}", response.status())),
+        },
+        Err(e) => DeliveryAttempt { response_status: None, error: Some(e.to_string()) },
+    }
+}
+
+/// The webhook_deliveries table
+struct WebhookOutbox {
+    pool: PgPool,
+    client: Client,
+}
+
+impl Outbox for WebhookOutbox {
+    type Item = DueDelivery;
+    type Attempt = DeliveryAttempt;
+
+    const POLICY: OutboxPolicy = DELIVERY_POLICY;
+    const NAME: &'static str = "webhooks";
+
+    async fn claim_due(&self) -> Result<Vec<DueDelivery>, sqlx::Error> {
+        sqlx::query_as!(
+            DueDelivery,
+            "UPDATE webhook_deliveries d SET next_attempt_at = NOW() + make_interval(secs => $1)
+            FROM webhook_endpoints e
+            WHERE e.id = d.endpoint_id AND d.id IN (
+                SELECT id FROM webhook_deliveries WHERE status = 'pending' AND next_attempt_at <= NOW()
+                ORDER BY next_attempt_at LIMIT $2 FOR UPDATE SKIP LOCKED
+            )
+            RETURNING d.id, d.event, d.payload, d.attempts, e.url, e.secret",
+            Self::POLICY.claim_lease_seconds(),
+            Self::POLICY.items_per_poll
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    fn previous_attempts(delivery: &DueDelivery) -> i32 {
+        delivery.attempts
+    }
+
+    async fn send(&self, delivery: &DueDelivery) -> DeliveryAttempt {
+        send_webhook(&self.client, delivery).await
+    }
+
+    fn succeeded(attempt: &DeliveryAttempt) -> bool {
+        attempt.error.is_none()
+    }
+
+    async fn record_attempt(&self, delivery: &DueDelivery, attempt: DeliveryAttempt, recorded: RecordedAttempt) -> Result<(), sqlx::Error> {
+        let status = match recorded.outcome {
+            AttemptOutcome::Succeeded => WebhookDeliveryStatus::Delivered,
+            AttemptOutcome::Retrying => WebhookDeliveryStatus::Pending,
+            AttemptOutcome::GaveUp => WebhookDeliveryStatus::Failed,
+        };
+
+        sqlx::query!(
+            "UPDATE webhook_deliveries SET
+                status = $2::varchar, attempts = $3, next_attempt_at = $4, last_attempt_at = NOW(), last_response_status = $5, last_error = $6,
+                delivered_at = CASE WHEN $2::varchar = 'delivered' THEN NOW() END
+            WHERE id = $1",
+            delivery.id,
+            status as WebhookDeliveryStatus,
+            recorded.attempts,
+            recorded.next_attempt_at,
+            attempt.response_status.map(i32::from),
+            attempt.error
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// Runs for the life of the server, sending webhooks from the outbox
+pub async fn deliver_webhooks(data: Arc<AppState>) {
+    let client = Client::builder()
+        .timeout(DELIVERY_POLICY.send_timeout)
+        // Receivers are configured by admins, but don't let a redirect point a delivery somewhere else
+        .redirect(Policy::none())
+        .build()
+        .expect("Client should build");
+
+    run_outbox(WebhookOutbox { pool: data.db.clone(), client }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{http::HeaderMap, routing::post, Router};
+    use tokio::sync::mpsc;
+
+    fn due_delivery(url: String) -> DueDelivery {
+        DueDelivery {
+            id: Uuid::new_v4(),
+            event: WebhookEvent::TestSaved.to_string(),
+            payload: event_payload(WebhookEvent::TestSaved, json!({ "test_id": Uuid::new_v4() })).unwrap(),
+            attempts: 0,
+            url,
+            secret: "whsec_test".to_string(),
+        }
+    }
+
+    /// Starts a receiver on a local port that records what it was sent and answers with the given status
+    async fn spawn_receiver(status: axum::http::StatusCode) -> (String, mpsc::UnboundedReceiver<(HeaderMap, String)>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let app = Router::new().route("/hook", post(move |headers: HeaderMap, body: String| {
+            let sender = sender.clone();
+            async move {
+                sender.send((headers, body)).unwrap();
+                status
+            }
+        }));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, receiver)
+    }
+
+    #[tokio::test]
+    async fn deliveries_are_signed() {
+        let (url, mut received) = spawn_receiver(axum::http::StatusCode::NO_CONTENT).await;
+        let delivery = due_delivery(url);
+
+        let attempt = send_webhook(&Client::new(), &delivery).await;
+        assert_eq!(attempt, DeliveryAttempt { response_status: Some(204), error: None });
+
+        let (headers, body) = received.recv().await.unwrap();
+        assert_eq!(body, delivery.payload);
+        assert_eq!(headers[EVENT_HEADER], "test.saved");
+        assert_eq!(headers[DELIVERY_HEADER], delivery.id.to_string().as_str());
+
+        let timestamp: i64 = headers[TIMESTAMP_HEADER].to_str().unwrap().parse().unwrap();
+        assert_eq!(headers[SIGNATURE_HEADER], sign_payload("whsec_test", timestamp, &body).as_str());
+        assert_ne!(headers[SIGNATURE_HEADER], sign_payload("whsec_other", timestamp, &body).as_str());
+    }
+
+    #[tokio::test]
+    async fn error_responses_are_failed_attempts() {
+        let (url, _received) = spawn_receiver(axum::http::StatusCode::SERVICE_UNAVAILABLE).await;
+
+        let attempt = send_webhook(&Client::new(), &due_delivery(url)).await;
+        assert_eq!(attempt.response_status, Some(503));
+        assert!(attempt.error.is_some());
+    }
+
+    #[test]
+    fn payloads_name_their_event() {
+        let payload: serde_json::Value = serde_json::from_str(&event_payload(WebhookEvent::TesteeEnqueued, json!({ "a": 1 })).unwrap()).unwrap();
+        assert_eq!(payload["event"], "testee.enqueued");
+        assert_eq!(payload["data"], json!({ "a": 1 }));
+    }
+}